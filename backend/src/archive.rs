@@ -0,0 +1,256 @@
+use serde_json;
+use std::io::{Read, Seek};
+use tweetstore::{Snowflake, TweetAuthor, TweetEntities, TweetFromTwitter};
+use zip::ZipArchive;
+
+// A user's own tweets, from the ZIP of their data which Twitter exports on request.
+#[derive(Debug, Eq, PartialEq)]
+pub struct TwitterArchive {
+    pub screen_name: String,
+    pub tweets: Vec<TweetFromTwitter>,
+}
+
+impl TwitterArchive {
+    // Reads the account and every part of the tweets from the ZIP, oldest tweet first.
+    pub fn read<R: Read + Seek>(reader: R) -> Result<TwitterArchive, String> {
+        let mut zip =
+            ZipArchive::new(reader).map_err(|err| format!("Error opening archive: {}", err))?;
+        let mut account = None;
+        let mut archived_tweets = Vec::new();
+        for index in 0..zip.len() {
+            let mut file = zip
+                .by_index(index)
+                .map_err(|err| format!("Error reading archive: {}", err))?;
+            let kind = match ArchiveFile::from_name(file.name()) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let mut js = String::new();
+            file.read_to_string(&mut js)
+                .map_err(|err| format!("Error reading {} from archive: {}", file.name(), err))?;
+            let json = strip_assignment(&js)
+                .ok_or_else(|| format!("Unexpected contents of {} in archive", file.name()))?;
+            match kind {
+                ArchiveFile::Account => {
+                    let entries: Vec<AccountEntry> = serde_json::from_str(json)
+                        .map_err(|err| format!("Error parsing account from archive: {}", err))?;
+                    account = entries.into_iter().next().map(|entry| entry.account);
+                }
+                ArchiveFile::Tweets => {
+                    let entries: Vec<ArchivedTweetEntry> = serde_json::from_str(json)
+                        .map_err(|err| format!("Error parsing tweets from archive: {}", err))?;
+                    archived_tweets.extend(
+                        entries
+                            .into_iter()
+                            .map(ArchivedTweetEntry::into_archived_tweet),
+                    );
+                }
+            }
+        }
+
+        let ArchivedAccount {
+            username,
+            display_name,
+        } = account.ok_or_else(|| "No account in archive".to_owned())?;
+        let author = TweetAuthor {
+            name: display_name.unwrap_or_else(|| username.clone()),
+            screen_name: username.clone(),
+        };
+        let mut tweets = archived_tweets
+            .into_iter()
+            .map(|archived_tweet| archived_tweet.into_tweet(&author))
+            .collect::<Result<Vec<_>, _>>()?;
+        tweets.sort();
+        tweets.dedup();
+        Ok(TwitterArchive {
+            screen_name: username,
+            tweets,
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ArchiveFile {
+    Account,
+    Tweets,
+}
+
+impl ArchiveFile {
+    // Tweets are in tweet.js or tweets.js depending on the age of the export, and large exports
+    // split them into tweet-part1.js and so on. tweet-headers.js and the like are ignored.
+    fn from_name(name: &str) -> Option<ArchiveFile> {
+        let file_name = name.rsplit('/').next()?;
+        let stem = file_name.trim_end_matches(".js");
+        if stem == file_name {
+            return None;
+        }
+        let stem = match stem.rfind("-part") {
+            Some(index) if stem[index + 5..].chars().all(|c| c.is_ascii_digit()) => &stem[..index],
+            _ => stem,
+        };
+        match stem {
+            "account" => Some(ArchiveFile::Account),
+            "tweet" | "tweets" => Some(ArchiveFile::Tweets),
+            _ => None,
+        }
+    }
+}
+
+// Each file is a script assigning JSON to a global, e.g. `window.YTD.tweet.part0 = [...]`.
+fn strip_assignment(js: &str) -> Option<&str> {
+    js.find('=').map(|index| js[index + 1..].trim())
+}
+
+#[derive(Deserialize)]
+struct AccountEntry {
+    account: ArchivedAccount,
+}
+
+#[derive(Deserialize)]
+struct ArchivedAccount {
+    username: String,
+    #[serde(rename = "accountDisplayName")]
+    display_name: Option<String>,
+}
+
+// Newer exports wrap each tweet in an object; older ones don't.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ArchivedTweetEntry {
+    Wrapped { tweet: ArchivedTweet },
+    Bare(ArchivedTweet),
+}
+
+impl ArchivedTweetEntry {
+    fn into_archived_tweet(self) -> ArchivedTweet {
+        match self {
+            ArchivedTweetEntry::Wrapped { tweet } => tweet,
+            ArchivedTweetEntry::Bare(tweet) => tweet,
+        }
+    }
+}
+
+// Ids are strings in archives. Archives don't say which tweet a retweet is of, so retweets can't
+// be told apart from other tweets.
+#[derive(Deserialize)]
+struct ArchivedTweet {
+    id_str: String,
+    full_text: Option<String>,
+    text: Option<String>,
+    created_at: Option<String>,
+    entities: Option<TweetEntities>,
+    in_reply_to_status_id_str: Option<String>,
+}
+
+impl ArchivedTweet {
+    fn into_tweet(self, author: &TweetAuthor) -> Result<TweetFromTwitter, String> {
+        let mut tweet = TweetFromTwitter::new(parse_id(&self.id_str)?);
+        tweet.text = self.full_text.or(self.text);
+        tweet.author = Some(author.clone());
+        tweet.created_at = self.created_at;
+        tweet.entities = self.entities;
+        tweet.in_reply_to_status_id = match self.in_reply_to_status_id_str {
+            Some(ref id) => Some(parse_id(id)?),
+            None => None,
+        };
+        Ok(tweet)
+    }
+}
+
+fn parse_id(id: &str) -> Result<Snowflake, String> {
+    id.parse()
+        .map(Snowflake)
+        .map_err(|err| format!("Bad tweet id {:?} in archive: {}", id, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArchiveFile, TwitterArchive};
+    use std::io::{Cursor, Write};
+    use tweetstore::{Snowflake, TweetAuthor};
+    use zip::write::{FileOptions, ZipWriter};
+
+    #[test]
+    fn archive_file_names() {
+        assert_eq!(
+            ArchiveFile::from_name("data/tweet.js"),
+            Some(ArchiveFile::Tweets)
+        );
+        assert_eq!(
+            ArchiveFile::from_name("data/tweets.js"),
+            Some(ArchiveFile::Tweets)
+        );
+        assert_eq!(
+            ArchiveFile::from_name("data/tweets-part2.js"),
+            Some(ArchiveFile::Tweets)
+        );
+        assert_eq!(
+            ArchiveFile::from_name("account.js"),
+            Some(ArchiveFile::Account)
+        );
+        assert_eq!(ArchiveFile::from_name("data/tweet-headers.js"), None);
+        assert_eq!(ArchiveFile::from_name("data/tweetdeck.js"), None);
+        assert_eq!(ArchiveFile::from_name("data/tweets_media/1.jpg"), None);
+    }
+
+    #[test]
+    fn read_archive() {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let files = vec![
+            (
+                "data/account.js",
+                r#"window.YTD.account.part0 = [ {
+                  "account" : { "username" : "someone", "accountDisplayName" : "Some One" }
+                } ]"#,
+            ),
+            (
+                "data/tweets.js",
+                r#"window.YTD.tweets.part0 = [ {
+                  "tweet" : {
+                    "id_str" : "1000",
+                    "id" : "1000",
+                    "full_text" : "Later",
+                    "in_reply_to_status_id_str" : "900",
+                    "entities" : { "hashtags" : [ { "text" : "tag", "indices" : [ "0", "4" ] } ] }
+                  }
+                } ]"#,
+            ),
+            (
+                "data/tweets-part1.js",
+                r#"window.YTD.tweets.part1 = [ { "id_str" : "500", "full_text" : "Earlier" } ]"#,
+            ),
+            (
+                "data/tweet-headers.js",
+                "window.YTD.tweet_headers.part0 = [ ]",
+            ),
+        ];
+        for (name, contents) in files {
+            zip.start_file(name, FileOptions::default())
+                .expect("Start file");
+            zip.write_all(contents.as_bytes()).expect("Write file");
+        }
+        let bytes = zip.finish().expect("Finish zip").into_inner();
+
+        let archive = TwitterArchive::read(Cursor::new(bytes)).expect("Read archive");
+        assert_eq!(archive.screen_name, "someone");
+        let ids: Vec<_> = archive.tweets.iter().map(|tweet| tweet.id).collect();
+        assert_eq!(ids, vec![Snowflake(500), Snowflake(1000)]);
+        let later = &archive.tweets[1];
+        assert_eq!(later.text, Some("Later".to_owned()));
+        assert_eq!(later.in_reply_to_status_id, Some(Snowflake(900)));
+        assert_eq!(
+            later.author,
+            Some(TweetAuthor {
+                screen_name: "someone".to_owned(),
+                name: "Some One".to_owned(),
+            })
+        );
+        assert_eq!(
+            later
+                .entities
+                .as_ref()
+                .map(|entities| entities.hashtags[0].text.clone()),
+            Some("tag".to_owned())
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use futures_util::future::FutureExt;
+use std::future::Future;
+use tokio::sync::RwLock;
+use {
+    ConflictPolicy, Interval, IntervalSet, IntervalStore, StoreView, UniquelyIdentifiedTimeValue,
+};
+
+// An IntervalStore behind a tokio lock, for use from async handlers: waiting for the lock yields
+// to other tasks instead of blocking the thread they run on.
+pub struct AsyncIntervalStore<Time, Value>
+where
+    Time: Ord,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+{
+    store: RwLock<IntervalStore<Time, Value>>,
+}
+
+impl<Time, Value> AsyncIntervalStore<Time, Value>
+where
+    Time: Ord + Copy + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + 'static,
+{
+    pub fn new(store: IntervalStore<Time, Value>) -> AsyncIntervalStore<Time, Value> {
+        AsyncIntervalStore {
+            store: RwLock::new(store),
+        }
+    }
+
+    pub fn has<'a>(&'a self, interval: &Interval<Time>) -> impl Future<Output = bool> + 'a {
+        let interval = *interval;
+        self.store.read().map(move |store| store.has(&interval))
+    }
+
+    pub fn missing<'a>(
+        &'a self,
+        interval: &Interval<Time>,
+    ) -> impl Future<Output = IntervalSet<Time>> + 'a {
+        let interval = *interval;
+        self.store.read().map(move |store| store.missing(&interval))
+    }
+
+    pub fn get<'a>(
+        &'a self,
+        interval: &Interval<Time>,
+    ) -> impl Future<Output = Option<Vec<Value>>> + 'a {
+        let interval = *interval;
+        self.store.read().map(move |store| store.get(&interval))
+    }
+
+    pub fn get_available<'a>(
+        &'a self,
+        interval: &Interval<Time>,
+    ) -> impl Future<Output = Vec<Value>> + 'a {
+        let interval = *interval;
+        self.store
+            .read()
+            .map(move |store| store.get_available(&interval))
+    }
+
+    // Releases the lock as soon as the view is taken, so that slow readers, e.g. serializing a
+    // large response, don't hold up writers.
+    pub fn view<'a>(&'a self) -> impl Future<Output = StoreView<Time, Value>> + 'a {
+        self.store.read().map(|store| store.view())
+    }
+
+    pub fn insert<'a>(
+        &'a self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+    ) -> impl Future<Output = Result<(), String>> + 'a {
+        self.insert_with_policy(interval, values, ConflictPolicy::Reject)
+    }
+
+    pub fn insert_with_policy<'a>(
+        &'a self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+        policy: ConflictPolicy,
+    ) -> impl Future<Output = Result<(), String>> + 'a {
+        let interval = *interval;
+        self.store
+            .write()
+            .map(move |mut store| store.insert_with_policy(&interval, values, policy))
+    }
+
+    pub fn remove<'a>(&'a self, interval: &Interval<Time>) -> impl Future<Output = ()> + 'a {
+        let interval = *interval;
+        self.store
+            .write()
+            .map(move |mut store| store.remove(&interval))
+    }
+
+    pub fn into_inner(self) -> IntervalStore<Time, Value> {
+        self.store.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncIntervalStore;
+    use futures_executor::block_on;
+    use {Interval, IntervalStore, UniquelyIdentifiedTimeValue};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Value(u64);
+
+    impl UniquelyIdentifiedTimeValue<u64> for Value {
+        type Id = u64;
+
+        fn time(&self) -> u64 {
+            self.0
+        }
+
+        fn id(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn insert_then_get() {
+        let store = AsyncIntervalStore::new(IntervalStore::new());
+        assert_eq!(block_on(store.get(&Interval(10, 20))), None);
+
+        block_on(store.insert(&Interval(10, 20), vec![Value(12), Value(15)])).expect("Insert");
+        assert_eq!(
+            block_on(store.get(&Interval(10, 15))),
+            Some(vec![Value(12), Value(15)])
+        );
+        assert!(block_on(store.has(&Interval(10, 20))));
+        assert_eq!(block_on(store.missing(&Interval(0, 20))).len(), 1);
+
+        let view = block_on(store.view());
+        block_on(store.remove(&Interval(10, 20)));
+        assert!(!block_on(store.has(&Interval(10, 20))));
+        assert_eq!(
+            block_on(store.get_available(&Interval(10, 20))),
+            Vec::<Value>::new()
+        );
+        assert_eq!(
+            view.get(&Interval(10, 20)),
+            Some(vec![Value(12), Value(15)])
+        );
+
+        assert!(block_on(store.insert(&Interval(0, 5), vec![Value(3)])).is_ok());
+        assert_eq!(store.into_inner().size().values, 1);
+    }
+}
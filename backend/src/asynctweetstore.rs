@@ -0,0 +1,88 @@
+use futures_util::future::{self, FutureExt};
+use std::future::Future;
+use tokio::task;
+use tweetstore::{FeedTweets, FetchError, Snowflake, TimelineOptions, TweetStore};
+use {Context, Interval};
+
+// TweetStore's fetching, for use from async handlers. Each fetch runs on tokio's blocking thread
+// pool, exactly as the blocking API would run it, so that slow Twitter requests don't tie up the
+// thread the handler runs on, and concurrent fetches of the same tweets are still shared. The
+// futures must be polled from within a tokio runtime. Dropping one doesn't stop its fetch, whose
+// tweets are still cached.
+impl TweetStore {
+    pub fn tweets_async(
+        &self,
+        context: Option<Context>,
+        user: String,
+        interval: Interval<Snowflake>,
+        options: TimelineOptions,
+    ) -> impl Future<Output = Result<FeedTweets, FetchError>> {
+        let store = self.clone();
+        // Spawned when first polled, as spawning needs the runtime.
+        future::lazy(move |_| {
+            task::spawn_blocking(move || store.tweets(context.as_ref(), &user, &interval, options))
+        })
+        .flatten()
+        .map(|result| {
+            result.unwrap_or_else(|err| Err(format!("Fetching tweets failed: {}", err).into()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cassette::{Cassette, CassetteMode};
+    use oauth;
+    use std::collections::HashSet;
+    use std::env;
+    use tokio::runtime;
+    use tweetstore::{
+        PreloadedInterval, RefreshPolicy, SearchEnvironments, Snowflake, StaleIfError,
+        TimelineOptions, TweetFromTwitter, TweetStore,
+    };
+    use {FetchStrategy, Interval};
+
+    #[test]
+    fn tweets_async_serves_cached_tweets() {
+        let store = TweetStore::new(
+            oauth::Oauth1Token {
+                oauth_token: "app".to_owned(),
+                oauth_token_secret: "secret".to_owned(),
+            },
+            HashSet::new(),
+            StaleIfError::default(),
+            RefreshPolicy::default(),
+            FetchStrategy::default(),
+            SearchEnvironments::default(),
+            vec![],
+        )
+        .with_cassette(Cassette::new(
+            CassetteMode::Replay,
+            env::temp_dir().join(format!("twimetravel-offline-{}", ::std::process::id())),
+        ));
+        store
+            .preload(&[PreloadedInterval {
+                user: "someone".to_owned(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+
+        let mut runtime = runtime::Builder::new().basic_scheduler().build().unwrap();
+        let feed_tweets = runtime
+            .block_on(store.tweets_async(
+                None,
+                "someone".to_owned(),
+                Interval(Snowflake(10), Snowflake(20)),
+                TimelineOptions::default(),
+            ))
+            .unwrap();
+        assert_eq!(
+            feed_tweets.tweets,
+            vec![TweetFromTwitter::new(Snowflake(15))]
+        );
+        assert!(!feed_tweets.partial);
+    }
+}
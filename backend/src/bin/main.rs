@@ -1,4 +1,5 @@
 extern crate env_logger;
+extern crate futures;
 extern crate gotham;
 #[macro_use]
 extern crate gotham_derive;
@@ -19,16 +20,25 @@ extern crate twimetravel;
 extern crate url;
 extern crate walkdir;
 
+use futures::{Future, Stream};
+use gotham::handler::IntoHandlerError;
 use gotham::router::builder::{DefineSingleRoute, DrawRoutes};
 use gotham::state::FromState;
-use hyper::header::AccessControlAllowOrigin;
+use hyper::header::{AccessControlAllowOrigin, RetryAfter, Warning};
 use mime_guess::from_ext;
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use twimetravel::{
-    oauth, Context, Interval, SecondsSinceUnixEpoch, TweetStore, UniquelyIdentifiedTimeValue,
+    oauth, CacheLimits, Cassette, CassetteMode, Context, EventLog, ExportFormat, FeedTweets,
+    FetchError, FetchPriority, FetchRule, FetchSchedulingPolicy, FetchStrategy, FrontendEvent,
+    HttpTimeouts, Interval, MediaProxy, PreloadedInterval, Quota, RefreshPolicy,
+    RevalidationPolicy, Role, SearchEnvironments, SearchOptions, SecondsSinceUnixEpoch,
+    SessionBackend, ShareLink, ShareLinkSigner, Snowflake, SourceCapabilities, StaleIfError,
+    TimelineOptions, TweetAuthor, TweetEntities, TweetFromTwitter, TweetRefreshPolicy, TweetSource,
+    TweetStore, UniquelyIdentifiedTimeValue, WindowLimits,
 };
 use walkdir::WalkDir;
 
@@ -71,11 +81,89 @@ fn main() {
     }
 
     let server = Server::new(&config, static_bytes);
+    let background_tweets = server
+        .tweets
+        .clone()
+        .with_priority(FetchPriority::Background);
+    if let Some(ref revalidation) = config.revalidation {
+        spawn_revalidation(background_tweets.clone(), revalidation);
+    }
+    if let Some(ref tweet_refresh) = config.tweet_refresh {
+        spawn_tweet_refresh(background_tweets.clone(), tweet_refresh);
+    }
+    if let Some(ref cache_file) = config.cache_file {
+        spawn_cache_saving(server.tweets.clone(), cache_file);
+    }
+    if let Some(ref live_edge) = config.live_edge {
+        spawn_live_edge(background_tweets.clone(), live_edge);
+    }
 
     println!("Listening for requests at http://{}", config.listen_address);
     gotham::start(config.listen_address, router(server))
 }
 
+// Periodically drops cached tweets which have been deleted upstream, for as long as the server runs.
+fn spawn_revalidation(tweets: TweetStore, config: &RevalidationConfig) {
+    let every = Duration::from_secs(config.every_secs);
+    let policy = RevalidationPolicy {
+        max_age: config.max_age_secs.map(Duration::from_secs),
+        drop_withheld: config.drop_withheld,
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        match tweets.revalidate(&policy) {
+            Ok(dropped) => info!("Revalidated cached tweets, dropping {}", dropped),
+            Err(err) => warn!("Error revalidating cached tweets: {}", err),
+        }
+    });
+}
+
+// Periodically looks up stale cached tweets again, for as long as the server runs.
+fn spawn_tweet_refresh(tweets: TweetStore, config: &TweetRefreshConfig) {
+    let every = Duration::from_secs(config.every_secs);
+    let policy = TweetRefreshPolicy {
+        max_staleness: Duration::from_secs(config.max_staleness_secs),
+        max_tweets: config.max_tweets,
+    };
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        match tweets.refresh_stale(&policy) {
+            Ok((refreshed, dropped)) => info!(
+                "Refreshed {} stale cached tweets, dropping {}",
+                refreshed, dropped
+            ),
+            Err(err) => warn!("Error refreshing stale cached tweets: {}", err),
+        }
+    });
+}
+
+// Periodically saves the cache, for as long as the server runs, to be loaded on the next start.
+fn spawn_cache_saving(tweets: TweetStore, config: &CacheFileConfig) {
+    let every = Duration::from_secs(config.save_every_secs);
+    let path = config.path.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(every);
+        if let Err(err) = tweets.save(&path) {
+            warn!("Error saving cached tweets: {}", err);
+        }
+    });
+}
+
+// Periodically fetches the followed users' newest tweets, for as long as the server runs.
+fn spawn_live_edge(tweets: TweetStore, config: &LiveEdgeConfig) {
+    let every = Duration::from_secs(config.every_secs);
+    let window = Duration::from_secs(config.window_secs);
+    let users = config.users.clone();
+    std::thread::spawn(move || loop {
+        for user in &users {
+            if let Err(err) = tweets.poll_live_edge(user, window) {
+                warn!("Error polling newest tweets for {}: {}", user, err);
+            }
+        }
+        std::thread::sleep(every);
+    });
+}
+
 fn read_file<P: AsRef<Path>>(path: P) -> Vec<u8> {
     let mut bytes = Vec::new();
     let mut file = std::fs::File::open(path).expect("Opening file");
@@ -87,10 +175,22 @@ struct Server {
     oauth_handler: oauth::OauthHandler,
     tweets: TweetStore,
     static_bytes: HashMap<String, (Vec<u8>, mime::Mime)>,
+    search_enabled_display_names: HashSet<String>,
+    admins: Arc<HashSet<String>>,
+    api_keys: HashMap<String, Context>,
+    window_limits: WindowLimits,
+    share_links: Option<ShareLinks>,
+    public_display_names: HashSet<String>,
+    public_feed_rate_limiter: RateLimiter,
+    events: Arc<EventLog>,
+    snap_slack: Duration,
     domain_name: String,
     cors_origin: String,
     index_url: url::Url,
     oauth_request_url: url::Url,
+    login_cancelled_url: url::Url,
+    media_proxy: Option<MediaProxy>,
+    session_backend: SessionBackend,
 }
 
 impl<'a> Server {
@@ -102,14 +202,79 @@ impl<'a> Server {
         let tweets = TweetStore::new(
             app_token.clone(),
             config.search_enabled_display_names.clone(),
+            StaleIfError {
+                max_staleness: config
+                    .stale_if_error
+                    .max_staleness_secs
+                    .map(Duration::from_secs),
+                max_error_duration: config
+                    .stale_if_error
+                    .max_error_duration_secs
+                    .map(Duration::from_secs),
+            },
+            config.refresh_after_secs.clone(),
+            if config.fetch_rules.is_empty() {
+                FetchStrategy::default()
+            } else {
+                FetchStrategy::new(config.fetch_rules.clone())
+            },
+            config.search_environments.clone(),
+            config.quotas.clone(),
         );
+        let http_client = Self::http_client(config);
+        let tweets = tweets
+            .with_cache_limits(config.cache_limits)
+            .with_http_client(http_client.clone());
+        let tweets = match config.max_concurrent_twitter_requests {
+            Some(max_requests) => tweets.with_max_concurrent_requests(max_requests),
+            None => tweets,
+        };
+        let tweets = tweets.with_fetch_scheduling(config.fetch_scheduling);
+        let tweets = match config.http_cache_max_responses {
+            Some(max_responses) => tweets.with_http_cache(max_responses),
+            None => tweets,
+        };
+        if let Some(ref cache_file) = config.cache_file {
+            // There's nothing to load on the first start.
+            if Path::new(&cache_file.path).exists() {
+                let users = tweets
+                    .load(&cache_file.path)
+                    .unwrap_or_else(|err| panic!("Error loading cached tweets: {}", err));
+                info!("Loaded cached tweets for {} users", users);
+            }
+        }
+        let tweets = match config.cassette {
+            Some(ref cassette) => {
+                tweets.with_cassette(Cassette::new(cassette.mode, cassette.dir.clone()))
+            }
+            None => tweets,
+        };
+        let tweets = match (&config.sqlite_path, &config.redis_url) {
+            (&Some(_), &Some(_)) => panic!("At most one of sqlite_path and redis_url may be set"),
+            (&Some(ref path), &None) => Self::with_sqlite(tweets, path),
+            (&None, &Some(ref url)) => Self::with_redis(tweets, url),
+            (&None, &None) => tweets,
+        };
+        tweets
+            .preload(&config.preload)
+            .expect("Error preloading tweets");
+        for path in config.import_archives.iter() {
+            Self::import_archive(&tweets, path, config.hydrate_imports);
+        }
 
         let oauth_handler = oauth::OauthHandler::new(
             url::Url::parse("https://api.twitter.com/oauth/request_token").unwrap(),
             url::Url::parse("https://api.twitter.com/oauth/authenticate").unwrap(),
             url::Url::parse("https://api.twitter.com/1.1/account/verify_credentials.json").unwrap(),
             app_token,
-        );
+        )
+        .with_http_client(http_client.clone());
+        let media_proxy = config.media_proxy.as_ref().map(|media_proxy| {
+            MediaProxy::new(
+                http_client,
+                media_proxy.cache_dir.as_ref().map(PathBuf::from),
+            )
+        });
 
         let domain_name = config.domain_name.clone();
         let cors_origin = format!("https://{}", domain_name);
@@ -117,18 +282,154 @@ impl<'a> Server {
             url::Url::parse(&format!("{}/", cors_origin)).expect("Failed to parse index URL");
         let oauth_request_url = url::Url::parse(&format!("https://{}/oauth-request", domain_name))
             .expect("Failed to parse oauth request URL");
+        let login_cancelled_url = match config.oauth.login_cancelled_url {
+            Some(ref login_cancelled_url) => {
+                url::Url::parse(login_cancelled_url).expect("Failed to parse login cancelled URL")
+            }
+            None => index_url.clone(),
+        };
 
         Server {
             oauth_handler,
             tweets,
             static_bytes,
+            search_enabled_display_names: config.search_enabled_display_names.clone(),
+            admins: Arc::new(config.admins.clone()),
+            api_keys: config
+                .api_keys
+                .iter()
+                .map(|api_key| {
+                    (
+                        api_key.key.clone(),
+                        Context {
+                            user_oauth_token: oauth::Oauth1Token {
+                                oauth_token: api_key.oauth_token.clone(),
+                                oauth_token_secret: api_key.oauth_token_secret.clone(),
+                            },
+                            user_screen_name: api_key.screen_name.clone(),
+                        },
+                    )
+                })
+                .collect(),
+            window_limits: config.max_window_secs.clone(),
+            share_links: config.share_links.as_ref().map(|share_links| ShareLinks {
+                signer: ShareLinkSigner::new(share_links.secret.as_bytes()),
+                ttl: Duration::from_secs(share_links.ttl_secs),
+            }),
+            public_display_names: config.public_display_names.clone(),
+            public_feed_rate_limiter: RateLimiter::new(
+                config.public_feed_requests_per_minute,
+                Duration::from_secs(60),
+            ),
+            events: Arc::new(EventLog::new(config.event_log_capacity)),
+            snap_slack: Duration::from_secs(config.snap_slack_secs),
             domain_name,
             cors_origin,
             index_url,
             oauth_request_url,
+            login_cancelled_url,
+            media_proxy,
+            session_backend: match config.session_redis_url {
+                Some(ref url) => Self::redis_session_backend(url),
+                None => SessionBackend::default(),
+            },
+        }
+    }
+
+    fn http_client(config: &Config) -> reqwest::blocking::Client {
+        let builder = config.http_timeouts.client_builder();
+        let builder = match config.http_proxy {
+            Some(ref http_proxy) => builder.proxy(
+                reqwest::Proxy::all(http_proxy.as_str())
+                    .unwrap_or_else(|err| panic!("Bad http_proxy {}: {}", http_proxy, err)),
+            ),
+            None => builder,
+        };
+        builder
+            .build()
+            .unwrap_or_else(|err| panic!("Error building HTTP client: {}", err))
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn with_sqlite(tweets: TweetStore, path: &str) -> TweetStore {
+        let persistence = twimetravel::SqlitePersistence::open(path)
+            .unwrap_or_else(|err| panic!("Error opening {}: {}", path, err));
+        tweets
+            .with_persistence(Arc::new(persistence))
+            .unwrap_or_else(|err| panic!("Error loading tweets from {}: {}", path, err))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    fn with_sqlite(_tweets: TweetStore, path: &str) -> TweetStore {
+        panic!(
+            "sqlite_path is set to {}, but the server was built without the sqlite feature",
+            path
+        )
+    }
+
+    #[cfg(feature = "redis")]
+    fn redis_session_backend(url: &str) -> SessionBackend {
+        twimetravel::RedisSessionBackend::open(url, SESSION_TTL)
+            .map(SessionBackend::Redis)
+            .unwrap_or_else(|err| panic!("Error opening {}: {}", url, err))
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn redis_session_backend(url: &str) -> SessionBackend {
+        panic!(
+            "session_redis_url is set to {}, but the server was built without the redis feature",
+            url
+        )
+    }
+
+    #[cfg(feature = "archive")]
+    fn import_archive(tweets: &TweetStore, path: &str, hydrate: bool) {
+        let file = std::fs::File::open(path)
+            .unwrap_or_else(|err| panic!("Error opening {}: {}", path, err));
+        let archive = twimetravel::TwitterArchive::read(std::io::BufReader::new(file))
+            .unwrap_or_else(|err| panic!("Error reading {}: {}", path, err));
+        let ids: Vec<_> = archive.tweets.iter().map(|tweet| tweet.id).collect();
+        match tweets.import(&archive.screen_name, archive.tweets) {
+            Ok(Some(interval)) => info!(
+                "Imported tweets by {} from {} to {} from {}",
+                archive.screen_name, interval.0, interval.1, path
+            ),
+            Ok(None) => info!("No tweets to import from {}", path),
+            Err(err) => panic!("Error importing {}: {}", path, err),
+        }
+        if hydrate {
+            match tweets.hydrate(&archive.screen_name, &ids) {
+                Ok(hydrated) => info!("Hydrated {} tweets from {}", hydrated.len(), path),
+                Err(err) => warn!("Error hydrating tweets from {}: {}", path, err),
+            }
         }
     }
 
+    #[cfg(not(feature = "archive"))]
+    fn import_archive(_tweets: &TweetStore, path: &str, _hydrate: bool) {
+        panic!(
+            "import_archives includes {}, but the server was built without the archive feature",
+            path
+        )
+    }
+
+    #[cfg(feature = "redis")]
+    fn with_redis(tweets: TweetStore, url: &str) -> TweetStore {
+        let persistence = twimetravel::RedisPersistence::open(url)
+            .unwrap_or_else(|err| panic!("Error opening {}: {}", url, err));
+        tweets
+            .with_persistence(Arc::new(persistence))
+            .unwrap_or_else(|err| panic!("Error loading tweets from {}: {}", url, err))
+    }
+
+    #[cfg(not(feature = "redis"))]
+    fn with_redis(_tweets: TweetStore, url: &str) -> TweetStore {
+        panic!(
+            "redis_url is set to {}, but the server was built without the redis feature",
+            url
+        )
+    }
+
     pub fn static_page(
         &self,
         state: gotham::state::State,
@@ -136,10 +437,9 @@ impl<'a> Server {
         let res = {
             let path = hyper::Uri::borrow_from(&state).path();
             if path == "/"
-                && gotham::middleware::session::SessionData::<Option<oauth::Context>>::borrow_from(
-                    &state,
-                )
-                .is_none()
+                && gotham::middleware::session::SessionData::<oauth::Session>::borrow_from(&state)
+                    .context
+                    .is_none()
             {
                 let redirect_url = {
                     let uri = hyper::Uri::borrow_from(&state);
@@ -175,7 +475,7 @@ impl<'a> Server {
 
     pub fn oauth_request(
         &self,
-        state: gotham::state::State,
+        mut state: gotham::state::State,
     ) -> (gotham::state::State, hyper::Response) {
         let redirect_url = {
             let query_params: &RedirectUrlQueryParam = RedirectUrlQueryParam::borrow_from(&state);
@@ -194,7 +494,10 @@ impl<'a> Server {
             }
         };
         let response = match self.oauth_handler.dance(redirect_url) {
-            Ok(url_to_redirect_to) => {
+            Ok((url_to_redirect_to, awaiting_callback)) => {
+                let session: &mut oauth::Session =
+                    gotham::middleware::session::SessionData::borrow_mut_from(&mut state);
+                session.awaiting_callback = Some(awaiting_callback);
                 gotham::http::response::create_response(&state, hyper::StatusCode::Found, None)
                     .with_header(hyper::header::Location::new(
                         url_to_redirect_to.into_string(),
@@ -214,26 +517,65 @@ impl<'a> Server {
     ) -> (gotham::state::State, hyper::Response) {
         let response = {
             let exchange_result = {
+                let awaiting_callback = {
+                    let session: &mut oauth::Session =
+                        gotham::middleware::session::SessionData::borrow_mut_from(&mut state);
+                    session.awaiting_callback.take()
+                };
                 let query_params = OauthCallbackQueryParam::borrow_from(&state);
-                self.oauth_handler.exchange(
-                    query_params.oauth_token.clone(),
-                    query_params.oauth_verifier.clone(),
-                )
+                match (awaiting_callback, query_params) {
+                    (
+                        _,
+                        &OauthCallbackQueryParam {
+                            denied: Some(ref token),
+                            ..
+                        },
+                    ) => {
+                        info!("User cancelled logging in with token {}", token);
+                        None
+                    }
+                    (
+                        Some(ref awaiting_callback),
+                        &OauthCallbackQueryParam {
+                            oauth_token: Some(ref token),
+                            oauth_verifier: Some(ref verifier),
+                            ..
+                        },
+                    ) if awaiting_callback.is_for(token) => Some(self.oauth_handler.exchange(
+                        awaiting_callback,
+                        token.clone(),
+                        verifier.clone(),
+                    )),
+                    // e.g. the session expired, or the callback was for a different dance.
+                    (_, query_params) => {
+                        warn!(
+                            "OAuth callback {:?} isn't for a dance started by the session",
+                            query_params
+                        );
+                        None
+                    }
+                }
             };
             match exchange_result {
-                Ok((url, context)) => {
+                None => {
+                    gotham::http::response::create_response(&state, hyper::StatusCode::Found, None)
+                        .with_header(hyper::header::Location::new(
+                            self.login_cancelled_url.as_str().to_owned(),
+                        ))
+                }
+                Some(Ok((url, context))) => {
                     let response = gotham::http::response::create_response(
                         &state,
                         hyper::StatusCode::Found,
                         None,
                     )
                     .with_header(hyper::header::Location::new(url.into_string()));
-                    let session_data: &mut Option<Context> =
+                    let session: &mut oauth::Session =
                         gotham::middleware::session::SessionData::borrow_mut_from(&mut state);
-                    *session_data = Some(context);
+                    session.context = Some(context);
                     response
                 }
-                Err(err) => {
+                Some(Err(err)) => {
                     warn!("Error in oauth callback: {}", err);
                     Self::internal_server_error(&state)
                 }
@@ -245,23 +587,34 @@ impl<'a> Server {
     pub fn feed(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
         let response = {
             let feed_path = FeedPath::borrow_from(&state);
-            let maybe_context: &Option<Context> =
-                gotham::middleware::session::SessionData::borrow_from(&state);
-            let mut response = match maybe_context {
-                &Some(ref context) => {
-                    let (status_code, contents) = self
-                        .feed_impl(feed_path, context)
-                        .map(|v| (hyper::StatusCode::Ok, v))
-                        .unwrap_or_else(|(status_code, contents)| {
-                            (status_code, contents.as_bytes().to_vec())
+            let query = FeedQueryParam::borrow_from(&state);
+            let snap = query.snap.unwrap_or(false);
+            let detail = query.detail.unwrap_or(FeedDetail::Ids);
+            let options = query.timeline_options();
+            let mut response = match self.context(&state) {
+                Some(context) => {
+                    let result = self
+                        .validate_window(feed_path.from, feed_path.until, self.role(Some(context)))
+                        .and_then(|()| {
+                            self.feed_impl(feed_path, Some(context), snap, detail, options)
                         });
-                    gotham::http::response::create_response(
-                        &state,
-                        status_code,
-                        Some((contents, mime::APPLICATION_JSON)),
-                    )
+                    self.feed_response(&state, result)
+                }
+                None if self.public_display_names.contains(&feed_path.who) => {
+                    if self.public_feed_rate_limiter.allow(&feed_path.who) {
+                        let result = self
+                            .validate_window(feed_path.from, feed_path.until, Role::Anonymous)
+                            .and_then(|()| self.feed_impl(feed_path, None, snap, detail, options));
+                        self.feed_response(&state, result)
+                    } else {
+                        gotham::http::response::create_response(
+                            &state,
+                            hyper::StatusCode::TooManyRequests,
+                            Some(("Too many requests".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+                        )
+                    }
                 }
-                &None => {
+                None => {
                     eprintln!("Not authorized");
                     gotham::http::response::create_response(
                         &state,
@@ -281,115 +634,1558 @@ impl<'a> Server {
         (state, response)
     }
 
-    fn feed_impl(
+    // Lists may be private, so unlike user feeds these always need a logged in user.
+    pub fn list_feed(
         &self,
-        feed_path: &FeedPath,
-        context: &Context,
-    ) -> Result<Vec<u8>, (hyper::StatusCode, String)> {
-        let tweets: Vec<_> = self
-            .tweets
-            .tweets(
-                context,
-                &feed_path.who,
-                &Interval(feed_path.from.into(), feed_path.until.into()),
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let list_feed_path = ListFeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(
+                &state,
+                list_feed_path.from,
+                list_feed_path.until,
+                |context| {
+                    let interval =
+                        Interval(list_feed_path.from.into(), list_feed_path.until.into());
+                    self.tweets
+                        .list_tweets(Some(context), &list_feed_path.list_id, &interval)
+                        .map_err(Self::fetch_error)
+                        .and_then(|feed_tweets| {
+                            Self::filtered_feed_contents(feed_tweets, list_feed_path.from, query)
+                        })
+                },
             )
-            .iter()
-            .map(|tweet| {
-                let seconds_since_unix_epoch: SecondsSinceUnixEpoch = tweet.time().into();
-                TweetForJavascript {
-                    id: format!("{}", tweet.id),
-                    seconds_since_start: seconds_since_unix_epoch.0 - feed_path.from.0,
+        };
+        (state, response)
+    }
+
+    pub fn likes_feed(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let likes_feed_path = LikesFeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(
+                &state,
+                likes_feed_path.from,
+                likes_feed_path.until,
+                |context| {
+                    let interval =
+                        Interval(likes_feed_path.from.into(), likes_feed_path.until.into());
+                    self.tweets
+                        .liked_tweets(Some(context), &likes_feed_path.who, &interval)
+                        .map_err(Self::fetch_error)
+                        .and_then(|feed_tweets| {
+                            Self::filtered_feed_contents(feed_tweets, likes_feed_path.from, query)
+                        })
+                },
+            )
+        };
+        (state, response)
+    }
+
+    // Other users' mentions are found by searching, so are limited to users allowed to fall back to
+    // search.
+    pub fn mentions_feed(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let feed_path = FeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(&state, feed_path.from, feed_path.until, |context| {
+                let own_mentions = context
+                    .user_screen_name
+                    .eq_ignore_ascii_case(&feed_path.who);
+                match self.role(Some(context)) {
+                    _ if own_mentions => {}
+                    Role::SearchEnabled | Role::Admin => {}
+                    _ => {
+                        return Err(ErrorResponse::new(
+                            hyper::StatusCode::Forbidden,
+                            "Search isn't enabled for you".to_owned(),
+                        ))
+                    }
                 }
+                let interval = Interval(feed_path.from.into(), feed_path.until.into());
+                self.tweets
+                    .mentions_tweets(Some(context), &feed_path.who, &interval)
+                    .map_err(Self::fetch_error)
+                    .and_then(|feed_tweets| {
+                        Self::filtered_feed_contents(feed_tweets, feed_path.from, query)
+                    })
             })
-            .collect();
+        };
+        (state, response)
+    }
 
-        let contents = serde_json::to_vec(&tweets).map_err(|err| {
-            (
-                hyper::StatusCode::InternalServerError,
-                format!("Error serializing JSON: {:?}", err),
-            )
-        })?;
-        Ok(contents)
+    // Hashtags can only be followed by searching, so are limited to users allowed to fall back to
+    // search.
+    pub fn tag_feed(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let tag_feed_path = TagFeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(&state, tag_feed_path.from, tag_feed_path.until, |context| {
+                match self.role(Some(context)) {
+                    Role::SearchEnabled | Role::Admin => {}
+                    _ => {
+                        return Err(ErrorResponse::new(
+                            hyper::StatusCode::Forbidden,
+                            "Search isn't enabled for you".to_owned(),
+                        ))
+                    }
+                }
+                let interval = Interval(tag_feed_path.from.into(), tag_feed_path.until.into());
+                self.tweets
+                    .hashtag_tweets(Some(context), &tag_feed_path.tag, &interval)
+                    .map_err(Self::fetch_error)
+                    .and_then(|feed_tweets| {
+                        Self::filtered_feed_contents(feed_tweets, tag_feed_path.from, query)
+                    })
+            })
+        };
+        (state, response)
     }
 
-    fn internal_server_error(state: &gotham::state::State) -> hyper::Response {
-        gotham::http::response::create_response(
-            &state,
-            hyper::StatusCode::InternalServerError,
-            Some((
-                "Internal server error".as_bytes().to_vec(),
-                mime::TEXT_PLAIN,
-            )),
-        )
+    // Searching is billed per request, so is limited to users allowed to fall back to search.
+    pub fn search_feed(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let feed_path = FeedPath::borrow_from(&state);
+            let query = SearchFeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(&state, feed_path.from, feed_path.until, |context| {
+                match self.role(Some(context)) {
+                    Role::SearchEnabled | Role::Admin => {}
+                    _ => {
+                        return Err(ErrorResponse::new(
+                            hyper::StatusCode::Forbidden,
+                            "Search isn't enabled for you".to_owned(),
+                        ))
+                    }
+                }
+                let interval = Interval(feed_path.from.into(), feed_path.until.into());
+                self.tweets
+                    .search_tweets(
+                        Some(context),
+                        &feed_path.who,
+                        &interval,
+                        &query.search_options(),
+                    )
+                    .map_err(Self::fetch_error)
+                    .and_then(|feed_tweets| {
+                        Self::feed_contents(
+                            feed_tweets,
+                            feed_path.from,
+                            query.detail.unwrap_or(FeedDetail::Ids),
+                        )
+                    })
+            })
+        };
+        (state, response)
     }
-}
 
-fn router(server: Server) -> gotham::router::Router {
-    let server = Arc::new(server);
-    let server2 = server.clone();
-    let server3 = server.clone();
-    let server4 = server.clone();
-    let (chain, pipelines) = gotham::pipeline::single::single_pipeline(
-        gotham::pipeline::new_pipeline()
-            .add(
-                gotham::middleware::session::NewSessionMiddleware::default()
-                    .with_session_type::<Option<oauth::Context>>(),
+    // Fetching from sources other than Twitter doesn't need the user's Twitter credentials, but as
+    // with user feeds, only logged in users may use them.
+    pub fn source_feed(
+        &self,
+        state: gotham::state::State,
+        source: &str,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let source_feed_path = SourceFeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(
+                &state,
+                source_feed_path.from,
+                source_feed_path.until,
+                |_context| {
+                    let interval =
+                        Interval(source_feed_path.from.into(), source_feed_path.until.into());
+                    self.tweets
+                        .source_tweets(source, &source_feed_path.key, &interval)
+                        .map_err(Self::fetch_error)
+                        .and_then(|feed_tweets| {
+                            Self::filtered_feed_contents(feed_tweets, source_feed_path.from, query)
+                        })
+                },
             )
-            .build(),
-    );
-    gotham::router::builder::build_router(chain, pipelines, |route| {
-        route.get("/healthz").to(healthz);
-        for path in server.static_bytes.keys() {
-            let server = server.clone();
-            route.get(path).to_new_handler(move || {
-                let server = server.clone();
-                Ok(move |state| server.static_page(state))
-            });
+        };
+        (state, response)
+    }
+
+    pub fn sources(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let sources: Vec<_> = self
+            .tweets
+            .sources()
+            .into_iter()
+            .map(|(name, capabilities)| SourceDescription { name, capabilities })
+            .collect();
+        let mut response = match serde_json::to_vec(&sources) {
+            Ok(contents) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((contents, mime::APPLICATION_JSON)),
+            ),
+            Err(err) => {
+                warn!("Error serializing sources: {:?}", err);
+                Self::internal_server_error(&state)
+            }
+        };
+        {
+            let headers = response.headers_mut();
+            headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
         }
-        // TODO: Tie these paths statically to Server fields.
-        route
-            .get("/oauth-request")
-            .with_query_string_extractor::<RedirectUrlQueryParam>()
-            .to_new_handler(move || {
-                let server = server2.clone();
-                Ok(move |state| server.oauth_request(state))
-            });
-        route
-            .get("/oauth-callback")
-            .with_query_string_extractor::<OauthCallbackQueryParam>()
-            .to_new_handler(move || {
-                let server = server3.clone();
-                Ok(move |state| server.oauth_callback(state))
-            });
-        route
-            .get("/feed/:who/:from/:until")
-            .with_path_extractor::<FeedPath>()
-            .to_new_handler(move || {
-                let server = server4.clone();
-                Ok(move |state| server.feed(state))
-            });
-    })
-}
+        (state, response)
+    }
 
-#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
-struct FeedPath {
-    who: String,
-    from: SecondsSinceUnixEpoch,
-    until: SecondsSinceUnixEpoch,
-}
+    pub fn home_feed(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let home_feed_path = HomeFeedPath::borrow_from(&state);
+            let query = FeedQueryParam::borrow_from(&state);
+            self.logged_in_feed(
+                &state,
+                home_feed_path.from,
+                home_feed_path.until,
+                |context| {
+                    let interval =
+                        Interval(home_feed_path.from.into(), home_feed_path.until.into());
+                    self.tweets
+                        .home_tweets(context, &interval)
+                        .map_err(Self::fetch_error)
+                        .and_then(|feed_tweets| {
+                            Self::filtered_feed_contents(feed_tweets, home_feed_path.from, query)
+                        })
+                },
+            )
+        };
+        (state, response)
+    }
 
-#[derive(Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
-struct TweetForJavascript {
-    id: String,
-    seconds_since_start: u64,
-}
+    fn logged_in_feed<F>(
+        &self,
+        state: &gotham::state::State,
+        from: SecondsSinceUnixEpoch,
+        until: SecondsSinceUnixEpoch,
+        feed: F,
+    ) -> hyper::Response
+    where
+        F: FnOnce(&Context) -> Result<(Vec<u8>, bool), ErrorResponse>,
+    {
+        let mut response = match self.context(state) {
+            Some(context) => {
+                let result = self
+                    .validate_window(from, until, self.role(Some(context)))
+                    .and_then(|()| feed(context));
+                self.feed_response(state, result)
+            }
+            None => gotham::http::response::create_response(
+                state,
+                hyper::StatusCode::Unauthorized,
+                Some(("Not authorized".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+            ),
+        };
+        {
+            let headers = response.headers_mut();
+            headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
+        }
+        response
+    }
 
-pub fn healthz(state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
-    let res = gotham::http::response::create_response(
-        &state,
-        hyper::StatusCode::Ok,
-        Some(("ok".as_bytes().to_vec(), mime::TEXT_PLAIN)),
-    );
+    // A user's tweets in the window, in a format for keeping, fetching any which aren't cached as
+    // their feed would. Like feeds, the window is limited by the viewer's role.
+    pub fn export(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let export_path = ExportPath::borrow_from(&state);
+            let format = ExportQueryParam::borrow_from(&state)
+                .format
+                .unwrap_or(ExportFormat::Json);
+            let mut response = match self.context(&state) {
+                Some(context) => {
+                    let result = self
+                        .validate_window(
+                            export_path.from,
+                            export_path.until,
+                            self.role(Some(context)),
+                        )
+                        .and_then(|()| {
+                            Self::snowflake_interval(export_path.from, export_path.until)
+                        })
+                        .and_then(|interval| {
+                            let mut contents = Vec::new();
+                            self.tweets
+                                .export(
+                                    Some(context),
+                                    &export_path.who,
+                                    &interval,
+                                    format,
+                                    &mut contents,
+                                )
+                                .map_err(Self::fetch_error)?;
+                            Ok(contents)
+                        });
+                    match result {
+                        Ok(contents) => gotham::http::response::create_response(
+                            &state,
+                            hyper::StatusCode::Ok,
+                            Some((
+                                contents,
+                                format.mime_type().parse().expect("Bad export MIME type"),
+                            )),
+                        ),
+                        Err(err) => err.into_response(&state, mime::TEXT_PLAIN),
+                    }
+                }
+                None => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Unauthorized,
+                    Some(("Not authorized".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+                ),
+            };
+            {
+                let headers = response.headers_mut();
+                headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
+            }
+            response
+        };
+        (state, response)
+    }
+
+    pub fn share(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let feed_path = FeedPath::borrow_from(&state);
+            let query_params = ShareQueryParam::borrow_from(&state);
+            match (&self.share_links, self.context(&state)) {
+                (&None, _) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::NotFound,
+                    None,
+                ),
+                (_, None) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Unauthorized,
+                    Some(("Not authorized".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+                ),
+                (&Some(ref share_links), Some(context)) => {
+                    let role = self.role(Some(context));
+                    let speed = query_params.speed.unwrap_or(1.0);
+                    match self
+                        .validate_window(feed_path.from, feed_path.until, role)
+                        .and_then(|()| Self::validate_speed(speed))
+                    {
+                        Ok(()) => {
+                            let link = ShareLink {
+                                who: feed_path.who.clone(),
+                                from: feed_path.from,
+                                until: feed_path.until,
+                                speed,
+                                expires: SecondsSinceUnixEpoch(
+                                    SecondsSinceUnixEpoch::now().0 + share_links.ttl.as_secs(),
+                                ),
+                            };
+                            let signature = share_links.signer.sign(&link);
+                            // The frontend's replay page, which fetches the feed from /shared.
+                            let mut url = self.index_url.clone();
+                            url.path_segments_mut()
+                                .expect("Index URL cannot be a base")
+                                .pop_if_empty()
+                                .extend(&[
+                                    "replay".to_owned(),
+                                    link.who,
+                                    format!("{}", link.from),
+                                    format!("{}", link.until),
+                                    format!("{}", link.speed),
+                                    format!("{}", link.expires),
+                                    signature,
+                                ]);
+                            match serde_json::to_vec(&ShareLinkResponse {
+                                url: url.into_string(),
+                            }) {
+                                Ok(contents) => gotham::http::response::create_response(
+                                    &state,
+                                    hyper::StatusCode::Ok,
+                                    Some((contents, mime::APPLICATION_JSON)),
+                                ),
+                                Err(err) => {
+                                    warn!("Error serializing share link: {:?}", err);
+                                    Self::internal_server_error(&state)
+                                }
+                            }
+                        }
+                        Err(err) => err.into_response(&state, mime::TEXT_PLAIN),
+                    }
+                }
+            }
+        };
+        (state, response)
+    }
+
+    // The frontend, for opening share links without logging in. The link is checked when the
+    // frontend fetches its feed.
+    pub fn replay_page(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let res = match self.static_bytes.get("/") {
+            Some(&(ref body, ref mime)) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((body.clone(), mime.clone())),
+            ),
+            None => {
+                gotham::http::response::create_response(&state, hyper::StatusCode::NotFound, None)
+            }
+        };
+        (state, res)
+    }
+
+    pub fn shared_feed(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let shared_feed_path = SharedFeedPath::borrow_from(&state);
+            let link = ShareLink {
+                who: shared_feed_path.who.clone(),
+                from: shared_feed_path.from,
+                until: shared_feed_path.until,
+                speed: shared_feed_path.speed,
+                expires: shared_feed_path.expires,
+            };
+            let mut response = match self.share_links {
+                None => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::NotFound,
+                    None,
+                ),
+                Some(ref share_links) => {
+                    match share_links.signer.verify(
+                        &link,
+                        &shared_feed_path.signature,
+                        SecondsSinceUnixEpoch::now(),
+                    ) {
+                        Ok(()) => {
+                            let feed_path = FeedPath {
+                                who: link.who,
+                                from: link.from,
+                                until: link.until,
+                            };
+                            // Shared links are viewed without logging in, so use app-only auth.
+                            let result = self.feed_impl(
+                                &feed_path,
+                                None,
+                                false,
+                                FeedDetail::Ids,
+                                TimelineOptions::default(),
+                            );
+                            self.feed_response(&state, result)
+                        }
+                        Err(err) => gotham::http::response::create_response(
+                            &state,
+                            hyper::StatusCode::Forbidden,
+                            Some((err.into_bytes(), mime::TEXT_PLAIN)),
+                        ),
+                    }
+                }
+            };
+
+            {
+                let headers = response.headers_mut();
+                headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
+            }
+            response
+        };
+
+        (state, response)
+    }
+
+    // Only media from cached tweets, or kept from them, is served, so that this isn't an open proxy.
+    pub fn media(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let id = Snowflake(MediaPath::borrow_from(&state).id);
+            let result = match self.media_proxy {
+                Some(ref media_proxy) => media_proxy
+                    .get(id, self.tweets.media_url(id).as_deref())
+                    .map_err(Self::fetch_error),
+                None => Ok(None),
+            };
+            match result {
+                Ok(Some(media)) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Ok,
+                    Some((
+                        media.bytes,
+                        media
+                            .content_type
+                            .parse()
+                            .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+                    )),
+                ),
+                Ok(None) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::NotFound,
+                    None,
+                ),
+                Err(err) => err.into_response(&state, mime::TEXT_PLAIN),
+            }
+        };
+        (state, response)
+    }
+
+    // Anyone may see the threads of users whose feeds are public, like suggestions.
+    pub fn thread(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let thread_path = ThreadPath::borrow_from(&state);
+            let context = self.context(&state);
+            let result =
+                if context.is_none() && !self.public_display_names.contains(&thread_path.who) {
+                    Err(ErrorResponse::new(
+                        hyper::StatusCode::Unauthorized,
+                        "Not authorized".to_owned(),
+                    ))
+                } else {
+                    self.thread_json(context, thread_path)
+                };
+            let mut response = match result {
+                Ok(contents) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Ok,
+                    Some((contents, mime::APPLICATION_JSON)),
+                ),
+                Err(err) => err.into_response(&state, mime::TEXT_PLAIN),
+            };
+            {
+                let headers = response.headers_mut();
+                headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
+            }
+            response
+        };
+        (state, response)
+    }
+
+    fn thread_json(
+        &self,
+        context: Option<&Context>,
+        thread_path: &ThreadPath,
+    ) -> Result<Vec<u8>, ErrorResponse> {
+        let thread = self
+            .tweets
+            .thread(context, &thread_path.who, Snowflake(thread_path.id))
+            .map_err(Self::fetch_error)?
+            .ok_or_else(|| {
+                ErrorResponse::new(
+                    hyper::StatusCode::NotFound,
+                    format!(
+                        "Tweet {} by {} isn't cached",
+                        thread_path.id, thread_path.who
+                    ),
+                )
+            })?;
+        serde_json::to_vec(&thread).map_err(|err| {
+            ErrorResponse::new(
+                hyper::StatusCode::InternalServerError,
+                format!("Error serializing thread: {:?}", err),
+            )
+        })
+    }
+
+    pub fn suggest(&self, state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let suggest_path = SuggestPath::borrow_from(&state);
+            let query_params = SuggestQueryParam::borrow_from(&state);
+            let window_secs = query_params.window_secs.unwrap_or(3600);
+            let mut response = if self.context(&state).is_none()
+                && !self.public_display_names.contains(&suggest_path.who)
+            {
+                gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Unauthorized,
+                    Some(("Not authorized".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+                )
+            } else if window_secs == 0 {
+                gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::BadRequest,
+                    Some((
+                        "window_secs must be positive".as_bytes().to_vec(),
+                        mime::TEXT_PLAIN,
+                    )),
+                )
+            } else {
+                let windows = self.tweets.busiest_windows(
+                    &suggest_path.who,
+                    Duration::from_secs(window_secs),
+                    query_params.count.unwrap_or(5),
+                );
+                match serde_json::to_vec(&windows) {
+                    Ok(contents) => gotham::http::response::create_response(
+                        &state,
+                        hyper::StatusCode::Ok,
+                        Some((contents, mime::APPLICATION_JSON)),
+                    ),
+                    Err(err) => {
+                        warn!("Error serializing suggestions: {:?}", err);
+                        Self::internal_server_error(&state)
+                    }
+                }
+            };
+
+            {
+                let headers = response.headers_mut();
+                headers.set(AccessControlAllowOrigin::Value(self.cors_origin.clone()));
+            }
+            response
+        };
+        (state, response)
+    }
+
+    pub fn record_event(
+        &self,
+        mut state: gotham::state::State,
+    ) -> Box<gotham::handler::HandlerFuture> {
+        let declared_too_large = hyper::Headers::borrow_from(&state)
+            .get::<hyper::header::ContentLength>()
+            .map(|length| length.0 > MAX_EVENT_BYTES as u64)
+            .unwrap_or(false);
+        if declared_too_large {
+            let response = gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::PayloadTooLarge,
+                None,
+            );
+            return Box::new(futures::future::ok((state, response)));
+        }
+        let events = self.events.clone();
+        // Reading stops as soon as the body is too large, rather than buffering all of it.
+        let f = hyper::Body::take_from(&mut state)
+            .map_err(EventBodyError::Hyper)
+            .fold(Vec::new(), |mut body, chunk| {
+                if body.len() + chunk.len() > MAX_EVENT_BYTES {
+                    return Err(EventBodyError::TooLarge);
+                }
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .then(move |full_body| {
+                let status_code = match full_body {
+                    Ok(body) => match serde_json::from_slice::<FrontendEvent>(&body) {
+                        Ok(event) => {
+                            events.record(event, SecondsSinceUnixEpoch::now());
+                            hyper::StatusCode::NoContent
+                        }
+                        Err(err) => {
+                            warn!("Error parsing event: {}", err);
+                            hyper::StatusCode::BadRequest
+                        }
+                    },
+                    Err(EventBodyError::TooLarge) => hyper::StatusCode::PayloadTooLarge,
+                    Err(EventBodyError::Hyper(err)) => {
+                        return Err((state, err.into_handler_error()))
+                    }
+                };
+                let response = gotham::http::response::create_response(&state, status_code, None);
+                Ok((state, response))
+            });
+        Box::new(f)
+    }
+
+    pub fn admin_events(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = match serde_json::to_vec(&self.events.summary()) {
+            Ok(contents) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((contents, mime::APPLICATION_JSON)),
+            ),
+            Err(err) => {
+                warn!("Error serializing events: {:?}", err);
+                Self::internal_server_error(&state)
+            }
+        };
+        (state, response)
+    }
+
+    pub fn admin_api_usage(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = match serde_json::to_vec(&self.tweets.api_usage()) {
+            Ok(contents) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((contents, mime::APPLICATION_JSON)),
+            ),
+            Err(err) => {
+                warn!("Error serializing API usage: {:?}", err);
+                Self::internal_server_error(&state)
+            }
+        };
+        (state, response)
+    }
+
+    pub fn admin_metrics(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = match serde_json::to_vec(&self.tweets.metrics()) {
+            Ok(contents) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((contents, mime::APPLICATION_JSON)),
+            ),
+            Err(err) => {
+                warn!("Error serializing metrics: {:?}", err);
+                Self::internal_server_error(&state)
+            }
+        };
+        (state, response)
+    }
+
+    pub fn admin_coverage(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = {
+            let coverage_path = CoveragePath::borrow_from(&state);
+            let coverage: Vec<_> = self
+                .tweets
+                .provenance(&coverage_path.who)
+                .iter()
+                .map(|provenance| {
+                    let from: SecondsSinceUnixEpoch = provenance.interval.0.into();
+                    let until: SecondsSinceUnixEpoch = provenance.interval.1.into();
+                    CoverageForJavascript {
+                        from,
+                        until,
+                        source: provenance.source,
+                        fetched_at: provenance.fetched_at,
+                        needs_refresh: self.tweets.needs_refresh(provenance),
+                    }
+                })
+                .collect();
+            match serde_json::to_vec(&coverage) {
+                Ok(contents) => gotham::http::response::create_response(
+                    &state,
+                    hyper::StatusCode::Ok,
+                    Some((contents, mime::APPLICATION_JSON)),
+                ),
+                Err(err) => {
+                    warn!("Error serializing coverage: {:?}", err);
+                    Self::internal_server_error(&state)
+                }
+            }
+        };
+        (state, response)
+    }
+
+    fn feed_response(
+        &self,
+        state: &gotham::state::State,
+        result: Result<(Vec<u8>, bool), ErrorResponse>,
+    ) -> hyper::Response {
+        match result {
+            Ok((contents, partial)) => {
+                let mut response = gotham::http::response::create_response(
+                    state,
+                    hyper::StatusCode::Ok,
+                    Some((contents, mime::APPLICATION_JSON)),
+                );
+                if partial {
+                    response.headers_mut().set(Warning {
+                        code: 110,
+                        agent: self.domain_name.clone(),
+                        text: "Twitter unavailable, serving cached tweets only".to_owned(),
+                        date: None,
+                    });
+                }
+                response
+            }
+            Err(err) => err.into_response(state, mime::APPLICATION_JSON),
+        }
+    }
+
+    fn feed_impl(
+        &self,
+        feed_path: &FeedPath,
+        context: Option<&Context>,
+        snap: bool,
+        detail: FeedDetail,
+        options: TimelineOptions,
+    ) -> Result<(Vec<u8>, bool), ErrorResponse> {
+        let mut interval = Interval(feed_path.from.into(), feed_path.until.into());
+        if snap {
+            interval = self
+                .tweets
+                .snap_to_cached(&feed_path.who, &interval, self.snap_slack);
+        }
+        let mut feed_tweets = self
+            .tweets
+            .tweets(context, &feed_path.who, &interval, options)
+            .map_err(Self::fetch_error)?;
+        if detail == FeedDetail::Full {
+            self.hydrate(&feed_path.who, &mut feed_tweets.tweets);
+        }
+        Self::feed_contents(feed_tweets, feed_path.from, detail)
+    }
+
+    // Fills in the details of tweets only known by id. Failing to is logged, and the tweets are
+    // served without them.
+    fn hydrate(&self, user: &String, tweets: &mut [TweetFromTwitter]) {
+        let ids: Vec<_> = tweets
+            .iter()
+            .filter(|tweet| tweet.text.is_none())
+            .map(|tweet| tweet.id)
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        match self.tweets.hydrate(user, &ids) {
+            Ok(hydrated) => {
+                let hydrated: HashMap<_, _> = hydrated
+                    .into_iter()
+                    .map(|tweet| (tweet.id, tweet))
+                    .collect();
+                for tweet in tweets.iter_mut() {
+                    if let Some(hydrated) = hydrated.get(&tweet.id) {
+                        *tweet = hydrated.clone();
+                    }
+                }
+            }
+            Err(err) => warn!("Error hydrating tweets for {}: {}", user, err),
+        }
+    }
+
+    fn fetch_error(err: FetchError) -> ErrorResponse {
+        let (status, retry_after) = match err {
+            FetchError::RateLimited(ref rate_limited) => (
+                hyper::StatusCode::TooManyRequests,
+                Some(Duration::from_secs(
+                    rate_limited
+                        .reset
+                        .0
+                        .saturating_sub(SecondsSinceUnixEpoch::now().0),
+                )),
+            ),
+            FetchError::Unavailable(_) => (hyper::StatusCode::ServiceUnavailable, None),
+            FetchError::NotAuthorized(_) => (hyper::StatusCode::Forbidden, None),
+            FetchError::Other(_) => (hyper::StatusCode::BadGateway, None),
+        };
+        ErrorResponse {
+            status,
+            message: format!("Error fetching tweets: {}", err),
+            retry_after,
+        }
+    }
+
+    // Like feed_contents, for timelines which TweetStore doesn't filter per TimelineOptions.
+    fn filtered_feed_contents(
+        mut feed_tweets: FeedTweets,
+        from: SecondsSinceUnixEpoch,
+        query: &FeedQueryParam,
+    ) -> Result<(Vec<u8>, bool), ErrorResponse> {
+        let options = query.timeline_options();
+        feed_tweets.tweets.retain(|tweet| options.includes(tweet));
+        Self::feed_contents(feed_tweets, from, query.detail.unwrap_or(FeedDetail::Ids))
+    }
+
+    // The feed's JSON, and whether it's only partial.
+    fn feed_contents(
+        feed_tweets: FeedTweets,
+        from: SecondsSinceUnixEpoch,
+        detail: FeedDetail,
+    ) -> Result<(Vec<u8>, bool), ErrorResponse> {
+        let full = detail == FeedDetail::Full;
+        let tweets: Vec<_> = feed_tweets
+            .tweets
+            .iter()
+            .map(|tweet| {
+                let seconds_since_unix_epoch: SecondsSinceUnixEpoch = tweet.time().into();
+                TweetForJavascript {
+                    id: format!("{}", tweet.id),
+                    seconds_since_start: seconds_since_unix_epoch.0 - from.0,
+                    text: tweet.text.as_ref().filter(|_| full).cloned(),
+                    author: tweet.author.as_ref().filter(|_| full).cloned(),
+                    created_at: tweet.created_at.as_ref().filter(|_| full).cloned(),
+                    entities: tweet.entities.as_ref().filter(|_| full).cloned(),
+                }
+            })
+            .collect();
+
+        let contents = serde_json::to_vec(&tweets).map_err(|err| {
+            ErrorResponse::new(
+                hyper::StatusCode::InternalServerError,
+                format!("Error serializing JSON: {:?}", err),
+            )
+        })?;
+        Ok((contents, feed_tweets.partial))
+    }
+
+    pub fn admin_users(
+        &self,
+        state: gotham::state::State,
+    ) -> (gotham::state::State, hyper::Response) {
+        let response = match serde_json::to_vec(&self.tweets.users()) {
+            Ok(contents) => gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Ok,
+                Some((contents, mime::APPLICATION_JSON)),
+            ),
+            Err(err) => {
+                warn!("Error serializing users: {:?}", err);
+                Self::internal_server_error(&state)
+            }
+        };
+        (state, response)
+    }
+
+    // The session's context, or failing that the context of the request's API key.
+    fn context<'s>(&'s self, state: &'s gotham::state::State) -> Option<&'s Context> {
+        let session: &oauth::Session = gotham::middleware::session::SessionData::borrow_from(state);
+        session.context.as_ref().or_else(|| {
+            hyper::Headers::borrow_from(state)
+                .get_raw(API_KEY_HEADER)
+                .and_then(|raw| raw.one())
+                .and_then(|key| std::str::from_utf8(key).ok())
+                .and_then(|key| self.api_keys.get(key))
+        })
+    }
+
+    fn role(&self, context: Option<&Context>) -> Role {
+        match context {
+            None => Role::Anonymous,
+            Some(context) => {
+                if self.admins.contains(&context.user_screen_name) {
+                    Role::Admin
+                } else if self
+                    .search_enabled_display_names
+                    .contains(&context.user_screen_name)
+                {
+                    Role::SearchEnabled
+                } else {
+                    Role::User
+                }
+            }
+        }
+    }
+
+    fn validate_window(
+        &self,
+        from: SecondsSinceUnixEpoch,
+        until: SecondsSinceUnixEpoch,
+        role: Role,
+    ) -> Result<(), ErrorResponse> {
+        if until < from {
+            return Err(ErrorResponse::new(
+                hyper::StatusCode::BadRequest,
+                format!("Window end {} is before its start {}", until, from),
+            ));
+        }
+        Self::snowflake_interval(from, until)?;
+        let window = Duration::from_secs(until.0 - from.0);
+        match self.window_limits.max_window(role) {
+            Some(max_window) if window > max_window => Err(ErrorResponse::new(
+                hyper::StatusCode::Forbidden,
+                format!(
+                    "Window of {}s exceeds the maximum of {}s",
+                    window.as_secs(),
+                    max_window.as_secs()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Playback speeds are multiples of real time.
+    fn validate_speed(speed: f64) -> Result<(), ErrorResponse> {
+        if speed.is_finite() && speed > 0.0 {
+            Ok(())
+        } else {
+            Err(ErrorResponse::new(
+                hyper::StatusCode::BadRequest,
+                format!("Speed {} must be a positive number", speed),
+            ))
+        }
+    }
+
+    // Feed parameters are validated with this, so that they can then be converted with into.
+    fn snowflake_interval(
+        from: SecondsSinceUnixEpoch,
+        until: SecondsSinceUnixEpoch,
+    ) -> Result<Interval<Snowflake>, ErrorResponse> {
+        let snowflake = |seconds| {
+            Snowflake::from_seconds(seconds).map_err(|err| {
+                ErrorResponse::new(
+                    hyper::StatusCode::BadRequest,
+                    format!("Bad window: {}", err),
+                )
+            })
+        };
+        Ok(Interval(snowflake(from)?, snowflake(until)?))
+    }
+
+    fn internal_server_error(state: &gotham::state::State) -> hyper::Response {
+        gotham::http::response::create_response(
+            &state,
+            hyper::StatusCode::InternalServerError,
+            Some((
+                "Internal server error".as_bytes().to_vec(),
+                mime::TEXT_PLAIN,
+            )),
+        )
+    }
+}
+
+// Why a request couldn't be served.
+struct ErrorResponse {
+    status: hyper::StatusCode,
+    message: String,
+    // When the request is worth retrying, e.g. once Twitter's rate limit resets.
+    retry_after: Option<Duration>,
+}
+
+impl ErrorResponse {
+    fn new(status: hyper::StatusCode, message: String) -> ErrorResponse {
+        ErrorResponse {
+            status,
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn into_response(self, state: &gotham::state::State, mime: mime::Mime) -> hyper::Response {
+        let mut response = gotham::http::response::create_response(
+            state,
+            self.status,
+            Some((self.message.into_bytes(), mime)),
+        );
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().set(RetryAfter::Delay(retry_after));
+        }
+        response
+    }
+}
+
+struct ShareLinks {
+    signer: ShareLinkSigner,
+    ttl: Duration,
+}
+
+// Allows up to a fixed number of requests per key in each window.
+struct RateLimiter {
+    max_requests: u32,
+    window: Duration,
+    // key -> (window start, requests so far in window)
+    requests: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    fn new(max_requests: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            max_requests,
+            window,
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn allow(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        let entry = requests.entry(key.to_owned()).or_insert((now, 0));
+        if now.duration_since(entry.0) >= self.window {
+            *entry = (now, 0);
+        }
+        if entry.1 >= self.max_requests {
+            return false;
+        }
+        entry.1 += 1;
+        true
+    }
+}
+
+fn router(server: Server) -> gotham::router::Router {
+    let server = Arc::new(server);
+    let server2 = server.clone();
+    let server3 = server.clone();
+    let server4 = server.clone();
+    let server5 = server.clone();
+    let server6 = server.clone();
+    let server7 = server.clone();
+    let server8 = server.clone();
+    let server9 = server.clone();
+    let server10 = server.clone();
+    let server11 = server.clone();
+    let server12 = server.clone();
+    let server13 = server.clone();
+    let server14 = server.clone();
+    let server15 = server.clone();
+    let server16 = server.clone();
+    let server17 = server.clone();
+    let server18 = server.clone();
+    let server19 = server.clone();
+    let server20 = server.clone();
+    let server21 = server.clone();
+    let server22 = server.clone();
+    let server23 = server.clone();
+    let server24 = server.clone();
+    let server25 = server.clone();
+    let server26 = server.clone();
+    let (chain, pipelines) = gotham::pipeline::single::single_pipeline(
+        gotham::pipeline::new_pipeline()
+            .add(
+                gotham::middleware::session::NewSessionMiddleware::new(
+                    server.session_backend.clone(),
+                )
+                .with_session_type::<oauth::Session>(),
+            )
+            .add(AdminAuthorizationMiddleware {
+                admins: server.admins.clone(),
+            })
+            .build(),
+    );
+    gotham::router::builder::build_router(chain, pipelines, |route| {
+        route.get("/healthz").to(healthz);
+        for path in server.static_bytes.keys() {
+            let server = server.clone();
+            route.get(path).to_new_handler(move || {
+                let server = server.clone();
+                Ok(move |state| server.static_page(state))
+            });
+        }
+        // TODO: Tie these paths statically to Server fields.
+        route
+            .get("/oauth-request")
+            .with_query_string_extractor::<RedirectUrlQueryParam>()
+            .to_new_handler(move || {
+                let server = server2.clone();
+                Ok(move |state| server.oauth_request(state))
+            });
+        route
+            .get("/oauth-callback")
+            .with_query_string_extractor::<OauthCallbackQueryParam>()
+            .to_new_handler(move || {
+                let server = server3.clone();
+                Ok(move |state| server.oauth_callback(state))
+            });
+        route
+            .get("/feed/:who/:from/:until")
+            .with_path_extractor::<FeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server4.clone();
+                Ok(move |state| server.feed(state))
+            });
+        route
+            .get("/feed/list/:list_id/:from/:until")
+            .with_path_extractor::<ListFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server13.clone();
+                Ok(move |state| server.list_feed(state))
+            });
+        route
+            .get("/feed/likes/:who/:from/:until")
+            .with_path_extractor::<LikesFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server15.clone();
+                Ok(move |state| server.likes_feed(state))
+            });
+        route
+            .get("/feed/search/:who/:from/:until")
+            .with_path_extractor::<FeedPath>()
+            .with_query_string_extractor::<SearchFeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server21.clone();
+                Ok(move |state| server.search_feed(state))
+            });
+        route
+            .get("/feed/mentions/:who/:from/:until")
+            .with_path_extractor::<FeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server24.clone();
+                Ok(move |state| server.mentions_feed(state))
+            });
+        route
+            .get("/feed/tag/:tag/:from/:until")
+            .with_path_extractor::<TagFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server25.clone();
+                Ok(move |state| server.tag_feed(state))
+            });
+        // Mastodon accounts are given as user@instance, and Bluesky ones by handle or DID.
+        route
+            .get("/feed/mastodon/:key/:from/:until")
+            .with_path_extractor::<SourceFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server17.clone();
+                Ok(move |state| server.source_feed(state, "mastodon"))
+            });
+        route
+            .get("/feed/bluesky/:key/:from/:until")
+            .with_path_extractor::<SourceFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server18.clone();
+                Ok(move |state| server.source_feed(state, "bluesky"))
+            });
+        // Not under /feed, where it would shadow a user called home.
+        route
+            .get("/home/:from/:until")
+            .with_path_extractor::<HomeFeedPath>()
+            .with_query_string_extractor::<FeedQueryParam>()
+            .to_new_handler(move || {
+                let server = server14.clone();
+                Ok(move |state| server.home_feed(state))
+            });
+        route
+            .get("/share/:who/:from/:until")
+            .with_path_extractor::<FeedPath>()
+            .with_query_string_extractor::<ShareQueryParam>()
+            .to_new_handler(move || {
+                let server = server6.clone();
+                Ok(move |state| server.share(state))
+            });
+        route
+            .get("/shared/:who/:from/:until/:speed/:expires/:signature")
+            .with_path_extractor::<SharedFeedPath>()
+            .to_new_handler(move || {
+                let server = server7.clone();
+                Ok(move |state| server.shared_feed(state))
+            });
+        route
+            .get("/replay/:who/:from/:until/:speed/:expires/:signature")
+            .with_path_extractor::<SharedFeedPath>()
+            .to_new_handler(move || {
+                let server = server26.clone();
+                Ok(move |state| server.replay_page(state))
+            });
+        route
+            .get("/api/v1/suggest/:who")
+            .with_path_extractor::<SuggestPath>()
+            .with_query_string_extractor::<SuggestQueryParam>()
+            .to_new_handler(move || {
+                let server = server10.clone();
+                Ok(move |state| server.suggest(state))
+            });
+        route
+            .get("/api/v1/thread/:who/:id")
+            .with_path_extractor::<ThreadPath>()
+            .to_new_handler(move || {
+                let server = server22.clone();
+                Ok(move |state| server.thread(state))
+            });
+        route
+            .get("/export/:who/:from/:until")
+            .with_path_extractor::<ExportPath>()
+            .with_query_string_extractor::<ExportQueryParam>()
+            .to_new_handler(move || {
+                let server = server16.clone();
+                Ok(move |state| server.export(state))
+            });
+        route
+            .get("/media/:id")
+            .with_path_extractor::<MediaPath>()
+            .to_new_handler(move || {
+                let server = server23.clone();
+                Ok(move |state| server.media(state))
+            });
+        route.get("/api/v1/sources").to_new_handler(move || {
+            let server = server19.clone();
+            Ok(move |state| server.sources(state))
+        });
+        route.post("/api/v1/events").to_new_handler(move || {
+            let server = server8.clone();
+            Ok(move |state| server.record_event(state))
+        });
+        route.get("/admin/users").to_new_handler(move || {
+            let server = server5.clone();
+            Ok(move |state| server.admin_users(state))
+        });
+        route.get("/admin/events").to_new_handler(move || {
+            let server = server9.clone();
+            Ok(move |state| server.admin_events(state))
+        });
+        route.get("/admin/api_usage").to_new_handler(move || {
+            let server = server12.clone();
+            Ok(move |state| server.admin_api_usage(state))
+        });
+        route.get("/admin/metrics").to_new_handler(move || {
+            let server = server20.clone();
+            Ok(move |state| server.admin_metrics(state))
+        });
+        route
+            .get("/admin/coverage/:who")
+            .with_path_extractor::<CoveragePath>()
+            .to_new_handler(move || {
+                let server = server11.clone();
+                Ok(move |state| server.admin_coverage(state))
+            });
+    })
+}
+
+const ADMIN_PATH_PREFIX: &str = "/admin/";
+const API_KEY_HEADER: &str = "X-Api-Key";
+const MAX_EVENT_BYTES: usize = 1024;
+// Sessions kept in Redis expire after going unused this long, as in-memory sessions do.
+#[cfg(feature = "redis")]
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+enum EventBodyError {
+    Hyper(hyper::Error),
+    // Longer than MAX_EVENT_BYTES.
+    TooLarge,
+}
+
+// Rejects requests for admin paths unless the session belongs to a configured admin.
+#[derive(Clone)]
+struct AdminAuthorizationMiddleware {
+    admins: Arc<HashSet<String>>,
+}
+
+impl gotham::middleware::NewMiddleware for AdminAuthorizationMiddleware {
+    type Instance = AdminAuthorizationMiddleware;
+
+    fn new_middleware(&self) -> std::io::Result<AdminAuthorizationMiddleware> {
+        Ok(self.clone())
+    }
+}
+
+impl gotham::middleware::Middleware for AdminAuthorizationMiddleware {
+    fn call<Chain>(
+        self,
+        state: gotham::state::State,
+        chain: Chain,
+    ) -> Box<gotham::handler::HandlerFuture>
+    where
+        Chain: FnOnce(gotham::state::State) -> Box<gotham::handler::HandlerFuture> + 'static,
+    {
+        let authorized = {
+            let path = hyper::Uri::borrow_from(&state).path();
+            let session: &oauth::Session =
+                gotham::middleware::session::SessionData::borrow_from(&state);
+            !path.starts_with(ADMIN_PATH_PREFIX)
+                || session
+                    .context
+                    .as_ref()
+                    .map(|context| self.admins.contains(&context.user_screen_name))
+                    .unwrap_or(false)
+        };
+        if authorized {
+            chain(state)
+        } else {
+            let response = gotham::http::response::create_response(
+                &state,
+                hyper::StatusCode::Forbidden,
+                Some(("Forbidden".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+            );
+            Box::new(futures::future::ok((state, response)))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct FeedPath {
+    who: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct ListFeedPath {
+    list_id: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct LikesFeedPath {
+    who: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct TagFeedPath {
+    // With or without its "#", which would need to be escaped.
+    tag: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct SourceFeedPath {
+    // Identifies an account on the source.
+    key: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Serialize)]
+struct SourceDescription {
+    name: &'static str,
+    capabilities: SourceCapabilities,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct HomeFeedPath {
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct FeedQueryParam {
+    // Whether to shrink the window to avoid fetching small uncached slivers at its ends.
+    snap: Option<bool>,
+    detail: Option<FeedDetail>,
+    include_rts: Option<bool>,
+    exclude_replies: Option<bool>,
+}
+
+impl FeedQueryParam {
+    fn timeline_options(&self) -> TimelineOptions {
+        let default = TimelineOptions::default();
+        TimelineOptions {
+            include_rts: self.include_rts.unwrap_or(default.include_rts),
+            exclude_replies: self.exclude_replies.unwrap_or(default.exclude_replies),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct SearchFeedQueryParam {
+    detail: Option<FeedDetail>,
+    lang: Option<String>,
+    has_media: Option<bool>,
+    // Comma separated.
+    keywords: Option<String>,
+}
+
+impl SearchFeedQueryParam {
+    fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            lang: self.lang.clone(),
+            has_media: self.has_media.unwrap_or(false),
+            keywords: self
+                .keywords
+                .iter()
+                .flat_map(|keywords| keywords.split(','))
+                .map(str::trim)
+                .filter(|keyword| !keyword.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+}
+
+// How much of each tweet the feed includes. Ids is enough to embed tweets.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FeedDetail {
+    Ids,
+    // Also text, author, created_at and entities, where they are known.
+    Full,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct ShareQueryParam {
+    speed: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct SharedFeedPath {
+    who: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+    speed: f64,
+    expires: SecondsSinceUnixEpoch,
+    signature: String,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct SuggestPath {
+    who: String,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct ThreadPath {
+    who: String,
+    id: u64,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct MediaPath {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct SuggestQueryParam {
+    window_secs: Option<u64>,
+    count: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct CoveragePath {
+    who: String,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct ExportPath {
+    who: String,
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+}
+
+#[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
+struct ExportQueryParam {
+    format: Option<ExportFormat>,
+}
+
+#[derive(Serialize)]
+struct CoverageForJavascript {
+    from: SecondsSinceUnixEpoch,
+    until: SecondsSinceUnixEpoch,
+    source: TweetSource,
+    fetched_at: SecondsSinceUnixEpoch,
+    needs_refresh: bool,
+}
+
+#[derive(Serialize)]
+struct ShareLinkResponse {
+    url: String,
+}
+
+#[derive(Eq, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+struct TweetForJavascript {
+    id: String,
+    seconds_since_start: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<TweetAuthor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entities: Option<TweetEntities>,
+}
+
+pub fn healthz(state: gotham::state::State) -> (gotham::state::State, hyper::Response) {
+    let res = gotham::http::response::create_response(
+        &state,
+        hyper::StatusCode::Ok,
+        Some(("ok".as_bytes().to_vec(), mime::TEXT_PLAIN)),
+    );
 
     (state, res)
 }
@@ -401,12 +2197,190 @@ struct Config {
     domain_name: String,
     static_site_path: String,
     search_enabled_display_names: HashSet<String>,
+    #[serde(default)]
+    admins: HashSet<String>,
+    #[serde(default)]
+    api_keys: Vec<ApiKeyConfig>,
+    #[serde(default)]
+    stale_if_error: StaleIfErrorConfig,
+    #[serde(default)]
+    max_window_secs: WindowLimits,
+    share_links: Option<ShareLinksConfig>,
+    // Accounts whose feeds may be replayed without logging in.
+    #[serde(default)]
+    public_display_names: HashSet<String>,
+    #[serde(default = "default_public_feed_requests_per_minute")]
+    public_feed_requests_per_minute: u32,
+    #[serde(default = "default_event_log_capacity")]
+    event_log_capacity: usize,
+    #[serde(default = "default_snap_slack_secs")]
+    snap_slack_secs: u64,
+    #[serde(default)]
+    refresh_after_secs: RefreshPolicy,
+    // If empty, FetchStrategy::default() is used.
+    #[serde(default)]
+    fetch_rules: Vec<FetchRule>,
+    // Defaults to only the 30day product, in the "dev" environment.
+    #[serde(default)]
+    search_environments: SearchEnvironments,
+    // Defaults to 10s to connect, and 30s for the whole request.
+    #[serde(default)]
+    http_timeouts: HttpTimeouts,
+    // If set, requests to Twitter and other sources go through this proxy, e.g.
+    // "http://proxy.example.com:3128".
+    http_proxy: Option<String>,
+    // Without limits, every tweet ever fetched stays in memory.
+    #[serde(default)]
+    cache_limits: CacheLimits,
+    // If set, at most this many requests to Twitter are in flight at once, across all users;
+    // further requests wait for one to finish.
+    max_concurrent_twitter_requests: Option<usize>,
+    // How interactive fetches are prioritised over background ones, e.g. revalidation. Defaults to
+    // unlimited queues, with no rate limit reserved for interactive fetches.
+    #[serde(default)]
+    fetch_scheduling: FetchSchedulingPolicy,
+    // If set, up to this many of Twitter's responses with an ETag or Last-Modified header are kept,
+    // and requests which would get them again are made conditional.
+    http_cache_max_responses: Option<usize>,
+    // If set, fetched tweets are kept in this SQLite database, and reloaded on startup.
+    // Requires the sqlite feature.
+    sqlite_path: Option<String>,
+    // If set, fetched tweets are shared with other servers through this Redis, e.g.
+    // "redis://127.0.0.1/". Requires the redis feature.
+    redis_url: Option<String>,
+    // If set, sessions, i.e. who is logged in and any OAuth dance in progress, are kept in this
+    // Redis, so that they're shared with other servers, e.g. "redis://127.0.0.1/". Otherwise
+    // they're kept in memory, and an OAuth callback must reach the server which started the
+    // dance. Requires the redis feature.
+    session_redis_url: Option<String>,
+    // Caps on Twitter API calls per category. Categories without a quota are only limited by
+    // Twitter's rate limits.
+    #[serde(default)]
+    quotas: Vec<Quota>,
+    // Tweets to serve without fetching, e.g. for demos:
+    //   [[preload]]
+    //   user = "someone"
+    //   from = 963140650398646272
+    //   until = 963155749893046272
+    //   tweet_ids = [963143061558743040, 963152907255377921]
+    #[serde(default)]
+    preload: Vec<PreloadedInterval>,
+    // Paths of Twitter data export ZIPs, whose tweets are imported on startup. Requires the
+    // archive feature.
+    #[serde(default)]
+    import_archives: Vec<String>,
+    // Whether imported tweets are looked up on Twitter, to fill in what archives leave out, e.g.
+    // which tweets retweets are of. This takes a request per 100 tweets.
+    #[serde(default)]
+    hydrate_imports: bool,
+    // If set, cached tweets are periodically re-checked, and dropped if deleted upstream.
+    revalidation: Option<RevalidationConfig>,
+    // If set, cached tweets are periodically looked up again once stale, to keep e.g. their
+    // engagement counts up to date, and dropped if deleted upstream.
+    tweet_refresh: Option<TweetRefreshConfig>,
+    // If set, cached tweets are periodically saved to a file, and loaded from it on startup, so
+    // that restarting doesn't mean fetching them all again.
+    cache_file: Option<CacheFileConfig>,
+    // If set, tweets' media is served from /media/:id, so that replayed tweets still render where
+    // Twitter blocks hotlinking.
+    media_proxy: Option<MediaProxyConfig>,
+    // If set, the given users' newest tweets are kept cached by polling Twitter.
+    live_edge: Option<LiveEdgeConfig>,
+    // If set, Twitter API responses are recorded to, or replayed from, fixture files, for working
+    // offline. Logging in still talks to Twitter.
+    cassette: Option<CassetteConfig>,
+}
+
+fn default_snap_slack_secs() -> u64 {
+    60
+}
+
+fn default_event_log_capacity() -> usize {
+    1000
+}
+
+fn default_public_feed_requests_per_minute() -> u32 {
+    30
+}
+
+fn default_live_edge_window_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Deserialize)]
+struct ShareLinksConfig {
+    secret: String,
+    ttl_secs: u64,
+}
+
+// Allows non-browser clients to authenticate as the given user without an OAuth dance.
+#[derive(Deserialize)]
+struct ApiKeyConfig {
+    key: String,
+    screen_name: String,
+    oauth_token: String,
+    oauth_token_secret: String,
+}
+
+#[derive(Deserialize)]
+struct RevalidationConfig {
+    every_secs: u64,
+    // Only tweets younger than this are re-checked. If unset, every cached tweet is.
+    max_age_secs: Option<u64>,
+    #[serde(default)]
+    drop_withheld: bool,
+}
+
+#[derive(Deserialize)]
+struct TweetRefreshConfig {
+    every_secs: u64,
+    max_staleness_secs: u64,
+    // At most this many tweets are refreshed each time. If unset, every stale tweet is.
+    max_tweets: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CacheFileConfig {
+    path: String,
+    save_every_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct MediaProxyConfig {
+    // If set, media is kept here once fetched, so that it still renders after being deleted from
+    // Twitter.
+    cache_dir: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LiveEdgeConfig {
+    users: Vec<String>,
+    every_secs: u64,
+    // How far back each poll fetches, which should be longer than every_secs so that polls
+    // overlap.
+    #[serde(default = "default_live_edge_window_secs")]
+    window_secs: u64,
+}
+
+#[derive(Deserialize)]
+struct CassetteConfig {
+    mode: CassetteMode,
+    dir: String,
+}
+
+#[derive(Default, Deserialize)]
+struct StaleIfErrorConfig {
+    max_staleness_secs: Option<u64>,
+    max_error_duration_secs: Option<u64>,
 }
 
 #[derive(Deserialize)]
 struct OauthConfig {
     app_key: String,
     app_secret: String,
+    // Where users are sent if they cancel logging in, or if it can't be completed, e.g. because
+    // their session expired during it. Defaults to the index page.
+    login_cancelled_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
@@ -416,6 +2390,8 @@ struct RedirectUrlQueryParam {
 
 #[derive(Debug, Deserialize, StateData, StaticResponseExtender)]
 struct OauthCallbackQueryParam {
-    oauth_token: String,
-    oauth_verifier: String,
+    oauth_token: Option<String>,
+    oauth_verifier: Option<String>,
+    // Instead of the others, if the user cancelled logging in.
+    denied: Option<String>,
 }
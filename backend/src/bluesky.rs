@@ -0,0 +1,470 @@
+use cassette::ApiRequest;
+use serde_json;
+use source::{SocialSource, SourceCapabilities, SourceClient};
+use tweetstore::{
+    FetchError, Hashtag, MillisSinceUnixEpoch, RetweetedStatus, SecondsSinceUnixEpoch, Snowflake,
+    SnowflakeScheme, TweetAuthor, TweetEntities, TweetFromTwitter, TweetUrl, UserMention,
+};
+use Interval;
+
+// The public AppView, which serves public posts without logging in.
+const GET_AUTHOR_FEED_URL: &str = "https://public.api.bsky.app/xrpc/app.bsky.feed.getAuthorFeed";
+// The most posts getAuthorFeed returns per request.
+const PAGE_SIZE: usize = 100;
+// Bluesky keeps every post, so this only guards against paging indefinitely.
+const MAX_PAGES: usize = 100;
+
+const TID_ALPHABET: &[u8] = b"234567abcdefghijklmnopqrstuvwxyz";
+const TID_LENGTH: usize = 13;
+
+// Posts, and reposts, by Bluesky accounts, keyed by handle or DID.
+pub struct Bluesky;
+
+impl Bluesky {
+    // Posts, and reposts, from before the cursor; at most a page's worth, newest first.
+    fn fetch_page(
+        client: &dyn SourceClient,
+        actor: &str,
+        cursor: &str,
+    ) -> Result<AuthorFeed, FetchError> {
+        info!("Fetching from app.bsky.feed.getAuthorFeed"); // TODO: Binary log requests and responses.
+
+        let request = ApiRequest::get(
+            GET_AUTHOR_FEED_URL,
+            vec![
+                ("actor".to_owned(), actor.to_owned()),
+                ("limit".to_owned(), format!("{}", PAGE_SIZE)),
+                ("cursor".to_owned(), cursor.to_owned()),
+            ],
+        );
+        let json_string = client.get("bsky.app", &request)?;
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Bluesky: {:?}", err).into())
+    }
+}
+
+impl SocialSource for Bluesky {
+    fn name(&self) -> &'static str {
+        "bluesky"
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        SourceCapabilities {
+            reposts: true,
+            replies: true,
+            entities: true,
+        }
+    }
+
+    fn parse_key(&self, key: &str) -> Result<String, String> {
+        parse_actor(key)
+    }
+
+    // Post ids are record keys, or whole at:// URIs.
+    fn snowflake(&self, post_id: &str) -> Option<Snowflake> {
+        snowflake_from_uri(post_id)
+    }
+
+    fn post_id(&self, snowflake: Snowflake) -> String {
+        tid_from_snowflake(snowflake)
+    }
+
+    // Pages back from the end of the interval until a whole page of posts is older than it. Posts
+    // are listed in the order they were indexed, which can differ slightly from their TIDs.
+    fn fetch_timeline(
+        &self,
+        client: &dyn SourceClient,
+        key: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let until = SecondsSinceUnixEpoch::from(interval.1);
+        let mut cursor = SecondsSinceUnixEpoch(until.0 + 1).utc_timestamp();
+        let mut tweets = Vec::new();
+        for _ in 0..MAX_PAGES {
+            let page = Bluesky::fetch_page(client, key, &cursor)?;
+            let page_tweets: Vec<_> = page
+                .feed
+                .into_iter()
+                .filter_map(FeedViewPost::into_tweet)
+                .collect();
+            let all_before = page_tweets.iter().all(|tweet| tweet.id < interval.0);
+            tweets.extend(page_tweets);
+            match page.cursor {
+                Some(next_cursor) if !all_before => cursor = next_cursor,
+                _ => {
+                    tweets.retain(|tweet| interval.contains(&tweet.id));
+                    tweets.sort();
+                    tweets.dedup();
+                    return Ok(tweets);
+                }
+            }
+        }
+        Err(format!(
+            "Too many pages of posts from {} between {} and {}",
+            key, interval.0, interval.1
+        )
+        .into())
+    }
+}
+
+// Handles are domain names, e.g. someone.bsky.social, but DIDs are accepted too. Handles are
+// case-insensitive.
+pub fn parse_actor(actor: &str) -> Result<String, String> {
+    let actor = actor.trim_start_matches('@');
+    if !actor.is_empty()
+        && actor.contains(&['.', ':'][..])
+        && actor
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':')
+    {
+        Ok(actor.to_lowercase())
+    } else {
+        Err(format!(
+            "Bad Bluesky handle {:?}, expected e.g. someone.bsky.social",
+            actor
+        ))
+    }
+}
+
+// Record keys are usually TIDs: 13 base32 characters encoding microseconds since the Unix epoch,
+// shifted left 10 bits, with a clock id in the low bits. Converting them to Snowflakes keeps
+// their order and their time, so that posts can be cached and served exactly like tweets.
+pub fn snowflake_from_tid(tid: &str) -> Option<Snowflake> {
+    if tid.len() != TID_LENGTH {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for c in tid.bytes() {
+        let digit = TID_ALPHABET.iter().position(|&d| d == c)?;
+        value = value.checked_mul(32)? | digit as u64;
+    }
+    let micros = value >> 10;
+    let clock_id = value & ((1 << 10) - 1);
+    SnowflakeScheme::TWITTER
+        .id(
+            MillisSinceUnixEpoch(micros / 1000),
+            ((micros % 1000) << 10) | clock_id,
+        )
+        .ok()
+        .map(Snowflake)
+}
+
+// The TID which snowflake_from_tid converts to the Snowflake.
+pub fn tid_from_snowflake(snowflake: Snowflake) -> String {
+    let millis = SnowflakeScheme::TWITTER.millis(snowflake.0);
+    let sequence = SnowflakeScheme::TWITTER.sequence(snowflake.0);
+    let micros = millis.0 * 1000 + (sequence >> 10).min(999);
+    let mut value = (micros << 10) | (sequence & ((1 << 10) - 1));
+    let mut tid = vec![0; TID_LENGTH];
+    for c in tid.iter_mut().rev() {
+        *c = TID_ALPHABET[(value % 32) as usize];
+        value /= 32;
+    }
+    String::from_utf8(tid).expect("TIDs are ASCII")
+}
+
+// For at://did/collection/rkey URIs whose record key is a TID.
+pub fn snowflake_from_uri(uri: &str) -> Option<Snowflake> {
+    uri.rsplit('/').next().and_then(snowflake_from_tid)
+}
+
+#[derive(Deserialize)]
+pub struct AuthorFeed {
+    // Absent after the last page.
+    pub cursor: Option<String>,
+    pub feed: Vec<FeedViewPost>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeedViewPost {
+    pub post: PostView,
+    pub reason: Option<FeedReason>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PostView {
+    pub uri: String,
+    pub author: ProfileView,
+    pub record: PostRecord,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProfileView {
+    pub handle: String,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+}
+
+impl ProfileView {
+    fn into_author(self) -> TweetAuthor {
+        TweetAuthor {
+            name: match self.display_name {
+                Some(ref display_name) if !display_name.is_empty() => display_name.clone(),
+                _ => self.handle.clone(),
+            },
+            screen_name: self.handle,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PostRecord {
+    #[serde(default)]
+    pub text: String,
+    pub reply: Option<ReplyRef>,
+    #[serde(default)]
+    pub facets: Vec<Facet>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplyRef {
+    pub parent: StrongRef,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StrongRef {
+    pub uri: String,
+}
+
+// Rich text annotations, over a range of the text's UTF-8 bytes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Facet {
+    pub index: ByteSlice,
+    pub features: Vec<FacetFeature>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ByteSlice {
+    #[serde(rename = "byteStart")]
+    pub byte_start: usize,
+    #[serde(rename = "byteEnd")]
+    pub byte_end: usize,
+}
+
+// Which fields are present depends on the type, e.g. app.bsky.richtext.facet#tag has a tag.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FacetFeature {
+    #[serde(rename = "$type")]
+    pub kind: String,
+    pub tag: Option<String>,
+    pub uri: Option<String>,
+}
+
+// Why a post is in the feed, if not because the author posted it.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeedReason {
+    #[serde(rename = "$type")]
+    pub kind: String,
+    pub by: Option<ProfileView>,
+    // Of the repost record, which older AppViews leave out.
+    pub uri: Option<String>,
+    #[serde(rename = "indexedAt")]
+    pub indexed_at: Option<String>,
+}
+
+const REASON_REPOST: &str = "app.bsky.feed.defs#reasonRepost";
+const FEATURE_TAG: &str = "app.bsky.richtext.facet#tag";
+const FEATURE_MENTION: &str = "app.bsky.richtext.facet#mention";
+const FEATURE_LINK: &str = "app.bsky.richtext.facet#link";
+
+impl FeedViewPost {
+    // Reposts become retweets, at the time they were reposted. None for pinned posts, which are
+    // out of order, and for posts whose time can't be told from their URI.
+    pub fn into_tweet(self) -> Option<TweetFromTwitter> {
+        let FeedViewPost { post, reason } = self;
+        let post_id = snowflake_from_uri(&post.uri)?;
+        let (id, author, retweeted_status) = match reason {
+            Some(reason) => {
+                if reason.kind != REASON_REPOST {
+                    return None;
+                }
+                let id = match reason.uri.as_ref().and_then(|uri| snowflake_from_uri(uri)) {
+                    Some(id) => id,
+                    // Borrow the post's sub-millisecond bits, to tell apart reposts made at the
+                    // same time.
+                    None => {
+                        let reposted_at = reason.indexed_at.as_ref().and_then(|indexed_at| {
+                            SecondsSinceUnixEpoch::parse_utc_timestamp(indexed_at)
+                        })?;
+                        Snowflake(
+                            Snowflake::from(reposted_at).0
+                                | SnowflakeScheme::TWITTER.sequence(post_id.0),
+                        )
+                    }
+                };
+                let author = reason.by.unwrap_or(post.author);
+                (id, author, Some(RetweetedStatus { id: post_id }))
+            }
+            None => (post_id, post.author, None),
+        };
+
+        let record = post.record;
+        let mut entities = TweetEntities::default();
+        for facet in record.facets.iter() {
+            let text = record
+                .text
+                .get(facet.index.byte_start..facet.index.byte_end)
+                .unwrap_or("");
+            for feature in facet.features.iter() {
+                match feature.kind.as_str() {
+                    FEATURE_TAG => entities.hashtags.extend(
+                        feature
+                            .tag
+                            .as_ref()
+                            .map(|tag| Hashtag { text: tag.clone() }),
+                    ),
+                    FEATURE_MENTION => entities.user_mentions.push(UserMention {
+                        screen_name: text.trim_start_matches('@').to_owned(),
+                    }),
+                    FEATURE_LINK => {
+                        entities
+                            .urls
+                            .extend(feature.uri.as_ref().map(|uri| TweetUrl {
+                                url: text.to_owned(),
+                                expanded_url: Some(uri.clone()),
+                            }))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut tweet = TweetFromTwitter::new(id);
+        tweet.text = Some(record.text);
+        tweet.author = Some(author.into_author());
+        tweet.created_at = Some(id.created_at());
+        tweet.entities = Some(entities);
+        tweet.retweeted_status = retweeted_status;
+        tweet.in_reply_to_status_id = record
+            .reply
+            .and_then(|reply| snowflake_from_uri(&reply.parent.uri));
+        Some(tweet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_actor, snowflake_from_tid, snowflake_from_uri, tid_from_snowflake, AuthorFeed,
+    };
+    use serde_json;
+    use tweetstore::{SecondsSinceUnixEpoch, Snowflake};
+
+    #[test]
+    fn parse_handles() {
+        assert_eq!(
+            parse_actor("@Someone.bsky.social"),
+            Ok("someone.bsky.social".to_owned())
+        );
+        assert_eq!(
+            parse_actor("did:plc:abc123"),
+            Ok("did:plc:abc123".to_owned())
+        );
+        assert!(parse_actor("someone").is_err());
+        assert!(parse_actor("someone.bsky.social/../x").is_err());
+    }
+
+    #[test]
+    fn tids_keep_their_time_and_order() {
+        // Posted at 2018-11-01T00:00:00.123456Z, with clock ids 7 and 8.
+        let earlier = snowflake_from_tid("3ftlwgtgsm22b").expect("Snowflake");
+        let later = snowflake_from_tid("3ftlwgtgsm22c").expect("Snowflake");
+        assert_eq!(earlier, Snowflake(1057784281814212615));
+        assert!(earlier < later);
+        assert_eq!(
+            SecondsSinceUnixEpoch::from(earlier),
+            SecondsSinceUnixEpoch(1541030400)
+        );
+        assert_eq!(
+            snowflake_from_uri("at://did:plc:abc/app.bsky.feed.post/3ftlwgtgsm22b"),
+            Some(earlier)
+        );
+        assert_eq!(tid_from_snowflake(later), "3ftlwgtgsm22c");
+        assert_eq!(snowflake_from_tid("self"), None);
+        assert_eq!(snowflake_from_tid("3ftlwgtgsm22!"), None);
+    }
+
+    #[test]
+    fn convert_feed() {
+        let feed: AuthorFeed = serde_json::from_str(
+            r#"{
+              "cursor": "2018-11-01T00:00:00.000Z",
+              "feed": [
+                {
+                  "post": {
+                    "uri": "at://did:plc:abc/app.bsky.feed.post/3ftlwgtgsm22c",
+                    "author": { "handle": "someone.bsky.social", "displayName": "Some One" },
+                    "record": {
+                      "$type": "app.bsky.feed.post",
+                      "text": "Hi @other.bsky.social #tag",
+                      "createdAt": "2018-11-01T00:00:00.123Z",
+                      "reply": {
+                        "root": { "uri": "at://did:plc:def/app.bsky.feed.post/3ftlwgtgsm22b" },
+                        "parent": { "uri": "at://did:plc:def/app.bsky.feed.post/3ftlwgtgsm22b" }
+                      },
+                      "facets": [
+                        {
+                          "index": { "byteStart": 3, "byteEnd": 21 },
+                          "features": [ { "$type": "app.bsky.richtext.facet#mention", "did": "did:plc:def" } ]
+                        },
+                        {
+                          "index": { "byteStart": 22, "byteEnd": 26 },
+                          "features": [ { "$type": "app.bsky.richtext.facet#tag", "tag": "tag" } ]
+                        }
+                      ]
+                    },
+                    "indexedAt": "2018-11-01T00:00:00.200Z"
+                  }
+                },
+                {
+                  "post": {
+                    "uri": "at://did:plc:def/app.bsky.feed.post/3ftlwgtgsm22b",
+                    "author": { "handle": "other.bsky.social" },
+                    "record": { "text": "Reposted" },
+                    "indexedAt": "2018-11-01T00:00:00.200Z"
+                  },
+                  "reason": {
+                    "$type": "app.bsky.feed.defs#reasonRepost",
+                    "by": { "handle": "someone.bsky.social", "displayName": "Some One" },
+                    "indexedAt": "2018-11-01T00:01:00.000Z"
+                  }
+                }
+              ]
+            }"#,
+        )
+        .expect("Parsing feed");
+        let tweets: Vec<_> = feed
+            .feed
+            .into_iter()
+            .filter_map(|item| item.into_tweet())
+            .collect();
+        assert_eq!(tweets.len(), 2);
+
+        let post = &tweets[0];
+        let parent = snowflake_from_tid("3ftlwgtgsm22b");
+        assert_eq!(post.in_reply_to_status_id, parent);
+        let entities = post.entities.as_ref().expect("Entities");
+        assert_eq!(entities.user_mentions[0].screen_name, "other.bsky.social");
+        assert_eq!(entities.hashtags[0].text, "tag");
+
+        let repost = &tweets[1];
+        assert_eq!(
+            repost
+                .retweeted_status
+                .as_ref()
+                .map(|retweeted| retweeted.id),
+            parent
+        );
+        assert_eq!(
+            SecondsSinceUnixEpoch::from(repost.id),
+            SecondsSinceUnixEpoch(1541030460)
+        );
+        assert_eq!(
+            repost
+                .author
+                .as_ref()
+                .map(|author| author.screen_name.as_str()),
+            Some("someone.bsky.social")
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use ring::digest;
+use serde_json;
+use std::fs;
+use std::path::PathBuf;
+
+// Whether Twitter is actually talked to.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CassetteMode {
+    // Requests are made as usual, and every response is written to a fixture file.
+    Record,
+    // No requests are made; responses are read back from fixture files, and requests without one
+    // fail.
+    Replay,
+}
+
+// A directory of recorded Twitter API responses, one file per distinct request, so that
+// development and tests can run without network access or credentials.
+#[derive(Clone, Debug)]
+pub struct Cassette {
+    mode: CassetteMode,
+    dir: PathBuf,
+}
+
+// A request to Twitter, or another source's API, described as data so that it can be keyed on.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ApiRequest {
+    pub url: String,
+    // Query parameters, which are also what's signed for OAuth 1.
+    pub query: Vec<(String, String)>,
+    // The body of a POST; requests without one are GETs.
+    pub json: Option<String>,
+}
+
+impl ApiRequest {
+    pub fn get(url: &str, query: Vec<(String, String)>) -> ApiRequest {
+        ApiRequest {
+            url: url.to_owned(),
+            query,
+            json: None,
+        }
+    }
+
+    pub fn post(url: &str, json: String) -> ApiRequest {
+        ApiRequest {
+            url: url.to_owned(),
+            query: vec![],
+            json: Some(json),
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        if self.json.is_some() {
+            "POST"
+        } else {
+            "GET"
+        }
+    }
+
+    // Identifies the request. Query parameters are sorted so that the order they were built in
+    // doesn't matter.
+    pub fn key(&self) -> String {
+        let mut query = self.query.clone();
+        query.sort();
+        format!(
+            "{} {} {:?} {}",
+            self.method(),
+            self.url,
+            query,
+            self.json.as_ref().map_or("", |json| json.as_str())
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl ApiResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.0.eq_ignore_ascii_case(name))
+            .map(|header| header.1.as_str())
+    }
+}
+
+// The request is stored alongside the response only so that fixture files can be read by people;
+// it isn't checked on replay.
+#[derive(Deserialize, Serialize)]
+struct Fixture {
+    request: ApiRequest,
+    response: ApiResponse,
+}
+
+impl Cassette {
+    pub fn new<P: Into<PathBuf>>(mode: CassetteMode, dir: P) -> Cassette {
+        Cassette {
+            mode,
+            dir: dir.into(),
+        }
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    pub fn record(&self, request: &ApiRequest, response: &ApiResponse) -> Result<(), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|err| format!("Error creating cassette directory {:?}: {}", self.dir, err))?;
+        let path = self.fixture_path(request);
+        let fixture = Fixture {
+            request: request.clone(),
+            response: response.clone(),
+        };
+        let json = serde_json::to_string_pretty(&fixture)
+            .map_err(|err| format!("Error serializing fixture: {}", err))?;
+        fs::write(&path, json).map_err(|err| format!("Error writing fixture {:?}: {}", path, err))
+    }
+
+    pub fn replay(&self, request: &ApiRequest) -> Result<ApiResponse, String> {
+        let path = self.fixture_path(request);
+        let json = fs::read_to_string(&path).map_err(|err| {
+            format!(
+                "No recorded response for {} {} {:?} in {:?}: {}",
+                request.method(),
+                request.url,
+                request.query,
+                path,
+                err
+            )
+        })?;
+        serde_json::from_str::<Fixture>(&json)
+            .map(|fixture| fixture.response)
+            .map_err(|err| format!("Error parsing fixture {:?}: {}", path, err))
+    }
+
+    fn fixture_path(&self, request: &ApiRequest) -> PathBuf {
+        let hash: String = digest::digest(&digest::SHA256, request.key().as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.join(format!("{}.json", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApiRequest, ApiResponse, Cassette, CassetteMode};
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn record_then_replay() {
+        let dir = env::temp_dir().join(format!("twimetravel-cassette-{}", ::std::process::id()));
+        let recorder = Cassette::new(CassetteMode::Record, &dir);
+        let replayer = Cassette::new(CassetteMode::Replay, &dir);
+        let request = |query: Vec<(&str, &str)>| {
+            ApiRequest::get(
+                "https://api.twitter.com/1.1/statuses/user_timeline.json",
+                query
+                    .into_iter()
+                    .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                    .collect(),
+            )
+        };
+        let response = ApiResponse {
+            status: 200,
+            headers: vec![("X-Rate-Limit-Remaining".to_owned(), "899".to_owned())],
+            body: "[]".to_owned(),
+        };
+
+        recorder
+            .record(
+                &request(vec![("screen_name", "someone"), ("count", "200")]),
+                &response,
+            )
+            .expect("Record");
+        let replayed = replayer
+            .replay(&request(vec![("count", "200"), ("screen_name", "someone")]))
+            .expect("Replay");
+        assert_eq!(replayed, response);
+        assert_eq!(replayed.header("x-rate-limit-remaining"), Some("899"));
+        assert!(replayer
+            .replay(&request(vec![("screen_name", "someone else")]))
+            .is_err());
+
+        fs::remove_dir_all(&dir).expect("Remove cassette directory");
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use SecondsSinceUnixEpoch;
+
+// Usage beacons sent by the frontend.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum FrontendEvent {
+    ReplayStarted {
+        who: String,
+        from: SecondsSinceUnixEpoch,
+        until: SecondsSinceUnixEpoch,
+    },
+    WindowSelected {
+        from: SecondsSinceUnixEpoch,
+        until: SecondsSinceUnixEpoch,
+    },
+    PlaybackSpeed {
+        speed: f64,
+    },
+}
+
+impl FrontendEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &FrontendEvent::ReplayStarted { .. } => "replay_started",
+            &FrontendEvent::WindowSelected { .. } => "window_selected",
+            &FrontendEvent::PlaybackSpeed { .. } => "playback_speed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecordedEvent {
+    pub received: SecondsSinceUnixEpoch,
+    pub event: FrontendEvent,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventSummary {
+    pub counts: BTreeMap<&'static str, u64>,
+    pub recent: Vec<RecordedEvent>,
+}
+
+// Counts every event recorded, and keeps the most recent ones.
+pub struct EventLog {
+    capacity: usize,
+    inner: Mutex<EventLogInner>,
+}
+
+struct EventLogInner {
+    counts: BTreeMap<&'static str, u64>,
+    recent: VecDeque<RecordedEvent>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        EventLog {
+            capacity,
+            inner: Mutex::new(EventLogInner {
+                counts: BTreeMap::new(),
+                recent: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn record(&self, event: FrontendEvent, received: SecondsSinceUnixEpoch) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.counts.entry(event.name()).or_insert(0) += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if inner.recent.len() == self.capacity {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(RecordedEvent { received, event });
+    }
+
+    pub fn summary(&self) -> EventSummary {
+        let inner = self.inner.lock().unwrap();
+        EventSummary {
+            counts: inner.counts.clone(),
+            recent: inner.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventLog, FrontendEvent, RecordedEvent};
+    use serde_json;
+    use SecondsSinceUnixEpoch;
+
+    #[test]
+    fn parse() {
+        let event: FrontendEvent =
+            serde_json::from_str(r#"{"event": "playback_speed", "speed": 2.5}"#).expect("Parse");
+        assert_eq!(event, FrontendEvent::PlaybackSpeed { speed: 2.5 });
+    }
+
+    #[test]
+    fn keeps_recent_and_counts_all() {
+        let log = EventLog::new(2);
+        for speed in 1..4 {
+            log.record(
+                FrontendEvent::PlaybackSpeed {
+                    speed: speed as f64,
+                },
+                SecondsSinceUnixEpoch(speed),
+            );
+        }
+        log.record(
+            FrontendEvent::WindowSelected {
+                from: SecondsSinceUnixEpoch(10),
+                until: SecondsSinceUnixEpoch(20),
+            },
+            SecondsSinceUnixEpoch(4),
+        );
+
+        let summary = log.summary();
+        assert_eq!(summary.counts.get("playback_speed"), Some(&3));
+        assert_eq!(summary.counts.get("window_selected"), Some(&1));
+        assert_eq!(
+            summary.recent,
+            vec![
+                RecordedEvent {
+                    received: SecondsSinceUnixEpoch(3),
+                    event: FrontendEvent::PlaybackSpeed { speed: 3.0 },
+                },
+                RecordedEvent {
+                    received: SecondsSinceUnixEpoch(4),
+                    event: FrontendEvent::WindowSelected {
+                        from: SecondsSinceUnixEpoch(10),
+                        until: SecondsSinceUnixEpoch(20),
+                    },
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use serde_json;
+use std::io::Write;
+use tweetstore::{SecondsSinceUnixEpoch, Snowflake, TweetFromTwitter};
+use UniquelyIdentifiedTimeValue;
+
+// How to write out exported tweets.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    // A single JSON array.
+    Json,
+    // One JSON object per line.
+    Ndjson,
+    // A header row, then one row per tweet, with only the most commonly wanted fields.
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match *self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv",
+        }
+    }
+}
+
+// A tweet, along with when it was posted as derived from its id, which is present even for tweets
+// cached before created_at was captured.
+#[derive(Serialize)]
+struct ExportedTweet<'a> {
+    id: Snowflake,
+    timestamp: SecondsSinceUnixEpoch,
+    tweet: &'a TweetFromTwitter,
+}
+
+impl<'a> ExportedTweet<'a> {
+    fn new(tweet: &'a TweetFromTwitter) -> ExportedTweet<'a> {
+        ExportedTweet {
+            id: tweet.id,
+            timestamp: tweet.time().into(),
+            tweet,
+        }
+    }
+}
+
+pub fn write_tweets<W: Write>(
+    tweets: &[TweetFromTwitter],
+    format: ExportFormat,
+    writer: &mut W,
+) -> Result<(), String> {
+    match format {
+        ExportFormat::Json => {
+            let exported: Vec<_> = tweets.iter().map(ExportedTweet::new).collect();
+            serde_json::to_writer(&mut *writer, &exported)
+                .map_err(|err| format!("Error writing JSON: {}", err))
+        }
+        ExportFormat::Ndjson => {
+            for tweet in tweets {
+                serde_json::to_writer(&mut *writer, &ExportedTweet::new(tweet))
+                    .map_err(|err| format!("Error writing JSON: {}", err))?;
+                writeln!(writer).map_err(|err| format!("Error writing JSON: {}", err))?;
+            }
+            Ok(())
+        }
+        ExportFormat::Csv => {
+            write_csv_row(
+                writer,
+                &["id", "timestamp", "created_at", "screen_name", "text"],
+            )?;
+            for tweet in tweets {
+                let timestamp: SecondsSinceUnixEpoch = tweet.time().into();
+                write_csv_row(
+                    writer,
+                    &[
+                        &format!("{}", tweet.id),
+                        &format!("{}", timestamp),
+                        tweet.created_at.as_ref().map_or("", |s| s.as_str()),
+                        tweet
+                            .author
+                            .as_ref()
+                            .map_or("", |author| author.screen_name.as_str()),
+                        tweet.text.as_ref().map_or("", |s| s.as_str()),
+                    ],
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_csv_row<W: Write>(writer: &mut W, fields: &[&str]) -> Result<(), String> {
+    let row: Vec<_> = fields.iter().map(|field| csv_field(field)).collect();
+    write!(writer, "{}\r\n", row.join(",")).map_err(|err| format!("Error writing CSV: {}", err))
+}
+
+// Quotes the field per RFC 4180 if it contains anything which would otherwise be ambiguous.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_tweets, ExportFormat};
+    use tweetstore::{Snowflake, TweetFromTwitter};
+
+    #[test]
+    fn csv() {
+        let mut tweet = TweetFromTwitter::new(Snowflake(1 << 22));
+        tweet.text = Some("Hello, \"world\"\nagain".to_owned());
+        let mut bytes = Vec::new();
+        write_tweets(&[tweet], ExportFormat::Csv, &mut bytes).expect("Write");
+        assert_eq!(
+            String::from_utf8(bytes).expect("UTF-8"),
+            "id,timestamp,created_at,screen_name,text\r\n\
+             4194304,1288834974,,,\"Hello, \"\"world\"\"\nagain\"\r\n"
+        );
+    }
+
+    #[test]
+    fn ndjson() {
+        let tweets = vec![
+            TweetFromTwitter::new(Snowflake(1)),
+            TweetFromTwitter::new(Snowflake(2)),
+        ];
+        let mut bytes = Vec::new();
+        write_tweets(&tweets, ExportFormat::Ndjson, &mut bytes).expect("Write");
+        let lines: Vec<_> = String::from_utf8(bytes)
+            .expect("UTF-8")
+            .lines()
+            .map(|line| line.starts_with("{\"id\":") && line.ends_with('}'))
+            .collect();
+        assert_eq!(lines, vec![true, true]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+// A way of getting tweets for an interval which isn't cached.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchMethod {
+    UserTimeline,
+    Search30Day,
+    SearchFullArchive,
+    // Whichever configured search product can reach back to the start of the interval, preferring
+    // 30day.
+    Search,
+    // Never fetch; only serve what is already cached.
+    CacheOnly,
+}
+
+// What the session requesting the fetch is allowed to do.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entitlements {
+    pub logged_in: bool,
+    pub search_enabled: bool,
+}
+
+// A rule applies if all of its set conditions match. Its methods are tried in order until one
+// produces tweets.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FetchRule {
+    // Age of the start of the interval.
+    pub min_age_secs: Option<u64>,
+    pub max_age_secs: Option<u64>,
+    pub min_window_secs: Option<u64>,
+    pub max_window_secs: Option<u64>,
+    pub logged_in: Option<bool>,
+    pub search_enabled: Option<bool>,
+    pub methods: Vec<FetchMethod>,
+}
+
+impl FetchRule {
+    fn matches(&self, age: Duration, window: Duration, entitlements: Entitlements) -> bool {
+        let at_least =
+            |value: Duration, min: Option<u64>| min.map_or(true, |min| value.as_secs() >= min);
+        let at_most =
+            |value: Duration, max: Option<u64>| max.map_or(true, |max| value.as_secs() <= max);
+        at_least(age, self.min_age_secs)
+            && at_most(age, self.max_age_secs)
+            && at_least(window, self.min_window_secs)
+            && at_most(window, self.max_window_secs)
+            && self
+                .logged_in
+                .map_or(true, |logged_in| logged_in == entitlements.logged_in)
+            && self.search_enabled.map_or(true, |search_enabled| {
+                search_enabled == entitlements.search_enabled
+            })
+    }
+}
+
+// Ordered rules deciding how to fetch an interval; the first matching rule wins.
+#[derive(Clone, Debug)]
+pub struct FetchStrategy {
+    rules: Vec<FetchRule>,
+}
+
+impl FetchStrategy {
+    pub fn new(rules: Vec<FetchRule>) -> FetchStrategy {
+        FetchStrategy { rules }
+    }
+
+    pub fn methods(
+        &self,
+        age: Duration,
+        window: Duration,
+        entitlements: Entitlements,
+    ) -> Option<&[FetchMethod]> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(age, window, entitlements))
+            .map(|rule| rule.methods.as_slice())
+    }
+}
+
+impl Default for FetchStrategy {
+    // Try the user timeline, falling back to search for users allowed to use it.
+    fn default() -> FetchStrategy {
+        FetchStrategy::new(vec![
+            FetchRule {
+                min_age_secs: None,
+                max_age_secs: None,
+                min_window_secs: None,
+                max_window_secs: None,
+                logged_in: None,
+                search_enabled: Some(true),
+                methods: vec![FetchMethod::UserTimeline, FetchMethod::Search],
+            },
+            FetchRule {
+                min_age_secs: None,
+                max_age_secs: None,
+                min_window_secs: None,
+                max_window_secs: None,
+                logged_in: None,
+                search_enabled: None,
+                methods: vec![FetchMethod::UserTimeline],
+            },
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entitlements, FetchMethod, FetchRule, FetchStrategy};
+    use std::time::Duration;
+
+    #[test]
+    fn default_search_enabled() {
+        assert_eq!(
+            FetchStrategy::default().methods(secs(10), secs(10), entitlements(true)),
+            Some(&[FetchMethod::UserTimeline, FetchMethod::Search][..])
+        );
+    }
+
+    #[test]
+    fn default_not_search_enabled() {
+        assert_eq!(
+            FetchStrategy::default().methods(secs(10), secs(10), entitlements(false)),
+            Some(&[FetchMethod::UserTimeline][..])
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let strategy = FetchStrategy::new(vec![
+            rule(Some(100), None, vec![FetchMethod::CacheOnly]),
+            rule(None, Some(60), vec![FetchMethod::Search30Day]),
+            rule(None, None, vec![FetchMethod::UserTimeline]),
+        ]);
+        assert_eq!(
+            strategy.methods(secs(200), secs(10), entitlements(true)),
+            Some(&[FetchMethod::CacheOnly][..])
+        );
+        assert_eq!(
+            strategy.methods(secs(50), secs(10), entitlements(true)),
+            Some(&[FetchMethod::Search30Day][..])
+        );
+        assert_eq!(
+            strategy.methods(secs(50), secs(100), entitlements(true)),
+            Some(&[FetchMethod::UserTimeline][..])
+        );
+    }
+
+    #[test]
+    fn no_matching_rule() {
+        let strategy = FetchStrategy::new(vec![rule(Some(100), None, vec![])]);
+        assert_eq!(
+            strategy.methods(secs(50), secs(10), entitlements(true)),
+            None
+        );
+    }
+
+    fn rule(
+        min_age_secs: Option<u64>,
+        max_window_secs: Option<u64>,
+        methods: Vec<FetchMethod>,
+    ) -> FetchRule {
+        FetchRule {
+            min_age_secs,
+            max_age_secs: None,
+            min_window_secs: None,
+            max_window_secs,
+            logged_in: None,
+            search_enabled: None,
+            methods,
+        }
+    }
+
+    fn entitlements(search_enabled: bool) -> Entitlements {
+        Entitlements {
+            logged_in: true,
+            search_enabled,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+}
@@ -0,0 +1,46 @@
+use reqwest;
+use std::time::Duration;
+
+// How long requests to Twitter and other sources may take, so that a hung endpoint fails the
+// fetch rather than hanging whoever is waiting on it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub struct HttpTimeouts {
+    #[serde(default = "default_connect_secs")]
+    pub connect_secs: u64,
+    // Including connecting, and reading the whole response.
+    #[serde(default = "default_request_secs")]
+    pub request_secs: u64,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> HttpTimeouts {
+        HttpTimeouts {
+            connect_secs: default_connect_secs(),
+            request_secs: default_request_secs(),
+        }
+    }
+}
+
+impl HttpTimeouts {
+    pub fn client_builder(&self) -> reqwest::blocking::ClientBuilder {
+        reqwest::blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_secs))
+            .timeout(Duration::from_secs(self.request_secs))
+    }
+
+    // Clients with the default timeouts, for when none is given.
+    pub fn default_client() -> reqwest::blocking::Client {
+        HttpTimeouts::default()
+            .client_builder()
+            .build()
+            .expect("Building HTTP client")
+    }
+}
+
+fn default_connect_secs() -> u64 {
+    10
+}
+
+fn default_request_secs() -> u64 {
+    30
+}
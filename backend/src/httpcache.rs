@@ -0,0 +1,152 @@
+use cassette::{ApiRequest, ApiResponse};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+// A successful response, and what the server said identifies its version.
+struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    response: ApiResponse,
+}
+
+#[derive(Default)]
+struct CachedResponses {
+    by_request: HashMap<String, CachedResponse>,
+    // Oldest first, so that the oldest responses are the first to be forgotten.
+    requests: VecDeque<String>,
+}
+
+// Responses which came with an ETag or Last-Modified header, so that sending the same request
+// again can be made conditional on the response having changed. Refetching recently fetched
+// intervals then costs a 304 rather than the whole response.
+pub struct HttpCache {
+    max_responses: usize,
+    responses: Mutex<CachedResponses>,
+}
+
+impl HttpCache {
+    pub fn new(max_responses: usize) -> HttpCache {
+        HttpCache {
+            max_responses,
+            responses: Mutex::new(CachedResponses::default()),
+        }
+    }
+
+    // Headers which make the request conditional on its cached response, if there is one.
+    pub fn conditional_headers(&self, request: &ApiRequest) -> Vec<(&'static str, String)> {
+        let responses = self.responses.lock().unwrap();
+        let cached = match responses.by_request.get(&request.key()) {
+            Some(cached) => cached,
+            None => return vec![],
+        };
+        let mut headers = vec![];
+        if let Some(ref etag) = cached.etag {
+            headers.push(("If-None-Match", etag.clone()));
+        }
+        if let Some(ref last_modified) = cached.last_modified {
+            headers.push(("If-Modified-Since", last_modified.clone()));
+        }
+        headers
+    }
+
+    // The cached response if the server says it's unchanged, keeping the new response's headers,
+    // e.g. for their rate limits. Otherwise the response itself, cached if it can be validated.
+    pub fn resolve(&self, request: &ApiRequest, response: ApiResponse) -> ApiResponse {
+        let key = request.key();
+        let mut responses = self.responses.lock().unwrap();
+        if response.status == 304 {
+            return match responses.by_request.get(&key) {
+                Some(cached) => ApiResponse {
+                    status: cached.response.status,
+                    headers: response.headers,
+                    body: cached.response.body.clone(),
+                },
+                None => response,
+            };
+        }
+        let etag = response.header("etag").map(str::to_owned);
+        let last_modified = response.header("last-modified").map(str::to_owned);
+        if response.status != 200 || (etag.is_none() && last_modified.is_none()) {
+            if responses.by_request.remove(&key).is_some() {
+                responses.requests.retain(|request| *request != key);
+            }
+            return response;
+        }
+        let cached = CachedResponse {
+            etag,
+            last_modified,
+            response: response.clone(),
+        };
+        if responses.by_request.insert(key.clone(), cached).is_none() {
+            responses.requests.push_back(key);
+            while responses.requests.len() > self.max_responses {
+                if let Some(oldest) = responses.requests.pop_front() {
+                    responses.by_request.remove(&oldest);
+                }
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpCache;
+    use cassette::{ApiRequest, ApiResponse};
+
+    #[test]
+    fn unchanged_response_is_served_from_cache() {
+        let cache = HttpCache::new(1);
+        let request = |screen_name: &str| {
+            ApiRequest::get(
+                "https://api.twitter.com/1.1/statuses/user_timeline.json",
+                vec![("screen_name".to_owned(), screen_name.to_owned())],
+            )
+        };
+        let response = |status: u16, headers: Vec<(&str, &str)>, body: &str| ApiResponse {
+            status,
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            body: body.to_owned(),
+        };
+
+        assert!(cache.conditional_headers(&request("someone")).is_empty());
+        cache.resolve(
+            &request("someone"),
+            response(200, vec![("ETag", "\"v1\"")], "[1]"),
+        );
+        assert_eq!(
+            cache.conditional_headers(&request("someone")),
+            vec![("If-None-Match", "\"v1\"".to_owned())]
+        );
+
+        let resolved = cache.resolve(
+            &request("someone"),
+            response(304, vec![("x-rate-limit-remaining", "898")], ""),
+        );
+        assert_eq!(
+            resolved,
+            response(200, vec![("x-rate-limit-remaining", "898")], "[1]")
+        );
+
+        // Only one response is kept, so the oldest is forgotten.
+        cache.resolve(
+            &request("someone else"),
+            response(
+                200,
+                vec![("Last-Modified", "Mon, 12 Feb 2018 20:00:00 GMT")],
+                "[2]",
+            ),
+        );
+        assert!(cache.conditional_headers(&request("someone")).is_empty());
+        assert_eq!(
+            cache.conditional_headers(&request("someone else")),
+            vec![(
+                "If-Modified-Since",
+                "Mon, 12 Feb 2018 20:00:00 GMT".to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+// A fetch which other callers wanting the same thing wait for, rather than fetching it again.
+struct InFlightFetch<T> {
+    result: Mutex<Option<T>>,
+    done: Condvar,
+}
+
+// Fetches in progress, by what they're fetching.
+pub struct InFlightFetches<K: Eq + Hash, T> {
+    fetches: Mutex<HashMap<K, Arc<InFlightFetch<T>>>>,
+}
+
+// Held by whichever caller is doing the fetch. Its result is published to the callers waiting
+// for it when this is dropped, even if the fetch panicked, so that they're never left waiting.
+struct Leader<'a, K: Eq + Hash, T> {
+    fetches: &'a InFlightFetches<K, T>,
+    key: K,
+    fetch: Arc<InFlightFetch<T>>,
+    result: Option<T>,
+    abandoned: Option<T>,
+}
+
+impl<'a, K: Eq + Hash, T> Drop for Leader<'a, K, T> {
+    fn drop(&mut self) {
+        let result = self.result.take().or_else(|| self.abandoned.take());
+        *lock(&self.fetch.result) = result;
+        self.fetch.done.notify_all();
+        lock(&self.fetches.fetches).remove(&self.key);
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Clone> InFlightFetches<K, T> {
+    pub fn new() -> InFlightFetches<K, T> {
+        InFlightFetches {
+            fetches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs fetch, unless a fetch with the same key is already in progress, in which case waits
+    // for it and returns its result instead. If that fetch panics, its waiters get abandoned.
+    pub fn coalesce<F>(&self, key: K, fetch: F, abandoned: T) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let (in_flight, leading) = {
+            let mut fetches = lock(&self.fetches);
+            match fetches.get(&key) {
+                Some(in_flight) => (in_flight.clone(), false),
+                None => {
+                    let in_flight = Arc::new(InFlightFetch {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    fetches.insert(key.clone(), in_flight.clone());
+                    (in_flight, true)
+                }
+            }
+        };
+
+        if !leading {
+            let mut result = lock(&in_flight.result);
+            while result.is_none() {
+                result = in_flight
+                    .done
+                    .wait(result)
+                    .unwrap_or_else(|err| err.into_inner());
+            }
+            return result.clone().unwrap();
+        }
+
+        let mut leader = Leader {
+            fetches: self,
+            key,
+            fetch: in_flight,
+            result: None,
+            abandoned: Some(abandoned),
+        };
+        let result = fetch();
+        leader.result = Some(result.clone());
+        result
+    }
+}
+
+// A panicking fetch never holds these locks, so their data is still consistent if one did.
+fn lock<T>(mutex: &Mutex<T>) -> ::std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InFlightFetches;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Long enough for a waiting caller to have found the fetch in progress.
+    const SETTLE: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn concurrent_callers_share_one_fetch() {
+        let fetches = Arc::new(InFlightFetches::<&str, Result<u32, String>>::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let fetches = fetches.clone();
+            let calls = calls.clone();
+            thread::spawn(move || {
+                fetches.coalesce(
+                    "someone",
+                    || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        started_sender.send(()).unwrap();
+                        released.recv().unwrap();
+                        Err("Failed".to_owned())
+                    },
+                    Err("Abandoned".to_owned()),
+                )
+            })
+        };
+        started.recv().unwrap();
+        let waiter = {
+            let fetches = fetches.clone();
+            let calls = calls.clone();
+            thread::spawn(move || {
+                fetches.coalesce(
+                    "someone",
+                    || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(1)
+                    },
+                    Err("Abandoned".to_owned()),
+                )
+            })
+        };
+        thread::sleep(SETTLE);
+        release.send(()).unwrap();
+
+        assert_eq!(leader.join().unwrap(), Err("Failed".to_owned()));
+        assert_eq!(waiter.join().unwrap(), Err("Failed".to_owned()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panicking_fetch_releases_waiters() {
+        let fetches = Arc::new(InFlightFetches::<&str, Result<u32, String>>::new());
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let leader = {
+            let fetches = fetches.clone();
+            thread::spawn(move || {
+                fetches.coalesce(
+                    "someone",
+                    || {
+                        started_sender.send(()).unwrap();
+                        released.recv().unwrap();
+                        panic!("Fetch panicked")
+                    },
+                    Err("Abandoned".to_owned()),
+                )
+            })
+        };
+        started.recv().unwrap();
+        let waiter = {
+            let fetches = fetches.clone();
+            thread::spawn(move || {
+                fetches.coalesce("someone", || Ok(1), Err("Abandoned".to_owned()))
+            })
+        };
+        thread::sleep(SETTLE);
+        release.send(()).unwrap();
+
+        assert!(leader.join().is_err());
+        assert_eq!(waiter.join().unwrap(), Err("Abandoned".to_owned()));
+        // The abandoned fetch is forgotten, so the next caller fetches afresh.
+        assert_eq!(
+            fetches.coalesce("someone", || Ok(2), Err("Abandoned".to_owned())),
+            Ok(2)
+        );
+    }
+}
@@ -0,0 +1,1245 @@
+// Closed intervals and sets of them. Only needs alloc, so that it can be built without std,
+// e.g. for client-side caching in the frontend.
+use alloc::collections::{btree_set, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::fmt;
+use core::iter::{FromIterator, Sum};
+use core::ops::{Bound, Sub};
+use core::str::FromStr;
+use core::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Interval<T: Ord>(pub T, pub T);
+
+impl<T: Ord> Interval<T> {
+    pub fn contains(&self, time: &T) -> bool {
+        time >= &self.0 && time <= &self.1
+    }
+
+    pub fn contains_interval(&self, interval: &Interval<T>) -> bool {
+        self.contains(&interval.0) && self.contains(&interval.1)
+    }
+
+    pub fn intersects(&self, interval: &Interval<T>) -> bool {
+        interval.0 <= self.1 && self.0 <= interval.1
+    }
+}
+
+impl<T: Ord + Copy> Interval<T> {
+    pub fn len<D>(&self) -> D
+    where
+        T: Sub<Output = D>,
+    {
+        self.1 - self.0
+    }
+
+    // Only inverted intervals are empty; Interval(t, t) contains t.
+    pub fn is_empty(&self) -> bool {
+        self.1 < self.0
+    }
+
+    // The part of this interval within bounds, if any. (Not named clamp, which Ord provides.)
+    pub fn clamp_to(&self, bounds: &Interval<T>) -> Option<Interval<T>> {
+        let clamped = Interval(max(self.0, bounds.0), min(self.1, bounds.1));
+        if clamped.is_empty() {
+            None
+        } else {
+            Some(clamped)
+        }
+    }
+
+    // Splits the interval into the parts before and after time, which both include time.
+    pub fn split_at(&self, time: T) -> Option<(Interval<T>, Interval<T>)> {
+        if self.contains(&time) {
+            Some((Interval(self.0, time), Interval(time, self.1)))
+        } else {
+            None
+        }
+    }
+
+    // The parts of this interval not covered by other. As with IntervalSet::missing, the
+    // remaining parts share their boundaries with other.
+    pub fn subtract(&self, other: &Interval<T>) -> IntervalSet<T> {
+        let mut remaining = IntervalSet::new();
+        if other.1 < self.0 || self.1 < other.0 {
+            remaining.insert(self);
+            return remaining;
+        }
+        if self.0 < other.0 {
+            remaining.insert(&Interval(self.0, other.0));
+        }
+        if other.1 < self.1 {
+            remaining.insert(&Interval(other.1, self.1));
+        }
+        remaining
+    }
+}
+
+impl<T: Ord + fmt::Display> fmt::Display for Interval<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{},{}]", self.0, self.1)
+    }
+}
+
+// Parses the Display format, e.g. "[10,20]".
+impl<T: Ord + FromStr> FromStr for Interval<T> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Interval<T>, String> {
+        let s = s.trim();
+        if !s.starts_with('[') || !s.ends_with(']') {
+            return Err(format!("Interval {:?} should look like [from,until]", s));
+        }
+        let mut bounds = s[1..s.len() - 1].split(',');
+        let mut parse_bound = || {
+            let bound = bounds
+                .next()
+                .ok_or_else(|| format!("Interval {:?} should look like [from,until]", s))?;
+            bound
+                .trim()
+                .parse()
+                .map_err(|_| format!("Bad bound {:?} in interval {:?}", bound.trim(), s))
+        };
+        let interval = Interval(parse_bound()?, parse_bound()?);
+        if bounds.next().is_some() {
+            return Err(format!("Interval {:?} should look like [from,until]", s));
+        }
+        if interval.1 < interval.0 {
+            return Err(format!("Interval {:?} ends before it starts", s));
+        }
+        Ok(interval)
+    }
+}
+
+impl<'a, T: Ord + Copy> Into<IntervalSet<T>> for &'a Interval<T> {
+    fn into(self) -> IntervalSet<T> {
+        let mut set = IntervalSet::new();
+        set.insert(self);
+        set
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BoundKind {
+    Closed,
+    Open,
+}
+
+// An interval whose ends may each be open or closed. Interval is always closed at both ends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BoundedInterval<T: Ord> {
+    pub lower: T,
+    pub lower_kind: BoundKind,
+    pub upper: T,
+    pub upper_kind: BoundKind,
+}
+
+impl<T: Ord + Copy> BoundedInterval<T> {
+    pub fn new(lower: T, lower_kind: BoundKind, upper: T, upper_kind: BoundKind) -> Self {
+        BoundedInterval {
+            lower,
+            lower_kind,
+            upper,
+            upper_kind,
+        }
+    }
+
+    pub fn closed(lower: T, upper: T) -> Self {
+        BoundedInterval::new(lower, BoundKind::Closed, upper, BoundKind::Closed)
+    }
+
+    pub fn open(lower: T, upper: T) -> Self {
+        BoundedInterval::new(lower, BoundKind::Open, upper, BoundKind::Open)
+    }
+
+    // [lower, upper)
+    pub fn closed_open(lower: T, upper: T) -> Self {
+        BoundedInterval::new(lower, BoundKind::Closed, upper, BoundKind::Open)
+    }
+
+    // (lower, upper]
+    pub fn open_closed(lower: T, upper: T) -> Self {
+        BoundedInterval::new(lower, BoundKind::Open, upper, BoundKind::Closed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lower > self.upper
+            || (self.lower == self.upper
+                && (self.lower_kind == BoundKind::Open || self.upper_kind == BoundKind::Open))
+    }
+
+    pub fn contains(&self, time: &T) -> bool {
+        let above_lower = match self.lower_kind {
+            BoundKind::Closed => time >= &self.lower,
+            BoundKind::Open => time > &self.lower,
+        };
+        let below_upper = match self.upper_kind {
+            BoundKind::Closed => time <= &self.upper,
+            BoundKind::Open => time < &self.upper,
+        };
+        above_lower && below_upper
+    }
+
+    pub fn intersects(&self, other: &BoundedInterval<T>) -> bool {
+        !self.intersection(other).is_empty()
+    }
+
+    pub fn intersection(&self, other: &BoundedInterval<T>) -> BoundedInterval<T> {
+        // Where bounds have the same value, the open one is the tighter.
+        let (lower, lower_kind) = if self.lower > other.lower {
+            (self.lower, self.lower_kind)
+        } else if other.lower > self.lower {
+            (other.lower, other.lower_kind)
+        } else {
+            (self.lower, tighter(self.lower_kind, other.lower_kind))
+        };
+        let (upper, upper_kind) = if self.upper < other.upper {
+            (self.upper, self.upper_kind)
+        } else if other.upper < self.upper {
+            (other.upper, other.upper_kind)
+        } else {
+            (self.upper, tighter(self.upper_kind, other.upper_kind))
+        };
+        BoundedInterval::new(lower, lower_kind, upper, upper_kind)
+    }
+}
+
+fn tighter(left: BoundKind, right: BoundKind) -> BoundKind {
+    if left == BoundKind::Open || right == BoundKind::Open {
+        BoundKind::Open
+    } else {
+        BoundKind::Closed
+    }
+}
+
+impl<T: Ord + Copy> From<Interval<T>> for BoundedInterval<T> {
+    fn from(interval: Interval<T>) -> BoundedInterval<T> {
+        BoundedInterval::closed(interval.0, interval.1)
+    }
+}
+
+// Amounts, like lengths of intervals, which can be expressed as a fraction of each other.
+pub trait Ratio {
+    // 0 if whole is zero.
+    fn ratio(&self, whole: &Self) -> f64;
+}
+
+macro_rules! impl_ratio_for_numbers {
+    ($($number:ty),*) => {
+        $(
+            impl Ratio for $number {
+                fn ratio(&self, whole: &$number) -> f64 {
+                    if *whole == (0 as $number) {
+                        0.0
+                    } else {
+                        *self as f64 / *whole as f64
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_ratio_for_numbers!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+impl Ratio for Duration {
+    fn ratio(&self, whole: &Duration) -> f64 {
+        if *whole == Duration::from_secs(0) {
+            0.0
+        } else {
+            self.as_secs_f64() / whole.as_secs_f64()
+        }
+    }
+}
+
+// Time types with no values between a value and its successor, so that intervals like [1, 5]
+// and [6, 10] cover everything in [1, 10].
+pub trait Step: Sized {
+    fn successor(&self) -> Option<Self>;
+}
+
+macro_rules! impl_step_for_integers {
+    ($($integer:ty),*) => {
+        $(
+            impl Step for $integer {
+                fn successor(&self) -> Option<$integer> {
+                    self.checked_add(1)
+                }
+            }
+        )*
+    };
+}
+
+impl_step_for_integers!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IntervalSet<Time: Ord> {
+    intervals: BTreeSet<Interval<Time>>,
+}
+
+impl<Time: Ord + Copy> IntervalSet<Time> {
+    pub fn new() -> IntervalSet<Time> {
+        IntervalSet {
+            intervals: BTreeSet::new(),
+        }
+    }
+
+    // Like collect, but fails rather than merging intervals which overlap (including sharing a
+    // boundary), or accepting inverted ones.
+    pub fn try_from_iter<It>(iter: It) -> Result<IntervalSet<Time>, String>
+    where
+        It: IntoIterator<Item = Interval<Time>>,
+        Time: fmt::Debug,
+    {
+        let mut intervals: Vec<_> = iter.into_iter().collect();
+        intervals.sort();
+        if let Some(inverted) = intervals.iter().find(|interval| interval.is_empty()) {
+            return Err(format!("Interval {:?} ends before it starts", inverted));
+        }
+        for pair in intervals.windows(2) {
+            if pair[0].intersects(&pair[1]) {
+                return Err(format!("Intervals {:?} and {:?} overlap", pair[0], pair[1]));
+            }
+        }
+        Ok(IntervalSet {
+            intervals: intervals.into_iter().collect(),
+        })
+    }
+
+    pub fn insert(&mut self, interval: &Interval<Time>) {
+        // Merge any intervals which require merging
+        let mut lower_bound = interval.0;
+        let mut upper_bound = interval.1;
+        for existing_interval in self.intersecting(&interval).iter() {
+            if existing_interval.contains_interval(&interval) {
+                return;
+            }
+            lower_bound = min(lower_bound, existing_interval.0);
+            upper_bound = max(upper_bound, existing_interval.1);
+            self.intervals.remove(existing_interval);
+        }
+        self.intervals.insert(Interval(lower_bound, upper_bound));
+        self.debug_validate();
+    }
+
+    // Checks that no interval is inverted and that no two intervals overlap, which every other
+    // method assumes.
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, interval) in self.intervals.iter().enumerate() {
+            if interval.is_empty() {
+                return Err(format!(
+                    "Interval {} of {} ends before it starts",
+                    index,
+                    self.intervals.len()
+                ));
+            }
+        }
+        let mut previous: Option<&Interval<Time>> = None;
+        for (index, interval) in self.intervals.iter().enumerate() {
+            if let Some(previous) = previous {
+                if previous.intersects(interval) {
+                    return Err(format!(
+                        "Intervals {} and {} of {} overlap",
+                        index - 1,
+                        index,
+                        self.intervals.len()
+                    ));
+                }
+            }
+            previous = Some(interval);
+        }
+        Ok(())
+    }
+
+    // With the debug-invariants feature, debug builds check validate after every mutation.
+    fn debug_validate(&self) {
+        #[cfg(feature = "debug-invariants")]
+        debug_assert_eq!(self.validate(), Ok(()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    pub fn contains(&self, interval: &Interval<Time>) -> bool {
+        self.intersecting(&Interval(interval.0, interval.0))
+            .iter()
+            .any(|i| i.contains_interval(interval))
+    }
+
+    // Takes O(log n + k) for k intersecting intervals, however many small intervals there are.
+    pub fn intersecting(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
+        let query_start = Interval(interval.0, interval.0);
+        // The intervals don't overlap, so at most one which starts before the query can reach it.
+        let starting_before = self
+            .intervals
+            .range(..query_start)
+            .next_back()
+            .filter(|existing_interval| existing_interval.1 >= interval.0);
+        let starting_within = self
+            .intervals
+            .range((Bound::Included(query_start), Bound::Unbounded))
+            .take_while(|existing_interval| existing_interval.0 <= interval.1);
+        starting_before
+            .into_iter()
+            .chain(starting_within)
+            .cloned()
+            .collect()
+    }
+
+    pub fn missing(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
+        let mut missing = BTreeSet::new();
+
+        let mut missing_lower_bound = interval.0;
+
+        for existing_interval in self.intersecting(interval).iter() {
+            if existing_interval.1 < interval.0 {
+                continue;
+            } else if existing_interval.0 > interval.1 {
+                break;
+            } else if existing_interval.0 <= missing_lower_bound
+                && existing_interval.1 >= missing_lower_bound
+            {
+                missing_lower_bound = existing_interval.1;
+            } else if existing_interval.0 >= missing_lower_bound {
+                missing.insert(Interval(missing_lower_bound, existing_interval.0));
+                missing_lower_bound = existing_interval.1;
+            }
+        }
+
+        if missing_lower_bound < interval.1 {
+            missing.insert(Interval(missing_lower_bound, interval.1));
+        }
+
+        IntervalSet { intervals: missing }
+    }
+
+    // Like missing, but respecting the interval's bound kinds: the missing parts are open
+    // wherever they meet a covered interval.
+    pub fn missing_bounded(&self, interval: &BoundedInterval<Time>) -> Vec<BoundedInterval<Time>> {
+        let mut missing = Vec::new();
+        if interval.is_empty() {
+            return missing;
+        }
+
+        let mut missing_lower_bound = (interval.lower, interval.lower_kind);
+
+        for existing_interval in self.intervals.iter() {
+            if existing_interval.0 > interval.upper
+                || (existing_interval.0 == interval.upper && interval.upper_kind == BoundKind::Open)
+            {
+                break;
+            } else if existing_interval.1 < missing_lower_bound.0 {
+                continue;
+            }
+            if existing_interval.0 > missing_lower_bound.0 {
+                missing.push(BoundedInterval::new(
+                    missing_lower_bound.0,
+                    missing_lower_bound.1,
+                    existing_interval.0,
+                    BoundKind::Open,
+                ));
+            }
+            missing_lower_bound = (existing_interval.1, BoundKind::Open);
+        }
+
+        let remaining = BoundedInterval::new(
+            missing_lower_bound.0,
+            missing_lower_bound.1,
+            interval.upper,
+            interval.upper_kind,
+        );
+        if !remaining.is_empty() {
+            missing.push(remaining);
+        }
+
+        missing
+    }
+
+    // The parts of the set not covered by the interval.
+    pub fn subtract(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
+        let mut remaining = IntervalSet::new();
+        for existing_interval in self.intervals.iter() {
+            for part in existing_interval.subtract(interval).iter() {
+                remaining.insert(part);
+            }
+        }
+        remaining
+    }
+
+    // Everything within bounds which the set doesn't cover. Like missing, the parts share their
+    // boundaries with the set.
+    pub fn complement(&self, bounds: &Interval<Time>) -> IntervalSet<Time> {
+        let mut complement: IntervalSet<Time> = bounds.into();
+        for interval in self.intersecting(bounds).iter() {
+            complement = complement.subtract(interval);
+        }
+        complement
+    }
+
+    // The total length of the parts of the set within bounds.
+    pub fn covered_amount<D>(&self, bounds: &Interval<Time>) -> D
+    where
+        Time: Sub<Output = D>,
+        D: Sum,
+    {
+        self.intersecting(bounds)
+            .iter()
+            .filter_map(|interval| interval.clamp_to(bounds))
+            .map(|interval| interval.len())
+            .sum()
+    }
+
+    // The fraction of bounds covered by the set, from 0 to 1. Single-point bounds count as fully
+    // covered if the point is.
+    pub fn coverage_ratio<D>(&self, bounds: &Interval<Time>) -> f64
+    where
+        Time: Sub<Output = D>,
+        D: Sum + Ratio,
+    {
+        if self.contains(bounds) {
+            return 1.0;
+        }
+        self.covered_amount(bounds).ratio(&bounds.len())
+    }
+
+    // The uncovered ranges between the first and last intervals. Like missing, they share their
+    // boundaries with the set.
+    pub fn gaps<'a>(&'a self) -> impl Iterator<Item = Interval<Time>> + 'a {
+        self.intervals
+            .iter()
+            .zip(self.intervals.iter().skip(1))
+            .map(|(before, after)| Interval(before.1, after.0))
+    }
+
+    pub fn union(&self, other: &IntervalSet<Time>) -> IntervalSet<Time> {
+        let mut union = self.clone();
+        for interval in other.iter() {
+            union.insert(interval);
+        }
+        union
+    }
+
+    pub fn intersection(&self, other: &IntervalSet<Time>) -> IntervalSet<Time> {
+        let mut intersection = BTreeSet::new();
+        let mut ours = self.intervals.iter().peekable();
+        let mut theirs = other.intervals.iter().peekable();
+        loop {
+            let (our_interval, their_interval) = match (ours.peek(), theirs.peek()) {
+                (Some(our_interval), Some(their_interval)) => (**our_interval, **their_interval),
+                _ => break,
+            };
+            let lower_bound = max(our_interval.0, their_interval.0);
+            let upper_bound = min(our_interval.1, their_interval.1);
+            if lower_bound <= upper_bound {
+                intersection.insert(Interval(lower_bound, upper_bound));
+            }
+            // Whichever interval ends first can't overlap anything else in the other set.
+            if our_interval.1 < their_interval.1 {
+                ours.next();
+            } else {
+                theirs.next();
+            }
+        }
+        IntervalSet {
+            intervals: intersection,
+        }
+    }
+
+    pub fn difference(&self, other: &IntervalSet<Time>) -> IntervalSet<Time> {
+        let mut difference = self.clone();
+        for interval in other.iter() {
+            difference = difference.subtract(interval);
+        }
+        difference
+    }
+
+    // What is covered by exactly one of the sets. As with difference, the results share
+    // boundaries with what both cover.
+    pub fn symmetric_difference(&self, other: &IntervalSet<Time>) -> IntervalSet<Time> {
+        self.union(other).difference(&self.intersection(other))
+    }
+
+    pub fn iter(&self) -> btree_set::Iter<Interval<Time>> {
+        self.intervals.iter()
+    }
+}
+
+impl<Time: Ord + Copy + Step> IntervalSet<Time> {
+    // Like insert, but also merges intervals which abut the inserted one without overlapping it.
+    pub fn insert_merging_adjacent(&mut self, interval: &Interval<Time>) {
+        self.insert(interval);
+        let mut merged = match self.intersecting(interval).iter().next() {
+            Some(merged) => *merged,
+            None => return,
+        };
+        let before = self.intervals.range(..merged).next_back().cloned();
+        if let Some(before) = before {
+            if before.1.successor() == Some(merged.0) {
+                self.intervals.remove(&before);
+                self.intervals.remove(&merged);
+                merged = Interval(before.0, merged.1);
+                self.intervals.insert(merged);
+            }
+        }
+        let after = self
+            .intervals
+            .range((Bound::Excluded(merged), Bound::Unbounded))
+            .next()
+            .cloned();
+        if let Some(after) = after {
+            if merged.1.successor() == Some(after.0) {
+                self.intervals.remove(&after);
+                self.intervals.remove(&merged);
+                self.intervals.insert(Interval(merged.0, after.1));
+            }
+        }
+        self.debug_validate();
+    }
+}
+
+impl<Time: Ord + fmt::Display> fmt::Display for IntervalSet<Time> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.intervals.is_empty() {
+            return write!(f, "∅");
+        }
+        for (index, interval) in self.intervals.iter().enumerate() {
+            if index > 0 {
+                write!(f, " ∪ ")?;
+            }
+            write!(f, "{}", interval)?;
+        }
+        Ok(())
+    }
+}
+
+// Parses the Display format, e.g. "[10,20] ∪ [30,40]" or "∅". Overlapping intervals are merged.
+impl<Time: Ord + Copy + FromStr> FromStr for IntervalSet<Time> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IntervalSet<Time>, String> {
+        let mut set = IntervalSet::new();
+        let s = s.trim();
+        if s.is_empty() || s == "∅" {
+            return Ok(set);
+        }
+        for interval in s.split('∪') {
+            set.insert(&interval.parse()?);
+        }
+        Ok(set)
+    }
+}
+
+// Overlapping intervals are merged, as by insert.
+impl<Time: Ord + Copy> FromIterator<Interval<Time>> for IntervalSet<Time> {
+    fn from_iter<It: IntoIterator<Item = Interval<Time>>>(iter: It) -> Self {
+        let mut set = IntervalSet::new();
+        for interval in iter {
+            set.insert(&interval);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod interval_tests {
+    use super::{Interval, IntervalSet};
+
+    #[test]
+    fn len() {
+        assert_eq!(Interval(10, 25).len(), 15);
+        assert_eq!(Interval(10, 10).len(), 0);
+        assert!(!Interval(10, 10).is_empty());
+        assert!(Interval(10, 9).is_empty());
+    }
+
+    #[test]
+    fn clamp_to() {
+        assert_eq!(
+            Interval(10, 20).clamp_to(&Interval(15, 30)),
+            Some(Interval(15, 20))
+        );
+        assert_eq!(
+            Interval(10, 20).clamp_to(&Interval(0, 30)),
+            Some(Interval(10, 20))
+        );
+        assert_eq!(
+            Interval(10, 20).clamp_to(&Interval(20, 30)),
+            Some(Interval(20, 20))
+        );
+        assert_eq!(Interval(10, 20).clamp_to(&Interval(21, 30)), None);
+    }
+
+    #[test]
+    fn split_at() {
+        assert_eq!(
+            Interval(10, 20).split_at(15),
+            Some((Interval(10, 15), Interval(15, 20)))
+        );
+        assert_eq!(
+            Interval(10, 20).split_at(10),
+            Some((Interval(10, 10), Interval(10, 20)))
+        );
+        assert_eq!(Interval(10, 20).split_at(21), None);
+    }
+
+    #[test]
+    fn subtract_disjoint() {
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(25, 30)),
+            interval_set_of(vec![Interval(10, 20)])
+        );
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(1, 5)),
+            interval_set_of(vec![Interval(10, 20)])
+        );
+    }
+
+    #[test]
+    fn subtract_covering() {
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(10, 20)),
+            IntervalSet::new()
+        );
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(5, 25)),
+            IntervalSet::new()
+        );
+    }
+
+    #[test]
+    fn subtract_lower() {
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(5, 15)),
+            interval_set_of(vec![Interval(15, 20)])
+        );
+    }
+
+    #[test]
+    fn subtract_upper() {
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(15, 25)),
+            interval_set_of(vec![Interval(10, 15)])
+        );
+    }
+
+    #[test]
+    fn subtract_middle() {
+        assert_eq!(
+            Interval(10, 20).subtract(&Interval(12, 15)),
+            interval_set_of(vec![Interval(10, 12), Interval(15, 20)])
+        );
+    }
+
+    fn interval_set_of(intervals: Vec<Interval<u32>>) -> IntervalSet<u32> {
+        IntervalSet {
+            intervals: intervals.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod bounded_interval_tests {
+    use super::{BoundKind, BoundedInterval, Interval, IntervalSet};
+
+    #[test]
+    fn contains() {
+        assert!(BoundedInterval::closed(10, 20).contains(&20));
+        assert!(BoundedInterval::closed_open(10, 20).contains(&10));
+        assert!(!BoundedInterval::closed_open(10, 20).contains(&20));
+        assert!(!BoundedInterval::open_closed(10, 20).contains(&10));
+        assert!(BoundedInterval::open_closed(10, 20).contains(&20));
+        assert!(BoundedInterval::open(10, 20).contains(&15));
+    }
+
+    #[test]
+    fn is_empty() {
+        assert!(!BoundedInterval::closed(10, 10).is_empty());
+        assert!(BoundedInterval::closed_open(10, 10).is_empty());
+        assert!(BoundedInterval::closed(20, 10).is_empty());
+    }
+
+    #[test]
+    fn adjacent_half_open_dont_intersect() {
+        assert!(
+            !BoundedInterval::closed_open(10, 15).intersects(&BoundedInterval::closed_open(15, 20))
+        );
+        assert!(BoundedInterval::closed(10, 15).intersects(&BoundedInterval::closed(15, 20)));
+        assert!(
+            BoundedInterval::closed_open(10, 16).intersects(&BoundedInterval::closed_open(15, 20))
+        );
+    }
+
+    #[test]
+    fn missing_bounded_none() {
+        let set = interval_set_of(vec![Interval(10, 20)]);
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::closed_open(10, 20)),
+            vec![]
+        );
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::closed(10, 20)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn missing_bounded_edges() {
+        let set = interval_set_of(vec![Interval(10, 20)]);
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::closed_open(5, 25)),
+            vec![
+                BoundedInterval::closed_open(5, 10),
+                BoundedInterval::open(20, 25),
+            ]
+        );
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::closed(20, 25)),
+            vec![BoundedInterval::open_closed(20, 25)]
+        );
+    }
+
+    #[test]
+    fn missing_bounded_middle() {
+        let set = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::closed_open(1, 20)),
+            vec![
+                BoundedInterval::closed_open(1, 5),
+                BoundedInterval::open(10, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_bounded_empty_set() {
+        let set = IntervalSet::new();
+        assert_eq!(
+            set.missing_bounded(&BoundedInterval::new(
+                1,
+                BoundKind::Open,
+                5,
+                BoundKind::Closed
+            )),
+            vec![BoundedInterval::open_closed(1, 5)]
+        );
+    }
+
+    fn interval_set_of(intervals: Vec<Interval<u32>>) -> IntervalSet<u32> {
+        IntervalSet {
+            intervals: intervals.into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod intervalset_tests {
+    use super::{Interval, IntervalSet};
+
+    #[test]
+    fn contains_empty() {
+        let set = IntervalSet::new();
+        assert!(!set.contains(&Interval(10, 20)));
+    }
+
+    #[test]
+    fn contains_part() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 15));
+        assert!(!set.contains(&Interval(10, 20)));
+    }
+
+    #[test]
+    fn contains_exact() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 20));
+        assert!(set.contains(&Interval(10, 20)));
+    }
+
+    #[test]
+    fn contains_more() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(5, 25));
+        assert!(set.contains(&Interval(10, 20)));
+    }
+
+    #[test]
+    fn missing_none() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 20));
+        assert_eq!(set.missing(&Interval(10, 20)), IntervalSet::new());
+        assert_eq!(set.missing(&Interval(12, 15)), IntervalSet::new());
+    }
+
+    #[test]
+    fn missing_lower() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 20));
+        assert_eq!(set.missing(&Interval(5, 10)), interval_set(Interval(5, 10)));
+        assert_eq!(set.missing(&Interval(5, 15)), interval_set(Interval(5, 10)));
+    }
+
+    #[test]
+    fn missing_upper() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 20));
+        assert_eq!(
+            set.missing(&Interval(20, 25)),
+            interval_set(Interval(20, 25))
+        );
+        assert_eq!(
+            set.missing(&Interval(15, 25)),
+            interval_set(Interval(20, 25))
+        );
+    }
+
+    #[test]
+    fn missing_middle() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(5, 10));
+        set.insert(&Interval(20, 30));
+        assert_eq!(
+            set.missing(&Interval(12, 15)),
+            interval_set(Interval(12, 15))
+        );
+        assert_eq!(
+            set.missing(&Interval(10, 15)),
+            interval_set(Interval(10, 15))
+        );
+        assert_eq!(
+            set.missing(&Interval(15, 20)),
+            interval_set(Interval(15, 20))
+        );
+        assert_eq!(
+            set.missing(&Interval(15, 25)),
+            interval_set(Interval(15, 20))
+        );
+    }
+
+    #[test]
+    fn missing_multi() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(5, 10));
+        set.insert(&Interval(20, 30));
+        assert_eq!(
+            set.missing(&Interval(1, 40)),
+            interval_set_of(vec![Interval(1, 5), Interval(10, 20), Interval(30, 40)])
+        );
+    }
+
+    #[test]
+    fn intersecting() {
+        let set = interval_set_of(vec![Interval(5, 10), Interval(20, 30), Interval(40, 50)]);
+        assert_eq!(set.intersecting(&Interval(11, 19)), IntervalSet::new());
+        assert_eq!(
+            set.intersecting(&Interval(8, 12)),
+            interval_set(Interval(5, 10))
+        );
+        assert_eq!(
+            set.intersecting(&Interval(10, 20)),
+            interval_set_of(vec![Interval(5, 10), Interval(20, 30)])
+        );
+        assert_eq!(
+            set.intersecting(&Interval(1, 45)),
+            interval_set_of(vec![Interval(5, 10), Interval(20, 30), Interval(40, 50)])
+        );
+        assert_eq!(
+            set.intersecting(&Interval(22, 25)),
+            interval_set(Interval(20, 30))
+        );
+    }
+
+    #[test]
+    fn missing_ignores_intervals_after_query() {
+        let set = interval_set_of(vec![Interval(10, 20), Interval(30, 40)]);
+        assert_eq!(set.missing(&Interval(10, 20)), IntervalSet::new());
+        assert_eq!(
+            set.missing(&Interval(15, 25)),
+            interval_set(Interval(20, 25))
+        );
+    }
+
+    #[test]
+    fn complement() {
+        let set = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        assert_eq!(
+            set.complement(&Interval(0, 40)),
+            interval_set_of(vec![Interval(0, 5), Interval(10, 20), Interval(30, 40)])
+        );
+        assert_eq!(
+            set.complement(&Interval(7, 25)),
+            interval_set(Interval(10, 20))
+        );
+        assert_eq!(set.complement(&Interval(21, 29)), IntervalSet::new());
+        assert_eq!(
+            set.complement(&Interval(12, 18)),
+            interval_set(Interval(12, 18))
+        );
+        for bounds in vec![Interval(0, 40), Interval(7, 25), Interval(12, 18)] {
+            assert_eq!(set.complement(&bounds), set.missing(&bounds));
+        }
+    }
+
+    #[test]
+    fn insert_merging_adjacent() {
+        let mut set = interval_set_of(vec![Interval(1, 5), Interval(11, 15)]);
+        set.insert_merging_adjacent(&Interval(7, 9));
+        assert_eq!(
+            set,
+            interval_set_of(vec![Interval(1, 5), Interval(7, 9), Interval(11, 15)])
+        );
+        set.insert_merging_adjacent(&Interval(6, 6));
+        assert_eq!(set, interval_set_of(vec![Interval(1, 9), Interval(11, 15)]));
+        set.insert_merging_adjacent(&Interval(10, 10));
+        assert_eq!(set, interval_set(Interval(1, 15)));
+
+        let mut set = IntervalSet::new();
+        set.insert_merging_adjacent(&Interval(core::u32::MAX - 1, core::u32::MAX));
+        set.insert_merging_adjacent(&Interval(0, 10));
+        assert_eq!(
+            set,
+            interval_set_of(vec![
+                Interval(0, 10),
+                Interval(core::u32::MAX - 1, core::u32::MAX)
+            ])
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        assert_eq!(IntervalSet::<u32>::new().gaps().count(), 0);
+        assert_eq!(interval_set(Interval(10, 20)).gaps().count(), 0);
+        let set = interval_set_of(vec![Interval(10, 20), Interval(30, 40), Interval(45, 50)]);
+        assert_eq!(
+            set.gaps().collect::<Vec<_>>(),
+            vec![Interval(20, 30), Interval(40, 45)]
+        );
+        assert_eq!(
+            set.gaps().collect::<IntervalSet<_>>(),
+            set.complement(&Interval(10, 50))
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", IntervalSet::<u32>::new()), "∅");
+        assert_eq!(format!("{}", interval_set(Interval(10, 20))), "[10,20]");
+        assert_eq!(
+            format!(
+                "{}",
+                interval_set_of(vec![Interval(30, 40), Interval(10, 20)])
+            ),
+            "[10,20] ∪ [30,40]"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("∅".parse(), Ok(IntervalSet::<u32>::new()));
+        assert_eq!("".parse(), Ok(IntervalSet::<u32>::new()));
+        assert_eq!(
+            " [30, 40] ∪[10,20] ".parse(),
+            Ok(interval_set_of(vec![Interval(10, 20), Interval(30, 40)]))
+        );
+        assert_eq!(
+            "[10,20] ∪ [15,25]".parse(),
+            Ok(interval_set(Interval(10, 25)))
+        );
+        for bad in vec![
+            "10,20",
+            "[10]",
+            "[10,20,30]",
+            "[20,10]",
+            "[a,20]",
+            "[10,20] ∪",
+        ] {
+            assert!(bad.parse::<IntervalSet<u32>>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn insert_containing() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(10, 20));
+        set.insert(&Interval(5, 25));
+        assert_eq!(set, interval_set(Interval(5, 25)));
+    }
+
+    #[test]
+    fn subtract() {
+        let mut set = IntervalSet::new();
+        set.insert(&Interval(5, 10));
+        set.insert(&Interval(20, 30));
+        assert_eq!(
+            set.subtract(&Interval(8, 25)),
+            interval_set_of(vec![Interval(5, 8), Interval(25, 30)])
+        );
+        assert_eq!(set.subtract(&Interval(1, 40)), IntervalSet::new());
+        assert_eq!(
+            set.subtract(&Interval(12, 15)),
+            interval_set_of(vec![Interval(5, 10), Interval(20, 30)])
+        );
+    }
+
+    #[test]
+    fn union() {
+        let left = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        let right = interval_set_of(vec![Interval(8, 12), Interval(40, 50)]);
+        assert_eq!(
+            left.union(&right),
+            interval_set_of(vec![Interval(5, 12), Interval(20, 30), Interval(40, 50)])
+        );
+        assert_eq!(left.union(&IntervalSet::new()), left);
+    }
+
+    #[test]
+    fn intersection() {
+        let left = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        let right = interval_set_of(vec![Interval(8, 25), Interval(28, 50)]);
+        assert_eq!(
+            left.intersection(&right),
+            interval_set_of(vec![Interval(8, 10), Interval(20, 25), Interval(28, 30)])
+        );
+        assert_eq!(left.intersection(&IntervalSet::new()), IntervalSet::new());
+    }
+
+    #[test]
+    fn intersection_disjoint() {
+        let left = interval_set_of(vec![Interval(5, 10)]);
+        let right = interval_set_of(vec![Interval(20, 30)]);
+        assert_eq!(left.intersection(&right), IntervalSet::new());
+    }
+
+    #[test]
+    fn difference() {
+        let left = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        let right = interval_set_of(vec![Interval(8, 22), Interval(25, 26)]);
+        assert_eq!(
+            left.difference(&right),
+            interval_set_of(vec![Interval(5, 8), Interval(22, 25), Interval(26, 30)])
+        );
+        assert_eq!(left.difference(&IntervalSet::new()), left);
+    }
+
+    #[test]
+    fn symmetric_difference() {
+        let left = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        let right = interval_set_of(vec![Interval(8, 22), Interval(40, 50)]);
+        let expected = interval_set_of(vec![
+            Interval(5, 8),
+            Interval(10, 20),
+            Interval(22, 30),
+            Interval(40, 50),
+        ]);
+        assert_eq!(left.symmetric_difference(&right), expected);
+        assert_eq!(right.symmetric_difference(&left), expected);
+        assert_eq!(left.symmetric_difference(&left), IntervalSet::new());
+        assert_eq!(left.symmetric_difference(&IntervalSet::new()), left);
+    }
+
+    #[test]
+    fn symmetric_difference_touching() {
+        // Sharing a single boundary isn't enough overlap to be subtracted.
+        let left = interval_set(Interval(0, 10));
+        let right = interval_set(Interval(10, 20));
+        assert_eq!(
+            left.symmetric_difference(&right),
+            interval_set(Interval(0, 20))
+        );
+    }
+
+    #[test]
+    fn from_iter_merges_overlaps() {
+        let set: IntervalSet<u32> = vec![
+            Interval(20, 30),
+            Interval(5, 10),
+            Interval(25, 40),
+            Interval(10, 12),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            set,
+            interval_set_of(vec![Interval(5, 12), Interval(20, 40)])
+        );
+    }
+
+    #[test]
+    fn try_from_iter() {
+        assert_eq!(
+            IntervalSet::try_from_iter(vec![Interval(20, 30), Interval(5, 10)]),
+            Ok(interval_set_of(vec![Interval(5, 10), Interval(20, 30)]))
+        );
+        assert_eq!(
+            IntervalSet::try_from_iter(vec![Interval(20, 30), Interval(5, 20)]),
+            Err(format!(
+                "Intervals Interval(5, 20) and Interval(20, 30) overlap"
+            ))
+        );
+        assert_eq!(
+            IntervalSet::try_from_iter(vec![Interval(5, 10), Interval(7, 8)]),
+            Err(format!(
+                "Intervals Interval(5, 10) and Interval(7, 8) overlap"
+            ))
+        );
+        assert_eq!(
+            IntervalSet::try_from_iter(vec![Interval(10, 5)]),
+            Err(format!("Interval Interval(10, 5) ends before it starts"))
+        );
+        assert_eq!(
+            IntervalSet::<u32>::try_from_iter(vec![]),
+            Ok(IntervalSet::new())
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(
+            interval_set_of(vec![Interval(5, 10), Interval(11, 20)]).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            interval_set_of(vec![Interval(5, 10), Interval(10, 20), Interval(30, 40)]).validate(),
+            Err(format!("Intervals 0 and 1 of 3 overlap"))
+        );
+        assert_eq!(
+            interval_set_of(vec![Interval(5, 10), Interval(20, 15)]).validate(),
+            Err(format!("Interval 1 of 2 ends before it starts"))
+        );
+    }
+
+    #[test]
+    fn covered_amount() {
+        let set = interval_set_of(vec![Interval(5, 10), Interval(20, 30), Interval(40, 50)]);
+        assert_eq!(set.covered_amount(&Interval(0, 100)), 25);
+        assert_eq!(set.covered_amount(&Interval(8, 25)), 7);
+        assert_eq!(set.covered_amount(&Interval(11, 19)), 0);
+        assert_eq!(IntervalSet::new().covered_amount(&Interval(0, 100)), 0);
+    }
+
+    #[test]
+    fn coverage_ratio() {
+        let set = interval_set_of(vec![Interval(5, 10), Interval(20, 30)]);
+        assert_eq!(set.coverage_ratio(&Interval(0, 40)), 0.375);
+        assert_eq!(set.coverage_ratio(&Interval(20, 30)), 1.0);
+        assert_eq!(set.coverage_ratio(&Interval(11, 19)), 0.0);
+        assert_eq!(set.coverage_ratio(&Interval(7, 7)), 1.0);
+        assert_eq!(set.coverage_ratio(&Interval(15, 15)), 0.0);
+    }
+
+    fn interval_set(interval: Interval<u32>) -> IntervalSet<u32> {
+        interval_set_of(vec![interval])
+    }
+
+    fn interval_set_of(intervals: Vec<Interval<u32>>) -> IntervalSet<u32> {
+        IntervalSet {
+            intervals: intervals.into_iter().collect(),
+        }
+    }
+}
@@ -1,344 +1,2300 @@
+use intervals::{BoundedInterval, Interval, IntervalSet, Step};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use std;
-use std::cmp::{max, min, Ordering};
-use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
-pub struct Interval<T: Ord>(pub T, pub T);
+pub trait UniquelyIdentifiedTimeValue<T: Ord> {
+    type Id: Ord + Clone;
+
+    fn time(&self) -> T;
+
+    // Distinguishes values which share a time. Values with the same time and id are the same
+    // value, e.g. different versions of it.
+    fn id(&self) -> Self::Id;
+
+    // Roughly how much memory the value occupies, for enforcing Budget::ApproximateBytes.
+    fn approximate_size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
+}
+
+// A stable binary encoding, used by IntervalStore::snapshot and IntervalStore::restore.
+pub trait Encodable: Sized {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! impl_encodable_for_integers {
+    ($($integer:ty),*) => {
+        $(
+            impl Encodable for $integer {
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_be_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> io::Result<$integer> {
+                    let mut bytes = [0; std::mem::size_of::<$integer>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$integer>::from_be_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_encodable_for_integers!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// Length-prefixed UTF-8.
+impl Encodable for String {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.len() as u64).encode(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<String> {
+        let len = u64::decode(reader)?;
+        let mut bytes = vec![];
+        reader.take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "String shorter than its length",
+            ));
+        }
+        String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// A limit on how much an IntervalStore holds before it starts evicting intervals.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Budget {
+    Values(usize),
+    ApproximateBytes(usize),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EvictionCandidate<Time: Ord> {
+    pub interval: Interval<Time>,
+    // Higher values were used more recently.
+    pub last_used: usize,
+    pub values: usize,
+    pub approximate_bytes: usize,
+}
+
+// Decides which covered interval an over-budget IntervalStore sheds next.
+// Returning None stops evicting, even if the store is still over budget.
+pub trait EvictionPolicy<Time: Ord>: Send + Sync {
+    fn choose(&self, candidates: &[EvictionCandidate<Time>]) -> Option<Interval<Time>>;
+}
+
+pub struct LeastRecentlyUsed;
+
+impl<Time: Ord + Copy> EvictionPolicy<Time> for LeastRecentlyUsed {
+    fn choose(&self, candidates: &[EvictionCandidate<Time>]) -> Option<Interval<Time>> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| candidate.last_used)
+            .map(|candidate| candidate.interval)
+    }
+}
+
+pub struct LargestFirst;
+
+impl<Time: Ord + Copy> EvictionPolicy<Time> for LargestFirst {
+    fn choose(&self, candidates: &[EvictionCandidate<Time>]) -> Option<Interval<Time>> {
+        candidates
+            .iter()
+            .max_by_key(|candidate| candidate.approximate_bytes)
+            .map(|candidate| candidate.interval)
+    }
+}
+
+pub struct EarliestFirst;
+
+impl<Time: Ord + Copy> EvictionPolicy<Time> for EarliestFirst {
+    fn choose(&self, candidates: &[EvictionCandidate<Time>]) -> Option<Interval<Time>> {
+        candidates
+            .iter()
+            .min_by_key(|candidate| candidate.interval)
+            .map(|candidate| candidate.interval)
+    }
+}
+
+// Values are keyed by time then id, so that distinct values may share a time. Before and After
+// sort around every id, to bound queries over a range of times.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum IdBound<Id> {
+    Before,
+    Id(Id),
+    After,
+}
+
+type ValueKey<Time, Id> = (Time, IdBound<Id>);
+
+fn value_key<Time: Ord, Value: UniquelyIdentifiedTimeValue<Time>>(
+    value: &Value,
+) -> ValueKey<Time, Value::Id> {
+    (value.time(), IdBound::Id(value.id()))
+}
+
+fn keys_within<Time: Ord + Copy, Id>(
+    interval: &Interval<Time>,
+) -> std::ops::RangeInclusive<ValueKey<Time, Id>> {
+    (interval.0, IdBound::Before)..=(interval.1, IdBound::After)
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StoreSize {
+    pub intervals: usize,
+    pub values: usize,
+    pub approximate_bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StoreStats {
+    pub size: StoreSize,
+    // Lookups are hits if the interval was fully covered, partial hits if it was partly covered,
+    // and misses otherwise.
+    pub lookups: u64,
+    pub hits: u64,
+    pub partial_hits: u64,
+    pub misses: u64,
+    // Successful inserts, and inserts whose values differed from existing ones, whether or not
+    // the conflict policy allowed them.
+    pub inserts: u64,
+    pub conflicts: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    lookups: AtomicU64,
+    hits: AtomicU64,
+    partial_hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    conflicts: AtomicU64,
+}
+
+// How IntervalStore::insert_with_policy reconciles new values with different existing values
+// for the same times.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConflictPolicy {
+    // Fail the insert, leaving the store unchanged.
+    Reject,
+    // Ignore the new values where they overlap existing coverage.
+    KeepExisting,
+    // Replace the existing values where they overlap the new interval.
+    OverwriteWithNew,
+    // Keep the union of both, preferring the new value where both have one for the same time.
+    MergeById,
+}
+
+// Why IntervalStore::insert_batch rejected some of its intervals.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConflictReport<Time: Ord, Value> {
+    pub conflicts: Vec<Conflict<Time, Value>>,
+}
+
+// Where an inserted interval overlapped existing coverage, and how the values there differed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conflict<Time: Ord, Value> {
+    pub overlap: Interval<Time>,
+    // Values being inserted which weren't already stored.
+    pub only_new: Vec<Value>,
+    // Stored values which weren't being inserted.
+    pub only_existing: Vec<Value>,
+}
+
+impl<Time: Ord + fmt::Display, Value: fmt::Debug> fmt::Display for ConflictReport<Time, Value> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} conflicts", self.conflicts.len())?;
+        for conflict in self.conflicts.iter() {
+            write!(
+                f,
+                "; in {} only new: {:?}, only existing: {:?}",
+                conflict.overlap, conflict.only_new, conflict.only_existing
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct IntervalStore<Time: Ord, Value: UniquelyIdentifiedTimeValue<Time> + Clone> {
+    // Shared with any StoreViews, and copied on write if they are still alive.
+    intervals: Arc<IntervalSet<Time>>,
+    values: Arc<BTreeMap<ValueKey<Time, Value::Id>, Value>>,
+    approximate_bytes: usize,
+    budget: Option<Budget>,
+    eviction_policy: Box<dyn EvictionPolicy<Time>>,
+    // When each covered interval was last read or written, according to clock.
+    // Only maintained if there is a budget.
+    clock: AtomicUsize,
+    last_used: Mutex<BTreeMap<Interval<Time>, usize>>,
+    // Inserted intervals which stop counting as covered at the given instant, unless they are
+    // inserted again first.
+    expiries: Vec<(Interval<Time>, Instant)>,
+    // Worked out from intervals and expiries once something has expired, and kept until either
+    // changes or the next expiry passes.
+    live: Mutex<Option<LiveIntervals<Time>>>,
+    // How inserted intervals are added to intervals, if not with IntervalSet::insert, e.g. to
+    // merge them with intervals they abut.
+    insert_interval: Option<fn(&mut IntervalSet<Time>, &Interval<Time>)>,
+    counters: Counters,
+}
+
+// The covered intervals which hadn't expired as of some instant, and until when that holds.
+struct LiveIntervals<Time: Ord> {
+    intervals: Arc<IntervalSet<Time>>,
+    until: Option<Instant>,
+}
+
+impl<Time, Value> fmt::Debug for IntervalStore<Time, Value>
+where
+    Time: Ord + fmt::Debug,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IntervalStore")
+            .field("intervals", &self.intervals)
+            .field("values", &self.values.values().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<Time: Ord + Copy + 'static, Value: UniquelyIdentifiedTimeValue<Time> + Clone>
+    IntervalStore<Time, Value>
+{
+    pub fn new() -> IntervalStore<Time, Value> {
+        IntervalStore {
+            intervals: Arc::new(IntervalSet::new()),
+            values: Arc::new(BTreeMap::new()),
+            approximate_bytes: 0,
+            budget: None,
+            eviction_policy: Box::new(LeastRecentlyUsed),
+            clock: AtomicUsize::new(0),
+            last_used: Mutex::new(BTreeMap::new()),
+            expiries: Vec::new(),
+            live: Mutex::new(None),
+            insert_interval: None,
+            counters: Counters::default(),
+        }
+    }
+
+    // A store which, whenever it holds more than max_values values, evicts the least recently
+    // used intervals until it fits.
+    pub fn with_max_values(max_values: usize) -> IntervalStore<Time, Value> {
+        IntervalStore::with_budget(Budget::Values(max_values), Box::new(LeastRecentlyUsed))
+    }
+
+    // A store which, whenever it exceeds the budget, evicts whichever intervals the policy
+    // chooses until it fits. The most recently used interval is never offered for eviction.
+    pub fn with_budget(
+        budget: Budget,
+        eviction_policy: Box<dyn EvictionPolicy<Time>>,
+    ) -> IntervalStore<Time, Value> {
+        IntervalStore {
+            budget: Some(budget),
+            eviction_policy,
+            ..IntervalStore::new()
+        }
+    }
+
+    // Excludes expired intervals, even if their values haven't been removed yet.
+    pub fn covered(&self) -> IntervalSet<Time> {
+        (*self.live_intervals()).clone()
+    }
+
+    // An immutable view of the store as it is now, which can be read without holding any lock on
+    // the store. Taking one is cheap; the next write to the store copies its data instead.
+    pub fn view(&self) -> StoreView<Time, Value> {
+        StoreView {
+            intervals: self.live_intervals(),
+            values: self.values.clone(),
+        }
+    }
+
+    pub fn size(&self) -> StoreSize {
+        StoreSize {
+            intervals: self.intervals.len(),
+            values: self.values.len(),
+            approximate_bytes: self.approximate_bytes,
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let load = |counter: &AtomicU64| counter.load(atomic::Ordering::Relaxed);
+        StoreStats {
+            size: self.size(),
+            lookups: load(&self.counters.lookups),
+            hits: load(&self.counters.hits),
+            partial_hits: load(&self.counters.partial_hits),
+            misses: load(&self.counters.misses),
+            inserts: load(&self.counters.inserts),
+            conflicts: load(&self.counters.conflicts),
+        }
+    }
+
+    fn record_lookup(&self, interval: &Interval<Time>, fully_covered: bool) {
+        let outcome = if fully_covered {
+            &self.counters.hits
+        } else if self.live_intervals().intersecting(interval).is_empty() {
+            &self.counters.misses
+        } else {
+            &self.counters.partial_hits
+        };
+        outcome.fetch_add(1, atomic::Ordering::Relaxed);
+        self.counters
+            .lookups
+            .fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub fn has(&self, interval: &Interval<Time>) -> bool {
+        self.live_intervals().contains(interval)
+    }
+
+    pub fn missing(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
+        self.live_intervals().missing(interval)
+    }
+
+    fn live_intervals(&self) -> Arc<IntervalSet<Time>> {
+        if self.expiries.is_empty() {
+            return self.intervals.clone();
+        }
+        let now = Instant::now();
+        let mut live = self.live.lock().unwrap();
+        if let Some(ref live) = *live {
+            if live.until.map_or(true, |until| now < until) {
+                return live.intervals.clone();
+            }
+        }
+        let expired: IntervalSet<Time> = self
+            .expiries
+            .iter()
+            .filter(|&&(_, expires_at)| expires_at <= now)
+            .map(|&(interval, _)| interval)
+            .collect();
+        if expired.is_empty() {
+            // Keeping a reference to intervals would make the next write copy them.
+            return self.intervals.clone();
+        }
+        let intervals = Arc::new(self.intervals.difference(&expired));
+        *live = Some(LiveIntervals {
+            intervals: intervals.clone(),
+            until: self
+                .expiries
+                .iter()
+                .map(|&(_, expires_at)| expires_at)
+                .filter(|&expires_at| expires_at > now)
+                .min(),
+        });
+        intervals
+    }
+
+    fn forget_live_intervals(&mut self) {
+        *self.live.get_mut().unwrap() = None;
+    }
+
+    pub fn get(&self, interval: &Interval<Time>) -> Option<Vec<Value>> {
+        let has = self.has(interval);
+        self.record_lookup(interval, has);
+        if !has {
+            return None;
+        }
+        return Some(self.available(interval));
+    }
+
+    // Whichever values are known within the interval, along with the parts of it which aren't
+    // covered and so may be missing values.
+    pub fn get_partial(&self, interval: &Interval<Time>) -> (Vec<Value>, IntervalSet<Time>) {
+        let missing = self.missing(interval);
+        self.record_lookup(interval, missing.is_empty());
+        (self.available(interval), missing)
+    }
+
+    // Like get, but respecting the interval's bound kinds, e.g. to exclude values at the end
+    // of a [from, until) interval.
+    pub fn get_bounded(&self, interval: &BoundedInterval<Time>) -> Option<Vec<Value>> {
+        let has = self.live_intervals().missing_bounded(interval).is_empty();
+        self.record_lookup(&Interval(interval.lower, interval.upper), has);
+        if !has {
+            return None;
+        }
+        self.touch(&Interval(interval.lower, interval.upper));
+        Some(
+            self.values_within(&Interval(interval.lower, interval.upper))
+                .filter(|&(key, _)| interval.contains(&key.0))
+                .map(|(_, value)| value.clone())
+                .collect(),
+        )
+    }
+
+    // Returns whichever values are known within the interval, even if it is only partially
+    // covered.
+    pub fn get_available(&self, interval: &Interval<Time>) -> Vec<Value> {
+        self.record_lookup(interval, self.has(interval));
+        self.available(interval)
+    }
+
+    fn available(&self, interval: &Interval<Time>) -> Vec<Value> {
+        self.touch(interval);
+        self.values_within(interval)
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    // How many values get would return, without cloning them.
+    pub fn count(&self, interval: &Interval<Time>) -> Option<usize> {
+        let has = self.has(interval);
+        self.record_lookup(interval, has);
+        if !has {
+            return None;
+        }
+        self.touch(interval);
+        Some(self.values_within(interval).count())
+    }
+
+    // The latest value at or before time. None if time isn't covered, or there is no value
+    // between the start of its covered interval and time, as anything earlier is unknown.
+    pub fn value_at_or_before(&self, time: Time) -> Option<Value> {
+        let covering = self.covering(time)?;
+        self.values_within(&Interval(covering.0, time))
+            .next_back()
+            .map(|(_, value)| value.clone())
+    }
+
+    // The earliest value at or after time, answering only from time's covered interval.
+    pub fn value_at_or_after(&self, time: Time) -> Option<Value> {
+        let covering = self.covering(time)?;
+        self.values_within(&Interval(time, covering.1))
+            .next()
+            .map(|(_, value)| value.clone())
+    }
+
+    fn covering(&self, time: Time) -> Option<Interval<Time>> {
+        self.live_intervals()
+            .intersecting(&Interval(time, time))
+            .iter()
+            .next()
+            .cloned()
+    }
+
+    fn values_within(
+        &self,
+        interval: &Interval<Time>,
+    ) -> std::collections::btree_map::Range<ValueKey<Time, Value::Id>, Value> {
+        if interval.0 > interval.1 {
+            // BTreeMap::range panics on inverted bounds; an empty range has the same type.
+            let start = (interval.0, IdBound::Before);
+            return self.values.range(start.clone()..start);
+        }
+        self.values.range(keys_within(interval))
+    }
+
+    // Whether inserting the values would fail with ConflictPolicy::Reject.
+    pub fn conflicts(&self, interval: &Interval<Time>, values: &[Value]) -> bool {
+        let mut keys: Vec<_> = values.iter().map(value_key).collect();
+        keys.sort();
+        keys.dedup();
+        self.intervals
+            .intersecting(interval)
+            .iter()
+            .filter_map(|existing_interval| existing_interval.clamp_to(interval))
+            .any(|overlap| {
+                keys.iter()
+                    .filter(|key| overlap.contains(&key.0))
+                    .collect::<Vec<_>>()
+                    != self
+                        .values_within(&overlap)
+                        .map(|(key, _)| key)
+                        .collect::<Vec<_>>()
+            })
+    }
+
+    pub fn insert(&mut self, interval: &Interval<Time>, values: Vec<Value>) -> Result<(), String> {
+        self.insert_with_policy(interval, values, ConflictPolicy::Reject)
+    }
+
+    // Inserts each interval in turn, as insert would. Rather than stopping at the first conflict,
+    // skips the intervals which conflict (with the store or with earlier intervals in the batch)
+    // and reports every conflict found.
+    pub fn insert_batch(
+        &mut self,
+        batch: Vec<(Interval<Time>, Vec<Value>)>,
+    ) -> Result<(), ConflictReport<Time, Value>> {
+        self.insert_prechecked(
+            batch
+                .into_iter()
+                .map(|(interval, values)| (interval, values, false)),
+        )
+    }
+
+    // Inserts a batch as insert_batch does, trusting items marked as having been checked against
+    // the store as it was before the batch, unless they overlap earlier items in the batch.
+    // (Inserting earlier items can only evict, which can't introduce conflicts.)
+    fn insert_prechecked<I>(&mut self, batch: I) -> Result<(), ConflictReport<Time, Value>>
+    where
+        I: IntoIterator<Item = (Interval<Time>, Vec<Value>, bool)>,
+    {
+        let mut inserted = IntervalSet::new();
+        let mut conflicts = Vec::new();
+        for (interval, values, checked) in batch {
+            let found = if checked && inserted.intersecting(&interval).is_empty() {
+                Vec::new()
+            } else {
+                self.conflicts_in(&interval, &values)
+            };
+            if found.is_empty() {
+                self.insert(&interval, values)
+                    .expect("Inserting values which don't conflict");
+                inserted.insert(&interval);
+            } else {
+                self.counters
+                    .conflicts
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                conflicts.extend(found);
+            }
+        }
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(ConflictReport { conflicts })
+        }
+    }
+
+    fn conflicts_in(
+        &self,
+        interval: &Interval<Time>,
+        values: &[Value],
+    ) -> Vec<Conflict<Time, Value>> {
+        let new_values: BTreeMap<_, _> = values
+            .iter()
+            .map(|value| (value_key(value), value))
+            .collect();
+        self.intervals
+            .intersecting(interval)
+            .iter()
+            .filter_map(|existing_interval| existing_interval.clamp_to(interval))
+            .filter_map(|overlap| {
+                let only_new: Vec<_> = new_values
+                    .range(keys_within(&overlap))
+                    .filter(|&(key, _)| !self.values.contains_key(key))
+                    .map(|(_, value)| (*value).clone())
+                    .collect();
+                let only_existing: Vec<_> = self
+                    .values_within(&overlap)
+                    .filter(|&(key, _)| !new_values.contains_key(key))
+                    .map(|(_, value)| value.clone())
+                    .collect();
+                if only_new.is_empty() && only_existing.is_empty() {
+                    None
+                } else {
+                    Some(Conflict {
+                        overlap,
+                        only_new,
+                        only_existing,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    pub fn insert_with_policy(
+        &mut self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+        policy: ConflictPolicy,
+    ) -> Result<(), String> {
+        self.insert_without_evicting(interval, values, policy)?;
+        self.evict();
+        Ok(())
+    }
+
+    // As insert_with_policy, but leaving the store over its budget, if it now is.
+    fn insert_without_evicting(
+        &mut self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+        policy: ConflictPolicy,
+    ) -> Result<(), String> {
+        let mut new_values: BTreeMap<_, _> = values
+            .into_iter()
+            .map(|value| (value_key(&value), value))
+            .collect();
+
+        let overlapping_existing_intervals = self.intervals.intersecting(&interval);
+
+        let mut conflicted = false;
+        for existing_interval in overlapping_existing_intervals.iter() {
+            let overlap = match existing_interval.clamp_to(interval) {
+                Some(overlap) => overlap,
+                None => continue,
+            };
+            if new_values
+                .range(keys_within(&overlap))
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>()
+                == self
+                    .values_within(&overlap)
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>()
+            {
+                continue;
+            }
+            if !conflicted {
+                conflicted = true;
+                self.counters
+                    .conflicts
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            }
+            match policy {
+                ConflictPolicy::Reject => return Err(format!("Conflicting values")),
+                ConflictPolicy::KeepExisting => {
+                    let keys: Vec<_> = new_values
+                        .range(keys_within(&overlap))
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    for key in keys {
+                        new_values.remove(&key);
+                    }
+                }
+                ConflictPolicy::OverwriteWithNew => self.remove_values(&overlap),
+                ConflictPolicy::MergeById => {}
+            }
+        }
+
+        {
+            let intervals = Arc::make_mut(&mut self.intervals);
+            match self.insert_interval {
+                Some(insert_interval) => insert_interval(intervals, interval),
+                None => intervals.insert(interval),
+            }
+        }
+        self.forget_live_intervals();
+
+        let values = Arc::make_mut(&mut self.values);
+        for (key, value) in new_values.into_iter() {
+            self.approximate_bytes += value.approximate_size();
+            if let Some(replaced) = values.insert(key, value) {
+                self.approximate_bytes -= replaced.approximate_size();
+            }
+        }
+
+        self.forget_expiries(interval);
+
+        if self.budget.is_some() {
+            // Whatever the interval was merged into, including any intervals it abutted, is
+            // now one interval, used as of now.
+            let merged = self.intervals.intersecting(interval);
+            {
+                let last_used = self.last_used.get_mut().unwrap();
+                for merged in merged.iter() {
+                    let merged_intervals: Vec<_> = last_used
+                        .range(Interval(merged.0, merged.0)..)
+                        .map(|(merged_interval, _)| *merged_interval)
+                        .take_while(|merged_interval| merged_interval.0 <= merged.1)
+                        .collect();
+                    for merged_interval in merged_intervals {
+                        last_used.remove(&merged_interval);
+                    }
+                }
+            }
+            self.touch(interval);
+        }
+
+        self.counters
+            .inserts
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        self.debug_validate();
+        Ok(())
+    }
+
+    // Forgets everything known about the interval, so that it will be reported as missing
+    // again. Partially covered intervals are split around it. As with IntervalSet::subtract, the
+    // remaining parts keep the interval's boundaries, so values on those stay too.
+    pub fn remove(&mut self, interval: &Interval<Time>) {
+        let remaining = self.intervals.subtract(interval);
+        let keys: Vec<_> = self
+            .values_within(interval)
+            .filter(|&(key, _)| !remaining.contains(&Interval(key.0, key.0)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.remove_keys(keys);
+        self.forget_expiries(interval);
+        let affected_intervals = self.intervals.intersecting(interval);
+        self.intervals = Arc::new(remaining);
+        self.forget_live_intervals();
+
+        let last_used = self.last_used.get_mut().unwrap();
+        for affected_interval in affected_intervals.iter() {
+            if let Some(used) = last_used.remove(affected_interval) {
+                for part in affected_interval.subtract(interval).iter() {
+                    last_used.insert(*part, used);
+                }
+            }
+        }
+        self.debug_validate();
+    }
+
+    // Replaces the value with the same identity, or adds it if there is none, returning the value
+    // it replaced. Its time must already be covered.
+    pub fn upsert(&mut self, value: Value) -> Result<Option<Value>, String> {
+        let key = value_key(&value);
+        if !self.intervals.contains(&Interval(key.0, key.0)) {
+            return Err(format!(
+                "Can't upsert a value outside the covered intervals"
+            ));
+        }
+        self.approximate_bytes += value.approximate_size();
+        let replaced = Arc::make_mut(&mut self.values).insert(key, value);
+        if let Some(ref replaced) = replaced {
+            self.approximate_bytes -= replaced.approximate_size();
+        }
+        self.debug_validate();
+        Ok(replaced)
+    }
+
+    // Drops the values in the interval for which keep returns false, e.g. tweets which have since
+    // been deleted upstream, leaving the interval covered. Returns how many were dropped.
+    pub fn retain_in<F>(&mut self, interval: &Interval<Time>, mut keep: F) -> usize
+    where
+        F: FnMut(&Value) -> bool,
+    {
+        let keys: Vec<_> = self
+            .values_within(interval)
+            .filter(|&(_, value)| !keep(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let values = Arc::make_mut(&mut self.values);
+        for key in keys.iter() {
+            if let Some(removed) = values.remove(key) {
+                self.approximate_bytes -= removed.approximate_size();
+            }
+        }
+        self.debug_validate();
+        keys.len()
+    }
+
+    // Checks that the covered intervals are valid, that every value lies inside one and is keyed
+    // by its own time and id, and that approximate_bytes adds up.
+    pub fn validate(&self) -> Result<(), String> {
+        self.intervals.validate()?;
+        let mut approximate_bytes = 0;
+        for (index, (key, value)) in self.values.iter().enumerate() {
+            if *key != value_key(value) {
+                return Err(format!(
+                    "Value {} of {} is stored under another value's key",
+                    index,
+                    self.values.len()
+                ));
+            }
+            if !self.intervals.contains(&Interval(key.0, key.0)) {
+                return Err(format!(
+                    "Value {} of {} is outside the covered intervals",
+                    index,
+                    self.values.len()
+                ));
+            }
+            approximate_bytes += value.approximate_size();
+        }
+        if approximate_bytes != self.approximate_bytes {
+            return Err(format!(
+                "Values add up to {} approximate bytes, but {} are accounted for",
+                approximate_bytes, self.approximate_bytes
+            ));
+        }
+        Ok(())
+    }
+
+    // With the debug-invariants feature, debug builds check validate after every mutation.
+    fn debug_validate(&self) {
+        #[cfg(feature = "debug-invariants")]
+        debug_assert_eq!(self.validate(), Ok(()));
+    }
+
+    // Like insert_with_policy, but the interval stops being covered once expires_at passes, until
+    // it is inserted again. Values in expired intervals are still returned by get_available.
+    pub fn insert_with_expiry(
+        &mut self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+        policy: ConflictPolicy,
+        expires_at: Instant,
+    ) -> Result<(), String> {
+        self.insert_with_policy(interval, values, policy)?;
+        self.expiries.push((*interval, expires_at));
+        self.forget_live_intervals();
+        Ok(())
+    }
+
+    // Removes expired intervals and their values, returning how many intervals expired.
+    pub fn remove_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .expiries
+            .iter()
+            .filter(|&&(_, expires_at)| expires_at <= now)
+            .map(|&(interval, _)| interval)
+            .collect();
+        for interval in expired.iter() {
+            self.remove(interval);
+        }
+        expired.len()
+    }
+
+    fn forget_expiries(&mut self, interval: &Interval<Time>) {
+        self.expiries = self
+            .expiries
+            .iter()
+            .flat_map(|&(expiring_interval, expires_at)| {
+                expiring_interval
+                    .subtract(interval)
+                    .iter()
+                    .map(|part| (*part, expires_at))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+    }
+
+    fn remove_values(&mut self, interval: &Interval<Time>) {
+        let keys: Vec<_> = self
+            .values_within(interval)
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.remove_keys(keys);
+    }
+
+    fn remove_keys(&mut self, keys: Vec<ValueKey<Time, Value::Id>>) {
+        let values = Arc::make_mut(&mut self.values);
+        for key in keys {
+            if let Some(removed) = values.remove(&key) {
+                self.approximate_bytes -= removed.approximate_size();
+            }
+        }
+    }
+
+    fn touch(&self, interval: &Interval<Time>) {
+        if self.budget.is_none() {
+            return;
+        }
+        let now = self.clock.fetch_add(1, atomic::Ordering::Relaxed);
+        let mut last_used = self.last_used.lock().unwrap();
+        for covered_interval in self.intervals.intersecting(interval).iter() {
+            last_used.insert(*covered_interval, now);
+        }
+    }
+
+    fn over_budget(&self) -> bool {
+        match self.budget {
+            Some(Budget::Values(max_values)) => self.values.len() > max_values,
+            Some(Budget::ApproximateBytes(max_bytes)) => self.approximate_bytes > max_bytes,
+            None => false,
+        }
+    }
+
+    fn evict(&mut self) {
+        while let Some(interval) = self.next_eviction() {
+            self.remove(&interval);
+        }
+    }
+
+    // The interval to evict next, if the store is over its budget and the policy chooses one.
+    fn next_eviction(&self) -> Option<Interval<Time>> {
+        if !self.over_budget() || self.intervals.len() <= 1 {
+            return None;
+        }
+        let candidates = self.eviction_candidates();
+        self.eviction_policy.choose(&candidates)
+    }
+
+    fn eviction_candidates(&self) -> Vec<EvictionCandidate<Time>> {
+        let last_used = self.last_used.lock().unwrap();
+        let most_recently_used = last_used
+            .iter()
+            .max_by_key(|&(_, used)| *used)
+            .map(|(interval, _)| *interval);
+        self.intervals
+            .iter()
+            .filter(|interval| Some(**interval) != most_recently_used)
+            .map(|interval| {
+                let (values, approximate_bytes) = self
+                    .values_within(interval)
+                    .fold((0, 0), |(values, bytes), (_, value)| {
+                        (values + 1, bytes + value.approximate_size())
+                    });
+                EvictionCandidate {
+                    interval: *interval,
+                    last_used: last_used.get(interval).cloned().unwrap_or(0),
+                    values,
+                    approximate_bytes,
+                }
+            })
+            .collect()
+    }
+}
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"TTIS";
+const SNAPSHOT_VERSION: u8 = 1;
+
+// Returned by IntervalStore::view. Expired intervals are excluded as of when it was taken.
+#[derive(Clone)]
+pub struct StoreView<Time: Ord, Value: UniquelyIdentifiedTimeValue<Time>> {
+    intervals: Arc<IntervalSet<Time>>,
+    values: Arc<BTreeMap<ValueKey<Time, Value::Id>, Value>>,
+}
+
+impl<Time: Ord + Copy, Value: UniquelyIdentifiedTimeValue<Time> + Clone> StoreView<Time, Value> {
+    pub fn covered(&self) -> &IntervalSet<Time> {
+        &self.intervals
+    }
+
+    pub fn has(&self, interval: &Interval<Time>) -> bool {
+        self.intervals.contains(interval)
+    }
+
+    pub fn missing(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
+        self.intervals.missing(interval)
+    }
 
-impl<T: Ord> Interval<T> {
-    pub fn contains(&self, time: &T) -> bool {
-        time >= &self.0 && time <= &self.1
+    pub fn get(&self, interval: &Interval<Time>) -> Option<Vec<Value>> {
+        if self.has(interval) {
+            Some(self.iter_within(interval).cloned().collect())
+        } else {
+            None
+        }
+    }
+
+    // The values in the interval, in time order, whether or not it is all covered.
+    pub fn iter_within<'a>(&'a self, interval: &Interval<Time>) -> impl Iterator<Item = &'a Value> {
+        let values = if interval.0 > interval.1 {
+            None
+        } else {
+            Some(
+                self.values
+                    .range(keys_within(interval))
+                    .map(|(_, value)| value),
+            )
+        };
+        values.into_iter().flat_map(|values| values)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
     }
+}
 
-    pub fn contains_interval(&self, interval: &Interval<T>) -> bool {
-        self.contains(&interval.0) && self.contains(&interval.1)
+// Parallel variants of bulk operations, for importing and exporting whole archives.
+#[cfg(feature = "parallel")]
+impl<Time, Value> IntervalStore<Time, Value>
+where
+    Time: Ord + Copy + Send + Sync + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + Send + Sync,
+    Value::Id: Send + Sync,
+{
+    // Like insert_batch, but checks every interval against the store in parallel before
+    // inserting them in order.
+    pub fn par_insert_batch(
+        &mut self,
+        batch: Vec<(Interval<Time>, Vec<Value>)>,
+    ) -> Result<(), ConflictReport<Time, Value>> {
+        let checked: Vec<_> = {
+            let store = &*self;
+            batch
+                .into_par_iter()
+                .map(|(interval, values)| {
+                    let clear = store.conflicts_in(&interval, &values).is_empty();
+                    (interval, values, clear)
+                })
+                .collect()
+        };
+        self.insert_prechecked(checked)
     }
 
-    pub fn intersects(&self, interval: &Interval<T>) -> bool {
-        self.contains(&interval.0) || self.contains(&interval.1)
+    // Like get_available, but clones the values in parallel.
+    pub fn par_get_available(&self, interval: &Interval<Time>) -> Vec<Value> {
+        let values: Vec<&Value> = self
+            .values_within(interval)
+            .map(|(_, value)| value)
+            .collect();
+        values.into_par_iter().map(|value| value.clone()).collect()
     }
 }
 
-impl<'a, T: Ord + Copy> Into<IntervalSet<T>> for &'a Interval<T> {
-    fn into(self) -> IntervalSet<T> {
-        let mut set = IntervalSet::new();
-        set.insert(self);
-        set
+impl<Time, Value> IntervalStore<Time, Value>
+where
+    Time: Ord + Copy + Step + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+{
+    // Makes inserts merge intervals which abut without overlapping, e.g. [1, 5] and [6, 10], so
+    // that everything between them counts as covered.
+    pub fn merging_adjacent(mut self) -> IntervalStore<Time, Value> {
+        self.insert_interval = Some(IntervalSet::insert_merging_adjacent);
+        self
     }
 }
 
-pub trait UniquelyIdentifiedTimeValue<T: Ord> {
-    fn time(&self) -> T;
+impl<Time, Value> IntervalStore<Time, Value>
+where
+    Time: Ord + Copy + Encodable + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + Encodable,
+{
+    // Writes the covered intervals and their values. Expired intervals, and values which only
+    // they covered, are left out, but expiry times aren't kept. The format is:
+    //   magic "TTIS", version (u8),
+    //   interval count (u64), then each interval's start and end,
+    //   value count (u64), then each value,
+    // with integers big-endian.
+    pub fn snapshot<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+        self.write_snapshot(writer)
+            .map_err(|err| format!("Error writing snapshot: {}", err))
+    }
+
+    fn write_snapshot<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        SNAPSHOT_VERSION.encode(writer)?;
+        let intervals = self.covered();
+        (intervals.len() as u64).encode(writer)?;
+        for interval in intervals.iter() {
+            interval.0.encode(writer)?;
+            interval.1.encode(writer)?;
+        }
+        let values: Vec<_> = intervals
+            .iter()
+            .flat_map(|interval| self.values_within(interval).map(|(_, value)| value))
+            .collect();
+        (values.len() as u64).encode(writer)?;
+        for value in values {
+            value.encode(writer)?;
+        }
+        writer.flush()
+    }
+
+    // Reads a store written by snapshot. The restored store has no budget, and nothing in it
+    // expires.
+    pub fn restore<R: Read>(reader: &mut R) -> Result<IntervalStore<Time, Value>, String> {
+        let mut magic = [0; 4];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| format!("Error reading snapshot: {}", err))?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(format!("Not an IntervalStore snapshot"));
+        }
+        let version =
+            u8::decode(reader).map_err(|err| format!("Error reading snapshot: {}", err))?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", version));
+        }
+        let store = IntervalStore::read_snapshot(reader)
+            .map_err(|err| format!("Error reading snapshot: {}", err))?;
+        store
+            .validate()
+            .map_err(|err| format!("Invalid snapshot: {}", err))?;
+        Ok(store)
+    }
+
+    // Applies the mutations recorded by a LoggedIntervalStore, returning how many were applied.
+    // A truncated final record, e.g. from crashing mid-write, is ignored.
+    pub fn replay_log<R: Read>(&mut self, reader: &mut R) -> Result<usize, String> {
+        let mut applied = 0;
+        loop {
+            match self.replay_log_record(reader) {
+                Ok(true) => applied += 1,
+                Ok(false) => return Ok(applied),
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(applied),
+                Err(err) => return Err(format!("Error replaying log: {}", err)),
+            }
+        }
+    }
+
+    fn replay_log_record<R: Read>(&mut self, reader: &mut R) -> io::Result<bool> {
+        let mut tag = [0];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(false);
+        }
+        let interval = Interval(Time::decode(reader)?, Time::decode(reader)?);
+        match tag[0] {
+            LOG_INSERT => {
+                let policy = match u8::decode(reader)? {
+                    0 => ConflictPolicy::Reject,
+                    1 => ConflictPolicy::KeepExisting,
+                    2 => ConflictPolicy::OverwriteWithNew,
+                    3 => ConflictPolicy::MergeById,
+                    policy => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Unknown conflict policy {}", policy),
+                        ))
+                    }
+                };
+                let value_count = u64::decode(reader)?;
+                let mut values = vec![];
+                for _ in 0..value_count {
+                    values.push(Value::decode(reader)?);
+                }
+                // Only inserts which can't fail are logged, so this can only fail if the log was
+                // replayed onto a different store than it was recorded against. Any evictions
+                // which followed are logged separately.
+                self.insert_without_evicting(&interval, values, policy)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            }
+            LOG_REMOVE | LOG_EVICT => self.remove(&interval),
+            LOG_TOUCH => self.touch(&interval),
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown log record {}", tag),
+                ))
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_snapshot<R: Read>(reader: &mut R) -> io::Result<IntervalStore<Time, Value>> {
+        let mut store = IntervalStore::new();
+        let interval_count = u64::decode(reader)?;
+        for _ in 0..interval_count {
+            let start = Time::decode(reader)?;
+            let end = Time::decode(reader)?;
+            Arc::make_mut(&mut store.intervals).insert(&Interval(start, end));
+        }
+        let value_count = u64::decode(reader)?;
+        for _ in 0..value_count {
+            let value = Value::decode(reader)?;
+            store.approximate_bytes += value.approximate_size();
+            Arc::make_mut(&mut store.values).insert(value_key(&value), value);
+        }
+        Ok(store)
+    }
+}
+
+// How many locks IntervalStoreMap spreads its keys over.
+const MAP_SHARDS: usize = 16;
+
+// IntervalStores keyed by e.g. user, each behind its own lock so that different keys don't
+// contend with each other. Keys are spread by hash over several maps, each behind its own lock
+// too, so that looking up or creating one key's store rarely waits on another's.
+pub struct IntervalStoreMap<K, Time, Value>
+where
+    Time: Ord,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+{
+    shards: Vec<RwLock<HashMap<K, Arc<RwLock<IntervalStore<Time, Value>>>>>>,
+    new_store: Box<dyn Fn() -> IntervalStore<Time, Value> + Send + Sync>,
+}
+
+impl<K, Time, Value> IntervalStoreMap<K, Time, Value>
+where
+    K: Clone + Eq + Hash,
+    Time: Ord + Copy + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + 'static,
+{
+    pub fn new() -> IntervalStoreMap<K, Time, Value> {
+        IntervalStoreMap::with_factory(IntervalStore::new)
+    }
+
+    // Stores for new keys are created with new_store, e.g. to give each a budget.
+    pub fn with_factory<F>(new_store: F) -> IntervalStoreMap<K, Time, Value>
+    where
+        F: Fn() -> IntervalStore<Time, Value> + Send + Sync + 'static,
+    {
+        IntervalStoreMap {
+            shards: (0..MAP_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            new_store: Box::new(new_store),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<RwLock<IntervalStore<Time, Value>>>> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn get_or_create(&self, key: &K) -> Arc<RwLock<IntervalStore<Time, Value>>> {
+        if let Some(store) = self.get(key) {
+            return store;
+        }
+        let mut stores = self.shard(key).write().unwrap();
+        stores
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(RwLock::new((self.new_store)())))
+            .clone()
+    }
+
+    // Replaces any existing store for the key.
+    pub fn insert(&self, key: K, store: IntervalStore<Time, Value>) {
+        self.shard(&key)
+            .write()
+            .unwrap()
+            .insert(key, Arc::new(RwLock::new(store)));
+    }
+
+    // Anyone still holding the key's store can use it, but it's no longer in the map.
+    pub fn remove(&self, key: &K) -> Option<Arc<RwLock<IntervalStore<Time, Value>>>> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    // Keys added or removed while listing them may or may not be included.
+    pub fn keys(&self) -> Vec<K> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn stats(&self) -> Vec<(K, StoreStats)> {
+        let stores: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, store)| (key.clone(), store.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        // Sizes are read without holding the map locks, so one busy store doesn't block others.
+        stores
+            .into_iter()
+            .map(|(key, store)| {
+                let stats = store.read().unwrap().stats();
+                (key, stats)
+            })
+            .collect()
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, Arc<RwLock<IntervalStore<Time, Value>>>>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+const LOG_INSERT: u8 = 1;
+const LOG_REMOVE: u8 = 2;
+const LOG_EVICT: u8 = 3;
+const LOG_TOUCH: u8 = 4;
+
+// An IntervalStore which appends every insert, remove and eviction to a log, so that mutations
+// made since the last snapshot can be replayed after a crash. Each is logged and flushed before
+// being applied, so a mutation is only ever lost along with its log record. Reads which mark
+// intervals as used are logged too, so that a replayed store with a budget evicts the same
+// intervals. Other reads go through Deref.
+pub struct LoggedIntervalStore<Time, Value, W>
+where
+    Time: Ord,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+    W: Write,
+{
+    store: IntervalStore<Time, Value>,
+    log: W,
+}
+
+impl<Time, Value, W> LoggedIntervalStore<Time, Value, W>
+where
+    Time: Ord + Copy + Encodable + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone + Encodable,
+    W: Write,
+{
+    pub fn new(store: IntervalStore<Time, Value>, log: W) -> LoggedIntervalStore<Time, Value, W> {
+        LoggedIntervalStore { store, log }
+    }
+
+    pub fn insert(&mut self, interval: &Interval<Time>, values: Vec<Value>) -> Result<(), String> {
+        self.insert_with_policy(interval, values, ConflictPolicy::Reject)
+    }
+
+    pub fn insert_with_policy(
+        &mut self,
+        interval: &Interval<Time>,
+        values: Vec<Value>,
+        policy: ConflictPolicy,
+    ) -> Result<(), String> {
+        // Rejected inserts aren't logged, so that every logged insert succeeds when replayed.
+        if policy == ConflictPolicy::Reject && self.store.conflicts(interval, &values) {
+            return Err(format!("Conflicting values"));
+        }
+        let mut record = vec![LOG_INSERT];
+        encode_insert(&mut record, interval, &values, policy)
+            .map_err(|err| format!("Error encoding log record: {}", err))?;
+        self.append(&record)?;
+        self.store
+            .insert_without_evicting(interval, values, policy)?;
+        while let Some(evicted) = self.store.next_eviction() {
+            self.append_interval(LOG_EVICT, &evicted)?;
+            self.store.remove(&evicted);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, interval: &Interval<Time>) -> Result<(), String> {
+        self.append_interval(LOG_REMOVE, interval)?;
+        self.store.remove(interval);
+        Ok(())
+    }
+
+    pub fn get(&mut self, interval: &Interval<Time>) -> Result<Option<Vec<Value>>, String> {
+        if self.store.has(interval) {
+            self.touch(interval)?;
+        }
+        Ok(self.store.get(interval))
+    }
+
+    pub fn get_partial(
+        &mut self,
+        interval: &Interval<Time>,
+    ) -> Result<(Vec<Value>, IntervalSet<Time>), String> {
+        self.touch(interval)?;
+        Ok(self.store.get_partial(interval))
+    }
+
+    pub fn get_bounded(
+        &mut self,
+        interval: &BoundedInterval<Time>,
+    ) -> Result<Option<Vec<Value>>, String> {
+        if self
+            .store
+            .live_intervals()
+            .missing_bounded(interval)
+            .is_empty()
+        {
+            self.touch(&Interval(interval.lower, interval.upper))?;
+        }
+        Ok(self.store.get_bounded(interval))
+    }
+
+    pub fn get_available(&mut self, interval: &Interval<Time>) -> Result<Vec<Value>, String> {
+        self.touch(interval)?;
+        Ok(self.store.get_available(interval))
+    }
+
+    pub fn count(&mut self, interval: &Interval<Time>) -> Result<Option<usize>, String> {
+        if self.store.has(interval) {
+            self.touch(interval)?;
+        }
+        Ok(self.store.count(interval))
+    }
+
+    // Logs that the store is about to mark the interval as used. Only stores with a budget
+    // track use.
+    fn touch(&mut self, interval: &Interval<Time>) -> Result<(), String> {
+        if self.store.budget.is_none() {
+            return Ok(());
+        }
+        self.append_interval(LOG_TOUCH, interval)
+    }
+
+    // Snapshots the store, after which the existing log is no longer needed, and starts logging
+    // to new_log instead.
+    pub fn checkpoint<S: Write>(&mut self, snapshot: &mut S, new_log: W) -> Result<(), String> {
+        self.store.snapshot(snapshot)?;
+        self.log = new_log;
+        Ok(())
+    }
+
+    fn append_interval(&mut self, tag: u8, interval: &Interval<Time>) -> Result<(), String> {
+        let mut record = vec![tag];
+        interval
+            .0
+            .encode(&mut record)
+            .and_then(|()| interval.1.encode(&mut record))
+            .map_err(|err| format!("Error encoding log record: {}", err))?;
+        self.append(&record)
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<(), String> {
+        self.log
+            .write_all(record)
+            .and_then(|()| self.log.flush())
+            .map_err(|err| format!("Error appending to log: {}", err))
+    }
 }
 
-struct Wrapper<Time, Value> {
-    time: Time,
-    value: Value,
+fn encode_insert<Time: Ord + Encodable, Value: Encodable>(
+    record: &mut Vec<u8>,
+    interval: &Interval<Time>,
+    values: &[Value],
+    policy: ConflictPolicy,
+) -> io::Result<()> {
+    interval.0.encode(record)?;
+    interval.1.encode(record)?;
+    let policy: u8 = match policy {
+        ConflictPolicy::Reject => 0,
+        ConflictPolicy::KeepExisting => 1,
+        ConflictPolicy::OverwriteWithNew => 2,
+        ConflictPolicy::MergeById => 3,
+    };
+    policy.encode(record)?;
+    (values.len() as u64).encode(record)?;
+    for value in values {
+        value.encode(record)?;
+    }
+    Ok(())
+}
+
+impl<Time, Value, W> std::ops::Deref for LoggedIntervalStore<Time, Value, W>
+where
+    Time: Ord,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+    W: Write,
+{
+    type Target = IntervalStore<Time, Value>;
+
+    fn deref(&self) -> &IntervalStore<Time, Value> {
+        &self.store
+    }
 }
 
-impl<Time: PartialEq, Value> PartialEq for Wrapper<Time, Value> {
-    fn eq(&self, other: &Wrapper<Time, Value>) -> bool {
-        self.time == other.time
+#[cfg(test)]
+mod intervalstoremap_tests {
+    use super::{Interval, IntervalStore, IntervalStoreMap, StoreSize};
+
+    #[test]
+    fn get_or_create() {
+        let map: IntervalStoreMap<String, u64, u32> = IntervalStoreMap::new();
+        let alice = "alice".to_owned();
+        assert!(map.get(&alice).is_none());
+        map.get_or_create(&alice)
+            .write()
+            .unwrap()
+            .insert(&Interval(10, 20), vec![10])
+            .expect("Insert");
+        assert_eq!(
+            map.get(&alice)
+                .unwrap()
+                .read()
+                .unwrap()
+                .get(&Interval(10, 20)),
+            Some(vec![10])
+        );
+        assert_eq!(
+            map.get_or_create(&alice)
+                .read()
+                .unwrap()
+                .get(&Interval(10, 20)),
+            Some(vec![10])
+        );
+        assert_eq!(map.keys(), vec![alice.clone()]);
+
+        assert!(map.remove(&alice).is_some());
+        assert!(map.get(&alice).is_none());
+        assert!(map.keys().is_empty());
+        assert!(map.remove(&alice).is_none());
+    }
+
+    #[test]
+    fn keys_across_shards() {
+        let map: IntervalStoreMap<u64, u64, u32> = IntervalStoreMap::new();
+        for key in 0..100 {
+            map.get_or_create(&key)
+                .write()
+                .unwrap()
+                .insert(&Interval(key, key), vec![key as u32])
+                .expect("Insert");
+        }
+        let mut keys = map.keys();
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+        assert_eq!(map.stats().len(), 100);
+        for key in 0..100 {
+            assert_eq!(
+                map.get(&key)
+                    .unwrap()
+                    .read()
+                    .unwrap()
+                    .get(&Interval(key, key)),
+                Some(vec![key as u32])
+            );
+        }
+    }
+
+    #[test]
+    fn factory_and_stats() {
+        let map: IntervalStoreMap<&str, u64, u32> =
+            IntervalStoreMap::with_factory(|| IntervalStore::with_max_values(1));
+        {
+            let store = map.get_or_create(&"alice");
+            let mut store = store.write().unwrap();
+            store.insert(&Interval(10, 20), vec![10]).expect("Insert");
+            store.insert(&Interval(30, 40), vec![30]).expect("Insert");
+        }
+        let mut bob = IntervalStore::new();
+        bob.insert(&Interval(10, 20), vec![10, 11]).expect("Insert");
+        map.insert("bob", bob);
+
+        let mut stats = map.stats();
+        stats.sort_by_key(|&(key, _)| key);
+        assert_eq!(
+            stats
+                .into_iter()
+                .map(|(key, stats)| (key, stats.size))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "alice",
+                    StoreSize {
+                        intervals: 1,
+                        values: 1,
+                        approximate_bytes: 4,
+                    }
+                ),
+                (
+                    "bob",
+                    StoreSize {
+                        intervals: 1,
+                        values: 2,
+                        approximate_bytes: 8,
+                    }
+                ),
+            ]
+        );
     }
 }
 
-impl<Time: Eq, Value> Eq for Wrapper<Time, Value> {}
+#[cfg(test)]
+mod intervalstore_tests {
+    use super::{
+        value_key, BoundedInterval, Budget, Conflict, ConflictPolicy, EarliestFirst, Encodable,
+        Interval, IntervalStore, LargestFirst, LeastRecentlyUsed, LoggedIntervalStore, StoreSize,
+        StoreStats, UniquelyIdentifiedTimeValue,
+    };
+    use std::io::{self, Write};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn get_missing() {
+        let store = new();
+        assert_eq!(store.get(&Interval(10, 20)), None);
+    }
+
+    #[test]
+    fn get_empty_bucket() {
+        let mut store = new();
+        store.insert(&Interval(10, 20), vec![]).expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![]));
+    }
+
+    #[test]
+    fn get_whole_bucket() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10, 11, 15]));
+    }
+
+    #[test]
+    fn get_part_of_bucket() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 14)), Some(vec![10, 11]));
+    }
+
+    #[test]
+    fn get_includes_both_ends() {
+        let mut store = new();
+        store
+            .insert(&Interval(0, 1000), (0..1000).collect())
+            .expect("Insert");
+        assert_eq!(
+            store.get(&Interval(500, 505)),
+            Some(vec![500, 501, 502, 503, 504, 505])
+        );
+        assert_eq!(store.get(&Interval(999, 999)), Some(vec![999]));
+    }
+
+    #[test]
+    fn get_available_partially_covered() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(12, 30)), None);
+        assert_eq!(store.get_available(&Interval(12, 30)), vec![15]);
+        assert!(store.get_available(&Interval(25, 30)).is_empty());
+    }
+
+    #[test]
+    fn get_partial() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        store.insert(&Interval(30, 40), vec![35]).expect("Insert");
+        let (values, missing) = store.get_partial(&Interval(12, 50));
+        assert_eq!(values, vec![15, 35]);
+        assert_eq!(
+            missing.iter().collect::<Vec<_>>(),
+            vec![&Interval(20, 30), &Interval(40, 50)]
+        );
+
+        let (values, missing) = store.get_partial(&Interval(10, 20));
+        assert_eq!(values, vec![10, 11, 15]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn expired_intervals_are_missing() {
+        let mut store = new();
+        let past = Instant::now() - Duration::from_secs(1);
+        let future = Instant::now() + Duration::from_secs(3600);
+        store
+            .insert_with_expiry(
+                &Interval(10, 20),
+                vec![10, 15],
+                ConflictPolicy::Reject,
+                past,
+            )
+            .expect("Insert");
+        store
+            .insert_with_expiry(&Interval(30, 40), vec![35], ConflictPolicy::Reject, future)
+            .expect("Insert");
+        assert!(!store.has(&Interval(10, 20)));
+        assert_eq!(store.get(&Interval(10, 20)), None);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![35]));
+        assert_eq!(
+            store.missing(&Interval(10, 40)).iter().collect::<Vec<_>>(),
+            vec![&Interval(10, 30)]
+        );
+        assert_eq!(store.get_available(&Interval(10, 20)), vec![10, 15]);
+
+        store.insert(&Interval(12, 18), vec![15]).expect("Refetch");
+        assert_eq!(store.get(&Interval(12, 18)), Some(vec![15]));
+        assert_eq!(store.get(&Interval(10, 20)), None);
+
+        assert_eq!(store.remove_expired(), 2);
+        assert_eq!(store.get_available(&Interval(10, 20)), vec![15]);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![35]));
+    }
+
+    #[test]
+    fn live_intervals_follow_later_expiries() {
+        let mut store = new();
+        let past = Instant::now() - Duration::from_secs(1);
+        let soon = Instant::now() + Duration::from_millis(100);
+        store
+            .insert_with_expiry(&Interval(10, 20), vec![15], ConflictPolicy::Reject, past)
+            .expect("Insert");
+        store
+            .insert_with_expiry(&Interval(30, 40), vec![35], ConflictPolicy::Reject, soon)
+            .expect("Insert");
+        assert!(!store.has(&Interval(10, 20)));
+        assert!(store.has(&Interval(30, 40)));
+
+        ::std::thread::sleep(Duration::from_millis(150));
+        assert!(!store.has(&Interval(30, 40)));
+
+        store.insert(&Interval(10, 20), vec![15]).expect("Refetch");
+        assert!(store.has(&Interval(10, 20)));
+        assert!(!store.has(&Interval(30, 40)));
+    }
+
+    #[test]
+    fn stats() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        assert!(store.insert(&Interval(15, 25), vec![16]).is_err());
+        store
+            .insert_with_policy(&Interval(15, 25), vec![16], ConflictPolicy::MergeById)
+            .expect("Insert");
+        store.get(&Interval(10, 20));
+        store.get(&Interval(20, 30));
+        store.get(&Interval(40, 50));
+        store.get_partial(&Interval(0, 10));
+        store.get_available(&Interval(12, 14));
+
+        assert_eq!(
+            store.stats(),
+            StoreStats {
+                size: StoreSize {
+                    intervals: 1,
+                    values: 4,
+                    approximate_bytes: 16,
+                },
+                lookups: 5,
+                hits: 2,
+                partial_hits: 2,
+                misses: 1,
+                inserts: 2,
+                conflicts: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn count() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![12, 15, 18])
+            .expect("Insert");
+        assert_eq!(store.count(&Interval(10, 20)), Some(3));
+        assert_eq!(store.count(&Interval(13, 18)), Some(2));
+        assert_eq!(store.count(&Interval(13, 14)), Some(0));
+        assert_eq!(store.count(&Interval(15, 25)), None);
+    }
 
-impl<Time: Ord, Value> PartialOrd for Wrapper<Time, Value> {
-    fn partial_cmp(&self, other: &Wrapper<Time, Value>) -> Option<Ordering> {
-        Some(self.cmp(other))
+    #[test]
+    fn values_sharing_a_time() {
+        let mut store: IntervalStore<u64, (u64, u32)> = IntervalStore::new();
+        store
+            .insert(&Interval(10, 20), vec![(15, 2), (15, 1), (12, 3)])
+            .expect("Insert");
+        assert_eq!(
+            store.get(&Interval(10, 20)),
+            Some(vec![(12, 3), (15, 1), (15, 2)])
+        );
+        assert_eq!(store.count(&Interval(15, 15)), Some(2));
+        assert!(store
+            .insert(&Interval(15, 25), vec![(15, 1), (15, 3)])
+            .is_err());
+        store
+            .insert(&Interval(15, 25), vec![(15, 1), (15, 2), (22, 4)])
+            .expect("Insert");
+        assert_eq!(store.count(&Interval(10, 25)), Some(4));
     }
-}
 
-impl<Time: Ord, Value> Ord for Wrapper<Time, Value> {
-    fn cmp(&self, other: &Wrapper<Time, Value>) -> Ordering {
-        self.time.cmp(&other.time)
+    #[test]
+    fn upsert() {
+        let mut store: IntervalStore<u64, (u64, &str)> = IntervalStore::new();
+        store
+            .insert(&Interval(10, 20), vec![(10, "before"), (15, "unchanged")])
+            .expect("Insert");
+        assert_eq!(store.upsert((10, "after")), Ok(Some((10, "before"))));
+        assert_eq!(store.upsert((12, "new")), Ok(None));
+        assert!(store.upsert((25, "uncovered")).is_err());
+        assert_eq!(
+            store.get(&Interval(10, 20)),
+            Some(vec![(10, "after"), (12, "new"), (15, "unchanged")])
+        );
     }
-}
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct IntervalSet<Time: Ord> {
-    intervals: BTreeSet<Interval<Time>>,
-}
+    #[test]
+    fn view_is_unaffected_by_later_writes() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 15, 20])
+            .expect("Insert");
+        let view = store.view();
+        assert!(Arc::ptr_eq(&view.values, &store.values));
 
-impl<Time: Ord + Copy> IntervalSet<Time> {
-    pub fn new() -> IntervalSet<Time> {
-        IntervalSet {
-            intervals: BTreeSet::new(),
-        }
-    }
+        store
+            .insert_with_policy(
+                &Interval(15, 30),
+                vec![25],
+                ConflictPolicy::OverwriteWithNew,
+            )
+            .expect("Insert");
+        store.remove(&Interval(10, 12));
 
-    pub fn insert(&mut self, interval: &Interval<Time>) {
-        // Merge any intervals which require merging
-        let mut lower_bound = interval.0;
-        let mut upper_bound = interval.1;
-        for existing_interval in self.intersecting(&interval).iter() {
-            if existing_interval.contains_interval(&interval) {
-                return;
-            }
-            lower_bound = min(lower_bound, existing_interval.0);
-            upper_bound = max(upper_bound, existing_interval.1);
-            self.intervals.remove(existing_interval);
-        }
-        self.intervals.insert(Interval(lower_bound, upper_bound));
+        assert_eq!(view.get(&Interval(10, 20)), Some(vec![10, 15, 20]));
+        assert_eq!(view.get(&Interval(10, 30)), None);
+        assert_eq!(
+            view.iter_within(&Interval(12, 40))
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![15, 20]
+        );
+        assert_eq!(view.missing(&Interval(0, 30)).len(), 2);
+        assert_eq!(view.len(), 3);
+        assert_eq!(store.get(&Interval(13, 30)), Some(vec![25]));
     }
 
-    pub fn contains(&self, interval: &Interval<Time>) -> bool {
-        self.intervals.iter().any(|i| i.contains_interval(interval))
-    }
+    #[test]
+    fn insert_batch_reports_conflicts() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 15, 20])
+            .expect("Insert");
+        let report = store
+            .insert_batch(vec![
+                (Interval(0, 5), vec![1, 2]),
+                (Interval(15, 25), vec![16, 20, 22]),
+                (Interval(4, 8), vec![3, 4, 6]),
+                (Interval(30, 40), vec![35]),
+            ])
+            .expect_err("Should conflict");
+        assert_eq!(
+            report.conflicts,
+            vec![
+                Conflict {
+                    overlap: Interval(15, 20),
+                    only_new: vec![16],
+                    only_existing: vec![15],
+                },
+                Conflict {
+                    overlap: Interval(4, 5),
+                    only_new: vec![4],
+                    only_existing: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "2 conflicts; in [15,20] only new: [16], only existing: [15]; \
+             in [4,5] only new: [4], only existing: []"
+        );
+        assert_eq!(store.get(&Interval(0, 5)), Some(vec![1, 2]));
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![35]));
+        assert!(!store.has(&Interval(15, 25)));
+        assert!(!store.has(&Interval(4, 8)));
+        assert_eq!(store.stats().conflicts, 2);
 
-    // TODO: Find overlap more efficiently than O(n)
-    pub fn intersecting(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
-        self.intervals
-            .iter()
-            .filter(|existing_interval| existing_interval.intersects(&interval))
-            .map(|i| i.clone())
-            .collect()
+        assert_eq!(
+            store.insert_batch(vec![(Interval(40, 50), vec![35, 45])]),
+            Ok(())
+        );
     }
 
-    pub fn missing(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
-        let mut missing = BTreeSet::new();
-
-        let mut missing_lower_bound = interval.0;
+    #[test]
+    fn validate() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 15, 20])
+            .expect("Insert");
+        assert_eq!(store.validate(), Ok(()));
 
-        for existing_interval in self.intervals.iter() {
-            if existing_interval.1 < interval.0 {
-                continue;
-            } else if existing_interval.0 <= missing_lower_bound
-                && existing_interval.1 >= missing_lower_bound
-            {
-                missing_lower_bound = existing_interval.1;
-            } else if existing_interval.0 >= missing_lower_bound {
-                missing.insert(Interval(
-                    missing_lower_bound,
-                    min(interval.1, existing_interval.0),
-                ));
-                missing_lower_bound = existing_interval.1;
-            } else if existing_interval.0 > interval.1 {
-                break;
-            }
-        }
+        Arc::make_mut(&mut store.values).insert(value_key(&30), 30);
+        assert_eq!(
+            store.validate(),
+            Err(format!("Value 3 of 4 is outside the covered intervals"))
+        );
 
-        if missing_lower_bound < interval.1 {
-            missing.insert(Interval(missing_lower_bound, interval.1));
-        }
+        Arc::make_mut(&mut store.values).remove(&value_key(&30));
+        Arc::make_mut(&mut store.values).insert(value_key(&12), 13);
+        assert_eq!(
+            store.validate(),
+            Err(format!("Value 1 of 4 is stored under another value's key"))
+        );
 
-        IntervalSet { intervals: missing }
+        Arc::make_mut(&mut store.values).remove(&value_key(&12));
+        store.approximate_bytes = 0;
+        assert_eq!(
+            store.validate(),
+            Err(format!(
+                "Values add up to 12 approximate bytes, but 0 are accounted for"
+            ))
+        );
     }
 
-    pub fn iter(&self) -> std::collections::btree_set::Iter<Interval<Time>> {
-        self.intervals.iter()
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn par_insert_batch_matches_insert_batch() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 15, 20])
+            .expect("Insert");
+        let mut parallel_store = new();
+        parallel_store
+            .insert(&Interval(10, 20), vec![10, 15, 20])
+            .expect("Insert");
+        let batch = vec![
+            (Interval(0, 5), vec![1, 2]),
+            (Interval(15, 25), vec![16, 20, 22]),
+            (Interval(4, 8), vec![3, 4, 6]),
+            (Interval(30, 40), vec![35]),
+            (Interval(40, 50), vec![45]),
+        ];
+        assert_eq!(
+            parallel_store.par_insert_batch(batch.clone()),
+            store.insert_batch(batch)
+        );
+        assert_eq!(
+            parallel_store.par_get_available(&Interval(0, 50)),
+            store.get_available(&Interval(0, 50))
+        );
+        assert_eq!(
+            parallel_store.par_get_available(&Interval(0, 50)),
+            vec![1, 2, 10, 15, 20, 35, 45]
+        );
     }
-}
 
-impl<Time: Ord> std::iter::FromIterator<Interval<Time>> for IntervalSet<Time> {
-    fn from_iter<It: IntoIterator<Item = Interval<Time>>>(iter: It) -> Self {
-        IntervalSet {
-            intervals: BTreeSet::from_iter(iter),
-        }
+    #[test]
+    fn retain_in() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 12, 13, 14])
+            .expect("Insert");
+        assert_eq!(
+            store.retain_in(&Interval(11, 20), |value| value % 2 == 0),
+            2
+        );
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10, 12, 14]));
+        assert_eq!(store.size().approximate_bytes, 12);
     }
-}
 
-pub struct IntervalStore<Time: Ord, Value: UniquelyIdentifiedTimeValue<Time> + Clone> {
-    intervals: IntervalSet<Time>,
-    values: BTreeSet<Wrapper<Time, Value>>,
-}
-
-impl<Time: Ord + Copy, Value: UniquelyIdentifiedTimeValue<Time> + Clone>
-    IntervalStore<Time, Value>
-{
-    pub fn new() -> IntervalStore<Time, Value> {
-        IntervalStore {
-            intervals: IntervalSet::new(),
-            values: BTreeSet::new(),
-        }
+    #[test]
+    fn nearest_values() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![12, 15, 18])
+            .expect("Insert");
+        store.insert(&Interval(30, 40), vec![35]).expect("Insert");
+        assert_eq!(store.value_at_or_before(16), Some(15));
+        assert_eq!(store.value_at_or_before(15), Some(15));
+        assert_eq!(store.value_at_or_before(11), None);
+        assert_eq!(store.value_at_or_before(32), None);
+        assert_eq!(store.value_at_or_before(25), None);
+        assert_eq!(store.value_at_or_after(16), Some(18));
+        assert_eq!(store.value_at_or_after(19), None);
+        assert_eq!(store.value_at_or_after(30), Some(35));
+        assert_eq!(store.value_at_or_after(5), None);
     }
 
-    pub fn has(&self, interval: &Interval<Time>) -> bool {
-        self.intervals.contains(interval)
+    #[test]
+    fn get_bounded_excludes_open_ends() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15, 20])
+            .expect("Insert");
+        assert_eq!(
+            store.get_bounded(&BoundedInterval::closed_open(10, 20)),
+            Some(vec![10, 11, 15])
+        );
+        assert_eq!(
+            store.get_bounded(&BoundedInterval::open_closed(10, 20)),
+            Some(vec![11, 15, 20])
+        );
+        assert_eq!(store.get_bounded(&BoundedInterval::closed(10, 21)), None);
     }
 
-    pub fn missing(&self, interval: &Interval<Time>) -> IntervalSet<Time> {
-        self.intervals.missing(interval)
+    #[test]
+    fn remove_punches_hole() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15, 18, 20])
+            .expect("Insert");
+        store.remove(&Interval(11, 15));
+        assert_eq!(store.get(&Interval(10, 20)), None);
+        assert_eq!(store.get(&Interval(10, 10)), Some(vec![10]));
+        assert_eq!(store.get(&Interval(16, 20)), Some(vec![18, 20]));
+        assert_eq!(
+            store.missing(&Interval(10, 20)).iter().collect::<Vec<_>>(),
+            vec![&Interval(11, 15)]
+        );
     }
 
-    pub fn get(&self, interval: &Interval<Time>) -> Option<Vec<Value>> {
-        if !self.has(interval) {
-            return None;
-        }
-        // TODO: Use range
-        return Some(
-            self.values
-                .iter()
-                .filter(|w| interval.contains(&w.time))
-                .map(|w| w.value.clone())
-                .collect(),
+    #[test]
+    fn remove_keeps_values_on_remaining_boundaries() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 12, 15, 18, 20])
+            .expect("Insert");
+        store.remove(&Interval(11, 15));
+        assert_eq!(store.get(&Interval(10, 11)), Some(vec![10, 11]));
+        assert_eq!(store.get(&Interval(15, 20)), Some(vec![15, 18, 20]));
+        assert_eq!(store.get(&Interval(11, 15)), None);
+        store
+            .insert(&Interval(11, 15), vec![11, 13, 15])
+            .expect("Refetch of the removed interval");
+        assert_eq!(
+            store.get(&Interval(10, 20)),
+            Some(vec![10, 11, 13, 15, 18, 20])
         );
     }
 
-    pub fn insert(&mut self, interval: &Interval<Time>, values: Vec<Value>) -> Result<(), String> {
-        let mut wrapped_values: BTreeSet<_> = values
-            .into_iter()
-            .map(|v| Wrapper {
-                time: v.time(),
-                value: v,
-            })
-            .collect();
+    #[test]
+    fn remove_then_reinsert_different_values() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        store.remove(&Interval(10, 20));
+        store
+            .insert(&Interval(10, 20), vec![12, 13])
+            .expect("Reinsert");
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![12, 13]));
+    }
 
-        let overlapping_existing_intervals = self.intervals.intersecting(&interval);
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut store = IntervalStore::with_max_values(4);
+        store
+            .insert(&Interval(10, 20), vec![10, 15])
+            .expect("Insert");
+        store
+            .insert(&Interval(30, 40), vec![30, 35])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10, 15]));
+        store.insert(&Interval(50, 60), vec![50]).expect("Insert");
 
-        for existing_interval in overlapping_existing_intervals.iter() {
-            let overlap = Interval(
-                max(existing_interval.0, interval.0),
-                min(existing_interval.1, interval.1),
-            );
-            if wrapped_values
-                .iter()
-                .filter(|w| overlap.contains(&w.time))
-                .collect::<Vec<_>>()
-                != self
-                    .values
-                    .iter()
-                    .filter(|w| overlap.contains(&w.time))
-                    .collect::<Vec<_>>()
-            {
-                return Err(format!("Conflicting values"));
-            }
-        }
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10, 15]));
+        assert_eq!(store.get(&Interval(30, 40)), None);
+        assert_eq!(store.get(&Interval(50, 60)), Some(vec![50]));
+        assert!(store.get_available(&Interval(30, 40)).is_empty());
+    }
 
-        self.intervals.insert(&interval);
+    #[test]
+    fn merges_adjacent_intervals() {
+        let mut store = IntervalStore::with_max_values(3).merging_adjacent();
+        store.insert(&Interval(1, 5), vec![1]).expect("Insert");
+        store.insert(&Interval(20, 25), vec![20]).expect("Insert");
+        store.insert(&Interval(6, 10), vec![6]).expect("Insert");
+        assert_eq!(store.get(&Interval(1, 10)), Some(vec![1, 6]));
+        assert_eq!(store.get(&Interval(20, 25)), Some(vec![20]));
 
-        self.values.append(&mut wrapped_values);
+        // [1, 5] was merged into [1, 10], which is evicted whole.
+        store.insert(&Interval(30, 35), vec![30]).expect("Insert");
+        assert!(!store.has(&Interval(1, 5)));
+        assert!(!store.has(&Interval(6, 10)));
+        assert!(store.has(&Interval(20, 25)));
+        assert!(store.has(&Interval(30, 35)));
+    }
 
-        Ok(())
+    #[test]
+    fn never_evicts_most_recently_used() {
+        let mut store: IntervalStore<u64, u32> = IntervalStore::with_max_values(1);
+        store.insert(&Interval(10, 20), vec![10]).expect("Insert");
+        store
+            .insert(&Interval(30, 40), vec![30, 35])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), None);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30, 35]));
     }
-}
 
-#[cfg(test)]
-mod intervalset_tests {
-    use super::{Interval, IntervalSet};
+    #[test]
+    fn eviction_tracks_merged_and_split_intervals() {
+        let mut store = IntervalStore::with_max_values(3);
+        store.insert(&Interval(10, 20), vec![10]).expect("Insert");
+        store.insert(&Interval(30, 40), vec![30]).expect("Insert");
+        store.insert(&Interval(15, 25), vec![25]).expect("Insert");
+        store.remove(&Interval(12, 18));
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
+        store
+            .insert(&Interval(50, 60), vec![50, 55])
+            .expect("Insert");
+
+        assert_eq!(store.get(&Interval(10, 11)), None);
+        assert_eq!(store.get(&Interval(19, 25)), None);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
+        assert_eq!(store.get(&Interval(50, 60)), Some(vec![50, 55]));
+    }
 
     #[test]
-    fn contains_empty() {
-        let set = IntervalSet::new();
-        assert!(!set.contains(&Interval(10, 20)));
+    fn evicts_to_byte_budget() {
+        // Each u32 is 4 bytes.
+        let mut store =
+            IntervalStore::with_budget(Budget::ApproximateBytes(12), Box::new(LeastRecentlyUsed));
+        store
+            .insert(&Interval(10, 20), vec![10, 15])
+            .expect("Insert");
+        store.insert(&Interval(30, 40), vec![30]).expect("Insert");
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
+        store
+            .insert(&Interval(50, 60), vec![50, 55])
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), None);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
+        assert_eq!(store.get(&Interval(50, 60)), Some(vec![50, 55]));
     }
 
     #[test]
-    fn contains_part() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(10, 15));
-        assert!(!set.contains(&Interval(10, 20)));
+    fn evicts_with_policy() {
+        let mut store = IntervalStore::with_budget(Budget::Values(4), Box::new(LargestFirst));
+        store.insert(&Interval(10, 20), vec![10]).expect("Insert");
+        store
+            .insert(&Interval(30, 40), vec![30, 35, 40])
+            .expect("Insert");
+        store.insert(&Interval(50, 60), vec![50]).expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10]));
+        assert_eq!(store.get(&Interval(30, 40)), None);
+
+        let mut store = IntervalStore::with_budget(Budget::Values(2), Box::new(EarliestFirst));
+        store.insert(&Interval(30, 40), vec![30]).expect("Insert");
+        store.insert(&Interval(10, 20), vec![10]).expect("Insert");
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
+        store.insert(&Interval(50, 60), vec![50]).expect("Insert");
+        assert_eq!(store.get(&Interval(10, 20)), None);
+        assert_eq!(store.get(&Interval(30, 40)), Some(vec![30]));
     }
 
     #[test]
-    fn contains_exact() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(10, 20));
-        assert!(set.contains(&Interval(10, 20)));
+    fn snapshot_round_trip() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        store.insert(&Interval(30, 40), vec![]).expect("Insert");
+        let mut bytes = vec![];
+        store.snapshot(&mut bytes).expect("Snapshot");
+
+        let restored: IntervalStore<u64, u32> =
+            IntervalStore::restore(&mut &bytes[..]).expect("Restore");
+        assert_eq!(restored.covered(), store.covered());
+        assert_eq!(restored.get(&Interval(10, 20)), Some(vec![10, 11, 15]));
+        assert_eq!(restored.get(&Interval(30, 40)), Some(vec![]));
     }
 
     #[test]
-    fn contains_more() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(5, 25));
-        assert!(set.contains(&Interval(10, 20)));
+    fn snapshot_leaves_out_expired_intervals() {
+        let mut store = new();
+        let past = Instant::now() - Duration::from_secs(1);
+        store
+            .insert_with_expiry(
+                &Interval(10, 20),
+                vec![10, 15],
+                ConflictPolicy::Reject,
+                past,
+            )
+            .expect("Insert");
+        store.insert(&Interval(30, 40), vec![35]).expect("Insert");
+        let mut bytes = vec![];
+        store.snapshot(&mut bytes).expect("Snapshot");
+
+        let restored: IntervalStore<u64, u32> =
+            IntervalStore::restore(&mut &bytes[..]).expect("Restore");
+        assert_eq!(
+            restored.covered().iter().collect::<Vec<_>>(),
+            vec![&Interval(30, 40)]
+        );
+        assert!(restored.get_available(&Interval(10, 20)).is_empty());
+        assert_eq!(restored.get(&Interval(30, 40)), Some(vec![35]));
     }
 
     #[test]
-    fn missing_none() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(10, 20));
-        assert_eq!(set.missing(&Interval(10, 20)), IntervalSet::new());
-        assert_eq!(set.missing(&Interval(12, 15)), IntervalSet::new());
+    fn encode_strings() {
+        let mut bytes = vec![];
+        "".to_owned().encode(&mut bytes).expect("Encode");
+        "h\u{e9}llo".to_owned().encode(&mut bytes).expect("Encode");
+        let mut reader = &bytes[..];
+        assert_eq!(String::decode(&mut reader).expect("Decode"), "");
+        assert_eq!(String::decode(&mut reader).expect("Decode"), "h\u{e9}llo");
+        let mut truncated = &bytes[8..bytes.len() - 1];
+        assert!(String::decode(&mut truncated).is_err());
     }
 
     #[test]
-    fn missing_lower() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(10, 20));
-        assert_eq!(set.missing(&Interval(5, 10)), interval_set(Interval(5, 10)));
-        assert_eq!(set.missing(&Interval(5, 15)), interval_set(Interval(5, 10)));
+    fn restore_rejects_bad_snapshots() {
+        let mut store = new();
+        store
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        let mut bytes = vec![];
+        store.snapshot(&mut bytes).expect("Snapshot");
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(IntervalStore::<u64, u32>::restore(&mut &truncated[..]).is_err());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(IntervalStore::<u64, u32>::restore(&mut &wrong_magic[..]).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 99;
+        assert!(IntervalStore::<u64, u32>::restore(&mut &wrong_version[..]).is_err());
     }
 
     #[test]
-    fn missing_upper() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(10, 20));
-        assert_eq!(
-            set.missing(&Interval(20, 25)),
-            interval_set(Interval(20, 25))
-        );
-        assert_eq!(
-            set.missing(&Interval(15, 25)),
-            interval_set(Interval(20, 25))
-        );
+    fn replay_log() {
+        let mut logged = LoggedIntervalStore::new(new(), vec![]);
+        logged
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        logged
+            .insert_with_policy(
+                &Interval(15, 30),
+                vec![16],
+                ConflictPolicy::OverwriteWithNew,
+            )
+            .expect("Insert");
+        logged.remove(&Interval(25, 30)).expect("Remove");
+        assert!(logged.insert(&Interval(10, 20), vec![12]).is_err());
+
+        let mut replayed = new();
+        let applied = replayed.replay_log(&mut &logged.log[..]).expect("Replay");
+        assert_eq!(applied, 3);
+        assert_eq!(replayed.covered(), logged.covered());
+        assert_eq!(replayed.get(&Interval(10, 25)), Some(vec![10, 11, 16]));
     }
 
     #[test]
-    fn missing_middle() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(5, 10));
-        set.insert(&Interval(20, 30));
-        assert_eq!(
-            set.missing(&Interval(12, 15)),
-            interval_set(Interval(12, 15))
-        );
-        assert_eq!(
-            set.missing(&Interval(10, 15)),
-            interval_set(Interval(10, 15))
-        );
-        assert_eq!(
-            set.missing(&Interval(15, 20)),
-            interval_set(Interval(15, 20))
-        );
-        assert_eq!(
-            set.missing(&Interval(15, 25)),
-            interval_set(Interval(15, 20))
-        );
+    fn replay_ignores_truncated_record() {
+        let mut logged = LoggedIntervalStore::new(new(), vec![]);
+        logged
+            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .expect("Insert");
+        logged.insert(&Interval(30, 40), vec![30]).expect("Insert");
+        let truncated = &logged.log[..logged.log.len() - 2];
+
+        let mut replayed = new();
+        let applied = replayed.replay_log(&mut &truncated[..]).expect("Replay");
+        assert_eq!(applied, 1);
+        assert_eq!(replayed.get(&Interval(10, 20)), Some(vec![10, 11, 15]));
+        assert_eq!(replayed.get(&Interval(30, 40)), None);
     }
 
     #[test]
-    fn missing_multi() {
-        let mut set = IntervalSet::new();
-        set.insert(&Interval(5, 10));
-        set.insert(&Interval(20, 30));
+    fn replay_log_repeats_evictions_and_touches() {
+        let mut logged = LoggedIntervalStore::new(IntervalStore::with_max_values(4), vec![]);
+        logged
+            .insert(&Interval(10, 20), vec![10, 15])
+            .expect("Insert");
+        logged
+            .insert(&Interval(30, 40), vec![30, 35])
+            .expect("Insert");
         assert_eq!(
-            set.missing(&Interval(1, 40)),
-            interval_set_of(vec![Interval(1, 5), Interval(10, 20), Interval(30, 40)])
+            logged.get(&Interval(10, 20)).expect("Get"),
+            Some(vec![10, 15])
         );
-    }
+        logged.insert(&Interval(50, 60), vec![50]).expect("Insert");
+        assert_eq!(logged.get(&Interval(30, 40)).expect("Get"), None);
 
-    fn interval_set(interval: Interval<u32>) -> IntervalSet<u32> {
-        interval_set_of(vec![interval])
-    }
+        // Replayed without a budget, the store only matches if the eviction was logged.
+        let mut replayed = new();
+        replayed.replay_log(&mut &logged.log[..]).expect("Replay");
+        assert_eq!(replayed.covered(), logged.covered());
 
-    fn interval_set_of(intervals: Vec<Interval<u32>>) -> IntervalSet<u32> {
-        IntervalSet {
-            intervals: intervals.into_iter().collect(),
-        }
+        // Replayed with the same budget, the last touch decides which interval goes next.
+        logged.get(&Interval(10, 20)).expect("Get");
+        let mut replayed = IntervalStore::with_max_values(4);
+        replayed.replay_log(&mut &logged.log[..]).expect("Replay");
+        replayed
+            .insert(&Interval(70, 80), vec![70, 75])
+            .expect("Insert");
+        logged
+            .insert(&Interval(70, 80), vec![70, 75])
+            .expect("Insert");
+        assert_eq!(logged.get(&Interval(50, 60)).expect("Get"), None);
+        assert_eq!(replayed.covered(), logged.covered());
     }
-}
 
-#[cfg(test)]
-mod intervalstore_tests {
-    use super::{Interval, IntervalStore, UniquelyIdentifiedTimeValue};
+    struct FailingLog;
 
-    #[test]
-    fn get_missing() {
-        let store = new();
-        assert_eq!(store.get(&Interval(10, 20)), None);
+    impl Write for FailingLog {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "Disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
     }
 
     #[test]
-    fn get_empty_bucket() {
-        let mut store = new();
-        store.insert(&Interval(10, 20), vec![]).expect("Insert");
-        assert_eq!(store.get(&Interval(10, 20)), Some(vec![]));
+    fn unlogged_mutations_are_not_applied() {
+        let mut logged = LoggedIntervalStore::new(new(), FailingLog);
+        assert!(logged.insert(&Interval(10, 20), vec![10]).is_err());
+        assert!(logged.covered().is_empty());
     }
 
     #[test]
-    fn get_whole_bucket() {
+    fn insert() {
         let mut store = new();
         store
             .insert(&Interval(10, 20), vec![10, 11, 15])
@@ -347,21 +2303,46 @@ mod intervalstore_tests {
     }
 
     #[test]
-    fn get_part_of_bucket() {
-        let mut store = new();
+    fn insert_with_conflict_policies() {
+        let existing = || {
+            let mut store = new();
+            store
+                .insert(&Interval(10, 20), vec![10, 11, 15])
+                .expect("Insert");
+            store
+        };
+
+        let mut store = existing();
+        assert!(store
+            .insert_with_policy(&Interval(15, 30), vec![16, 25], ConflictPolicy::Reject)
+            .is_err());
+        assert_eq!(store.get(&Interval(10, 30)), None);
+
+        let mut store = existing();
         store
-            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .insert_with_policy(
+                &Interval(15, 30),
+                vec![16, 25],
+                ConflictPolicy::KeepExisting,
+            )
             .expect("Insert");
-        assert_eq!(store.get(&Interval(10, 14)), Some(vec![10, 11]));
-    }
+        assert_eq!(store.get(&Interval(10, 30)), Some(vec![10, 11, 15, 25]));
 
-    #[test]
-    fn insert() {
-        let mut store = new();
+        let mut store = existing();
         store
-            .insert(&Interval(10, 20), vec![10, 11, 15])
+            .insert_with_policy(
+                &Interval(15, 30),
+                vec![16, 25],
+                ConflictPolicy::OverwriteWithNew,
+            )
             .expect("Insert");
-        assert_eq!(store.get(&Interval(10, 20)), Some(vec![10, 11, 15]));
+        assert_eq!(store.get(&Interval(10, 30)), Some(vec![10, 11, 16, 25]));
+
+        let mut store = existing();
+        store
+            .insert_with_policy(&Interval(15, 30), vec![16, 25], ConflictPolicy::MergeById)
+            .expect("Insert");
+        assert_eq!(store.get(&Interval(10, 30)), Some(vec![10, 11, 15, 16, 25]));
     }
 
     #[test]
@@ -478,8 +2459,38 @@ mod intervalstore_tests {
     }
 
     impl UniquelyIdentifiedTimeValue<u64> for u32 {
+        type Id = u32;
+
         fn time(&self) -> u64 {
             *self as u64
         }
+
+        fn id(&self) -> u32 {
+            *self
+        }
+    }
+
+    impl UniquelyIdentifiedTimeValue<u64> for (u64, u32) {
+        type Id = u32;
+
+        fn time(&self) -> u64 {
+            self.0
+        }
+
+        fn id(&self) -> u32 {
+            self.1
+        }
+    }
+
+    impl<'a> UniquelyIdentifiedTimeValue<u64> for (u64, &'a str) {
+        type Id = u64;
+
+        fn time(&self) -> u64 {
+            self.0
+        }
+
+        fn id(&self) -> u64 {
+            self.0
+        }
     }
 }
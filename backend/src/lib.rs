@@ -1,17 +1,165 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "timezones")]
+extern crate chrono_tz;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "server")]
+extern crate futures;
+#[cfg(all(test, feature = "async"))]
+extern crate futures_executor;
+#[cfg(feature = "async")]
+extern crate futures_util;
+#[cfg(feature = "server")]
 extern crate gotham;
+#[cfg(feature = "server")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "server")]
 extern crate oauthcli;
+#[cfg(feature = "testing")]
+extern crate proptest;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "redis")]
+extern crate redis_client as redis;
+#[cfg(feature = "server")]
 extern crate reqwest;
+#[cfg(feature = "server")]
+extern crate ring;
+#[cfg(feature = "server")]
+extern crate serde;
+#[cfg(feature = "server")]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "server")]
 extern crate serde_json;
+#[cfg(feature = "server")]
 extern crate serde_urlencoded;
+#[cfg(feature = "server")]
 extern crate time;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "server")]
 extern crate url;
+#[cfg(feature = "server")]
 extern crate uuid;
+#[cfg(feature = "archive")]
+extern crate zip;
 
+#[cfg(feature = "archive")]
+mod archive;
+#[cfg(feature = "archive")]
+pub use archive::TwitterArchive;
+#[cfg(feature = "async")]
+mod asyncintervalstore;
+#[cfg(feature = "async")]
+pub use asyncintervalstore::AsyncIntervalStore;
+#[cfg(all(feature = "server", feature = "async"))]
+mod asynctweetstore;
+#[cfg(feature = "server")]
+mod bluesky;
+#[cfg(feature = "server")]
+pub use bluesky::Bluesky;
+#[cfg(feature = "server")]
+mod cassette;
+#[cfg(feature = "server")]
+pub use cassette::{ApiRequest, Cassette, CassetteMode};
+#[cfg(feature = "server")]
+mod events;
+#[cfg(feature = "server")]
+pub use events::{EventLog, EventSummary, FrontendEvent, RecordedEvent};
+#[cfg(feature = "server")]
+mod export;
+#[cfg(feature = "server")]
+pub use export::ExportFormat;
+#[cfg(feature = "server")]
+mod fetchstrategy;
+#[cfg(feature = "server")]
+pub use fetchstrategy::{Entitlements, FetchMethod, FetchRule, FetchStrategy};
+#[cfg(feature = "server")]
+mod http;
+#[cfg(feature = "server")]
+pub use http::HttpTimeouts;
+mod intervals;
+pub use intervals::{BoundKind, BoundedInterval, Interval, IntervalSet, Ratio, Step};
+#[cfg(feature = "std")]
 mod intervalstore;
-pub use intervalstore::{Interval, IntervalSet, IntervalStore, UniquelyIdentifiedTimeValue};
+#[cfg(feature = "std")]
+pub use intervalstore::{
+    Budget, Conflict, ConflictPolicy, ConflictReport, EarliestFirst, Encodable, EvictionCandidate,
+    EvictionPolicy, IntervalStore, IntervalStoreMap, LargestFirst, LeastRecentlyUsed,
+    LoggedIntervalStore, StoreSize, StoreStats, StoreView, UniquelyIdentifiedTimeValue,
+};
+#[cfg(feature = "server")]
+mod httpcache;
+#[cfg(feature = "server")]
+mod inflight;
+#[cfg(feature = "server")]
+mod lrucache;
+#[cfg(feature = "server")]
+mod mastodon;
+#[cfg(feature = "server")]
+pub use mastodon::Mastodon;
+#[cfg(feature = "server")]
+mod media;
+#[cfg(feature = "server")]
+pub use media::{Media, MediaProxy};
+#[cfg(feature = "server")]
 pub mod oauth;
+#[cfg(feature = "server")]
 pub use oauth::Context;
+#[cfg(feature = "server")]
+mod persistence;
+#[cfg(feature = "server")]
+pub use persistence::{PersistedInterval, TweetPersistence};
+#[cfg(feature = "redis")]
+mod rediscache;
+#[cfg(feature = "redis")]
+pub use rediscache::RedisPersistence;
+#[cfg(feature = "server")]
+mod roles;
+#[cfg(feature = "server")]
+pub use roles::{Role, WindowLimits};
+#[cfg(feature = "server")]
+mod sessions;
+#[cfg(feature = "redis")]
+pub use sessions::RedisSessionBackend;
+#[cfg(feature = "server")]
+pub use sessions::SessionBackend;
+#[cfg(feature = "server")]
+mod sharelinks;
+#[cfg(feature = "server")]
+pub use sharelinks::{ShareLink, ShareLinkSigner};
+#[cfg(feature = "server")]
+mod source;
+#[cfg(feature = "server")]
+pub use source::{SocialSource, SourceCapabilities, SourceClient};
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqlitePersistence;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "server")]
 mod tweetstore;
-pub use tweetstore::{SecondsSinceUnixEpoch, TweetFromTwitter, TweetStore, TWEPOCH_MILLIS};
+#[cfg(feature = "server")]
+pub use tweetstore::{
+    ApiCategory, ApiUsage, CacheLimits, FeedTweets, FetchError, FetchPriority,
+    FetchSchedulingPolicy, Hashtag, IntervalProvenance, Metrics, MillisSinceUnixEpoch,
+    MultiFeedTweets, PreloadedInterval, Quota, RateLimited, RefreshPolicy, RetweetedStatus,
+    RevalidationPolicy, SearchEnvironments, SearchOptions, SecondsSinceUnixEpoch, Snowflake,
+    SnowflakeScheme, StaleIfError, TimelineOptions, TweetAuthor, TweetDensity, TweetEntities,
+    TweetFromTwitter, TweetMedia, TweetRefreshPolicy, TweetSource, TweetStore, TweetThread,
+    TweetUrl, UserMention, UserTweet, TWEPOCH_MILLIS,
+};
+#[cfg(feature = "server")]
+mod twitter;
+#[cfg(feature = "server")]
+pub use twitter::Twitter;
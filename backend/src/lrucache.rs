@@ -0,0 +1,102 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// A map which, once it holds more than max_entries, forgets whichever entries were least
+// recently inserted or looked up.
+pub struct LruCache<K: Eq + Hash, V> {
+    max_entries: usize,
+    // Each value, and when it was last used.
+    entries: HashMap<K, (V, u64)>,
+    // Keys by when they were last used, so that the least recently used is first.
+    by_use: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Eq + Hash + Clone, V> LruCache<K, V> {
+    pub fn new(max_entries: usize) -> LruCache<K, V> {
+        LruCache {
+            max_entries,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        self.by_use.remove(&entry.1);
+        self.by_use.insert(now, key.clone());
+        entry.1 = now;
+        Some(&mut entry.0)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let now = self.tick();
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, now)) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(now, key);
+        while self.entries.len() > self.max_entries {
+            let oldest = match self.by_use.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = self.by_use.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.by_use.remove(&used);
+        Some(value)
+    }
+
+    // Keeps only the entries for which keep returns true, without counting as using them.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let by_use = &mut self.by_use;
+        self.entries.retain(|key, &mut (ref mut value, used)| {
+            let kept = keep(key, value);
+            if !kept {
+                by_use.remove(&used);
+            }
+            kept
+        });
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+
+    #[test]
+    fn forgets_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some(&"one"));
+        cache.insert(3, "three");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
+
+        cache.retain(|&key, _| key != 1);
+        assert_eq!(cache.remove(&1), None);
+        cache.insert(4, "four");
+        assert_eq!(cache.get(&3), Some(&"three"));
+        assert_eq!(cache.get(&4), Some(&"four"));
+    }
+}
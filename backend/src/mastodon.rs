@@ -0,0 +1,465 @@
+use cassette::ApiRequest;
+use serde_json;
+use source::{SocialSource, SourceCapabilities, SourceClient};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tweetstore::{
+    FetchError, Hashtag, RetweetedStatus, Snowflake, SnowflakeScheme, TweetAuthor, TweetEntities,
+    TweetFromTwitter, UserMention,
+};
+use Interval;
+
+// The most statuses Mastodon returns per request.
+const PAGE_SIZE: usize = 40;
+// Mastodon instances keep every status, so this only guards against paging indefinitely.
+const MAX_PAGES: usize = 100;
+
+// Statuses posted by accounts on any Mastodon instance, keyed by user@instance.
+#[derive(Default)]
+pub struct Mastodon {
+    // Statuses are listed by account id, rather than by username.
+    account_ids: Mutex<HashMap<MastodonAccount, String>>,
+}
+
+impl Mastodon {
+    pub fn new() -> Mastodon {
+        Mastodon::default()
+    }
+
+    fn account_id(
+        &self,
+        client: &dyn SourceClient,
+        account: &MastodonAccount,
+    ) -> Result<String, FetchError> {
+        if let Some(account_id) = self.account_ids.lock().unwrap().get(account) {
+            return Ok(account_id.clone());
+        }
+        let request = ApiRequest::get(
+            &account.lookup_url(),
+            vec![("acct".to_owned(), account.username.clone())],
+        );
+        let json_string = client.get(&account.instance, &request)?;
+        let account_id = serde_json::from_str::<MastodonAccountFromApi>(&json_string)
+            .map(|found| found.id)
+            .map_err(|err| format!("Error parsing JSON from Mastodon: {:?}", err))?;
+        self.account_ids
+            .lock()
+            .unwrap()
+            .insert(account.clone(), account_id.clone());
+        Ok(account_id)
+    }
+
+    // Statuses after since_id and before max_id; at most a page's worth, newest first.
+    fn fetch_page(
+        client: &dyn SourceClient,
+        account: &MastodonAccount,
+        account_id: &str,
+        since_id: u64,
+        max_id: u64,
+    ) -> Result<Vec<MastodonStatus>, FetchError> {
+        info!("Fetching from {}", account.instance); // TODO: Binary log requests and responses.
+
+        let request = ApiRequest::get(
+            &account.statuses_url(account_id),
+            vec![
+                ("since_id".to_owned(), format!("{}", since_id)),
+                ("max_id".to_owned(), format!("{}", max_id)),
+                ("limit".to_owned(), format!("{}", PAGE_SIZE)),
+            ],
+        );
+        let json_string = client.get(&account.instance, &request)?;
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Mastodon: {:?}", err).into())
+    }
+}
+
+impl SocialSource for Mastodon {
+    fn name(&self) -> &'static str {
+        "mastodon"
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        SourceCapabilities {
+            reposts: true,
+            replies: true,
+            entities: true,
+        }
+    }
+
+    fn parse_key(&self, key: &str) -> Result<String, String> {
+        let account: MastodonAccount = key.parse()?;
+        Ok(format!("{}@{}", account.username, account.instance))
+    }
+
+    fn snowflake(&self, post_id: &str) -> Option<Snowflake> {
+        parse_id(post_id).ok().and_then(snowflake_from_mastodon_id)
+    }
+
+    fn post_id(&self, snowflake: Snowflake) -> String {
+        format!("{}", mastodon_id_from_snowflake(snowflake))
+    }
+
+    // Pages back from the end of the interval until the account runs out of statuses in it.
+    fn fetch_timeline(
+        &self,
+        client: &dyn SourceClient,
+        key: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let account: MastodonAccount = key.parse()?;
+        let account_id = self.account_id(client, &account)?;
+        let (since_id, mut max_id) = mastodon_bounds(interval);
+        let mut tweets = Vec::new();
+        for _ in 0..MAX_PAGES {
+            let page = Mastodon::fetch_page(client, &account, &account_id, since_id, max_id)?;
+            let mut oldest = None;
+            for status in page {
+                let id = status.parsed_id()?;
+                oldest = Some(oldest.map_or(id, |oldest: u64| oldest.min(id)));
+                tweets.extend(status.into_tweet()?);
+            }
+            match oldest {
+                Some(oldest) => max_id = oldest,
+                None => {
+                    tweets.retain(|tweet| interval.contains(&tweet.id));
+                    tweets.sort();
+                    tweets.dedup();
+                    return Ok(tweets);
+                }
+            }
+        }
+        Err(format!(
+            "Too many pages of statuses from {} between {} and {}",
+            key, interval.0, interval.1
+        )
+        .into())
+    }
+}
+
+// An account on a particular instance, written as user@instance.example.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct MastodonAccount {
+    pub username: String,
+    pub instance: String,
+}
+
+impl MastodonAccount {
+    pub fn lookup_url(&self) -> String {
+        format!("https://{}/api/v1/accounts/lookup", self.instance)
+    }
+
+    pub fn statuses_url(&self, account_id: &str) -> String {
+        format!(
+            "https://{}/api/v1/accounts/{}/statuses",
+            self.instance, account_id
+        )
+    }
+}
+
+impl FromStr for MastodonAccount {
+    type Err = String;
+
+    fn from_str(account: &str) -> Result<MastodonAccount, String> {
+        let mut parts = account.trim_start_matches('@').splitn(2, '@');
+        match (parts.next(), parts.next()) {
+            (Some(username), Some(instance))
+                if !username.is_empty()
+                    && !instance.is_empty()
+                    && !instance.contains(&['/', '?', '#'][..]) =>
+            {
+                Ok(MastodonAccount {
+                    username: username.to_owned(),
+                    instance: instance.to_owned(),
+                })
+            }
+            _ => Err(format!(
+                "Bad Mastodon account {:?}, expected user@instance",
+                account
+            )),
+        }
+    }
+}
+
+// Converting Mastodon's ids to Snowflakes keeps their order and their time, so that statuses can
+// be cached and served exactly like tweets. Statuses from before Mastodon's ids were time-based
+// (mid 2017), or before Twitter's epoch, have no Snowflake.
+pub fn snowflake_from_mastodon_id(id: u64) -> Option<Snowflake> {
+    let mastodon = SnowflakeScheme::MASTODON;
+    SnowflakeScheme::TWITTER
+        .id(mastodon.millis(id), mastodon.sequence(id))
+        .ok()
+        .map(Snowflake)
+}
+
+// The Mastodon id at the snowflake's time. Sequences too large for a Mastodon id are clamped, so
+// that the result is still a bound on the ids converted from Mastodon.
+pub fn mastodon_id_from_snowflake(snowflake: Snowflake) -> u64 {
+    let twitter = SnowflakeScheme::TWITTER;
+    SnowflakeScheme::MASTODON.clamped_id(twitter.millis(snowflake.0), twitter.sequence(snowflake.0))
+}
+
+// The since_id and max_id to page back from, which unlike Twitter's max_id are both exclusive.
+pub fn mastodon_bounds(interval: &Interval<Snowflake>) -> (u64, u64) {
+    (
+        mastodon_id_from_snowflake(interval.0).saturating_sub(1),
+        mastodon_id_from_snowflake(interval.1) + 1,
+    )
+}
+
+#[derive(Deserialize)]
+pub struct MastodonAccountFromApi {
+    pub id: String,
+}
+
+// Ids are strings in the Mastodon API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MastodonStatus {
+    pub id: String,
+    // HTML.
+    pub content: String,
+    pub account: MastodonStatusAccount,
+    pub in_reply_to_id: Option<String>,
+    pub reblog: Option<Box<MastodonStatus>>,
+    #[serde(default)]
+    pub tags: Vec<MastodonTag>,
+    #[serde(default)]
+    pub mentions: Vec<MastodonMention>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MastodonStatusAccount {
+    pub acct: String,
+    pub display_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MastodonTag {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MastodonMention {
+    pub acct: String,
+}
+
+impl MastodonStatus {
+    pub fn parsed_id(&self) -> Result<u64, String> {
+        parse_id(&self.id)
+    }
+
+    // Boosts become retweets, with the boosted status's content. None if the status has no
+    // Snowflake.
+    pub fn into_tweet(self) -> Result<Option<TweetFromTwitter>, String> {
+        let id = match snowflake_from_mastodon_id(self.parsed_id()?) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let in_reply_to_status_id = match self.in_reply_to_id {
+            Some(ref in_reply_to_id) => snowflake_from_mastodon_id(parse_id(in_reply_to_id)?),
+            None => None,
+        };
+        let (content, tags, mentions, retweeted_status) = match self.reblog {
+            Some(reblog) => {
+                let reblog = *reblog;
+                let retweeted_status = snowflake_from_mastodon_id(reblog.parsed_id()?)
+                    .map(|id| RetweetedStatus { id });
+                (
+                    reblog.content,
+                    reblog.tags,
+                    reblog.mentions,
+                    retweeted_status,
+                )
+            }
+            None => (self.content, self.tags, self.mentions, None),
+        };
+
+        let mut tweet = TweetFromTwitter::new(id);
+        tweet.text = Some(text_from_html(&content));
+        tweet.author = Some(TweetAuthor {
+            screen_name: self.account.acct.clone(),
+            name: if self.account.display_name.is_empty() {
+                self.account.acct
+            } else {
+                self.account.display_name
+            },
+        });
+        tweet.created_at = Some(id.created_at());
+        tweet.entities = Some(TweetEntities {
+            hashtags: tags
+                .into_iter()
+                .map(|tag| Hashtag { text: tag.name })
+                .collect(),
+            user_mentions: mentions
+                .into_iter()
+                .map(|mention| UserMention {
+                    screen_name: mention.acct,
+                })
+                .collect(),
+            urls: vec![],
+            media: vec![],
+        });
+        tweet.retweeted_status = retweeted_status;
+        tweet.in_reply_to_status_id = in_reply_to_status_id;
+        Ok(Some(tweet))
+    }
+}
+
+fn parse_id(id: &str) -> Result<u64, String> {
+    id.parse()
+        .map_err(|err| format!("Bad Mastodon status id {:?}: {}", id, err))
+}
+
+// Status content is HTML; paragraphs and line breaks become newlines, and other tags are dropped.
+fn text_from_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let name: String = rest[start + 1..end]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        if name.eq_ignore_ascii_case("br") || (name.eq_ignore_ascii_case("p") && !text.is_empty()) {
+            text.push('\n');
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        mastodon_bounds, mastodon_id_from_snowflake, snowflake_from_mastodon_id, text_from_html,
+        Mastodon, MastodonAccount, MastodonStatus,
+    };
+    use serde_json;
+    use source::SocialSource;
+    use tweetstore::{SecondsSinceUnixEpoch, Snowflake};
+    use Interval;
+
+    #[test]
+    fn parse_account() {
+        assert_eq!(
+            "@someone@mastodon.example".parse(),
+            Ok(MastodonAccount {
+                username: "someone".to_owned(),
+                instance: "mastodon.example".to_owned(),
+            })
+        );
+        assert!("someone".parse::<MastodonAccount>().is_err());
+        assert!("someone@".parse::<MastodonAccount>().is_err());
+        assert!("someone@evil.example/path"
+            .parse::<MastodonAccount>()
+            .is_err());
+    }
+
+    #[test]
+    fn ids_keep_their_time_and_order() {
+        // Posted at 2018-11-01T00:00:00Z.
+        let earlier = (1541030400000 << 16) | 5;
+        let later = (1541030400000 << 16) | 6;
+        let earlier_snowflake = snowflake_from_mastodon_id(earlier).expect("Snowflake");
+        let later_snowflake = snowflake_from_mastodon_id(later).expect("Snowflake");
+        assert!(earlier_snowflake < later_snowflake);
+        assert_eq!(
+            SecondsSinceUnixEpoch::from(earlier_snowflake),
+            SecondsSinceUnixEpoch(1541030400)
+        );
+        assert_eq!(mastodon_id_from_snowflake(earlier_snowflake), earlier);
+        // Sequential ids from before Mastodon used time-based ids.
+        assert_eq!(snowflake_from_mastodon_id(12345), None);
+
+        let mastodon = Mastodon::new();
+        assert_eq!(
+            mastodon.snowflake(&format!("{}", later)),
+            Some(later_snowflake)
+        );
+        assert_eq!(mastodon.post_id(later_snowflake), format!("{}", later));
+    }
+
+    #[test]
+    fn bounds_include_the_whole_interval() {
+        let from = SecondsSinceUnixEpoch(1541030400);
+        let until = SecondsSinceUnixEpoch(1541030460);
+        let (since_id, max_id) = mastodon_bounds(&Interval(
+            Snowflake::from(from),
+            Snowflake(Snowflake::from(until).0 | ((1 << 22) - 1)),
+        ));
+        assert_eq!(since_id, (1541030400000 << 16) - 1);
+        assert_eq!(max_id, ((1541030460000 << 16) | ((1 << 16) - 1)) + 1);
+    }
+
+    #[test]
+    fn rate_limit_reset_time() {
+        assert_eq!(
+            SecondsSinceUnixEpoch::parse_utc_timestamp("2018-11-01T00:01:00.588+00:00"),
+            Some(SecondsSinceUnixEpoch(1541030460))
+        );
+        assert_eq!(SecondsSinceUnixEpoch::parse_utc_timestamp("soon"), None);
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            text_from_html(
+                "<p>Hello <a href=\"https://example.com\">@world</a> &amp; friends</p>\
+                 <p>Line<br />break &lt;3</p>"
+            ),
+            "Hello @world & friends\nLine\nbreak <3"
+        );
+    }
+
+    #[test]
+    fn boost_becomes_retweet() {
+        let status: MastodonStatus = serde_json::from_str(
+            r#"{
+              "id": "101001808400000000",
+              "content": "",
+              "account": { "acct": "someone", "display_name": "" },
+              "in_reply_to_id": null,
+              "reblog": {
+                "id": "101001808300000000",
+                "content": "<p>Boosted #tag</p>",
+                "account": { "acct": "other@elsewhere.example", "display_name": "Other" },
+                "in_reply_to_id": null,
+                "reblog": null,
+                "tags": [ { "name": "tag" } ],
+                "mentions": []
+              },
+              "tags": [],
+              "mentions": []
+            }"#,
+        )
+        .expect("Parsing status");
+        let tweet = status.into_tweet().expect("Converting").expect("Snowflake");
+        assert_eq!(
+            tweet.retweeted_status.map(|retweeted| retweeted.id),
+            snowflake_from_mastodon_id(101001808300000000)
+        );
+        assert_eq!(tweet.text, Some("Boosted #tag".to_owned()));
+        assert_eq!(
+            tweet.author.map(|author| author.name),
+            Some("someone".to_owned())
+        );
+        assert_eq!(
+            tweet
+                .entities
+                .map(|entities| entities.hashtags[0].text.clone()),
+            Some("tag".to_owned())
+        );
+    }
+}
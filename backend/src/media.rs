@@ -0,0 +1,145 @@
+use reqwest;
+use std::fs;
+use std::path::PathBuf;
+use tweetstore::{FetchError, Snowflake};
+
+// A photo, video or GIF from a tweet.
+pub struct Media {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+// Fetches tweets' media on clients' behalf, so that replayed tweets still render where Twitter
+// blocks hotlinking. Media can also be kept on disk, so that it still renders after being deleted
+// upstream.
+pub struct MediaProxy {
+    client: reqwest::blocking::Client,
+    // One file per media id holding its bytes, next to one holding its content type. If None,
+    // media is fetched every time it's requested.
+    dir: Option<PathBuf>,
+}
+
+impl MediaProxy {
+    pub fn new(client: reqwest::blocking::Client, dir: Option<PathBuf>) -> MediaProxy {
+        MediaProxy { client, dir }
+    }
+
+    // The media, from disk if it's been kept there, and otherwise from its URL, if it's known.
+    // None if it's neither kept nor known.
+    pub fn get(&self, id: Snowflake, url: Option<&str>) -> Result<Option<Media>, FetchError> {
+        if let Some(media) = self.read(id)? {
+            return Ok(Some(media));
+        }
+        let url = match url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let media = self.fetch(url)?;
+        if let Err(err) = self.write(id, &media) {
+            warn!("Error keeping media {}: {}", id, err);
+        }
+        Ok(Some(media))
+    }
+
+    fn fetch(&self, url: &str) -> Result<Media, FetchError> {
+        let response = self.client.get(url).send().map_err(|err| {
+            FetchError::Unavailable(format!("Error making request to {}: {:?}", url, err))
+        })?;
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(FetchError::Unavailable(format!(
+                "Error {} from {}",
+                status, url
+            )));
+        } else if !status.is_success() {
+            return Err(format!("Error {} from {}", status, url).into());
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_owned();
+        let bytes = response
+            .bytes()
+            .map_err(|err| format!("Error getting bytes from {}: {:?}", url, err))?;
+        Ok(Media {
+            content_type,
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    fn read(&self, id: Snowflake) -> Result<Option<Media>, String> {
+        let (bytes_path, content_type_path) = match self.paths(id) {
+            Some(paths) => paths,
+            None => return Ok(None),
+        };
+        // The content type is written last, so media without one wasn't completely written.
+        if !content_type_path.exists() {
+            return Ok(None);
+        }
+        let content_type = fs::read_to_string(&content_type_path)
+            .map_err(|err| format!("Error reading {:?}: {}", content_type_path, err))?;
+        let bytes = fs::read(&bytes_path)
+            .map_err(|err| format!("Error reading {:?}: {}", bytes_path, err))?;
+        Ok(Some(Media {
+            content_type,
+            bytes,
+        }))
+    }
+
+    fn write(&self, id: Snowflake, media: &Media) -> Result<(), String> {
+        let (bytes_path, content_type_path) = match self.paths(id) {
+            Some(paths) => paths,
+            None => return Ok(()),
+        };
+        if let Some(ref dir) = self.dir {
+            fs::create_dir_all(dir)
+                .map_err(|err| format!("Error creating media directory {:?}: {}", dir, err))?;
+        }
+        fs::write(&bytes_path, &media.bytes)
+            .map_err(|err| format!("Error writing {:?}: {}", bytes_path, err))?;
+        fs::write(&content_type_path, &media.content_type)
+            .map_err(|err| format!("Error writing {:?}: {}", content_type_path, err))
+    }
+
+    fn paths(&self, id: Snowflake) -> Option<(PathBuf, PathBuf)> {
+        self.dir.as_ref().map(|dir| {
+            (
+                dir.join(format!("{}", id.0)),
+                dir.join(format!("{}.content-type", id.0)),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Media, MediaProxy};
+    use http::HttpTimeouts;
+    use std::env;
+    use std::fs;
+    use tweetstore::Snowflake;
+
+    #[test]
+    fn kept_media_is_served_without_fetching() {
+        let dir = env::temp_dir().join(format!("twimetravel-media-{}", ::std::process::id()));
+        let proxy = MediaProxy::new(HttpTimeouts::default_client(), Some(dir.clone()));
+        let media = Media {
+            content_type: "image/jpeg".to_owned(),
+            bytes: vec![0xff, 0xd8, 0xff],
+        };
+
+        assert!(proxy.get(Snowflake(1), None).expect("Get").is_none());
+        proxy.write(Snowflake(1), &media).expect("Write");
+        // The URL is unreachable, so would fail if it were fetched.
+        let kept = proxy
+            .get(Snowflake(1), Some("http://localhost:0/media.jpg"))
+            .expect("Get")
+            .expect("Kept media");
+        assert_eq!(kept.content_type, media.content_type);
+        assert_eq!(kept.bytes, media.bytes);
+
+        fs::remove_dir_all(&dir).expect("Remove media directory");
+    }
+}
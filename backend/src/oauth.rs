@@ -1,23 +1,41 @@
+use http::HttpTimeouts;
 use oauthcli;
 use reqwest;
 use serde_json;
 use serde_urlencoded;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 use url;
 
+// The state of a dance between starting it and Twitter sending the user back. It's kept in the
+// user's session, so that with a session backend shared between instances, the callback may be
+// handled by a different instance to the one which started it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AwaitingCallback {
+    request_token: Oauth1Token,
+    redirect_url: String,
+}
+
+impl AwaitingCallback {
+    pub fn is_for(&self, oauth_token: &str) -> bool {
+        self.request_token.oauth_token == oauth_token
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct Session {
+    // Set once the user has logged in.
+    pub context: Option<Context>,
+    // Set while a dance started by the session is waiting for its callback.
+    pub awaiting_callback: Option<AwaitingCallback>,
+}
+
 #[derive(Clone)]
 pub struct OauthHandler {
-    // token -> (secret, redirect_url)
-    // TODO: Expire these after some time
-    // TODO: Keep this in the session store
-    tokens_awaiting_callbacks: Arc<Mutex<HashMap<String, (String, url::Url)>>>,
-
     request_token_url: url::Url,
     authentication_url: url::Url,
     verify_credentials_url: url::Url,
 
     app_token: Oauth1Token,
+    http_client: reqwest::blocking::Client,
 }
 
 impl OauthHandler {
@@ -27,19 +45,25 @@ impl OauthHandler {
         verify_credentials_url: url::Url,
         app_token: Oauth1Token,
     ) -> OauthHandler {
-        let tokens_awaiting_callbacks = Arc::new(Mutex::new(HashMap::new()));
         OauthHandler {
-            tokens_awaiting_callbacks,
             request_token_url,
             authentication_url,
             verify_credentials_url,
             app_token,
+            http_client: HttpTimeouts::default_client(),
         }
     }
 
-    pub fn dance(&self, redirect_url: url::Url) -> Result<url::Url, String> {
-        let client = reqwest::blocking::Client::new();
-        let response = client
+    // Requests are made with the client, e.g. to go through a proxy.
+    pub fn with_http_client(mut self, http_client: reqwest::blocking::Client) -> OauthHandler {
+        self.http_client = http_client;
+        self
+    }
+
+    // The URL to send the user to, and what to keep in their session for the callback.
+    pub fn dance(&self, redirect_url: url::Url) -> Result<(url::Url, AwaitingCallback), String> {
+        let response = self
+            .http_client
             .get(self.request_token_url.as_str())
             .header(
                 reqwest::header::AUTHORIZATION,
@@ -70,33 +94,37 @@ impl OauthHandler {
         url.query_pairs_mut()
             .append_pair("oauth_token", &v.oauth_token);
 
-        {
-            let mut tokens_awaiting_callbacks = self.tokens_awaiting_callbacks.lock().unwrap();
-            tokens_awaiting_callbacks.insert(v.oauth_token, (v.oauth_token_secret, redirect_url));
-        }
-
-        Ok(url)
+        let awaiting_callback = AwaitingCallback {
+            request_token: v,
+            redirect_url: redirect_url.to_string(),
+        };
+        Ok((url, awaiting_callback))
     }
 
     pub fn exchange(
         &self,
+        awaiting_callback: &AwaitingCallback,
         oauth_token: String,
         oauth_verifier: String,
     ) -> Result<(url::Url, Context), String> {
-        let client = reqwest::blocking::Client::new();
+        if !awaiting_callback.is_for(&oauth_token) {
+            return Err(format!(
+                "Callback for token {} which this session isn't waiting for",
+                oauth_token
+            ));
+        }
+        let redirect_url = url::Url::parse(&awaiting_callback.redirect_url).map_err(|err| {
+            format!(
+                "Error parsing redirect_url {}: {}",
+                awaiting_callback.redirect_url, err
+            )
+        })?;
         let url =
             url::Url::parse("https://api.twitter.com/oauth/access_token").expect("Bad twitter URL");
         let params = vec![("oauth_verifier".to_owned(), oauth_verifier)];
-        let oauth_token_secret = {
-            let tokens_awaiting_callbacks = self.tokens_awaiting_callbacks.lock().unwrap();
-            tokens_awaiting_callbacks
-                .get(&oauth_token)
-                .expect("TODO")
-                .0
-                .clone()
-        };
+        let oauth_token_secret = awaiting_callback.request_token.oauth_token_secret.clone();
         // TODO: Avoid these clones, should just be references everywhere
-        let request = client.post(url.clone()).form(&params).header(
+        let request = self.http_client.post(url.clone()).form(&params).header(
             reqwest::header::AUTHORIZATION,
             oauth1_header(
                 "POST",
@@ -113,14 +141,6 @@ impl OauthHandler {
             .send()
             .map_err(|err| format!("Error making user timeline request to twitter: {:?}", err))?;
 
-        let redirect_url = {
-            let mut tokens_awaiting_callbacks = self.tokens_awaiting_callbacks.lock().unwrap();
-            tokens_awaiting_callbacks
-                .remove(&oauth_token)
-                .expect("TODO")
-                .1
-        };
-
         let response_text = response
             .text()
             .map_err(|err| format!("Error getting text from user timeline request {:?}", err))?;
@@ -131,10 +151,8 @@ impl OauthHandler {
                     response_text, err
                 )
             })?;
-        println!("DWH: Got dance response: {:?}", user_oauth_token);
         let user_screen_name = self.get_user(&user_oauth_token)?;
-
-        println!("DWH: User: {}", user_screen_name);
+        debug!("Logged in as {}", user_screen_name);
         let context = Context {
             user_oauth_token,
             user_screen_name,
@@ -145,9 +163,8 @@ impl OauthHandler {
 
     fn get_user(&self, user_token: &Oauth1Token) -> Result<String, String> {
         let url = &self.verify_credentials_url;
-        let client = reqwest::blocking::Client::new();
         // TODO: Avoid these clones, should just be references everywhere
-        let request = client.get(url.clone()).header(
+        let request = self.http_client.get(url.clone()).header(
             reqwest::header::AUTHORIZATION,
             oauth1_header("GET", &url, &self.app_token, Some(user_token), vec![]),
         );
@@ -165,7 +182,7 @@ impl OauthHandler {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Context {
     pub user_oauth_token: Oauth1Token,
     pub user_screen_name: String,
@@ -182,6 +199,39 @@ struct VerifyCredentialsResponse {
     pub screen_name: String,
 }
 
+#[derive(Deserialize)]
+struct BearerTokenResponse {
+    pub access_token: String,
+}
+
+// Gets a bearer token for making app-only authenticated requests, which aren't made on behalf of
+// any particular user.
+pub fn app_bearer_token(
+    client: &reqwest::blocking::Client,
+    app_token: &Oauth1Token,
+) -> Result<String, String> {
+    let response = client
+        .post("https://api.twitter.com/oauth2/token")
+        .basic_auth(&app_token.oauth_token, Some(&app_token.oauth_token_secret))
+        .form(&[("grant_type", "client_credentials")])
+        .send()
+        .map_err(|err| format!("Error requesting bearer token: {:?}", err))?;
+    let response_text = response
+        .text()
+        .map_err(|err| format!("Error getting text from bearer token request: {:?}", err))?;
+    parse_bearer_token_response(&response_text)
+}
+
+fn parse_bearer_token_response(response_text: &str) -> Result<String, String> {
+    let r: BearerTokenResponse = serde_json::from_str(response_text).map_err(|err| {
+        format!(
+            "Error deserializing bearer token response ({}): {:?}",
+            response_text, err
+        )
+    })?;
+    Ok(r.access_token)
+}
+
 pub fn oauth1_header(
     method: &str,
     url: &url::Url,
@@ -0,0 +1,36 @@
+use tweetstore::{Snowflake, TweetFromTwitter};
+use Interval;
+
+// An interval of a user's tweets which was fetched in full, and every tweet in it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PersistedInterval {
+    pub user: String,
+    pub interval: Interval<Snowflake>,
+    pub tweets: Vec<TweetFromTwitter>,
+}
+
+// Durable storage for the tweet cache, so that fetched tweets survive restarts. Like an
+// IntervalStore, an interval is either covered, with every tweet in it stored, or not at all.
+pub trait TweetPersistence: Send + Sync {
+    // Every covered interval to load at startup, with overlapping and touching intervals merged.
+    fn load(&self) -> Result<Vec<PersistedInterval>, String>;
+
+    // The covered intervals of the user's tweets which overlap interval, for picking up tweets
+    // saved by other servers sharing the storage. Storage used by a single server can rely on
+    // load having returned everything.
+    fn load_overlapping(
+        &self,
+        _user: &str,
+        _interval: &Interval<Snowflake>,
+    ) -> Result<Vec<PersistedInterval>, String> {
+        Ok(vec![])
+    }
+
+    // Marks the interval as covered, replacing whatever was stored within it with tweets.
+    fn save(
+        &self,
+        user: &str,
+        interval: &Interval<Snowflake>,
+        tweets: &[TweetFromTwitter],
+    ) -> Result<(), String>;
+}
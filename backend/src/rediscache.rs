@@ -0,0 +1,180 @@
+use persistence::{PersistedInterval, TweetPersistence};
+use redis::{self, Commands};
+use serde_json;
+use tweetstore::{Snowflake, TweetFromTwitter};
+use {Interval, IntervalSet};
+
+// Shares the tweet cache between servers through Redis. Each user has a sorted set of tweets,
+// whose members are the zero-padded id followed by the tweet's JSON, so that ranges of ids can be
+// selected exactly by lexicographic order, and a key holding their covered intervals as JSON.
+pub struct RedisPersistence {
+    client: redis::Client,
+}
+
+impl RedisPersistence {
+    pub fn open(url: &str) -> Result<RedisPersistence, String> {
+        let client =
+            redis::Client::open(url).map_err(|err| format!("Error parsing Redis URL: {}", err))?;
+        // Fail at startup rather than on the first fetch if Redis is unreachable.
+        client
+            .get_connection()
+            .map_err(|err| format!("Error connecting to Redis: {}", err))?;
+        Ok(RedisPersistence { client })
+    }
+
+    fn connection(&self) -> Result<redis::Connection, String> {
+        self.client
+            .get_connection()
+            .map_err(|err| format!("Error connecting to Redis: {}", err))
+    }
+}
+
+fn coverage_key(user: &str) -> String {
+    format!("twimetravel:coverage:{}", user)
+}
+
+fn tweets_key(user: &str) -> String {
+    format!("twimetravel:tweets:{}", user)
+}
+
+fn tweet_member(tweet: &TweetFromTwitter) -> Result<String, String> {
+    let json =
+        serde_json::to_string(tweet).map_err(|err| format!("Error serializing tweet: {}", err))?;
+    Ok(format!("{:020}:{}", tweet.id.0, json))
+}
+
+fn parse_tweet_member(member: &str) -> Result<TweetFromTwitter, String> {
+    let (_, json) = member
+        .split_once(':')
+        .ok_or_else(|| format!("Bad tweet in Redis: {}", member))?;
+    serde_json::from_str(json).map_err(|err| format!("Error parsing tweet from Redis: {}", err))
+}
+
+// ZRANGEBYLEX bounds selecting exactly the members for tweets in the interval.
+fn member_range(interval: &Interval<Snowflake>) -> (String, String) {
+    (
+        format!("[{:020}", (interval.0).0),
+        format!("({:020}", (interval.1).0.saturating_add(1)),
+    )
+}
+
+fn parse_coverage(json: Option<String>) -> Result<IntervalSet<Snowflake>, String> {
+    let pairs: Vec<(u64, u64)> = match json {
+        Some(json) => serde_json::from_str(&json)
+            .map_err(|err| format!("Error parsing coverage from Redis: {}", err))?,
+        None => vec![],
+    };
+    Ok(pairs
+        .into_iter()
+        .map(|(start, end)| Interval(Snowflake(start), Snowflake(end)))
+        .collect())
+}
+
+fn coverage_json(coverage: &IntervalSet<Snowflake>) -> Result<String, String> {
+    let pairs: Vec<(u64, u64)> = coverage
+        .iter()
+        .map(|interval| ((interval.0).0, (interval.1).0))
+        .collect();
+    serde_json::to_string(&pairs).map_err(|err| format!("Error serializing coverage: {}", err))
+}
+
+impl TweetPersistence for RedisPersistence {
+    // Nothing is loaded up front; tweets are read as they are requested, by load_overlapping.
+    fn load(&self) -> Result<Vec<PersistedInterval>, String> {
+        Ok(vec![])
+    }
+
+    fn load_overlapping(
+        &self,
+        user: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<PersistedInterval>, String> {
+        let mut connection = self.connection()?;
+        let coverage = parse_coverage(
+            connection
+                .get(coverage_key(user))
+                .map_err(|err| format!("Error reading coverage from Redis: {}", err))?,
+        )?;
+        let mut persisted = vec![];
+        for covered in coverage.intersecting(interval).iter() {
+            let (min, max) = member_range(covered);
+            let members: Vec<String> = connection
+                .zrangebylex(tweets_key(user), min, max)
+                .map_err(|err| format!("Error reading tweets from Redis: {}", err))?;
+            let tweets = members
+                .iter()
+                .map(|member| parse_tweet_member(member))
+                .collect::<Result<_, _>>()?;
+            persisted.push(PersistedInterval {
+                user: user.to_owned(),
+                interval: *covered,
+                tweets,
+            });
+        }
+        Ok(persisted)
+    }
+
+    // Atomic with respect to other servers saving the same user's tweets: the transaction is
+    // retried if the coverage changes between reading and rewriting it.
+    fn save(
+        &self,
+        user: &str,
+        interval: &Interval<Snowflake>,
+        tweets: &[TweetFromTwitter],
+    ) -> Result<(), String> {
+        let mut connection = self.connection()?;
+        let coverage_key = coverage_key(user);
+        let tweets_key = tweets_key(user);
+        let members = tweets
+            .iter()
+            .map(|tweet| tweet_member(tweet).map(|member| (0, member)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (min, max) = member_range(interval);
+        redis::transaction(&mut connection, &[&coverage_key], |connection, pipe| {
+            let mut coverage = parse_coverage(connection.get(&coverage_key)?)
+                .map_err(|err| redis::RedisError::from((redis::ErrorKind::TypeError, "", err)))?;
+            coverage.insert(interval);
+            let coverage = coverage_json(&coverage)
+                .map_err(|err| redis::RedisError::from((redis::ErrorKind::TypeError, "", err)))?;
+            pipe.set(&coverage_key, coverage)
+                .ignore()
+                .zrembylex(&tweets_key, &min, &max)
+                .ignore();
+            // ZADD requires at least one member.
+            if !members.is_empty() {
+                pipe.zadd_multiple(&tweets_key, &members).ignore();
+            }
+            pipe.query(connection)
+        })
+        .map_err(|err| format!("Error saving tweets to Redis: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{member_range, parse_tweet_member, tweet_member};
+    use tweetstore::{Snowflake, TweetFromTwitter};
+    use Interval;
+
+    #[test]
+    fn members_round_trip() {
+        let mut tweet = TweetFromTwitter::new(Snowflake(963143061558743040));
+        tweet.text = Some("With: colons".to_owned());
+        let member = tweet_member(&tweet).expect("Member");
+        assert!(member.starts_with("00963143061558743040:{"));
+        assert_eq!(parse_tweet_member(&member), Ok(tweet));
+    }
+
+    #[test]
+    fn member_range_selects_interval() {
+        let (min, max) = member_range(&Interval(Snowflake(10), Snowflake(20)));
+        let member = |id| tweet_member(&TweetFromTwitter::new(Snowflake(id))).expect("Member");
+        let in_range =
+            |member: &String| member.as_str() >= &min[1..] && member.as_str() < &max[1..];
+        assert!(!in_range(&member(9)));
+        assert!(in_range(&member(10)));
+        assert!(in_range(&member(20)));
+        assert!(!in_range(&member(21)));
+        assert!(!in_range(&member(200)));
+    }
+}
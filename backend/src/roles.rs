@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    // Requests made without a logged-in session.
+    Anonymous,
+    User,
+    // Users allowed to fall back to the (premium) search APIs.
+    SearchEnabled,
+    Admin,
+}
+
+// Maximum feed window length per role, in seconds. None means unlimited.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WindowLimits {
+    pub anonymous: Option<u64>,
+    pub user: Option<u64>,
+    pub search_enabled: Option<u64>,
+    pub admin: Option<u64>,
+}
+
+impl WindowLimits {
+    pub fn max_window(&self, role: Role) -> Option<Duration> {
+        let secs = match role {
+            Role::Anonymous => self.anonymous,
+            Role::User => self.user,
+            Role::SearchEnabled => self.search_enabled,
+            Role::Admin => self.admin,
+        };
+        secs.map(Duration::from_secs)
+    }
+}
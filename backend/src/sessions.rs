@@ -0,0 +1,143 @@
+#[cfg(feature = "redis")]
+use futures::future;
+use gotham::middleware::session::{
+    Backend, MemoryBackend, NewBackend, SessionError, SessionFuture, SessionIdentifier,
+};
+#[cfg(feature = "redis")]
+use redis::{self, Commands};
+use std::io;
+#[cfg(feature = "redis")]
+use std::time::Duration;
+
+// Where sessions, i.e. who is logged in and any OAuth dance in progress, are kept.
+#[derive(Clone)]
+pub enum SessionBackend {
+    // Sessions are only seen by the server which made them, so with several servers, an OAuth
+    // callback fails unless it reaches the server which started the dance.
+    Memory(MemoryBackend),
+    // Sessions are shared between all servers using the same Redis.
+    #[cfg(feature = "redis")]
+    Redis(RedisSessionBackend),
+}
+
+impl Default for SessionBackend {
+    fn default() -> SessionBackend {
+        SessionBackend::Memory(MemoryBackend::default())
+    }
+}
+
+impl NewBackend for SessionBackend {
+    type Instance = SessionBackend;
+
+    fn new_backend(&self) -> io::Result<SessionBackend> {
+        match *self {
+            SessionBackend::Memory(ref backend) => {
+                backend.new_backend().map(SessionBackend::Memory)
+            }
+            #[cfg(feature = "redis")]
+            SessionBackend::Redis(ref backend) => Ok(SessionBackend::Redis(backend.clone())),
+        }
+    }
+}
+
+impl Backend for SessionBackend {
+    fn persist_session(
+        &self,
+        identifier: SessionIdentifier,
+        content: &[u8],
+    ) -> Result<(), SessionError> {
+        match *self {
+            SessionBackend::Memory(ref backend) => backend.persist_session(identifier, content),
+            #[cfg(feature = "redis")]
+            SessionBackend::Redis(ref backend) => backend.persist_session(identifier, content),
+        }
+    }
+
+    fn read_session(&self, identifier: SessionIdentifier) -> Box<SessionFuture> {
+        match *self {
+            SessionBackend::Memory(ref backend) => backend.read_session(identifier),
+            #[cfg(feature = "redis")]
+            SessionBackend::Redis(ref backend) => {
+                Box::new(future::result(backend.read_session(&identifier)))
+            }
+        }
+    }
+
+    fn drop_session(&self, identifier: SessionIdentifier) -> Result<(), SessionError> {
+        match *self {
+            SessionBackend::Memory(ref backend) => backend.drop_session(identifier),
+            #[cfg(feature = "redis")]
+            SessionBackend::Redis(ref backend) => backend.drop_session(&identifier),
+        }
+    }
+}
+
+// Keeps sessions in Redis, each expiring once it's gone unused for the TTL, like MemoryBackend's.
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisSessionBackend {
+    client: redis::Client,
+    ttl: Duration,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionBackend {
+    pub fn open(url: &str, ttl: Duration) -> Result<RedisSessionBackend, String> {
+        let client =
+            redis::Client::open(url).map_err(|err| format!("Error parsing Redis URL: {}", err))?;
+        // Fail at startup rather than on the first request if Redis is unreachable.
+        client
+            .get_connection()
+            .map_err(|err| format!("Error connecting to Redis: {}", err))?;
+        Ok(RedisSessionBackend { client, ttl })
+    }
+
+    fn connection(&self) -> Result<redis::Connection, SessionError> {
+        self.client
+            .get_connection()
+            .map_err(|err| SessionError::Backend(format!("Error connecting to Redis: {}", err)))
+    }
+
+    fn ttl_secs(&self) -> usize {
+        self.ttl.as_secs() as usize
+    }
+
+    fn persist_session(
+        &self,
+        identifier: SessionIdentifier,
+        content: &[u8],
+    ) -> Result<(), SessionError> {
+        self.connection()?
+            .set_ex(session_key(&identifier), content, self.ttl_secs())
+            .map_err(|err| SessionError::Backend(format!("Error saving session: {}", err)))
+    }
+
+    // Reading a session counts as using it, so its TTL starts again.
+    fn read_session(
+        &self,
+        identifier: &SessionIdentifier,
+    ) -> Result<Option<Vec<u8>>, SessionError> {
+        let key = session_key(identifier);
+        let mut connection = self.connection()?;
+        let content: Option<Vec<u8>> = connection
+            .get(&key)
+            .map_err(|err| SessionError::Backend(format!("Error reading session: {}", err)))?;
+        if content.is_some() {
+            connection
+                .expire::<_, ()>(&key, self.ttl_secs())
+                .map_err(|err| SessionError::Backend(format!("Error renewing session: {}", err)))?;
+        }
+        Ok(content)
+    }
+
+    fn drop_session(&self, identifier: &SessionIdentifier) -> Result<(), SessionError> {
+        self.connection()?
+            .del(session_key(identifier))
+            .map_err(|err| SessionError::Backend(format!("Error dropping session: {}", err)))
+    }
+}
+
+#[cfg(feature = "redis")]
+fn session_key(identifier: &SessionIdentifier) -> String {
+    format!("twimetravel:session:{}", identifier.value)
+}
@@ -0,0 +1,113 @@
+use ring::{constant_time, hmac};
+use SecondsSinceUnixEpoch;
+
+// A replay window which can be viewed without logging in, until it expires.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShareLink {
+    pub who: String,
+    pub from: SecondsSinceUnixEpoch,
+    pub until: SecondsSinceUnixEpoch,
+    pub speed: f64,
+    pub expires: SecondsSinceUnixEpoch,
+}
+
+impl ShareLink {
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "{}/{}/{}/{}/{}",
+            self.who, self.from, self.until, self.speed, self.expires
+        )
+        .into_bytes()
+    }
+}
+
+pub struct ShareLinkSigner {
+    key: hmac::Key,
+}
+
+impl ShareLinkSigner {
+    pub fn new(secret: &[u8]) -> ShareLinkSigner {
+        ShareLinkSigner {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    // Hex-encoded signature of the link.
+    pub fn sign(&self, link: &ShareLink) -> String {
+        hmac::sign(&self.key, &link.signed_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn verify(
+        &self,
+        link: &ShareLink,
+        signature: &str,
+        now: SecondsSinceUnixEpoch,
+    ) -> Result<(), String> {
+        constant_time::verify_slices_are_equal(self.sign(link).as_bytes(), signature.as_bytes())
+            .map_err(|_| format!("Invalid signature"))?;
+        if link.expires < now {
+            return Err(format!("Link expired at {}", link.expires));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShareLink, ShareLinkSigner};
+    use SecondsSinceUnixEpoch;
+
+    #[test]
+    fn verify_signed() {
+        let signer = ShareLinkSigner::new(b"secret");
+        let link = link();
+        let signature = signer.sign(&link);
+        signer
+            .verify(&link, &signature, SecondsSinceUnixEpoch(150))
+            .expect("Verify");
+    }
+
+    #[test]
+    fn verify_expired() {
+        let signer = ShareLinkSigner::new(b"secret");
+        let link = link();
+        let signature = signer.sign(&link);
+        signer
+            .verify(&link, &signature, SecondsSinceUnixEpoch(201))
+            .expect_err("Verify");
+    }
+
+    #[test]
+    fn verify_tampered() {
+        let signer = ShareLinkSigner::new(b"secret");
+        let signature = signer.sign(&link());
+        let mut tampered = link();
+        tampered.until = SecondsSinceUnixEpoch(100000);
+        signer
+            .verify(&tampered, &signature, SecondsSinceUnixEpoch(150))
+            .expect_err("Verify");
+    }
+
+    #[test]
+    fn verify_other_secret() {
+        let link = link();
+        let signature = ShareLinkSigner::new(b"other").sign(&link);
+        ShareLinkSigner::new(b"secret")
+            .verify(&link, &signature, SecondsSinceUnixEpoch(150))
+            .expect_err("Verify");
+    }
+
+    fn link() -> ShareLink {
+        ShareLink {
+            who: "harrisimo".to_owned(),
+            from: SecondsSinceUnixEpoch(10),
+            until: SecondsSinceUnixEpoch(20),
+            speed: 1.5,
+            expires: SecondsSinceUnixEpoch(200),
+        }
+    }
+}
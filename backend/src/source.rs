@@ -0,0 +1,50 @@
+use cassette::ApiRequest;
+use tweetstore::{FetchError, Snowflake, TweetFromTwitter};
+use Interval;
+
+// What a source's posts can tell us, so that clients know which feed options make sense.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct SourceCapabilities {
+    // Whether shares of others' posts are included, as retweets.
+    pub reposts: bool,
+    // Whether replies say which post they're in reply to.
+    pub replies: bool,
+    // Whether hashtags and mentions are picked out of the text.
+    pub entities: bool,
+}
+
+// Makes requests on a source's behalf, so that they're recorded or replayed along with requests
+// to Twitter. Sources' APIs are public, so requests are made without credentials.
+pub trait SourceClient {
+    // The body of a successful response. Rate limits are reported against the service, e.g. an
+    // instance's domain.
+    fn get(&self, service: &str, request: &ApiRequest) -> Result<String, FetchError>;
+}
+
+// A service whose timelines can be replayed, e.g. Twitter itself or Mastodon. Other services'
+// posts are converted to tweets, with Snowflake ids which keep the time they were posted, so that
+// they can be cached and served exactly like tweets.
+pub trait SocialSource: Send + Sync {
+    // Used in feed URLs, e.g. "mastodon".
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> SourceCapabilities;
+
+    // The canonical form of an account, which its timeline is cached by, or why it isn't valid.
+    fn parse_key(&self, key: &str) -> Result<String, String>;
+
+    // The Snowflake a post with the source's own id is cached under, if its time can be told from
+    // its id.
+    fn snowflake(&self, post_id: &str) -> Option<Snowflake>;
+
+    // The source's own id for a post cached under the Snowflake.
+    fn post_id(&self, snowflake: Snowflake) -> String;
+
+    // Every post in the account's timeline in the interval, oldest first. The key has been parsed.
+    fn fetch_timeline(
+        &self,
+        client: &dyn SourceClient,
+        key: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError>;
+}
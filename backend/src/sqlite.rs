@@ -0,0 +1,229 @@
+use persistence::{PersistedInterval, TweetPersistence};
+use rusqlite::{params, Connection};
+use serde_json;
+use std::path::Path;
+use std::sync::Mutex;
+use tweetstore::{Snowflake, TweetFromTwitter};
+use {Interval, IntervalSet};
+
+// Persists the tweet cache to a SQLite database, with a table of tweets and a table of the
+// intervals they cover. Snowflakes are stored as INTEGERs, which are signed, but fit in 63 bits.
+pub struct SqlitePersistence {
+    connection: Mutex<Connection>,
+}
+
+impl SqlitePersistence {
+    // Creates the database and its tables if they don't already exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqlitePersistence, String> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Error opening SQLite database: {}", err))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS tweets (
+                    user TEXT NOT NULL,
+                    id INTEGER NOT NULL,
+                    tweet TEXT NOT NULL,
+                    PRIMARY KEY (user, id)
+                );
+                CREATE TABLE IF NOT EXISTS coverage (
+                    user TEXT NOT NULL,
+                    start_id INTEGER NOT NULL,
+                    end_id INTEGER NOT NULL,
+                    PRIMARY KEY (user, start_id)
+                );",
+            )
+            .map_err(|err| format!("Error creating SQLite tables: {}", err))?;
+        Ok(SqlitePersistence {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl TweetPersistence for SqlitePersistence {
+    fn load(&self) -> Result<Vec<PersistedInterval>, String> {
+        let connection = self.connection.lock().unwrap();
+        let coverage: Vec<(String, Interval<Snowflake>)> = {
+            let mut statement = connection
+                .prepare("SELECT user, start_id, end_id FROM coverage ORDER BY user, start_id")
+                .map_err(|err| format!("Error reading coverage from SQLite: {}", err))?;
+            let rows = statement
+                .query_map(params![], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        Interval(
+                            Snowflake(row.get::<_, i64>(1)? as u64),
+                            Snowflake(row.get::<_, i64>(2)? as u64),
+                        ),
+                    ))
+                })
+                .map_err(|err| format!("Error reading coverage from SQLite: {}", err))?;
+            rows.collect::<Result<_, _>>()
+                .map_err(|err| format!("Error reading coverage from SQLite: {}", err))?
+        };
+
+        let mut statement = connection
+            .prepare(
+                "SELECT tweet FROM tweets WHERE user = ?1 AND id BETWEEN ?2 AND ?3 ORDER BY id",
+            )
+            .map_err(|err| format!("Error reading tweets from SQLite: {}", err))?;
+        let mut persisted = Vec::with_capacity(coverage.len());
+        for (user, interval) in coverage {
+            let json: Vec<String> = statement
+                .query_map(
+                    params![user, (interval.0).0 as i64, (interval.1).0 as i64],
+                    |row| row.get(0),
+                )
+                .and_then(|rows| rows.collect())
+                .map_err(|err| format!("Error reading tweets from SQLite: {}", err))?;
+            let tweets = json
+                .iter()
+                .map(|json| serde_json::from_str(json))
+                .collect::<Result<_, _>>()
+                .map_err(|err| format!("Error parsing tweet from SQLite: {}", err))?;
+            persisted.push(PersistedInterval {
+                user,
+                interval,
+                tweets,
+            });
+        }
+        Ok(persisted)
+    }
+
+    fn save(
+        &self,
+        user: &str,
+        interval: &Interval<Snowflake>,
+        tweets: &[TweetFromTwitter],
+    ) -> Result<(), String> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .map_err(|err| format!("Error starting SQLite transaction: {}", err))?;
+        let (start, end) = ((interval.0).0 as i64, (interval.1).0 as i64);
+
+        transaction
+            .execute(
+                "DELETE FROM tweets WHERE user = ?1 AND id BETWEEN ?2 AND ?3",
+                params![user, start, end],
+            )
+            .map_err(|err| format!("Error deleting tweets from SQLite: {}", err))?;
+        for tweet in tweets {
+            let json = serde_json::to_string(tweet)
+                .map_err(|err| format!("Error serializing tweet: {}", err))?;
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO tweets (user, id, tweet) VALUES (?1, ?2, ?3)",
+                    params![user, tweet.id.0 as i64, json],
+                )
+                .map_err(|err| format!("Error inserting tweet into SQLite: {}", err))?;
+        }
+
+        // Merge the interval into the user's existing coverage, and rewrite it.
+        let mut coverage = IntervalSet::new();
+        coverage.insert(interval);
+        {
+            let mut statement = transaction
+                .prepare("SELECT start_id, end_id FROM coverage WHERE user = ?1")
+                .map_err(|err| format!("Error reading coverage from SQLite: {}", err))?;
+            let existing: Vec<(i64, i64)> = statement
+                .query_map(params![user], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(|rows| rows.collect())
+                .map_err(|err| format!("Error reading coverage from SQLite: {}", err))?;
+            for (start, end) in existing {
+                coverage.insert(&Interval(Snowflake(start as u64), Snowflake(end as u64)));
+            }
+        }
+        transaction
+            .execute("DELETE FROM coverage WHERE user = ?1", params![user])
+            .map_err(|err| format!("Error deleting coverage from SQLite: {}", err))?;
+        for covered in coverage.iter() {
+            transaction
+                .execute(
+                    "INSERT INTO coverage (user, start_id, end_id) VALUES (?1, ?2, ?3)",
+                    params![user, (covered.0).0 as i64, (covered.1).0 as i64],
+                )
+                .map_err(|err| format!("Error inserting coverage into SQLite: {}", err))?;
+        }
+
+        transaction
+            .commit()
+            .map_err(|err| format!("Error committing SQLite transaction: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqlitePersistence;
+    use persistence::{PersistedInterval, TweetPersistence};
+    use tweetstore::{Snowflake, TweetFromTwitter};
+    use Interval;
+
+    #[test]
+    fn save_then_load() {
+        let persistence = SqlitePersistence::open(":memory:").expect("Open");
+        assert_eq!(persistence.load(), Ok(vec![]));
+
+        persistence
+            .save("a", &interval(10, 20), &[tweet(12), tweet(15)])
+            .expect("Save");
+        persistence
+            .save("a", &interval(30, 40), &[tweet(35)])
+            .expect("Save");
+        persistence.save("b", &interval(10, 20), &[]).expect("Save");
+
+        assert_eq!(
+            persistence.load(),
+            Ok(vec![
+                persisted("a", interval(10, 20), vec![tweet(12), tweet(15)]),
+                persisted("a", interval(30, 40), vec![tweet(35)]),
+                persisted("b", interval(10, 20), vec![]),
+            ])
+        );
+    }
+
+    #[test]
+    fn save_merges_coverage_and_replaces_tweets() {
+        let persistence = SqlitePersistence::open(":memory:").expect("Open");
+        persistence
+            .save("a", &interval(10, 20), &[tweet(12), tweet(15)])
+            .expect("Save");
+        persistence
+            .save("a", &interval(30, 40), &[tweet(35)])
+            .expect("Save");
+        // 12 has since been deleted.
+        persistence
+            .save("a", &interval(11, 30), &[tweet(15), tweet(25)])
+            .expect("Save");
+
+        assert_eq!(
+            persistence.load(),
+            Ok(vec![persisted(
+                "a",
+                interval(10, 40),
+                vec![tweet(15), tweet(25), tweet(35)]
+            )])
+        );
+    }
+
+    fn interval(start: u64, end: u64) -> Interval<Snowflake> {
+        Interval(Snowflake(start), Snowflake(end))
+    }
+
+    fn tweet(id: u64) -> TweetFromTwitter {
+        let mut tweet = TweetFromTwitter::new(Snowflake(id));
+        tweet.text = Some(format!("Tweet {}", id));
+        tweet
+    }
+
+    fn persisted(
+        user: &str,
+        interval: Interval<Snowflake>,
+        tweets: Vec<TweetFromTwitter>,
+    ) -> PersistedInterval {
+        PersistedInterval {
+            user: user.to_owned(),
+            interval,
+            tweets,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+// proptest strategies for the interval types, so that code built on them can be property tested
+// without writing generators. The Arbitrary impls draw times from the whole range of the type;
+// the functions here take a strategy for times, e.g. 0u64..1000, to get more overlap.
+use proptest::arbitrary::{any, Arbitrary};
+use proptest::collection::vec;
+use proptest::strategy::{BoxedStrategy, Strategy};
+use std::cmp::{max, min};
+use std::fmt::Debug;
+use {Interval, IntervalSet, IntervalStore, UniquelyIdentifiedTimeValue};
+
+// The most intervals, or values, generated for one set or store.
+const MAX_LEN: usize = 16;
+
+pub fn interval<S>(times: S) -> impl Strategy<Value = Interval<S::Value>>
+where
+    S: Strategy,
+    S::Value: Ord + Copy,
+{
+    vec(times, 2).prop_map(|ends| Interval(min(ends[0], ends[1]), max(ends[0], ends[1])))
+}
+
+pub fn interval_set<S>(times: S) -> impl Strategy<Value = IntervalSet<S::Value>>
+where
+    S: Strategy,
+    S::Value: Ord + Copy,
+{
+    vec(interval(times), 0..MAX_LEN).prop_map(|intervals| intervals.into_iter().collect())
+}
+
+// Stores covering random intervals, plus the time of every generated value, with each value
+// inserted into the interval covering it.
+pub fn interval_store<S, V>(
+    times: S,
+    values: V,
+) -> impl Strategy<Value = IntervalStore<S::Value, V::Value>>
+where
+    S: Strategy,
+    S::Value: Ord + Copy + 'static,
+    V: Strategy,
+    V::Value: UniquelyIdentifiedTimeValue<S::Value> + Clone + 'static,
+{
+    (interval_set(times), vec(values, 0..MAX_LEN))
+        .prop_map(|(covered, values)| store_of(covered, values))
+}
+
+fn store_of<Time, Value>(
+    mut covered: IntervalSet<Time>,
+    values: Vec<Value>,
+) -> IntervalStore<Time, Value>
+where
+    Time: Ord + Copy + 'static,
+    Value: UniquelyIdentifiedTimeValue<Time> + Clone,
+{
+    for value in values.iter() {
+        covered.insert(&Interval(value.time(), value.time()));
+    }
+    let mut store = IntervalStore::new();
+    for interval in covered.iter() {
+        let within = values
+            .iter()
+            .filter(|value| interval.contains(&value.time()))
+            .cloned()
+            .collect();
+        store
+            .insert(interval, within)
+            .expect("Inserting into disjoint intervals");
+    }
+    store
+}
+
+impl<T: Arbitrary + Ord + Copy + 'static> Arbitrary for Interval<T> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Interval<T>>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        interval(any::<T>()).boxed()
+    }
+}
+
+impl<T: Arbitrary + Ord + Copy + 'static> Arbitrary for IntervalSet<T> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<IntervalSet<T>>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        interval_set(any::<T>()).boxed()
+    }
+}
+
+impl<Time, Value> Arbitrary for IntervalStore<Time, Value>
+where
+    Time: Arbitrary + Ord + Copy + 'static,
+    Value: Arbitrary + UniquelyIdentifiedTimeValue<Time> + Clone + Debug + 'static,
+{
+    type Parameters = ();
+    type Strategy = BoxedStrategy<IntervalStore<Time, Value>>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        interval_store(any::<Time>(), any::<Value>()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interval_set, interval_store};
+    use proptest::arbitrary::any;
+    use proptest::strategy::Strategy;
+    use proptest::test_runner::TestRunner;
+    use {Interval, IntervalSet, IntervalStore, UniquelyIdentifiedTimeValue};
+
+    #[derive(Clone, Debug)]
+    struct Value(u32);
+
+    impl UniquelyIdentifiedTimeValue<u32> for Value {
+        type Id = u32;
+
+        fn time(&self) -> u32 {
+            self.0
+        }
+
+        fn id(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn generated_sets_are_valid() {
+        let mut runner = TestRunner::default();
+        runner
+            .run(&interval_set(0u32..100), |set| {
+                assert_eq!(set.validate(), Ok(()));
+                Ok(())
+            })
+            .unwrap();
+        runner
+            .run(&any::<IntervalSet<u64>>(), |set| {
+                assert_eq!(set.validate(), Ok(()));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn generated_stores_are_valid_and_cover_their_values() {
+        let mut runner = TestRunner::default();
+        let values = (0u32..100).prop_map(Value);
+        runner
+            .run(
+                &interval_store(0u32..100, values),
+                |store: IntervalStore<u32, Value>| {
+                    assert_eq!(store.validate(), Ok(()));
+                    for value in store.get_available(&Interval(0, 100)) {
+                        assert!(store.has(&Interval(value.0, value.0)));
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+}
@@ -1,305 +1,4200 @@
+use bluesky::Bluesky;
+use cassette::{ApiRequest, ApiResponse, Cassette, CassetteMode};
+use export::{self, ExportFormat};
+use http::HttpTimeouts;
+use httpcache::HttpCache;
+use inflight::InFlightFetches;
+use lrucache::LruCache;
+use mastodon::Mastodon;
 use oauth;
+use persistence::TweetPersistence;
 use reqwest;
+use serde::de::DeserializeOwned;
 use serde_json;
+use source::{SocialSource, SourceCapabilities, SourceClient};
 use std;
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, RwLock};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use time;
+use twitter::{
+    self, Twitter, MAX_USER_TIMELINE_PAGES, USER_TIMELINE_ENDPOINT, USER_TIMELINE_LIMIT,
+    USER_TIMELINE_PAGE_SIZE,
+};
 use url;
-use {Context, Interval, IntervalSet, IntervalStore, UniquelyIdentifiedTimeValue};
+use {
+    ConflictPolicy, Context, Encodable, Entitlements, FetchMethod, FetchStrategy, Interval,
+    IntervalSet, IntervalStore, IntervalStoreMap, Step, StoreView, UniquelyIdentifiedTimeValue,
+};
 
 pub const TWEPOCH_MILLIS: u64 = 1288834974657;
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, Ord, PartialOrd, PartialEq)]
+const USERS_SHOW_URL: &str = "https://api.twitter.com/1.1/users/show.json";
+const LIST_STATUSES_URL: &str = "https://api.twitter.com/1.1/lists/statuses.json";
+// How far back lists/statuses reaches is undocumented, so give up after this many pages.
+const MAX_LIST_STATUSES_PAGES: usize = 50;
+const HOME_TIMELINE_URL: &str = "https://api.twitter.com/1.1/statuses/home_timeline.json";
+// home_timeline only reaches back 800 tweets.
+const MAX_HOME_TIMELINE_PAGES: usize = 800 / USER_TIMELINE_PAGE_SIZE + 1;
+const MENTIONS_TIMELINE_URL: &str = "https://api.twitter.com/1.1/statuses/mentions_timeline.json";
+// mentions_timeline only reaches back 800 tweets.
+const MAX_MENTIONS_TIMELINE_PAGES: usize = 800 / USER_TIMELINE_PAGE_SIZE + 1;
+const FAVORITES_LIST_URL: &str = "https://api.twitter.com/1.1/favorites/list.json";
+// How far back favorites/list reaches is undocumented, so give up after this many pages.
+const MAX_FAVORITES_LIST_PAGES: usize = 50;
+const STATUSES_LOOKUP_URL: &str = "https://api.twitter.com/1.1/statuses/lookup.json";
+// The most ids statuses/lookup accepts per request.
+const STATUSES_LOOKUP_BATCH_SIZE: usize = 100;
+const STATUSES_SHOW_URL: &str = "https://api.twitter.com/1.1/statuses/show.json";
+// The error statuses/show gives for a tweet which doesn't exist, as opposed to e.g. one whose
+// author is protected (179) or suspended (63).
+const NO_STATUS_FOUND_ERROR_CODE: u64 = 144;
+// Every search page is billed, so give up rather than paging indefinitely.
+const MAX_SEARCH_PAGES: usize = 50;
+// Each tweet in a thread or quote chain is looked up in turn, so long chains are cut short.
+const MAX_CHAIN_LENGTH: usize = 50;
+// At most this many tweets looked up for threads, and threads' conversations, are kept.
+const MAX_THREAD_TWEETS: usize = 10000;
+const MAX_CONVERSATIONS: usize = 10000;
+// How far back the 30day search product reaches.
+const THIRTY_DAY_SEARCH_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Ord, PartialOrd, PartialEq, Serialize)]
 pub struct SecondsSinceUnixEpoch(pub u64);
 
+impl SecondsSinceUnixEpoch {
+    pub fn now() -> SecondsSinceUnixEpoch {
+        SystemTime::now().into()
+    }
+
+    // Parses e.g. "2018-03-01T12:00:00Z" or "2018-03-01T13:00:00+01:00".
+    #[cfg(feature = "chrono")]
+    pub fn parse_rfc3339(s: &str) -> Result<SecondsSinceUnixEpoch, String> {
+        chrono::DateTime::parse_from_rfc3339(s)
+            .map(SecondsSinceUnixEpoch::from)
+            .map_err(|err| format!("Bad RFC3339 time {:?}: {}", s, err))
+    }
+
+    // Parses timestamps like "2018-11-01T00:00:00.000Z", as APIs other than Twitter's give them,
+    // ignoring fractions of a second. The time zone is assumed to be UTC.
+    pub fn parse_utc_timestamp(timestamp: &str) -> Option<SecondsSinceUnixEpoch> {
+        let tm = time::strptime(timestamp.get(..19)?, "%Y-%m-%dT%H:%M:%S").ok()?;
+        Some(SecondsSinceUnixEpoch(tm.to_timespec().sec as u64))
+    }
+
+    // Formatted as parse_utc_timestamp parses.
+    pub fn utc_timestamp(&self) -> String {
+        let tm = time::strptime(&format!("{}", self.0), "%s").expect("Parsing tm from seconds");
+        format!(
+            "{}",
+            tm.strftime("%Y-%m-%dT%H:%M:%S.000Z")
+                .expect("Formatting tm as timestamp")
+        )
+    }
+}
+
+// Times before the epoch are clamped to it.
+impl From<SystemTime> for SecondsSinceUnixEpoch {
+    fn from(time: SystemTime) -> SecondsSinceUnixEpoch {
+        SecondsSinceUnixEpoch(
+            time.duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0),
+        )
+    }
+}
+
+impl From<SecondsSinceUnixEpoch> for SystemTime {
+    fn from(seconds: SecondsSinceUnixEpoch) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds.0)
+    }
+}
+
+// Times before the epoch are clamped to it.
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for SecondsSinceUnixEpoch {
+    fn from(time: chrono::DateTime<Tz>) -> SecondsSinceUnixEpoch {
+        SecondsSinceUnixEpoch(std::cmp::max(time.timestamp(), 0) as u64)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<SecondsSinceUnixEpoch> for chrono::DateTime<chrono::Utc> {
+    fn from(seconds: SecondsSinceUnixEpoch) -> chrono::DateTime<chrono::Utc> {
+        let system_time: SystemTime = seconds.into();
+        system_time.into()
+    }
+}
+
+impl Interval<SecondsSinceUnixEpoch> {
+    // E.g. Interval::between(SystemTime::now() - day, SystemTime::now()).
+    pub fn between<T: Into<SecondsSinceUnixEpoch>>(
+        from: T,
+        until: T,
+    ) -> Interval<SecondsSinceUnixEpoch> {
+        Interval(from.into(), until.into())
+    }
+
+    #[cfg(feature = "chrono")]
+    pub fn parse_rfc3339(
+        from: &str,
+        until: &str,
+    ) -> Result<Interval<SecondsSinceUnixEpoch>, String> {
+        Ok(Interval(
+            SecondsSinceUnixEpoch::parse_rfc3339(from)?,
+            SecondsSinceUnixEpoch::parse_rfc3339(until)?,
+        ))
+    }
+}
+
 impl std::fmt::Display for SecondsSinceUnixEpoch {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Eq, Ord, PartialOrd, PartialEq)]
+// The precision of the time in a Snowflake.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Ord, PartialOrd, PartialEq, Serialize)]
+pub struct MillisSinceUnixEpoch(pub u64);
+
+// Times too far in the future are clamped.
+impl From<SecondsSinceUnixEpoch> for MillisSinceUnixEpoch {
+    fn from(seconds: SecondsSinceUnixEpoch) -> MillisSinceUnixEpoch {
+        MillisSinceUnixEpoch(seconds.0.saturating_mul(1000))
+    }
+}
+
+// Rounded down to the second.
+impl From<MillisSinceUnixEpoch> for SecondsSinceUnixEpoch {
+    fn from(millis: MillisSinceUnixEpoch) -> SecondsSinceUnixEpoch {
+        SecondsSinceUnixEpoch(millis.0 / 1000)
+    }
+}
+
+impl std::fmt::Display for MillisSinceUnixEpoch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}ms", self.0)
+    }
+}
+
+// How a service's time-based ids encode when they were made: as milliseconds since an epoch,
+// shifted left past a sequence which tells apart ids made in the same millisecond. Ids are
+// positive i64s, so the milliseconds have the bits left over above the sequence.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SnowflakeScheme {
+    pub epoch_millis: u64,
+    pub timestamp_shift: u32,
+}
+
+impl SnowflakeScheme {
+    pub const TWITTER: SnowflakeScheme = SnowflakeScheme {
+        epoch_millis: TWEPOCH_MILLIS,
+        timestamp_shift: 22,
+    };
+    pub const MASTODON: SnowflakeScheme = SnowflakeScheme {
+        epoch_millis: 0,
+        timestamp_shift: 16,
+    };
+    pub const DISCORD: SnowflakeScheme = SnowflakeScheme {
+        epoch_millis: 1420070400000,
+        timestamp_shift: 22,
+    };
+
+    pub fn millis(&self, id: u64) -> MillisSinceUnixEpoch {
+        MillisSinceUnixEpoch((id >> self.timestamp_shift) + self.epoch_millis)
+    }
+
+    pub fn sequence(&self, id: u64) -> u64 {
+        id & self.max_sequence()
+    }
+
+    // The id with the sequence made at the time, or why the time can't be represented.
+    pub fn id(&self, millis: MillisSinceUnixEpoch, sequence: u64) -> Result<u64, String> {
+        if millis.0 < self.epoch_millis {
+            return Err(format!("Time {} is before the ids' epoch", millis));
+        }
+        if millis.0 > self.max_millis() {
+            return Err(format!("Time {} is too late for an id", millis));
+        }
+        Ok(self.clamped_id(millis, sequence))
+    }
+
+    // Like id, but times and sequences out of range are clamped, so that the id is still a bound
+    // on the ids made at the time.
+    pub fn clamped_id(&self, millis: MillisSinceUnixEpoch, sequence: u64) -> u64 {
+        let millis = millis.0.max(self.epoch_millis).min(self.max_millis());
+        ((millis - self.epoch_millis) << self.timestamp_shift) | sequence.min(self.max_sequence())
+    }
+
+    fn max_millis(&self) -> u64 {
+        self.epoch_millis + ((1 << (63 - self.timestamp_shift)) - 1)
+    }
+
+    fn max_sequence(&self) -> u64 {
+        (1 << self.timestamp_shift) - 1
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, Ord, PartialOrd, PartialEq, Serialize)]
 pub struct Snowflake(pub u64);
 
+impl Snowflake {
+    // The first Snowflake of the millisecond, or why there isn't one.
+    pub fn from_millis(millis: MillisSinceUnixEpoch) -> Result<Snowflake, String> {
+        SnowflakeScheme::TWITTER.id(millis, 0).map(Snowflake)
+    }
+
+    // Like From<SecondsSinceUnixEpoch>, but rejecting times which Snowflakes can't represent,
+    // e.g. in feed parameters.
+    pub fn from_seconds(seconds: SecondsSinceUnixEpoch) -> Result<Snowflake, String> {
+        Snowflake::from_millis(seconds.into())
+    }
+
+    // When the tweet was posted, formatted as Twitter formats created_at, e.g. for statuses from
+    // elsewhere converted to tweets.
+    pub fn created_at(&self) -> String {
+        let seconds = SecondsSinceUnixEpoch::from(*self);
+        let tm = time::strptime(&format!("{}", seconds), "%s").expect("Parsing tm from snowflake");
+        format!(
+            "{}",
+            tm.strftime("%a %b %d %H:%M:%S +0000 %Y")
+                .expect("Formatting tm as created_at")
+        )
+    }
+}
+
+// Ids are whole numbers, so fetching [1, 5] and then [6, 10] has fetched everything in [1, 10].
+impl Step for Snowflake {
+    fn successor(&self) -> Option<Snowflake> {
+        self.0.checked_add(1).map(Snowflake)
+    }
+}
+
 impl std::fmt::Display for Snowflake {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+// The wall-clock time between two snowflakes, to the millisecond, or zero if other is later.
+impl std::ops::Sub for Snowflake {
+    type Output = Duration;
+
+    fn sub(self, other: Snowflake) -> Duration {
+        let millis = |snowflake: Snowflake| MillisSinceUnixEpoch::from(snowflake).0;
+        Duration::from_millis(millis(self).saturating_sub(millis(other)))
+    }
+}
+
+// Stores of fetched timelines, by whose timeline they are. Intervals fetched separately which
+// abut, e.g. [1, 5] and [6, 10], are merged, as nothing can be missing between them.
+fn timeline_stores() -> IntervalStoreMap<String, Snowflake, TweetFromTwitter> {
+    IntervalStoreMap::with_factory(|| IntervalStore::new().merging_adjacent())
+}
+
+impl Interval<Snowflake> {
+    // The wall-clock time the interval spans, to the second.
+    pub fn duration(&self) -> Duration {
+        let from: SecondsSinceUnixEpoch = self.0.into();
+        let until: SecondsSinceUnixEpoch = self.1.into();
+        Duration::from_secs(until.0.saturating_sub(from.0))
+    }
+
+    // From the start of the local date in the IANA time zone to the start of the next, e.g.
+    // Interval::local_day("2019-03-01", "Europe/London"). Local days needn't be 24 hours long,
+    // e.g. when the clocks change.
+    #[cfg(feature = "timezones")]
+    pub fn local_day(date: &str, time_zone: &str) -> Result<Interval<Snowflake>, String> {
+        Interval::local_days(date, date, time_zone)
+    }
+
+    // From the start of the local date from to the end of the local date until.
+    #[cfg(feature = "timezones")]
+    pub fn local_days(
+        from: &str,
+        until: &str,
+        time_zone: &str,
+    ) -> Result<Interval<Snowflake>, String> {
+        let time_zone: chrono_tz::Tz = time_zone
+            .parse()
+            .map_err(|err| format!("Bad time zone {:?}: {}", time_zone, err))?;
+        let from = parse_local_date(from)?;
+        let until = parse_local_date(until)?;
+        if until < from {
+            return Err(format!("{} is before {}", until, from));
+        }
+        let after = until
+            .succ_opt()
+            .ok_or_else(|| format!("No date after {}", until))?;
+        Ok(Interval(
+            start_of_local_day(time_zone, from)?.into(),
+            start_of_local_day(time_zone, after)?.into(),
+        ))
+    }
+}
+
+// Parses e.g. "2019-03-01".
+#[cfg(feature = "timezones")]
+fn parse_local_date(date: &str) -> Result<chrono::NaiveDate, String> {
+    chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|err| format!("Bad date {:?}: {}", date, err))
+}
+
+// Midnight, or if the clocks went forward over midnight, the first minute of the date which exists.
+#[cfg(feature = "timezones")]
+fn start_of_local_day(
+    time_zone: chrono_tz::Tz,
+    date: chrono::NaiveDate,
+) -> Result<SecondsSinceUnixEpoch, String> {
+    use chrono::TimeZone;
+    (0..24 * 60)
+        .filter_map(|minute| {
+            date.and_hms_opt(minute / 60, minute % 60, 0)
+                .and_then(|time| time_zone.from_local_datetime(&time).earliest())
+        })
+        .next()
+        .map(SecondsSinceUnixEpoch::from)
+        .ok_or_else(|| format!("{} never starts in {}", date, time_zone.name()))
+}
+
+// Times which Snowflakes can't represent are clamped to the earliest or latest Snowflake.
 impl From<SecondsSinceUnixEpoch> for Snowflake {
     fn from(epoch: SecondsSinceUnixEpoch) -> Snowflake {
-        Snowflake((epoch.0 * 1000 - TWEPOCH_MILLIS) << 22)
+        Snowflake(SnowflakeScheme::TWITTER.clamped_id(epoch.into(), 0))
+    }
+}
+
+impl From<Snowflake> for MillisSinceUnixEpoch {
+    fn from(snowflake: Snowflake) -> MillisSinceUnixEpoch {
+        SnowflakeScheme::TWITTER.millis(snowflake.0)
     }
 }
 
 impl From<Snowflake> for SecondsSinceUnixEpoch {
     fn from(epoch: Snowflake) -> SecondsSinceUnixEpoch {
-        SecondsSinceUnixEpoch(((epoch.0 >> 22) + TWEPOCH_MILLIS) / 1000)
+        MillisSinceUnixEpoch::from(epoch).into()
     }
 }
 
-#[derive(Clone, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+// Everything but the id is missing for tweets which were cached before it was captured.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct TweetFromTwitter {
     pub id: Snowflake,
+    pub text: Option<String>,
+    #[serde(rename = "user")]
+    pub author: Option<TweetAuthor>,
+    // As Twitter formats it, e.g. "Wed Oct 10 20:19:24 +0000 2018".
+    pub created_at: Option<String>,
+    pub entities: Option<TweetEntities>,
+    pub retweeted_status: Option<RetweetedStatus>,
+    pub in_reply_to_status_id: Option<Snowflake>,
+    pub quoted_status_id: Option<Snowflake>,
+    // As of when the tweet was fetched, or last refreshed.
+    pub retweet_count: Option<u64>,
+    pub favorite_count: Option<u64>,
 }
 
-impl UniquelyIdentifiedTimeValue<Snowflake> for TweetFromTwitter {
-    fn time(&self) -> Snowflake {
-        self.id
+impl TweetFromTwitter {
+    pub fn new(id: Snowflake) -> TweetFromTwitter {
+        TweetFromTwitter {
+            id,
+            text: None,
+            author: None,
+            created_at: None,
+            entities: None,
+            retweeted_status: None,
+            in_reply_to_status_id: None,
+            quoted_status_id: None,
+            retweet_count: None,
+            favorite_count: None,
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct TweetStore {
-    app_token: oauth::Oauth1Token,
-    search_enabled_display_names: HashSet<String>,
-    tweets: Arc<RwLock<HashMap<String, Arc<RwLock<IntervalStore<Snowflake, TweetFromTwitter>>>>>>,
+// The tweet a retweet is of; only its id is kept.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct RetweetedStatus {
+    pub id: Snowflake,
 }
 
-impl TweetStore {
-    pub fn new(
-        app_oauth_token: oauth::Oauth1Token,
-        search_enabled_display_names: HashSet<String>,
-    ) -> TweetStore {
-        TweetStore {
-            app_token: app_oauth_token,
-            search_enabled_display_names: search_enabled_display_names,
-            tweets: Arc::new(RwLock::new(HashMap::new())),
-        }
-    }
+// Which of a user's tweets to return. Every tweet is fetched and cached regardless, so that
+// requests with different options can share the cache.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimelineOptions {
+    pub include_rts: bool,
+    pub exclude_replies: bool,
+}
 
-    // TODO: Accept a list of users
-    pub fn tweets(
-        &self,
-        context: &Context,
-        user: &String,
-        interval: &Interval<Snowflake>,
-    ) -> Vec<TweetFromTwitter> {
-        match self.get_known_tweets(user, interval) {
-            Ok(tweets) => tweets,
-            Err(missing_intervals) => {
-                self.fetch_all_tweets(context, user, &missing_intervals)
-                    .expect("Fetching tweets");
-                self.tweets(context, user, interval)
-            }
-        }
+impl TimelineOptions {
+    pub fn includes(&self, tweet: &TweetFromTwitter) -> bool {
+        (self.include_rts || tweet.retweeted_status.is_none())
+            && !(self.exclude_replies && tweet.in_reply_to_status_id.is_some())
     }
+}
 
-    fn fetch_all_tweets(
-        &self,
-        context: &Context,
-        user: &String,
-        intervals: &IntervalSet<Snowflake>,
-    ) -> Result<(), String> {
-        for interval in intervals.iter() {
-            self.fetch_tweets(context, user, interval)?;
+impl Default for TimelineOptions {
+    // Matches user_timeline's defaults.
+    fn default() -> TimelineOptions {
+        TimelineOptions {
+            include_rts: true,
+            exclude_replies: false,
         }
-        Ok(())
     }
+}
 
-    fn fetch_tweets(
-        &self,
-        context: &Context,
-        user: &String,
-        interval: &Interval<Snowflake>,
-    ) -> Result<(), String> {
-        let tweets = match self.fetch_usertimeline(context, user, interval)? {
-            Some(tweets) => tweets,
-            None => {
-                if self
-                    .search_enabled_display_names
-                    .contains(&context.user_screen_name)
-                {
-                    self.fetch_user_tweets_from_search(context, user, interval)?
-                } else {
-                    return Err(format!(
-                        "No tweets found, but can't guarantee no tweets should have been found"
-                    ));
-                }
-            }
-        };
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct TweetAuthor {
+    pub screen_name: String,
+    pub name: String,
+}
 
-        let interval_store_lock = self.interval_store(user);
-        let mut interval_store = interval_store_lock.write().unwrap();
-        interval_store.insert(interval, tweets)
-    }
+#[derive(Clone, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct TweetEntities {
+    #[serde(default)]
+    pub hashtags: Vec<Hashtag>,
+    #[serde(default)]
+    pub user_mentions: Vec<UserMention>,
+    #[serde(default)]
+    pub urls: Vec<TweetUrl>,
+    // Only the first photo of tweets with several.
+    #[serde(default)]
+    pub media: Vec<TweetMedia>,
+}
 
-    fn fetch_usertimeline(
-        &self,
-        context: &Context,
-        user: &String,
-        interval: &Interval<Snowflake>,
-    ) -> Result<Option<Vec<TweetFromTwitter>>, String> {
-        println!("Fetching from user timeline"); // TODO: Binary log requests and responses.
-
-        let json_string = {
-            let client = reqwest::blocking::Client::new();
-            let url = "https://api.twitter.com/1.1/statuses/user_timeline.json";
-            let params = vec![
-                ("screen_name".to_owned(), user.to_owned()),
-                ("since_id".to_owned(), format!("{}", interval.0)),
-                ("max_id".to_owned(), format!("{}", &interval.1)),
-            ];
-            let request = client.get(url).query(&params).header(
-                reqwest::header::AUTHORIZATION,
-                oauth::oauth1_header(
-                    "GET",
-                    &url::Url::parse(url).expect("Bad twitter URL"),
-                    &self.app_token,
-                    Some(&context.user_oauth_token),
-                    params,
-                ),
-            );
-            let response = request.send().map_err(|err| {
-                format!("Error making user timeline request to twitter: {:?}", err)
-            })?;
-            response
-                .text()
-                .map_err(|err| format!("Error getting text from user timeline request {:?}", err))?
-        };
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Hashtag {
+    pub text: String,
+}
 
-        println!("DWH: Response: {}", json_string);
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct UserMention {
+    pub screen_name: String,
+}
 
-        let mut tweets: Vec<TweetFromTwitter> = serde_json::from_str(&json_string)
-            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err))?;
-        tweets.sort();
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct TweetUrl {
+    pub url: String,
+    pub expanded_url: Option<String>,
+}
 
-        if tweets.len() == 0 {
-            // It would be great if we had a better heuristic than
-            // "no tweets means we hit the 3200 tweet limit".
-            return Ok(None);
-        }
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct TweetMedia {
+    pub id: Snowflake,
+    pub media_url_https: String,
+    // e.g. "photo".
+    #[serde(rename = "type")]
+    pub media_type: String,
+}
+
+impl UniquelyIdentifiedTimeValue<Snowflake> for TweetFromTwitter {
+    type Id = Snowflake;
 
-        Ok(Some(tweets))
+    fn time(&self) -> Snowflake {
+        self.id
     }
 
-    fn fetch_user_tweets_from_search(
-        &self,
-        context: &Context,
-        user: &String,
-        interval: &Interval<Snowflake>,
-    ) -> Result<Vec<TweetFromTwitter>, String> {
-        println!("Fetching from search API"); // TODO: Binary log requests and responses.
-        let json_string = {
-            let client = reqwest::blocking::Client::new();
-            // TODO: Choose which API to use based on interval
-            let url = format!(
-                "https://api.twitter.com/1.1/tweets/search/{}",
-                "30day/dev.json"
-            );
-            let params: HashMap<&str, String> = vec![
-                ("query", format!("from:{}", user)),
-                ("fromDate", TweetStore::as_twitter_time(&interval.0.into())),
-                ("toDate", TweetStore::as_twitter_time(&interval.1.into())),
-            ]
-            .into_iter()
-            .collect();
-            let response = client
-                .post(url.as_str())
-                .json(&params)
-                .header(
-                    reqwest::header::AUTHORIZATION,
-                    oauth::oauth1_header(
-                        "POST",
-                        &url::Url::parse(&url).expect("Bad twitter URL"),
-                        &self.app_token,
-                        Some(&context.user_oauth_token),
-                        vec![],
-                    ),
-                )
-                .send()
-                .map_err(|err| format!("Error making search request to twitter: {:?}", err))?;
-            response
-                .text()
-                .map_err(|err| format!("Error getting text from search request {:?}", err))?
-        };
+    fn id(&self) -> Snowflake {
+        self.id
+    }
 
-        let mut tweets: Vec<_> = {
-            let response: ResponseFromTwitter = serde_json::from_str(&json_string)
-                .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err))?;
-            response.results
-        };
-        tweets.sort();
-        Ok(tweets)
+    fn approximate_size(&self) -> usize {
+        let strings = self.text.iter().chain(self.created_at.iter()).chain(
+            self.author
+                .iter()
+                .flat_map(|author| vec![&author.screen_name, &author.name]),
+        );
+        std::mem::size_of_val(self) + strings.map(|string| string.len()).sum::<usize>()
     }
+}
 
-    fn as_twitter_time(s: &SecondsSinceUnixEpoch) -> String {
-        let tm = time::strptime(&format!("{}", s), "%s").expect("Parsing tm from snowflake");
-        format!(
-            "{}",
-            tm.strftime("%Y%m%d%H%M")
-                .expect("Formatting tm as twitter time")
-        )
+impl Encodable for Snowflake {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.encode(writer)
     }
 
-    fn get_known_tweets(
-        &self,
-        user: &String,
-        interval: &Interval<Snowflake>,
-    ) -> Result<Vec<TweetFromTwitter>, IntervalSet<Snowflake>> {
-        let interval_store_lock = self.interval_store(user);
-        let interval_store = interval_store_lock.read().unwrap();
-        match interval_store.get(interval) {
-            Some(tweets) => Ok(tweets),
-            None => Err(interval_store.missing(interval)),
-        }
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Snowflake> {
+        u64::decode(reader).map(Snowflake)
     }
+}
 
-    fn interval_store(
-        &self,
-        user: &String,
-    ) -> Arc<RwLock<IntervalStore<Snowflake, TweetFromTwitter>>> {
-        {
-            let user_map = self.tweets.read().unwrap();
-            match user_map.get(user) {
-                Some(user_bucket) => return user_bucket.clone(),
-                None => {}
-            }
+// Encoded as JSON, so that fields can be added without breaking existing snapshots.
+impl Encodable for TweetFromTwitter {
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        serde_json::to_string(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            .encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<TweetFromTwitter> {
+        serde_json::from_str(&String::decode(reader)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+pub struct FeedTweets {
+    pub tweets: Vec<TweetFromTwitter>,
+    // Set when fetching from Twitter failed, and only the already-cached tweets are included.
+    pub partial: bool,
+}
+
+// A tweet in a feed merged from several users' timelines, and whose timeline it's from.
+pub struct UserTweet {
+    pub user: String,
+    pub tweet: TweetFromTwitter,
+}
+
+// A tweet along with what it replies to and quotes, so that it can be read in context.
+#[derive(Serialize)]
+pub struct TweetThread {
+    // The tweets it replies to, oldest first. If one couldn't be looked up, e.g. because it was
+    // deleted or is protected, the thread starts after it.
+    pub ancestors: Vec<TweetFromTwitter>,
+    pub tweet: TweetFromTwitter,
+    // The tweet it quotes, then the tweet that quotes, and so on.
+    pub quoted: Vec<TweetFromTwitter>,
+    // Every other tweet known to be in the same conversation, i.e. thread from the same first
+    // tweet, oldest first.
+    pub conversation: Vec<TweetFromTwitter>,
+}
+
+pub struct MultiFeedTweets {
+    pub tweets: Vec<UserTweet>,
+    pub partial: bool,
+}
+
+// Limits on serving partial cached tweets when fetching from Twitter fails.
+// None means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StaleIfError {
+    // How long ago the user's tweets must last have been successfully fetched.
+    pub max_staleness: Option<Duration>,
+    // How long a run of failed fetches for a user may be masked before errors are returned.
+    pub max_error_duration: Option<Duration>,
+}
+
+// Limits on how many tweets are cached in memory, so that a few prolific users can't use it all.
+// Evicted tweets are fetched again, or reloaded from persistence, if they're needed again.
+// None means no limit.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct CacheLimits {
+    // Beyond this, the user's least recently used intervals are evicted.
+    pub max_tweets_per_user: Option<usize>,
+    // Beyond this, the least recently used users' tweets are all evicted.
+    pub max_tweets: Option<usize>,
+}
+
+// Twitter has told us not to make any more requests to an endpoint until reset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateLimited {
+    pub endpoint: String,
+    pub reset: SecondsSinceUnixEpoch,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Rate limited on {} until {}", self.endpoint, self.reset)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FetchError {
+    RateLimited(RateLimited),
+    // Twitter, or another source, couldn't be reached or reported a server error, so the same
+    // fetch may well succeed later.
+    Unavailable(String),
+    // Twitter refused to show the timeline with the credentials used, e.g. because the account is
+    // protected and the viewer doesn't follow it.
+    NotAuthorized(String),
+    Other(String),
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            FetchError::RateLimited(ref rate_limited) => write!(f, "{}", rate_limited),
+            FetchError::Unavailable(ref err) => write!(f, "{}", err),
+            FetchError::NotAuthorized(ref err) => write!(f, "{}", err),
+            FetchError::Other(ref err) => write!(f, "{}", err),
         }
-        {
-            let mut user_map = self.tweets.write().unwrap();
-            if !user_map.contains_key(user) {
-                user_map.insert(user.clone(), Arc::new(RwLock::new(IntervalStore::new())));
-            }
-            user_map.get(user).unwrap().clone()
+    }
+}
+
+impl FetchError {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            FetchError::RateLimited(_) => "rate_limited",
+            FetchError::Unavailable(_) => "unavailable",
+            FetchError::NotAuthorized(_) => "not_authorized",
+            FetchError::Other(_) => "other",
         }
     }
+}
 
-    pub fn preload(&self) {
-        let mut interval_store = IntervalStore::new();
-        interval_store
-            .insert(
-                &Interval(Snowflake(963140650398646272), Snowflake(963155749893046272)),
-                vec![
-                    TweetFromTwitter {
-                        id: Snowflake(963143061558743040),
-                    },
-                    TweetFromTwitter {
-                        id: Snowflake(963143736631869440),
-                    },
-                    TweetFromTwitter {
-                        id: Snowflake(963144473604534272),
-                    },
-                    TweetFromTwitter {
-                        id: Snowflake(963146750457499648),
-                    },
-                    TweetFromTwitter {
-                        id: Snowflake(963152907255377921),
-                    },
-                ],
-            )
-            .expect("Inserting tweets");
-        let mut user_map = self.tweets.write().unwrap();
-        user_map.insert(
-            "harrisimo".to_owned(),
-            Arc::new(RwLock::new(interval_store)),
-        );
+impl From<String> for FetchError {
+    fn from(err: String) -> FetchError {
+        FetchError::Other(err)
     }
 }
 
-#[derive(Deserialize)]
-struct ResponseFromTwitter {
-    pub results: Vec<TweetFromTwitter>,
+// The last rate limit status Twitter reported for an endpoint.
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    remaining: u64,
+    reset: SecondsSinceUnixEpoch,
+}
+
+// Rate limits apply per endpoint, and per user for user auth or to the whole app for app-only auth.
+type RateLimitKey = (String, Option<String>);
+
+// A kind of Twitter API call, whose usage is metered separately.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiCategory {
+    UserTimeline,
+    UsersShow,
+    StatusesLookup,
+    StatusesShow,
+    ListStatuses,
+    HomeTimeline,
+    MentionsTimeline,
+    FavoritesList,
+    Search30Day,
+    SearchFullArchive,
+}
+
+// At most max_requests calls in each window, e.g. a premium search environment's monthly cap.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub struct Quota {
+    pub category: ApiCategory,
+    pub max_requests: u64,
+    pub window_secs: u64,
+}
+
+// Tweets to seed the cache with at startup, e.g. for demos. The interval is treated as fully
+// known, so only the given tweets are served for it. Tweets may be given as just ids, or in full.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct PreloadedInterval {
+    pub user: String,
+    pub from: Snowflake,
+    pub until: Snowflake,
+    #[serde(default)]
+    pub tweet_ids: Vec<Snowflake>,
+    #[serde(default)]
+    pub tweets: Vec<TweetFromTwitter>,
+}
+
+// Calls made in a category's current window. Categories without a quota have a single window,
+// starting from their first call.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct ApiUsage {
+    pub category: ApiCategory,
+    pub requests: u64,
+    pub window_start: SecondsSinceUnixEpoch,
+    pub quota: Option<u64>,
+}
+
+// Counters since the store was created, and how many tweets are cached now.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Metrics {
+    // Lookups of a timeline which were served entirely from the cache.
+    pub cache_hits: u64,
+    // Lookups of a timeline which needed some of it to be fetched.
+    pub cache_misses: u64,
+    // By the screen name of whose tweets they are.
+    pub tweets_cached: BTreeMap<String, usize>,
+    // Requests made to each Twitter endpoint (e.g. "statuses/user_timeline"), or to each other
+    // service, including those replayed from a cassette.
+    pub api_calls: BTreeMap<String, u64>,
+    // Failed fetches, by FetchError::kind.
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+// A timeline other than a user's own tweets, which is cached separately from them.
+#[derive(Clone, Copy, Debug)]
+enum Timeline<'a> {
+    // By list id.
+    List(&'a String),
+    // The logged in user's.
+    Home,
+    // Tweets mentioning the logged in user.
+    Mentions,
+    // By the screen name of who liked them.
+    Likes(&'a String),
+}
+
+impl<'a> Timeline<'a> {
+    fn url(&self) -> &'static str {
+        match *self {
+            Timeline::List(_) => LIST_STATUSES_URL,
+            Timeline::Home => HOME_TIMELINE_URL,
+            Timeline::Mentions => MENTIONS_TIMELINE_URL,
+            Timeline::Likes(_) => FAVORITES_LIST_URL,
+        }
+    }
+
+    fn endpoint(&self) -> &'static str {
+        match *self {
+            Timeline::List(_) => "lists/statuses",
+            Timeline::Home => "statuses/home_timeline",
+            Timeline::Mentions => "statuses/mentions_timeline",
+            Timeline::Likes(_) => "favorites/list",
+        }
+    }
+
+    fn category(&self) -> ApiCategory {
+        match *self {
+            Timeline::List(_) => ApiCategory::ListStatuses,
+            Timeline::Home => ApiCategory::HomeTimeline,
+            Timeline::Mentions => ApiCategory::MentionsTimeline,
+            Timeline::Likes(_) => ApiCategory::FavoritesList,
+        }
+    }
+
+    fn max_pages(&self) -> usize {
+        match *self {
+            Timeline::List(_) => MAX_LIST_STATUSES_PAGES,
+            Timeline::Home => MAX_HOME_TIMELINE_PAGES,
+            Timeline::Mentions => MAX_MENTIONS_TIMELINE_PAGES,
+            Timeline::Likes(_) => MAX_FAVORITES_LIST_PAGES,
+        }
+    }
+
+    fn params(&self, since_id: Snowflake, max_id: Snowflake) -> Vec<(String, String)> {
+        let mut params = vec![
+            ("since_id".to_owned(), format!("{}", since_id)),
+            ("max_id".to_owned(), format!("{}", max_id)),
+            ("count".to_owned(), format!("{}", USER_TIMELINE_PAGE_SIZE)),
+        ];
+        match *self {
+            Timeline::List(list_id) => {
+                params.push(("list_id".to_owned(), list_id.to_owned()));
+                params.push(("include_rts".to_owned(), "true".to_owned()));
+            }
+            Timeline::Home => params.push(("exclude_replies".to_owned(), "false".to_owned())),
+            Timeline::Mentions => {}
+            Timeline::Likes(user) => params.push(("screen_name".to_owned(), user.to_owned())),
+        }
+        params
+    }
+}
+
+// Authenticates the Twitter source's user_timeline requests. They're made with app-only auth,
+// unless the account is protected, in which case the viewer's token is used.
+struct UserTimelineClient<'a> {
+    store: &'a TweetStore,
+    context: Option<&'a Context>,
+    user: &'a String,
+}
+
+impl<'a> SourceClient for UserTimelineClient<'a> {
+    fn get(&self, _endpoint: &str, request: &ApiRequest) -> Result<String, FetchError> {
+        let store = self.store;
+        let auth = store.usertimeline_auth(self.context, self.user)?;
+        let result = store.send_usertimeline_request_as(auth, request);
+        match store.record_usertimeline_result(auth, self.context, self.user, &result) {
+            Some(viewer) => {
+                let result = store.send_usertimeline_request_as(Some(viewer), request);
+                store.record_usertimeline_result(Some(viewer), self.context, self.user, &result);
+                result
+            }
+            None => result,
+        }
+    }
+}
+
+// A source other than Twitter, with its accounts' timelines by key.
+#[derive(Clone)]
+struct RegisteredSource {
+    source: Arc<dyn SocialSource>,
+    timelines: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+}
+
+// Which cached tweets to re-check against Twitter, to drop ones deleted since they were fetched.
+#[derive(Clone, Copy, Debug)]
+pub struct RevalidationPolicy {
+    // Only tweets younger than this are re-checked; old tweets are rarely deleted, and numerous.
+    pub max_age: Option<Duration>,
+    // Whether to also drop tweets which have been withheld in some country, or for copyright.
+    pub drop_withheld: bool,
+}
+
+// Which cached tweets to look up again, to keep what Twitter says about them, e.g. their
+// engagement counts, up to date.
+#[derive(Clone, Copy, Debug)]
+pub struct TweetRefreshPolicy {
+    // Tweets fetched or last refreshed longer ago than this are refreshed.
+    pub max_staleness: Duration,
+    // At most this many tweets are refreshed at a time. None means no limit.
+    pub max_tweets: Option<usize>,
+}
+
+// How far back user_timeline reaches for a user.
+#[derive(Clone, Copy, Debug)]
+enum TimelineHorizon {
+    // The user hasn't posted more tweets than user_timeline returns, so all of them are reachable.
+    Everything,
+    // Tweets before the oldest one user_timeline returns can only be found by searching.
+    OldestReachable(Snowflake),
+}
+
+impl TimelineHorizon {
+    fn reaches(&self, interval: &Interval<Snowflake>) -> bool {
+        match *self {
+            TimelineHorizon::Everything => true,
+            TimelineHorizon::OldestReachable(oldest) => interval.0 >= oldest,
+        }
+    }
+}
+
+// The viewer, if any, and whose tweets are being fetched in which interval.
+type InFlightKey = (Option<String>, String, Interval<Snowflake>);
+
+// Whether a fetch has a client waiting on it, or is background work which can wait its turn.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FetchPriority {
+    Interactive,
+    // e.g. revalidation, refreshing stale tweets and polling the live edge.
+    Background,
+}
+
+// How fetches of each priority share requests to Twitter. None means no limit.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct FetchSchedulingPolicy {
+    // Beyond this many fetches waiting to make a request, further interactive fetches are refused.
+    pub max_interactive_queued: Option<usize>,
+    // Beyond this many fetches waiting to make a request, further background fetches are refused.
+    pub max_background_queued: Option<usize>,
+    // Background fetches aren't made while an endpoint has this few requests left in Twitter's
+    // rate limit window, so that they're left for interactive fetches.
+    #[serde(default)]
+    pub background_rate_limit_reserve: u64,
+}
+
+#[derive(Default)]
+struct FetchQueues {
+    in_progress: usize,
+    interactive_waiting: usize,
+    background_waiting: usize,
+}
+
+// Decides when requests to Twitter are made: at most max_requests at once, if set, with
+// background fetches only starting while no interactive fetch is waiting for its turn.
+struct FetchScheduler {
+    max_requests: Option<usize>,
+    policy: FetchSchedulingPolicy,
+    queues: Mutex<FetchQueues>,
+    finished: Condvar,
+}
+
+impl FetchScheduler {
+    fn new(max_requests: Option<usize>, policy: FetchSchedulingPolicy) -> FetchScheduler {
+        FetchScheduler {
+            max_requests,
+            policy,
+            queues: Mutex::new(FetchQueues::default()),
+            finished: Condvar::new(),
+        }
+    }
+
+    fn start(&self, priority: FetchPriority) -> Result<RequestPermit<'_>, FetchError> {
+        let mut queues = self.queues.lock().unwrap();
+        let (waiting, max_queued) = match priority {
+            FetchPriority::Interactive => (
+                queues.interactive_waiting,
+                self.policy.max_interactive_queued,
+            ),
+            FetchPriority::Background => {
+                (queues.background_waiting, self.policy.max_background_queued)
+            }
+        };
+        if max_queued.map_or(false, |max_queued| waiting >= max_queued) {
+            return Err(FetchError::Unavailable(format!(
+                "Too many {:?} fetches waiting to make requests to Twitter",
+                priority
+            )));
+        }
+        FetchScheduler::waiting(&mut queues, priority, 1);
+        while !self.may_start(&queues, priority) {
+            queues = self.finished.wait(queues).unwrap();
+        }
+        FetchScheduler::waiting(&mut queues, priority, -1);
+        queues.in_progress += 1;
+        Ok(RequestPermit(self))
+    }
+
+    fn may_start(&self, queues: &FetchQueues, priority: FetchPriority) -> bool {
+        let below_max = self
+            .max_requests
+            .map_or(true, |max_requests| queues.in_progress < max_requests);
+        below_max && (priority == FetchPriority::Interactive || queues.interactive_waiting == 0)
+    }
+
+    fn waiting(queues: &mut FetchQueues, priority: FetchPriority, change: isize) {
+        let waiting = match priority {
+            FetchPriority::Interactive => &mut queues.interactive_waiting,
+            FetchPriority::Background => &mut queues.background_waiting,
+        };
+        *waiting = (*waiting as isize + change) as usize;
+    }
+}
+
+// The request is finished when this is dropped.
+struct RequestPermit<'a>(&'a FetchScheduler);
+
+impl<'a> Drop for RequestPermit<'a> {
+    fn drop(&mut self) {
+        self.0.queues.lock().unwrap().in_progress -= 1;
+        // Waiters of both priorities may be able to start, so all of them check.
+        self.0.finished.notify_all();
+    }
+}
+
+#[derive(Default)]
+struct FetchStatus {
+    last_success: Option<SystemTime>,
+    failing_since: Option<SystemTime>,
+}
+
+// Where the tweets in a cached interval came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TweetSource {
+    UserTimeline,
+    Search30Day,
+    SearchFullArchive,
+    Preload,
+    // Imported rather than fetched, e.g. from a Twitter data export.
+    Import,
+    // Loaded from persistence or a cache file, which don't record where tweets came from.
+    Persistence,
+}
+
+#[derive(Clone, Debug)]
+pub struct IntervalProvenance {
+    pub interval: Interval<Snowflake>,
+    pub source: TweetSource,
+    pub fetched_at: SecondsSinceUnixEpoch,
+}
+
+// Where each part of a user's cached tweets last came from, by where the part starts. Parts don't
+// overlap, so fetching an interval again replaces what was recorded for it, and the provenance of
+// a tweet can be found without looking through every fetch. Adjacent parts from the same source
+// are merged, keeping the older fetch time, so that nothing is refreshed later than it should be.
+#[derive(Default)]
+struct ProvenanceIndex {
+    by_start: BTreeMap<Snowflake, IntervalProvenance>,
+}
+
+impl ProvenanceIndex {
+    fn record(&mut self, provenance: IntervalProvenance) {
+        let Interval(start, end) = provenance.interval;
+        // Parts are in order of both their starts and their ends, as they don't overlap.
+        let overlapping: Vec<Snowflake> = self
+            .by_start
+            .range(..=end)
+            .rev()
+            .take_while(|&(_, part)| part.interval.1 >= start)
+            .map(|(&part_start, _)| part_start)
+            .collect();
+        for part_start in overlapping {
+            let part = self.by_start.remove(&part_start).unwrap();
+            if part.interval.0 < start {
+                let before = Interval(part.interval.0, Snowflake(start.0 - 1));
+                self.by_start.insert(
+                    before.0,
+                    IntervalProvenance {
+                        interval: before,
+                        ..part.clone()
+                    },
+                );
+            }
+            if part.interval.1 > end {
+                let after = Interval(Snowflake(end.0 + 1), part.interval.1);
+                self.by_start.insert(
+                    after.0,
+                    IntervalProvenance {
+                        interval: after,
+                        ..part
+                    },
+                );
+            }
+        }
+
+        let mut merged = provenance;
+        let before = self
+            .by_start
+            .range(..start)
+            .next_back()
+            .filter(|&(_, part)| part.source == merged.source && (part.interval.1).0 + 1 == start.0)
+            .map(|(&part_start, _)| part_start);
+        let after = Some(end)
+            .filter(|end| end.0 < u64::max_value())
+            .map(|end| Snowflake(end.0 + 1))
+            .filter(|after| {
+                self.by_start
+                    .get(after)
+                    .map_or(false, |part| part.source == merged.source)
+            });
+        for part_start in before.into_iter().chain(after) {
+            let part = self.by_start.remove(&part_start).unwrap();
+            merged = IntervalProvenance {
+                interval: Interval(
+                    min(merged.interval.0, part.interval.0),
+                    max(merged.interval.1, part.interval.1),
+                ),
+                source: merged.source,
+                fetched_at: min(merged.fetched_at, part.fetched_at),
+            };
+        }
+        self.by_start.insert(merged.interval.0, merged);
+    }
+
+    fn at(&self, id: Snowflake) -> Option<&IntervalProvenance> {
+        self.by_start
+            .range(..=id)
+            .next_back()
+            .map(|(_, part)| part)
+            .filter(|part| part.interval.contains(&id))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &IntervalProvenance> {
+        self.by_start.values()
+    }
+
+    fn overlapping<'a>(
+        &'a self,
+        interval: &Interval<Snowflake>,
+    ) -> impl Iterator<Item = &'a IntervalProvenance> {
+        let from = self
+            .by_start
+            .range(..=interval.0)
+            .next_back()
+            .map_or(interval.0, |(&part_start, _)| part_start);
+        let start = interval.0;
+        self.by_start
+            .range(from..=interval.1)
+            .map(|(_, part)| part)
+            .filter(move |part| part.interval.1 >= start)
+    }
+}
+
+// How long tweets from each source may be cached before they should be re-verified.
+// None means they never need re-verifying.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RefreshPolicy {
+    pub user_timeline_secs: Option<u64>,
+    pub search_secs: Option<u64>,
+    // Tweets near now may still be deleted or indexed late, so intervals ending less than this
+    // long ago are refetched once they have been cached this long.
+    pub recent_secs: Option<u64>,
+}
+
+impl RefreshPolicy {
+    // How long a freshly fetched interval ending at until may be cached, if it is recent.
+    pub fn recent_ttl(
+        &self,
+        until: SecondsSinceUnixEpoch,
+        now: SecondsSinceUnixEpoch,
+    ) -> Option<Duration> {
+        self.recent_secs
+            .filter(|&recent_secs| now.0.saturating_sub(until.0) < recent_secs)
+            .map(Duration::from_secs)
+    }
+
+    pub fn needs_refresh(
+        &self,
+        provenance: &IntervalProvenance,
+        now: SecondsSinceUnixEpoch,
+    ) -> bool {
+        let max_age_secs = match provenance.source {
+            TweetSource::Search30Day | TweetSource::SearchFullArchive => self.search_secs,
+            // Most persisted tweets were fetched from user timelines.
+            TweetSource::UserTimeline | TweetSource::Persistence => self.user_timeline_secs,
+            TweetSource::Preload | TweetSource::Import => None,
+        };
+        match max_age_secs {
+            Some(max_age_secs) => now.0.saturating_sub(provenance.fetched_at.0) > max_age_secs,
+            None => false,
+        }
+    }
+}
+
+// The premium search environments to use, e.g. "dev" for tweets/search/30day/dev.json.
+// A product with no environment is treated as unavailable, and fetch methods using it are skipped.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct SearchEnvironments {
+    #[serde(default = "default_thirty_day_environment")]
+    pub thirty_day: Option<String>,
+    #[serde(default)]
+    pub full_archive: Option<String>,
+}
+
+// Search operators narrowing a search for a user's tweets down to a topic.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct SearchOptions {
+    // e.g. "en".
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub has_media: bool,
+    // Each of which must be in the tweet. Keywords containing spaces are searched for as phrases.
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl SearchOptions {
+    fn query(&self, user: &str) -> String {
+        let mut query = format!("from:{}", user);
+        for keyword in &self.keywords {
+            if keyword.contains(char::is_whitespace) {
+                query.push_str(&format!(" \"{}\"", keyword.replace('"', "")));
+            } else {
+                query.push_str(&format!(" {}", keyword));
+            }
+        }
+        if self.has_media {
+            query.push_str(" has:media");
+        }
+        if let Some(ref lang) = self.lang {
+            query.push_str(&format!(" lang:{}", lang));
+        }
+        query
+    }
+}
+
+impl Default for SearchEnvironments {
+    fn default() -> SearchEnvironments {
+        SearchEnvironments {
+            thirty_day: default_thirty_day_environment(),
+            full_archive: None,
+        }
+    }
+}
+
+fn default_thirty_day_environment() -> Option<String> {
+    Some("dev".to_owned())
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SearchProduct {
+    ThirtyDay,
+    FullArchive,
+}
+
+impl SearchProduct {
+    fn path(&self) -> &'static str {
+        match *self {
+            SearchProduct::ThirtyDay => "30day",
+            SearchProduct::FullArchive => "fullarchive",
+        }
+    }
+
+    // Whether the product can find tweets from an interval starting age ago.
+    fn reaches(&self, age: Duration) -> bool {
+        match *self {
+            SearchProduct::ThirtyDay => age <= THIRTY_DAY_SEARCH_MAX_AGE,
+            SearchProduct::FullArchive => true,
+        }
+    }
+
+    fn source(&self) -> TweetSource {
+        match *self {
+            SearchProduct::ThirtyDay => TweetSource::Search30Day,
+            SearchProduct::FullArchive => TweetSource::SearchFullArchive,
+        }
+    }
+
+    fn category(&self) -> ApiCategory {
+        match *self {
+            SearchProduct::ThirtyDay => ApiCategory::Search30Day,
+            SearchProduct::FullArchive => ApiCategory::SearchFullArchive,
+        }
+    }
+}
+
+// One way of trying to fetch an interval, in the order the FetchStrategy says to try them.
+#[derive(Clone, Copy, Debug)]
+enum FetchAttempt {
+    UserTimeline,
+    Search(SearchProduct),
+    CacheOnly,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TweetDensity {
+    pub from: SecondsSinceUnixEpoch,
+    pub until: SecondsSinceUnixEpoch,
+    pub tweets: usize,
+}
+
+#[derive(Clone)]
+pub struct TweetStore {
+    app_token: oauth::Oauth1Token,
+    search_enabled_display_names: HashSet<String>,
+    stale_if_error: StaleIfError,
+    refresh_policy: RefreshPolicy,
+    fetch_strategy: FetchStrategy,
+    search_environments: SearchEnvironments,
+    app_bearer_token: Arc<Mutex<Option<String>>>,
+    // Shared by every request, so that connections are pooled.
+    http_client: reqwest::blocking::Client,
+    tweets: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // Across every user's tweets.
+    max_cached_tweets: Option<usize>,
+    // When each user's tweets were last looked up or cached, for evicting whole users.
+    users_last_used: Arc<Mutex<HashMap<String, Instant>>>,
+    // Tweets in lists' timelines, by viewer and list id.
+    lists: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // Tweets in home timelines, by the screen name of whose home timeline it is.
+    home_timelines: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // Tweets users have liked, by viewer and the screen name of who liked them.
+    likes: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // Keyed by how they were found: "mentions:" and the screen name for those from a user's own
+    // mentions timeline, or otherwise the search query.
+    mentions: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // Tweets found by searching, by search query, e.g. with SearchOptions or for a hashtag.
+    searches: Arc<IntervalStoreMap<String, Snowflake, TweetFromTwitter>>,
+    // The URLs of the media in every tweet cached, by media id, so that it can be proxied.
+    media_urls: Arc<RwLock<HashMap<Snowflake, String>>>,
+    // Tweets looked up by id for threads, which needn't be in any cached timeline.
+    tweets_by_id: Arc<Mutex<LruCache<Snowflake, TweetFromTwitter>>>,
+    // The ids of the tweets in each thread looked up, by the id of the thread's first tweet.
+    conversations: Arc<Mutex<LruCache<Snowflake, BTreeSet<Snowflake>>>>,
+    // Sources other than Twitter, by name.
+    // Twitter users' timelines are fetched through this, and cached in tweets.
+    twitter: Arc<dyn SocialSource>,
+    sources: HashMap<&'static str, RegisteredSource>,
+    fetch_statuses: Arc<Mutex<HashMap<String, FetchStatus>>>,
+    rate_limits: Arc<Mutex<HashMap<RateLimitKey, RateLimit>>>,
+    persistence: Option<Arc<dyn TweetPersistence>>,
+    cassette: Option<Arc<Cassette>>,
+    quotas: HashMap<ApiCategory, Quota>,
+    api_usage: Arc<Mutex<HashMap<ApiCategory, ApiUsage>>>,
+    // By viewer, as what a fetch finds (or fails with) can depend on whose token it's made with.
+    in_flight: Arc<InFlightFetches<InFlightKey, Result<(), FetchError>>>,
+    // Across every user.
+    fetch_scheduler: Arc<FetchScheduler>,
+    // Across every user. If None, requests are never conditional.
+    http_cache: Option<Arc<HttpCache>>,
+    // The priority of fetches made through this handle.
+    priority: FetchPriority,
+    // Each user's horizon, and their statuses_count when it was found.
+    timeline_horizons: Arc<Mutex<HashMap<String, (TimelineHorizon, u64)>>>,
+    provenance: Arc<RwLock<HashMap<String, ProvenanceIndex>>>,
+    // When each user's tweets were last looked up by id, by tweet, if they have been since their
+    // interval was fetched. Tweets are forgotten once they're refetched or no longer cached.
+    refreshed_at: Arc<Mutex<HashMap<String, BTreeMap<Snowflake, SecondsSinceUnixEpoch>>>>,
+    // Accounts whose timelines Twitter wouldn't show with app-only auth, by screen name, with the
+    // viewers whose tokens it has shown them to. Their cached tweets are only served to those
+    // viewers.
+    protected_accounts: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    // Only the counters are kept up to date; tweets_cached is filled in by metrics.
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl TweetStore {
+    pub fn new(
+        app_oauth_token: oauth::Oauth1Token,
+        search_enabled_display_names: HashSet<String>,
+        stale_if_error: StaleIfError,
+        refresh_policy: RefreshPolicy,
+        fetch_strategy: FetchStrategy,
+        search_environments: SearchEnvironments,
+        quotas: Vec<Quota>,
+    ) -> TweetStore {
+        TweetStore {
+            app_token: app_oauth_token,
+            search_enabled_display_names: search_enabled_display_names,
+            stale_if_error,
+            refresh_policy,
+            fetch_strategy,
+            search_environments,
+            app_bearer_token: Arc::new(Mutex::new(None)),
+            http_client: HttpTimeouts::default_client(),
+            tweets: Arc::new(timeline_stores()),
+            max_cached_tweets: None,
+            users_last_used: Arc::new(Mutex::new(HashMap::new())),
+            lists: Arc::new(timeline_stores()),
+            home_timelines: Arc::new(timeline_stores()),
+            likes: Arc::new(timeline_stores()),
+            mentions: Arc::new(timeline_stores()),
+            searches: Arc::new(timeline_stores()),
+            media_urls: Arc::new(RwLock::new(HashMap::new())),
+            tweets_by_id: Arc::new(Mutex::new(LruCache::new(MAX_THREAD_TWEETS))),
+            conversations: Arc::new(Mutex::new(LruCache::new(MAX_CONVERSATIONS))),
+            twitter: Arc::new(Twitter),
+            sources: HashMap::new(),
+            fetch_statuses: Arc::new(Mutex::new(HashMap::new())),
+            rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            persistence: None,
+            cassette: None,
+            quotas: quotas
+                .into_iter()
+                .map(|quota| (quota.category, quota))
+                .collect(),
+            api_usage: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(InFlightFetches::new()),
+            fetch_scheduler: Arc::new(FetchScheduler::new(None, FetchSchedulingPolicy::default())),
+            priority: FetchPriority::Interactive,
+            http_cache: None,
+            timeline_horizons: Arc::new(Mutex::new(HashMap::new())),
+            provenance: Arc::new(RwLock::new(HashMap::new())),
+            refreshed_at: Arc::new(Mutex::new(HashMap::new())),
+            protected_accounts: Arc::new(Mutex::new(HashMap::new())),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+        }
+        .with_source(Arc::new(Mastodon::new()))
+        .with_source(Arc::new(Bluesky))
+    }
+
+    // Requests are made on behalf of the context's user, or with app-only auth if there is none.
+    // User timelines are the exception: they're shared between viewers, so are fetched with app-only
+    // auth unless the account turns out to be protected, and are then only served to viewers whose
+    // tokens Twitter shows them to.
+    pub fn tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        options: TimelineOptions,
+    ) -> Result<FeedTweets, FetchError> {
+        self.check_visible(context, user)?;
+        let mut feed_tweets = self.all_tweets(context, user, interval)?;
+        feed_tweets.tweets.retain(|tweet| options.includes(tweet));
+        Ok(feed_tweets)
+    }
+
+    // Several users' tweets merged into one feed, oldest first. Each user's tweets are fetched and
+    // cached as by tweets, and the feed is partial if any of theirs is.
+    pub fn tweets_multi(
+        &self,
+        context: Option<&Context>,
+        users: &[String],
+        interval: &Interval<Snowflake>,
+        options: TimelineOptions,
+    ) -> Result<MultiFeedTweets, FetchError> {
+        let mut tweets = Vec::new();
+        let mut partial = false;
+        for user in users {
+            let feed_tweets = self.tweets(context, user, interval, options)?;
+            partial |= feed_tweets.partial;
+            tweets.extend(feed_tweets.tweets.into_iter().map(|tweet| UserTweet {
+                user: user.clone(),
+                tweet,
+            }));
+        }
+        tweets.sort_by_key(|user_tweet| user_tweet.tweet.id);
+        Ok(MultiFeedTweets { tweets, partial })
+    }
+
+    // Tweets from the members of a list, which are cached separately from their own timelines, and
+    // per viewer, as lists may be private.
+    pub fn list_tweets(
+        &self,
+        context: Option<&Context>,
+        list_id: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        self.other_timeline_tweets(
+            &self.lists,
+            &TweetStore::viewer_key(context, list_id),
+            context,
+            Timeline::List(list_id),
+            interval,
+        )
+    }
+
+    // Tweets from the logged in user's home timeline, i.e. from the accounts they follow, cached
+    // per viewer.
+    pub fn home_tweets(
+        &self,
+        context: &Context,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        self.other_timeline_tweets(
+            &self.home_timelines,
+            &context.user_screen_name,
+            Some(context),
+            Timeline::Home,
+            interval,
+        )
+    }
+
+    // Tweets the user has liked. Twitter doesn't say when they were liked, so the interval is of
+    // when the liked tweets were posted; it's still roughly what the user was liking at the time.
+    // They're cached per viewer, as they may include tweets from protected accounts which only
+    // some viewers can see.
+    pub fn liked_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        self.other_timeline_tweets(
+            &self.likes,
+            &TweetStore::viewer_key(context, user),
+            context,
+            Timeline::Likes(user),
+            interval,
+        )
+    }
+
+    // Keys a timeline fetched with the viewer's token, if any, by who's viewing it too. Screen
+    // names can't contain "/", so keys can't collide.
+    fn viewer_key(context: Option<&Context>, key: &str) -> String {
+        let viewer = context.map_or("", |context| context.user_screen_name.as_str());
+        format!("{}/{}", viewer, key)
+    }
+
+    // Tweets mentioning the user, so that both sides of their conversations can be replayed. The
+    // logged in user's own are fetched from their mentions timeline; anyone else's are found by
+    // searching for replies to them, which misses mentions which aren't replies.
+    pub fn mentions_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        let own_mentions = context.map_or(false, |context| {
+            context.user_screen_name.eq_ignore_ascii_case(user)
+        });
+        if own_mentions {
+            return self.other_timeline_tweets(
+                &self.mentions,
+                &format!("mentions:{}", user.to_lowercase()),
+                context,
+                Timeline::Mentions,
+                interval,
+            );
+        }
+        let query = format!("to:{}", user);
+        self.cached_timeline_tweets(&self.mentions, &query, interval, |missing_interval| {
+            let product = self.search_product(missing_interval)?;
+            self.fetch_tweets_from_search(context, &query, missing_interval, product)
+        })
+    }
+
+    // Tweets with the hashtag, e.g. to replay an event, found by searching. The tag may be given with
+    // or without its "#", and they're cached under it with one, in any case, with searches.
+    pub fn hashtag_tweets(
+        &self,
+        context: Option<&Context>,
+        tag: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        let query = format!("#{}", tag.trim_start_matches('#').to_lowercase());
+        self.cached_timeline_tweets(&self.searches, &query, interval, |missing_interval| {
+            let product = self.search_product(missing_interval)?;
+            self.fetch_tweets_from_search(context, &query, missing_interval, product)
+        })
+    }
+
+    // The user's tweets which match the options, found with whichever configured search product
+    // reaches back to the start of the interval. They're cached by query, apart from the user's
+    // timeline.
+    pub fn search_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        options: &SearchOptions,
+    ) -> Result<FeedTweets, FetchError> {
+        self.cached_timeline_tweets(
+            &self.searches,
+            &options.query(user),
+            interval,
+            |missing_interval| {
+                let product = self.search_product(missing_interval)?;
+                self.fetch_user_tweets_from_search(
+                    context,
+                    user,
+                    missing_interval,
+                    product,
+                    options,
+                )
+            },
+        )
+    }
+
+    fn search_product(&self, interval: &Interval<Snowflake>) -> Result<SearchProduct, FetchError> {
+        let from: SecondsSinceUnixEpoch = interval.0.into();
+        let age = Duration::from_secs(SecondsSinceUnixEpoch::now().0.saturating_sub(from.0));
+        [SearchProduct::ThirtyDay, SearchProduct::FullArchive]
+            .iter()
+            .cloned()
+            .find(|product| product.reaches(age) && self.search_environment(*product).is_some())
+            .ok_or_else(|| {
+                format!(
+                    "No search product is configured for interval {:?}",
+                    interval
+                )
+                .into()
+            })
+    }
+
+    fn other_timeline_tweets(
+        &self,
+        timelines: &IntervalStoreMap<String, Snowflake, TweetFromTwitter>,
+        key: &String,
+        context: Option<&Context>,
+        timeline: Timeline,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        self.cached_timeline_tweets(timelines, key, interval, |missing_interval| {
+            self.fetch_timeline(context, timeline, missing_interval)
+        })
+    }
+
+    // Posts from the account on the named source, e.g. "mastodon", as tweets.
+    pub fn source_tweets(
+        &self,
+        source: &str,
+        key: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        let registered = self
+            .sources
+            .get(source)
+            .ok_or_else(|| format!("Unknown source {:?}", source))?;
+        let key = registered.source.parse_key(key)?;
+        self.cached_timeline_tweets(&registered.timelines, &key, interval, |missing_interval| {
+            registered
+                .source
+                .fetch_timeline(self, &key, missing_interval)
+        })
+    }
+
+    // The names of the sources, other than Twitter, which feeds can be fetched from.
+    pub fn sources(&self) -> Vec<(&'static str, SourceCapabilities)> {
+        let mut sources: Vec<_> = self
+            .sources
+            .values()
+            .map(|registered| (registered.source.name(), registered.source.capabilities()))
+            .collect();
+        sources.sort_by_key(|&(name, _)| name);
+        sources
+    }
+
+    fn cached_timeline_tweets<F>(
+        &self,
+        timelines: &IntervalStoreMap<String, Snowflake, TweetFromTwitter>,
+        key: &String,
+        interval: &Interval<Snowflake>,
+        fetch: F,
+    ) -> Result<FeedTweets, FetchError>
+    where
+        F: Fn(&Interval<Snowflake>) -> Result<Vec<TweetFromTwitter>, FetchError>,
+    {
+        let interval_store_lock = timelines.get_or_create(key);
+        let missing = interval_store_lock.read().unwrap().missing(interval);
+        self.count_lookup(missing.is_empty());
+        for missing_interval in missing.iter() {
+            let tweets = fetch(missing_interval).map_err(|err| self.count_error(err))?;
+            let mut interval_store = interval_store_lock.write().unwrap();
+            self.insert_fetched(&mut interval_store, missing_interval, tweets)?;
+        }
+        let tweets = interval_store_lock.read().unwrap().get_available(interval);
+        Ok(FeedTweets {
+            tweets,
+            partial: false,
+        })
+    }
+
+    fn all_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<FeedTweets, FetchError> {
+        let known = self.known_or_missing_tweets(user, interval);
+        self.count_lookup(known.is_ok());
+        self.known_or_fetched_tweets(context, user, interval, known)
+    }
+
+    fn known_or_fetched_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        known: Result<Vec<TweetFromTwitter>, IntervalSet<Snowflake>>,
+    ) -> Result<FeedTweets, FetchError> {
+        match known {
+            Ok(tweets) => Ok(FeedTweets {
+                tweets,
+                partial: false,
+            }),
+            Err(missing_intervals) => {
+                let fetch_result = self.fetch_all_tweets(context, user, &missing_intervals);
+                self.record_fetch_result(user, fetch_result.is_ok());
+                match fetch_result {
+                    Ok(()) => {
+                        let known = self.known_or_missing_tweets(user, interval);
+                        self.known_or_fetched_tweets(context, user, interval, known)
+                    }
+                    Err(err) => self.serve_after_error(user, interval, self.count_error(err)),
+                }
+            }
+        }
+    }
+
+    // The cached tweets in the interval, or the intervals which need fetching to complete them,
+    // after looking for them in persistent storage. Parts which the refresh policy says have been
+    // cached for too long need fetching again, even though they're cached.
+    fn known_or_missing_tweets(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, IntervalSet<Snowflake>> {
+        let known = match self.get_known_tweets(user, interval) {
+            Err(missing_intervals) if self.load_persisted(user, &missing_intervals) => {
+                self.get_known_tweets(user, interval)
+            }
+            result => result,
+        };
+        let due = self.due_for_refresh(user, interval);
+        if due.is_empty() {
+            return known;
+        }
+        let mut missing = known.err().unwrap_or_else(IntervalSet::new);
+        for part in due.iter() {
+            missing.insert(part);
+        }
+        Err(missing)
+    }
+
+    // The parts of the interval whose cached tweets the refresh policy says should be refetched.
+    fn due_for_refresh(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Vec<Interval<Snowflake>> {
+        let now = SecondsSinceUnixEpoch::now();
+        let provenance = self.provenance.read().unwrap();
+        let provenance = match provenance.get(user) {
+            Some(provenance) => provenance,
+            None => return vec![],
+        };
+        provenance
+            .overlapping(interval)
+            .filter(|part| self.refresh_policy.needs_refresh(part, now))
+            .map(|part| {
+                Interval(
+                    max(part.interval.0, interval.0),
+                    min(part.interval.1, interval.1),
+                )
+            })
+            .collect()
+    }
+
+    fn serve_after_error(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        err: FetchError,
+    ) -> Result<FeedTweets, FetchError> {
+        if !self.may_serve_stale(user) {
+            return Err(err);
+        }
+        match self.get_partially_known_tweets(user, interval) {
+            Some(tweets) => {
+                warn!("Serving partial tweets for {} after error: {}", user, err);
+                Ok(FeedTweets {
+                    tweets,
+                    partial: true,
+                })
+            }
+            None => Err(err),
+        }
+    }
+
+    fn record_fetch_result(&self, user: &String, success: bool) {
+        let now = SystemTime::now();
+        let mut fetch_statuses = self.fetch_statuses.lock().unwrap();
+        let status = fetch_statuses
+            .entry(user.clone())
+            .or_insert_with(FetchStatus::default);
+        if success {
+            status.last_success = Some(now);
+            status.failing_since = None;
+        } else if status.failing_since.is_none() {
+            status.failing_since = Some(now);
+        }
+    }
+
+    fn may_serve_stale(&self, user: &String) -> bool {
+        let now = SystemTime::now();
+        let within = |since: Option<SystemTime>, max: Option<Duration>| match (since, max) {
+            (_, None) => true,
+            (None, Some(_)) => false,
+            (Some(since), Some(max)) => now.duration_since(since).unwrap_or_default() <= max,
+        };
+        let fetch_statuses = self.fetch_statuses.lock().unwrap();
+        match fetch_statuses.get(user) {
+            Some(status) => {
+                within(status.last_success, self.stale_if_error.max_staleness)
+                    && within(status.failing_since, self.stale_if_error.max_error_duration)
+            }
+            None => false,
+        }
+    }
+
+    fn fetch_all_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        intervals: &IntervalSet<Snowflake>,
+    ) -> Result<(), FetchError> {
+        for interval in intervals.iter() {
+            self.fetch_tweets(context, user, interval)?;
+        }
+        Ok(())
+    }
+
+    // Concurrent calls for the same user and interval share a single fetch.
+    fn fetch_tweets(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<(), FetchError> {
+        let viewer = context.map(|context| context.user_screen_name.clone());
+        self.in_flight.coalesce(
+            (viewer, user.clone(), *interval),
+            || self.fetch_tweets_uncoalesced(context, user, interval),
+            Err(format!(
+                "Fetching tweets for {} in {:?} was abandoned",
+                user, interval
+            )
+            .into()),
+        )
+    }
+
+    fn fetch_tweets_uncoalesced(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<(), FetchError> {
+        let (tweets, source) = self.fetch_with_strategy(context, user, interval)?;
+        self.store_fetched(user, interval, tweets, source)
+    }
+
+    fn store_fetched(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        tweets: Vec<TweetFromTwitter>,
+        source: TweetSource,
+    ) -> Result<(), FetchError> {
+        if let Some(ref persistence) = self.persistence {
+            // The tweets are still worth serving from memory if they can't be persisted.
+            if let Err(err) = persistence.save(user, interval, &tweets) {
+                warn!("Error persisting tweets for {}: {}", user, err);
+            }
+        }
+        self.cache_refetched(user, interval, tweets)?;
+        self.record_provenance(user, interval, source);
+        Ok(())
+    }
+
+    fn cache(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        tweets: Vec<TweetFromTwitter>,
+    ) -> Result<(), String> {
+        let interval_store_lock = self.interval_store(user);
+        {
+            let mut interval_store = interval_store_lock.write().unwrap();
+            self.insert_fetched(&mut interval_store, interval, tweets)?;
+        }
+        self.evict_users(user);
+        Ok(())
+    }
+
+    // Fetched intervals were either missing or due for refresh, so whatever is still cached in
+    // them is out of date, and is replaced rather than checked against what was fetched.
+    fn cache_refetched(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        tweets: Vec<TweetFromTwitter>,
+    ) -> Result<(), String> {
+        let interval_store_lock = self.interval_store(user);
+        {
+            let mut interval_store = interval_store_lock.write().unwrap();
+            interval_store.remove(interval);
+            self.insert_fetched(&mut interval_store, interval, tweets)?;
+        }
+        self.evict_users(user);
+        Ok(())
+    }
+
+    // Evicts whole users, least recently used first, until the cache is back within
+    // max_cached_tweets. The user just cached is kept, even if they alone exceed it. Their media
+    // URLs go with them; tweets looked up for threads are bounded separately.
+    fn evict_users(&self, cached_user: &String) {
+        let max_cached_tweets = match self.max_cached_tweets {
+            Some(max_cached_tweets) => max_cached_tweets,
+            None => return,
+        };
+        let mut cached_tweets: HashMap<String, usize> = self
+            .tweets
+            .stats()
+            .into_iter()
+            .map(|(user, stats)| (user, stats.size.values))
+            .collect();
+        let mut total: usize = cached_tweets.values().sum();
+        if total <= max_cached_tweets {
+            return;
+        }
+        let mut users_last_used = self.users_last_used.lock().unwrap();
+        let mut users: Vec<_> = cached_tweets
+            .keys()
+            .filter(|&user| user != cached_user)
+            .map(|user| (users_last_used.get(user).cloned(), user.clone()))
+            .collect();
+        // Users never looked up sort first.
+        users.sort();
+        for (_, user) in users {
+            if total <= max_cached_tweets {
+                break;
+            }
+            if let Some(interval_store_lock) = self.tweets.remove(&user) {
+                self.forget_media_urls(&interval_store_lock.read().unwrap().view());
+            }
+            self.provenance.write().unwrap().remove(&user);
+            self.refreshed_at.lock().unwrap().remove(&user);
+            users_last_used.remove(&user);
+            let evicted = cached_tweets.remove(&user).unwrap_or(0);
+            info!("Evicted {} cached tweets for {}", evicted, user);
+            total -= evicted;
+        }
+    }
+
+    fn insert_fetched(
+        &self,
+        interval_store: &mut IntervalStore<Snowflake, TweetFromTwitter>,
+        interval: &Interval<Snowflake>,
+        tweets: Vec<TweetFromTwitter>,
+    ) -> Result<(), String> {
+        self.record_media_urls(&tweets);
+        // Expired intervals' tweets are only kept to be served if refetching them fails, so are
+        // dropped once something else is fetched, rather than piling up.
+        interval_store.remove_expired();
+        let policy = ConflictPolicy::Reject;
+        match self
+            .refresh_policy
+            .recent_ttl(interval.1.into(), SecondsSinceUnixEpoch::now())
+        {
+            Some(ttl) => {
+                interval_store.insert_with_expiry(interval, tweets, policy, Instant::now() + ttl)
+            }
+            None => interval_store.insert_with_policy(interval, tweets, policy),
+        }
+    }
+
+    fn record_media_urls(&self, tweets: &[TweetFromTwitter]) {
+        let media = tweets
+            .iter()
+            .filter_map(|tweet| tweet.entities.as_ref())
+            .flat_map(|entities| entities.media.iter());
+        let mut media_urls = self.media_urls.write().unwrap();
+        for media in media {
+            media_urls.insert(media.id, media.media_url_https.clone());
+        }
+    }
+
+    // Forgets the URLs of the tweets' media. Media which other cached tweets share, e.g. retweets
+    // of them, can't be proxied again until those are refetched.
+    fn forget_media_urls(&self, tweets: &StoreView<Snowflake, TweetFromTwitter>) {
+        let everything = Interval(Snowflake(0), Snowflake(u64::max_value()));
+        let mut media_urls = self.media_urls.write().unwrap();
+        for tweet in tweets.iter_within(&everything) {
+            if let Some(ref entities) = tweet.entities {
+                for media in entities.media.iter() {
+                    media_urls.remove(&media.id);
+                }
+            }
+        }
+    }
+
+    // Where the media with the id can be fetched from, if it's in a cached tweet.
+    pub fn media_url(&self, id: Snowflake) -> Option<String> {
+        self.media_urls.read().unwrap().get(&id).cloned()
+    }
+
+    // Caches (and persists) tweets which were obtained other than by fetching, e.g. from a Twitter
+    // data export, as every one of the user's tweets from the oldest of them to the newest.
+    // Returns the interval they cover, if there were any.
+    pub fn import(
+        &self,
+        user: &String,
+        mut tweets: Vec<TweetFromTwitter>,
+    ) -> Result<Option<Interval<Snowflake>>, FetchError> {
+        tweets.sort();
+        tweets.dedup();
+        let interval = match (tweets.first(), tweets.last()) {
+            (Some(oldest), Some(newest)) => Interval(oldest.id, newest.id),
+            _ => return Ok(None),
+        };
+        self.store_fetched(user, &interval, tweets, TweetSource::Import)?;
+        Ok(Some(interval))
+    }
+
+    // Writes every user's cached tweets to the file, for load to restore after a restart. Lists',
+    // home and liked timelines aren't saved. The file is replaced only once it's been written, so
+    // failing part way leaves any previous save intact. The format is the user count (u64), then
+    // each user's screen name and IntervalStore snapshot.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let temporary_path = path.with_extension("partial");
+        let file = fs::File::create(&temporary_path)
+            .map_err(|err| format!("Error creating {:?}: {}", temporary_path, err))?;
+        let mut writer = io::BufWriter::new(file);
+        let users = self.users();
+        (users.len() as u64)
+            .encode(&mut writer)
+            .map_err(|err| format!("Error writing {:?}: {}", temporary_path, err))?;
+        for user in users {
+            user.encode(&mut writer)
+                .map_err(|err| format!("Error writing {:?}: {}", temporary_path, err))?;
+            // Users evicted since listing them are saved as having nothing cached. Recent
+            // intervals which have expired are left out, to be fetched again after loading.
+            match self.existing_interval_store(&user) {
+                Some(interval_store_lock) => {
+                    interval_store_lock.read().unwrap().snapshot(&mut writer)
+                }
+                None => IntervalStore::<Snowflake, TweetFromTwitter>::new().snapshot(&mut writer),
+            }?;
+        }
+        writer
+            .flush()
+            .map_err(|err| format!("Error writing {:?}: {}", temporary_path, err))?;
+        fs::rename(&temporary_path, path)
+            .map_err(|err| format!("Error replacing {:?}: {}", path, err))
+    }
+
+    // Caches the tweets in a file written by save. When they were fetched isn't saved, so they're
+    // treated as fetched now: recent intervals are given a fresh TTL, and tweets are only stale
+    // once they've been loaded for the refresh policy's max staleness. Returns how many users'
+    // tweets were loaded.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<usize, String> {
+        let path = path.as_ref();
+        let file =
+            fs::File::open(path).map_err(|err| format!("Error opening {:?}: {}", path, err))?;
+        let mut reader = io::BufReader::new(file);
+        let user_count =
+            u64::decode(&mut reader).map_err(|err| format!("Error reading {:?}: {}", path, err))?;
+        for _ in 0..user_count {
+            let user = String::decode(&mut reader)
+                .map_err(|err| format!("Error reading {:?}: {}", path, err))?;
+            let interval_store: IntervalStore<Snowflake, TweetFromTwitter> =
+                IntervalStore::restore(&mut reader)
+                    .map_err(|err| format!("Error reading tweets for {}: {}", user, err))?;
+            let tweets = interval_store.view();
+            for interval in tweets.covered().iter() {
+                self.cache(
+                    &user,
+                    interval,
+                    tweets.iter_within(interval).cloned().collect(),
+                )?;
+                self.record_provenance(&user, interval, TweetSource::Persistence);
+            }
+        }
+        Ok(user_count as usize)
+    }
+
+    // Caches whatever other servers have persisted of the missing intervals, returning whether
+    // there was any. Errors are logged and otherwise ignored, falling back to fetching.
+    fn load_persisted(&self, user: &String, missing: &IntervalSet<Snowflake>) -> bool {
+        let persistence = match self.persistence {
+            Some(ref persistence) => persistence,
+            None => return false,
+        };
+        let mut loaded = false;
+        for interval in missing.iter() {
+            let result = persistence
+                .load_overlapping(user, interval)
+                .and_then(|persisted| {
+                    for persisted in persisted {
+                        self.cache(user, &persisted.interval, persisted.tweets)?;
+                        self.record_provenance(user, &persisted.interval, TweetSource::Persistence);
+                        loaded = true;
+                    }
+                    Ok(())
+                });
+            if let Err(err) = result {
+                warn!("Error loading persisted tweets for {}: {}", user, err);
+            }
+        }
+        loaded
+    }
+
+    // Loads every persisted tweet into the cache, and persists everything fetched from now on.
+    // When persisted intervals were fetched isn't recorded, so they're treated as fetched now, as
+    // with load.
+    pub fn with_persistence(
+        mut self,
+        persistence: Arc<dyn TweetPersistence>,
+    ) -> Result<TweetStore, String> {
+        for persisted in persistence.load()? {
+            self.cache(&persisted.user, &persisted.interval, persisted.tweets)?;
+            self.record_provenance(
+                &persisted.user,
+                &persisted.interval,
+                TweetSource::Persistence,
+            );
+        }
+        self.persistence = Some(persistence);
+        Ok(self)
+    }
+
+    // Must be set before anything is cached, as existing users' tweets aren't limited.
+    pub fn with_cache_limits(mut self, cache_limits: CacheLimits) -> TweetStore {
+        self.tweets = Arc::new(match cache_limits.max_tweets_per_user {
+            Some(max_tweets_per_user) => IntervalStoreMap::with_factory(move || {
+                IntervalStore::with_max_values(max_tweets_per_user).merging_adjacent()
+            }),
+            None => timeline_stores(),
+        });
+        self.max_cached_tweets = cache_limits.max_tweets;
+        self
+    }
+
+    // Serves feeds from the source, replacing any other source with the same name.
+    pub fn with_source(mut self, source: Arc<dyn SocialSource>) -> TweetStore {
+        self.sources.insert(
+            source.name(),
+            RegisteredSource {
+                source,
+                timelines: Arc::new(timeline_stores()),
+            },
+        );
+        self
+    }
+
+    // Requests are made with the client, e.g. to go through a proxy or trust other TLS roots.
+    pub fn with_http_client(mut self, http_client: reqwest::blocking::Client) -> TweetStore {
+        self.http_client = http_client;
+        self
+    }
+
+    // At most max_requests requests are made to Twitter at once, so that a burst of feed requests
+    // doesn't open so many connections that Twitter takes it for abuse. Others wait their turn.
+    pub fn with_max_concurrent_requests(mut self, max_requests: usize) -> TweetStore {
+        self.fetch_scheduler = Arc::new(FetchScheduler::new(
+            Some(max_requests),
+            self.fetch_scheduler.policy,
+        ));
+        self
+    }
+
+    pub fn with_fetch_scheduling(mut self, policy: FetchSchedulingPolicy) -> TweetStore {
+        self.fetch_scheduler = Arc::new(FetchScheduler::new(
+            self.fetch_scheduler.max_requests,
+            policy,
+        ));
+        self
+    }
+
+    // Keeps up to max_responses responses to Twitter with an ETag or Last-Modified header, and
+    // makes requests which would get them again conditional.
+    pub fn with_http_cache(mut self, max_responses: usize) -> TweetStore {
+        self.http_cache = Some(Arc::new(HttpCache::new(max_responses)));
+        self
+    }
+
+    // A handle to the same store whose fetches are made with the given priority.
+    pub fn with_priority(mut self, priority: FetchPriority) -> TweetStore {
+        self.priority = priority;
+        self
+    }
+
+    // Records every request to Twitter to the cassette, or replays them from it instead.
+    pub fn with_cassette(mut self, cassette: Cassette) -> TweetStore {
+        self.cassette = Some(Arc::new(cassette));
+        self
+    }
+
+    fn fetch_with_strategy(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<(Vec<TweetFromTwitter>, TweetSource), FetchError> {
+        // A rate limited attempt is skipped in favour of the next one, but if none of them
+        // produce tweets, being rate limited is more useful to report than finding nothing.
+        let mut rate_limited = None;
+        for attempt in self.fetch_attempts(context, interval)? {
+            let result = match attempt {
+                FetchAttempt::UserTimeline => self
+                    .fetch_usertimeline(context, user, interval)
+                    .map(|tweets| tweets.map(|tweets| (tweets, TweetSource::UserTimeline))),
+                FetchAttempt::Search(product) => self
+                    .fetch_user_tweets_from_search(
+                        context,
+                        user,
+                        interval,
+                        product,
+                        &SearchOptions::default(),
+                    )
+                    .map(|tweets| Some((tweets, product.source()))),
+                FetchAttempt::CacheOnly => Err(TweetStore::not_cached(user)),
+            };
+            match result {
+                Ok(Some(fetched)) => return Ok(fetched),
+                Ok(None) => {}
+                Err(FetchError::RateLimited(err)) => {
+                    rate_limited.get_or_insert(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(TweetStore::nothing_found(rate_limited))
+    }
+
+    // What fetching the interval should try, in order. Search products which aren't configured,
+    // or don't reach back to the start of the interval, are left out.
+    fn fetch_attempts(
+        &self,
+        context: Option<&Context>,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<FetchAttempt>, FetchError> {
+        let from: SecondsSinceUnixEpoch = interval.0.into();
+        let age = Duration::from_secs(SecondsSinceUnixEpoch::now().0.saturating_sub(from.0));
+        let window = interval.duration();
+        let entitlements = Entitlements {
+            logged_in: context.is_some(),
+            search_enabled: context
+                .map(|context| {
+                    self.search_enabled_display_names
+                        .contains(&context.user_screen_name)
+                })
+                .unwrap_or(false),
+        };
+        let methods = self
+            .fetch_strategy
+            .methods(age, window, entitlements)
+            .ok_or_else(|| format!("No fetch rule matches interval {:?}", interval))?;
+        let mut attempts = vec![];
+        for method in methods {
+            let products = match method {
+                &FetchMethod::UserTimeline => {
+                    attempts.push(FetchAttempt::UserTimeline);
+                    continue;
+                }
+                &FetchMethod::CacheOnly => {
+                    attempts.push(FetchAttempt::CacheOnly);
+                    continue;
+                }
+                &FetchMethod::Search30Day => &[SearchProduct::ThirtyDay][..],
+                &FetchMethod::SearchFullArchive => &[SearchProduct::FullArchive][..],
+                &FetchMethod::Search => &[SearchProduct::ThirtyDay, SearchProduct::FullArchive][..],
+            };
+            attempts.extend(
+                products
+                    .iter()
+                    .filter(|product| {
+                        product.reaches(age) && self.search_environment(**product).is_some()
+                    })
+                    .map(|product| FetchAttempt::Search(*product)),
+            );
+        }
+        Ok(attempts)
+    }
+
+    fn not_cached(user: &String) -> FetchError {
+        format!("Tweets for {} are not cached", user).into()
+    }
+
+    fn nothing_found(rate_limited: Option<RateLimited>) -> FetchError {
+        match rate_limited {
+            Some(rate_limited) => FetchError::RateLimited(rate_limited),
+            None => {
+                format!("No tweets found, but can't guarantee no tweets should have been found")
+                    .into()
+            }
+        }
+    }
+
+    fn search_environment(&self, product: SearchProduct) -> Option<&String> {
+        match product {
+            SearchProduct::ThirtyDay => self.search_environments.thirty_day.as_ref(),
+            SearchProduct::FullArchive => self.search_environments.full_archive.as_ref(),
+        }
+    }
+
+    fn rate_limit_key(context: Option<&Context>, endpoint: &str) -> RateLimitKey {
+        (
+            endpoint.to_owned(),
+            context.map(|context| context.user_screen_name.clone()),
+        )
+    }
+
+    // Counts a request about to be made against its category's quota, or refuses it if either the
+    // quota or Twitter's rate limit is exhausted.
+    fn reserve_request(&self, key: &RateLimitKey, category: ApiCategory) -> Result<(), FetchError> {
+        self.check_rate_limit(key)?;
+        let now = SecondsSinceUnixEpoch::now();
+        let quota = self.quotas.get(&category);
+        let mut api_usage = self.api_usage.lock().unwrap();
+        let usage = api_usage.entry(category).or_insert(ApiUsage {
+            category,
+            requests: 0,
+            window_start: now,
+            quota: quota.map(|quota| quota.max_requests),
+        });
+        if let Some(quota) = quota {
+            let window_end = SecondsSinceUnixEpoch(usage.window_start.0 + quota.window_secs);
+            if window_end <= now {
+                usage.requests = 0;
+                usage.window_start = now;
+            } else if usage.requests >= quota.max_requests {
+                return Err(FetchError::RateLimited(RateLimited {
+                    endpoint: key.0.clone(),
+                    reset: window_end,
+                }));
+            }
+        }
+        usage.requests += 1;
+        Ok(())
+    }
+
+    // How many calls of each category have been made in their current windows.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.lock().unwrap().clone();
+        for user in self.users() {
+            if let Some(interval_store_lock) = self.existing_interval_store(&user) {
+                let count = interval_store_lock.read().unwrap().view().len();
+                metrics.tweets_cached.insert(user, count);
+            }
+        }
+        metrics
+    }
+
+    fn count_lookup(&self, hit: bool) {
+        let mut metrics = self.metrics.lock().unwrap();
+        if hit {
+            metrics.cache_hits += 1;
+        } else {
+            metrics.cache_misses += 1;
+        }
+    }
+
+    fn count_api_call(&self, endpoint: &str) {
+        *self
+            .metrics
+            .lock()
+            .unwrap()
+            .api_calls
+            .entry(endpoint.to_owned())
+            .or_insert(0) += 1;
+    }
+
+    // Returns the error, so that it can be counted in passing.
+    fn count_error(&self, err: FetchError) -> FetchError {
+        *self
+            .metrics
+            .lock()
+            .unwrap()
+            .errors
+            .entry(err.kind())
+            .or_insert(0) += 1;
+        err
+    }
+
+    pub fn api_usage(&self) -> Vec<ApiUsage> {
+        let mut api_usage: Vec<_> = self.api_usage.lock().unwrap().values().cloned().collect();
+        api_usage.sort_by_key(|usage| usage.category);
+        api_usage
+    }
+
+    // Rejects a request which Twitter has told us would be refused, or which would use requests
+    // left for higher priority fetches, rather than sending it.
+    fn check_rate_limit(&self, key: &RateLimitKey) -> Result<(), FetchError> {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        if let Some(rate_limit) = rate_limits.get(key).cloned() {
+            if rate_limit.reset <= SecondsSinceUnixEpoch::now() {
+                rate_limits.remove(key);
+            } else if rate_limit.remaining <= self.rate_limit_reserve() {
+                return Err(FetchError::RateLimited(RateLimited {
+                    endpoint: key.0.clone(),
+                    reset: rate_limit.reset,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    // How many requests to leave in each rate limit window for fetches of higher priority.
+    fn rate_limit_reserve(&self) -> u64 {
+        match self.priority {
+            FetchPriority::Interactive => 0,
+            FetchPriority::Background => self.fetch_scheduler.policy.background_rate_limit_reserve,
+        }
+    }
+
+    // Remembers the rate limit status from the response's headers, and turns a 429 into an error.
+    fn record_rate_limit(
+        &self,
+        key: RateLimitKey,
+        response: &ApiResponse,
+    ) -> Result<(), FetchError> {
+        let header = |name: &str| {
+            response
+                .header(name)
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        let too_many_requests = response.status == reqwest::StatusCode::TOO_MANY_REQUESTS.as_u16();
+        let remaining = if too_many_requests {
+            Some(0)
+        } else {
+            header("x-rate-limit-remaining")
+        };
+        // Retry-After is in seconds from now, and only sent with 429s.
+        let reset = header("x-rate-limit-reset")
+            .or_else(|| header("retry-after").map(|secs| SecondsSinceUnixEpoch::now().0 + secs))
+            .map(SecondsSinceUnixEpoch);
+        if let (Some(remaining), Some(reset)) = (remaining, reset) {
+            self.rate_limits
+                .lock()
+                .unwrap()
+                .insert(key.clone(), RateLimit { remaining, reset });
+        }
+        if too_many_requests {
+            return Err(FetchError::RateLimited(RateLimited {
+                endpoint: key.0,
+                // Without a reset time, assume the usual 15 minute rate limit window.
+                reset: reset.unwrap_or_else(|| {
+                    SecondsSinceUnixEpoch(SecondsSinceUnixEpoch::now().0 + 15 * 60)
+                }),
+            }));
+        }
+        Ok(())
+    }
+
+    fn record_provenance(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        source: TweetSource,
+    ) {
+        self.provenance
+            .write()
+            .unwrap()
+            .entry(user.clone())
+            .or_default()
+            .record(IntervalProvenance {
+                interval: *interval,
+                source,
+                fetched_at: SecondsSinceUnixEpoch::now(),
+            });
+        // The tweets were just fetched, so any earlier lookups of them are out of date.
+        if let Some(refreshed_at) = self.refreshed_at.lock().unwrap().get_mut(user) {
+            let refetched: Vec<Snowflake> = refreshed_at
+                .range(interval.0..=interval.1)
+                .map(|(&id, _)| id)
+                .collect();
+            for id in refetched {
+                refreshed_at.remove(&id);
+            }
+        }
+    }
+
+    // Where each part of the user's cached tweets last came from, in order.
+    pub fn provenance(&self, user: &String) -> Vec<IntervalProvenance> {
+        let provenance = self.provenance.read().unwrap();
+        provenance
+            .get(user)
+            .map(|provenance| provenance.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn needs_refresh(&self, provenance: &IntervalProvenance) -> bool {
+        self.refresh_policy
+            .needs_refresh(provenance, SecondsSinceUnixEpoch::now())
+    }
+
+    // Pages back from the end of the interval until Twitter runs out of tweets in it, or None if
+    // the interval starts before the oldest tweet user_timeline can return. Knowing the horizon
+    // replaces fetching an extra page from before an empty interval to prove that it's empty:
+    // within the horizon, it always is.
+    fn fetch_usertimeline(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Option<Vec<TweetFromTwitter>>, FetchError> {
+        if !self.timeline_horizon(context, user)?.reaches(interval) {
+            return Ok(None);
+        }
+        let client = UserTimelineClient {
+            store: self,
+            context,
+            user,
+        };
+        self.twitter
+            .fetch_timeline(&client, user, interval)
+            .map(Some)
+    }
+
+    fn finish_usertimeline(mut tweets: Vec<TweetFromTwitter>) -> Vec<TweetFromTwitter> {
+        tweets.sort();
+        tweets.dedup();
+        tweets
+    }
+
+    fn timeline_horizon(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+    ) -> Result<TimelineHorizon, FetchError> {
+        // The oldest reachable tweet only moves when the user tweets (or deletes a tweet), so the
+        // horizon is only looked up again, which takes several pages, when statuses_count changes.
+        let statuses_count = self.fetch_statuses_count(context, user)?;
+        if let Some(horizon) = self.cached_timeline_horizon(user, statuses_count) {
+            return Ok(horizon);
+        }
+        let horizon = if statuses_count <= USER_TIMELINE_LIMIT {
+            TimelineHorizon::Everything
+        } else {
+            TimelineHorizon::OldestReachable(self.fetch_oldest_reachable(context, user)?)
+        };
+        self.record_timeline_horizon(user, horizon, statuses_count);
+        Ok(horizon)
+    }
+
+    fn cached_timeline_horizon(
+        &self,
+        user: &String,
+        statuses_count: u64,
+    ) -> Option<TimelineHorizon> {
+        let timeline_horizons = self.timeline_horizons.lock().unwrap();
+        match timeline_horizons.get(user) {
+            Some(&(horizon, found_at_count)) if found_at_count == statuses_count => Some(horizon),
+            _ => None,
+        }
+    }
+
+    fn record_timeline_horizon(
+        &self,
+        user: &String,
+        horizon: TimelineHorizon,
+        statuses_count: u64,
+    ) {
+        let mut timeline_horizons = self.timeline_horizons.lock().unwrap();
+        timeline_horizons.insert(user.clone(), (horizon, statuses_count));
+    }
+
+    // Pages back through user_timeline until it runs out, to find the oldest tweet it returns. If
+    // it returns nothing at all, nothing before now is reachable.
+    fn fetch_oldest_reachable(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+    ) -> Result<Snowflake, FetchError> {
+        // The first page is fetched without a max_id, so that the request doesn't depend on when
+        // it was made and can be replayed from a cassette.
+        let mut oldest = None;
+        for _ in 0..MAX_USER_TIMELINE_PAGES {
+            let page = self.fetch_usertimeline_page(context, user, None, oldest)?;
+            match TweetStore::oldest_before(oldest, &page) {
+                Some(oldest_on_page) => oldest = Some(oldest_on_page),
+                None => break,
+            }
+        }
+        Ok(oldest.unwrap_or_else(|| Snowflake::from(SecondsSinceUnixEpoch::now())))
+    }
+
+    // The oldest tweet on the page, if it's older than oldest so far.
+    fn oldest_before(oldest: Option<Snowflake>, page: &[TweetFromTwitter]) -> Option<Snowflake> {
+        page.iter()
+            .map(|tweet| tweet.id)
+            .min()
+            .filter(|&oldest_on_page| oldest.map_or(true, |oldest| oldest_on_page < oldest))
+    }
+
+    // How many tweets, including retweets, the user has posted.
+    fn fetch_statuses_count(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+    ) -> Result<u64, FetchError> {
+        info!("Fetching from users/show"); // TODO: Binary log requests and responses.
+
+        let rate_limit_key = TweetStore::rate_limit_key(context, "users/show");
+        self.reserve_request(&rate_limit_key, ApiCategory::UsersShow)?;
+        let request = ApiRequest::get(USERS_SHOW_URL, TweetStore::users_show_params(user));
+        let json_string = self.send(context, &request, rate_limit_key)?;
+
+        serde_json::from_str::<UserFromTwitter>(&json_string)
+            .map(|user| user.statuses_count)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err).into())
+    }
+
+    fn users_show_params(user: &String) -> Vec<(String, String)> {
+        vec![
+            ("screen_name".to_owned(), user.to_owned()),
+            ("include_entities".to_owned(), "false".to_owned()),
+        ]
+    }
+
+    // Tweets after since_id, up to and including max_id (each, if any); at most a page's worth,
+    // newest first. Fetched with app-only auth, unless the account is protected, in which case the
+    // viewer's token is used.
+    fn fetch_usertimeline_page(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        since_id: Option<Snowflake>,
+        max_id: Option<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let client = UserTimelineClient {
+            store: self,
+            context,
+            user,
+        };
+        Twitter::fetch_page(&client, user, since_id, max_id)
+    }
+
+    // The viewer whose token to fetch the user's timeline with, or None for app-only auth.
+    fn usertimeline_auth<'a>(
+        &self,
+        context: Option<&'a Context>,
+        user: &String,
+    ) -> Result<Option<&'a Context>, FetchError> {
+        if !self.protected_accounts.lock().unwrap().contains_key(user) {
+            return Ok(None);
+        }
+        match context {
+            Some(context) => Ok(Some(context)),
+            None => Err(TweetStore::protected(user)),
+        }
+    }
+
+    // Records what fetching the user's timeline with auth says about who can see it. Returns the
+    // viewer to retry as, if app-only auth was refused but there is a viewer.
+    fn record_usertimeline_result<'a, T>(
+        &self,
+        auth: Option<&Context>,
+        context: Option<&'a Context>,
+        user: &String,
+        result: &Result<T, FetchError>,
+    ) -> Option<&'a Context> {
+        let mut protected_accounts = self.protected_accounts.lock().unwrap();
+        match (auth, result) {
+            (None, &Err(FetchError::NotAuthorized(_))) => {
+                info!("Timeline of {} is protected", user);
+                protected_accounts
+                    .entry(user.clone())
+                    .or_insert_with(HashSet::new);
+                context
+            }
+            (Some(viewer), &Ok(_)) => {
+                if let Some(viewers) = protected_accounts.get_mut(user) {
+                    viewers.insert(viewer.user_screen_name.clone());
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn check_visible(&self, context: Option<&Context>, user: &String) -> Result<(), FetchError> {
+        if let Some(viewer) = self.unverified_viewer(context, user)? {
+            self.fetch_usertimeline_page(Some(viewer), user, None, None)?;
+        }
+        Ok(())
+    }
+
+    // The viewer, if the user's timeline is protected and Twitter hasn't yet shown it to them, in
+    // which case it must before any cached tweets are served to them.
+    fn unverified_viewer<'a>(
+        &self,
+        context: Option<&'a Context>,
+        user: &String,
+    ) -> Result<Option<&'a Context>, FetchError> {
+        match self.protected_accounts.lock().unwrap().get(user) {
+            None => Ok(None),
+            Some(viewers) => match context {
+                Some(context) if viewers.contains(&context.user_screen_name) => Ok(None),
+                Some(context) => Ok(Some(context)),
+                None => Err(TweetStore::protected(user)),
+            },
+        }
+    }
+
+    fn protected(user: &String) -> FetchError {
+        FetchError::NotAuthorized(format!(
+            "The timeline of {} is protected, so can only be seen when logged in as a follower",
+            user
+        ))
+    }
+
+    // Sends a user_timeline request as the viewer, or with app-only auth if there is none.
+    fn send_usertimeline_request_as(
+        &self,
+        context: Option<&Context>,
+        request: &ApiRequest,
+    ) -> Result<String, FetchError> {
+        let rate_limit_key = TweetStore::rate_limit_key(context, USER_TIMELINE_ENDPOINT);
+        self.reserve_request(&rate_limit_key, ApiCategory::UserTimeline)?;
+        let json_string = self.send(context, request, rate_limit_key)?;
+
+        debug!("Response from user timeline: {}", json_string);
+
+        Ok(json_string)
+    }
+
+    // Pages back from the end of the interval until Twitter runs out of tweets in it. Unlike
+    // user_timeline, there's no way to tell whether that was because the timeline doesn't reach
+    // any further back, so whatever it returns is taken to be complete.
+    fn fetch_timeline(
+        &self,
+        context: Option<&Context>,
+        timeline: Timeline,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let mut tweets = Vec::new();
+        let mut max_id = interval.1;
+        for _ in 0..timeline.max_pages() {
+            let page = self.fetch_timeline_page(context, timeline, interval.0, max_id)?;
+            let next_max_id = twitter::next_max_id(interval, &page);
+            tweets.extend(page);
+            match next_max_id {
+                Some(next_max_id) => max_id = next_max_id,
+                None => return Ok(TweetStore::finish_usertimeline(tweets)),
+            }
+        }
+        Err(format!(
+            "Too many pages of tweets from {} between {} and {}",
+            timeline.endpoint(),
+            interval.0,
+            interval.1
+        )
+        .into())
+    }
+
+    // Tweets after since_id, up to and including max_id; at most a page's worth, newest first.
+    fn fetch_timeline_page(
+        &self,
+        context: Option<&Context>,
+        timeline: Timeline,
+        since_id: Snowflake,
+        max_id: Snowflake,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        info!("Fetching from {}", timeline.endpoint()); // TODO: Binary log requests and responses.
+
+        let rate_limit_key = TweetStore::rate_limit_key(context, timeline.endpoint());
+        self.reserve_request(&rate_limit_key, timeline.category())?;
+        let request = ApiRequest::get(timeline.url(), timeline.params(since_id, max_id));
+        let json_string = self.send(context, &request, rate_limit_key)?;
+
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err).into())
+    }
+
+    fn send_unauthenticated(
+        &self,
+        service: &str,
+        request: &ApiRequest,
+    ) -> Result<String, FetchError> {
+        self.count_api_call(service);
+        let response = self.exchange(request, || self.send_request(request, None, &[]))?;
+        TweetStore::check_available(&request.url, &response)?;
+        match response.status {
+            status if status >= 200 && status < 300 => Ok(response.body),
+            429 => Err(FetchError::RateLimited(RateLimited {
+                endpoint: service.to_owned(),
+                // Mastodon gives a timestamp, and Bluesky seconds since the epoch. Both use 5
+                // minute windows.
+                reset: response
+                    .header("x-ratelimit-reset")
+                    .and_then(SecondsSinceUnixEpoch::parse_utc_timestamp)
+                    .or_else(|| {
+                        response
+                            .header("ratelimit-reset")
+                            .and_then(|reset| reset.parse().ok())
+                            .map(SecondsSinceUnixEpoch)
+                    })
+                    .unwrap_or_else(|| {
+                        SecondsSinceUnixEpoch(SecondsSinceUnixEpoch::now().0 + 5 * 60)
+                    }),
+            })),
+            status => {
+                Err(format!("Error {} from {}: {}", status, request.url, response.body).into())
+            }
+        }
+    }
+
+    fn fetch_user_tweets_from_search(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        product: SearchProduct,
+        options: &SearchOptions,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        self.fetch_tweets_from_search(context, &options.query(user), interval, product)
+    }
+
+    // Follows next tokens until every page of results has been fetched.
+    fn fetch_tweets_from_search(
+        &self,
+        context: Option<&Context>,
+        query: &str,
+        interval: &Interval<Snowflake>,
+        product: SearchProduct,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let environment = self.configured_search_environment(product)?;
+        let mut tweets = Vec::new();
+        let mut next = None;
+        for _ in 0..MAX_SEARCH_PAGES {
+            let response = self.fetch_search_page(
+                context,
+                query,
+                interval,
+                product,
+                environment,
+                next.take(),
+            )?;
+            tweets.extend(response.results);
+            next = response.next;
+            if next.is_none() {
+                tweets.sort();
+                tweets.dedup();
+                return Ok(tweets);
+            }
+        }
+        Err(TweetStore::too_many_search_pages(query, interval))
+    }
+
+    fn configured_search_environment(&self, product: SearchProduct) -> Result<&String, FetchError> {
+        self.search_environment(product)
+            .ok_or_else(|| format!("No environment is configured for {:?} search", product).into())
+    }
+
+    fn too_many_search_pages(query: &str, interval: &Interval<Snowflake>) -> FetchError {
+        format!(
+            "Search for {} in {:?} returned more than {} pages",
+            query, interval, MAX_SEARCH_PAGES
+        )
+        .into()
+    }
+
+    fn search_url(product: SearchProduct, environment: &str) -> String {
+        format!(
+            "https://api.twitter.com/1.1/tweets/search/{}/{}.json",
+            product.path(),
+            environment
+        )
+    }
+
+    fn search_rate_limit_key(
+        context: Option<&Context>,
+        product: SearchProduct,
+        environment: &str,
+    ) -> RateLimitKey {
+        TweetStore::rate_limit_key(
+            context,
+            &format!("tweets/search/{}/{}", product.path(), environment),
+        )
+    }
+
+    fn search_params(
+        query: &str,
+        interval: &Interval<Snowflake>,
+        next: Option<String>,
+    ) -> BTreeMap<&'static str, String> {
+        let mut params: BTreeMap<&str, String> = vec![
+            ("query", query.to_owned()),
+            ("fromDate", TweetStore::as_twitter_time(&interval.0.into())),
+            ("toDate", TweetStore::as_twitter_time(&interval.1.into())),
+        ]
+        .into_iter()
+        .collect();
+        if let Some(next) = next {
+            params.insert("next", next);
+        }
+        params
+    }
+
+    fn search_request(
+        query: &str,
+        interval: &Interval<Snowflake>,
+        product: SearchProduct,
+        environment: &str,
+        next: Option<String>,
+    ) -> Result<ApiRequest, FetchError> {
+        let params = TweetStore::search_params(query, interval, next);
+        let json = serde_json::to_string(&params)
+            .map_err(|err| format!("Error serializing search request: {:?}", err))?;
+        Ok(ApiRequest::post(
+            &TweetStore::search_url(product, environment),
+            json,
+        ))
+    }
+
+    fn fetch_search_page(
+        &self,
+        context: Option<&Context>,
+        query: &str,
+        interval: &Interval<Snowflake>,
+        product: SearchProduct,
+        environment: &str,
+        next: Option<String>,
+    ) -> Result<ResponseFromTwitter, FetchError> {
+        info!("Fetching from search API"); // TODO: Binary log requests and responses.
+        let rate_limit_key = TweetStore::search_rate_limit_key(context, product, environment);
+        self.reserve_request(&rate_limit_key, product.category())?;
+        let request = TweetStore::search_request(query, interval, product, environment, next)?;
+        let json_string = self.send(context, &request, rate_limit_key)?;
+
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err).into())
+    }
+
+    // Makes the request, or replays its recorded response, and returns the body of the response.
+    fn send(
+        &self,
+        context: Option<&Context>,
+        request: &ApiRequest,
+        rate_limit_key: RateLimitKey,
+    ) -> Result<String, FetchError> {
+        self.count_api_call(&rate_limit_key.0);
+        let response = self.exchange(request, || {
+            let authorization = self.authorization(
+                context,
+                request.method(),
+                &url::Url::parse(&request.url).expect("Bad twitter URL"),
+                request.query.clone(),
+            )?;
+            let _permit = self.fetch_scheduler.start(self.priority)?;
+            let response = self.send_request(
+                request,
+                Some(authorization),
+                &self.conditional_headers(request),
+            )?;
+            Ok(self.resolve_cached(request, response))
+        })?;
+        self.record_rate_limit(rate_limit_key, &response)?;
+        TweetStore::check_available(&request.url, &response)?;
+        TweetStore::check_authorized(&request.url, &response)?;
+        Ok(response.body)
+    }
+
+    fn check_available(url: &str, response: &ApiResponse) -> Result<(), FetchError> {
+        if response.status >= 500 {
+            return Err(FetchError::Unavailable(format!(
+                "Error {} from {}: {}",
+                response.status, url, response.body
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_authorized(url: &str, response: &ApiResponse) -> Result<(), FetchError> {
+        if response.status == 401 {
+            return Err(FetchError::NotAuthorized(format!(
+                "Not authorized by {}: {}",
+                url, response.body
+            )));
+        }
+        Ok(())
+    }
+
+    // Replays the request's recorded response when replaying. Otherwise sends it, recording the
+    // response when recording.
+    fn exchange<F>(&self, request: &ApiRequest, send: F) -> Result<ApiResponse, FetchError>
+    where
+        F: FnOnce() -> Result<ApiResponse, FetchError>,
+    {
+        match self.cassette {
+            Some(ref cassette) if cassette.mode() == CassetteMode::Replay => {
+                Ok(cassette.replay(request)?)
+            }
+            _ => {
+                let response = send()?;
+                if let Some(ref cassette) = self.cassette {
+                    cassette.record(request, &response)?;
+                }
+                Ok(response)
+            }
+        }
+    }
+
+    fn send_request(
+        &self,
+        request: &ApiRequest,
+        authorization: Option<String>,
+        headers: &[(&'static str, String)],
+    ) -> Result<ApiResponse, FetchError> {
+        let client = &self.http_client;
+        let mut builder = match request.json {
+            Some(ref json) => client
+                .post(request.url.as_str())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json.clone()),
+            None => client.get(request.url.as_str()).query(&request.query),
+        };
+        if let Some(authorization) = authorization {
+            builder = builder.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+        for &(name, ref value) in headers {
+            builder = builder.header(name, value.as_str());
+        }
+        let response = builder.send().map_err(|err| {
+            FetchError::Unavailable(format!(
+                "Error making request to {}: {:?}",
+                request.url, err
+            ))
+        })?;
+        let status = response.status().as_u16();
+        let headers = TweetStore::response_headers(response.headers());
+        let body = response
+            .text()
+            .map_err(|err| format!("Error getting text from {} {:?}", request.url, err))?;
+        Ok(ApiResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn conditional_headers(&self, request: &ApiRequest) -> Vec<(&'static str, String)> {
+        self.http_cache
+            .as_ref()
+            .map_or(vec![], |http_cache| http_cache.conditional_headers(request))
+    }
+
+    fn resolve_cached(&self, request: &ApiRequest, response: ApiResponse) -> ApiResponse {
+        match self.http_cache {
+            Some(ref http_cache) => http_cache.resolve(request, response),
+            None => response,
+        }
+    }
+
+    fn response_headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|value| (name.as_str().to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    fn authorization(
+        &self,
+        context: Option<&Context>,
+        method: &str,
+        url: &url::Url,
+        params: Vec<(String, String)>,
+    ) -> Result<String, String> {
+        match context {
+            Some(context) => Ok(oauth::oauth1_header(
+                method,
+                url,
+                &self.app_token,
+                Some(&context.user_oauth_token),
+                params,
+            )),
+            None => {
+                if let Some(ref app_bearer_token) = *self.app_bearer_token.lock().unwrap() {
+                    return Ok(format!("Bearer {}", app_bearer_token));
+                }
+                // Not locked while requesting, so that other requests aren't held up behind it.
+                // Concurrent requests may each request one, but Twitter gives them all the same.
+                let app_bearer_token = oauth::app_bearer_token(&self.http_client, &self.app_token)?;
+                *self.app_bearer_token.lock().unwrap() = Some(app_bearer_token.clone());
+                Ok(format!("Bearer {}", app_bearer_token))
+            }
+        }
+    }
+
+    fn as_twitter_time(s: &SecondsSinceUnixEpoch) -> String {
+        let tm = time::strptime(&format!("{}", s), "%s").expect("Parsing tm from snowflake");
+        format!(
+            "{}",
+            tm.strftime("%Y%m%d%H%M")
+                .expect("Formatting tm as twitter time")
+        )
+    }
+
+    fn get_known_tweets(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, IntervalSet<Snowflake>> {
+        let interval_store_lock = self.interval_store(user);
+        let (tweets, missing) = interval_store_lock.read().unwrap().get_partial(interval);
+        if missing.is_empty() {
+            Ok(tweets)
+        } else {
+            Err(missing)
+        }
+    }
+
+    // Fetches the user's tweets from the last window up to now, replacing whatever was cached for
+    // it, so that replays running up to now are served from the cache rather than each fetching
+    // the last few seconds. Polling more often than the window is long keeps the cached interval
+    // unbroken. Returns how many tweets are cached for the window.
+    pub fn poll_live_edge(&self, user: &String, window: Duration) -> Result<usize, FetchError> {
+        let now = SecondsSinceUnixEpoch::now();
+        let interval = Interval(
+            Snowflake::from(SecondsSinceUnixEpoch(
+                now.0.saturating_sub(window.as_secs()),
+            )),
+            Snowflake::from(now),
+        );
+        let fetch_result = self.fetch_tweets(None, user, &interval);
+        self.record_fetch_result(user, fetch_result.is_ok());
+        fetch_result.map_err(|err| self.count_error(err))?;
+        Ok(self
+            .interval_store(user)
+            .read()
+            .unwrap()
+            .get_available(&interval)
+            .len())
+    }
+
+    // Re-checks cached tweets against Twitter in batches, dropping those which have since been
+    // deleted (or withheld, per the policy). Tweets statuses/lookup leaves out are only dropped if
+    // statuses/show confirms they don't exist, as it also leaves out the tweets of protected and
+    // suspended accounts. Protected accounts' tweets aren't re-checked at all, as app-only auth
+    // can't see them. Their intervals stay covered, so that replays don't resurrect them by
+    // refetching. Returns how many tweets were dropped.
+    pub fn revalidate(&self, policy: &RevalidationPolicy) -> Result<usize, FetchError> {
+        let oldest = policy.max_age.map(|max_age| {
+            let now = SecondsSinceUnixEpoch::now();
+            Snowflake::from(SecondsSinceUnixEpoch(
+                now.0.saturating_sub(max_age.as_secs()),
+            ))
+        });
+        let mut dropped = 0;
+        for user in self.users() {
+            dropped += self.revalidate_user(&user, oldest, policy.drop_withheld)?;
+        }
+        Ok(dropped)
+    }
+
+    fn revalidate_user(
+        &self,
+        user: &String,
+        oldest: Option<Snowflake>,
+        drop_withheld: bool,
+    ) -> Result<usize, FetchError> {
+        if self.protected_accounts.lock().unwrap().contains_key(user) {
+            return Ok(0);
+        }
+        let interval_store_lock = match self.existing_interval_store(user) {
+            Some(interval_store_lock) => interval_store_lock,
+            None => return Ok(0),
+        };
+        let mut ids = Vec::new();
+        {
+            let tweets = interval_store_lock.read().unwrap().view();
+            for interval in tweets.covered().iter() {
+                ids.extend(
+                    tweets
+                        .iter_within(interval)
+                        .map(|tweet| tweet.id)
+                        .filter(|&id| oldest.map_or(true, |oldest| id >= oldest)),
+                );
+            }
+        }
+
+        let mut gone = HashSet::new();
+        for batch in ids.chunks(STATUSES_LOOKUP_BATCH_SIZE) {
+            let looked_up = self.lookup_statuses::<LookedUpTweet>(batch, false)?;
+            gone.extend(
+                looked_up
+                    .iter()
+                    .filter(|tweet| drop_withheld && tweet.is_withheld())
+                    .map(|tweet| tweet.id),
+            );
+            let available: HashSet<Snowflake> = looked_up.iter().map(|tweet| tweet.id).collect();
+            gone.extend(self.deleted_among(batch, &available)?);
+        }
+        if gone.is_empty() {
+            return Ok(0);
+        }
+        self.forget_looked_up(&gone);
+        if let Some(refreshed_at) = self.refreshed_at.lock().unwrap().get_mut(user) {
+            for id in gone.iter() {
+                refreshed_at.remove(id);
+            }
+        }
+
+        let mut dropped = 0;
+        let mut changed = Vec::new();
+        {
+            let mut interval_store = interval_store_lock.write().unwrap();
+            let covered: Vec<Interval<Snowflake>> =
+                interval_store.covered().iter().cloned().collect();
+            for interval in covered {
+                let dropped_here =
+                    interval_store.retain_in(&interval, |tweet| !gone.contains(&tweet.id));
+                if dropped_here > 0 {
+                    dropped += dropped_here;
+                    changed.push((interval, interval_store.get(&interval).unwrap_or_default()));
+                }
+            }
+        }
+        if let Some(ref persistence) = self.persistence {
+            for (interval, tweets) in changed {
+                if let Err(err) = persistence.save(user, &interval, &tweets) {
+                    warn!("Error persisting revalidated tweets for {}: {}", user, err);
+                }
+            }
+        }
+        Ok(dropped)
+    }
+
+    // Replaces the user's cached tweets with the ids, e.g. ones only known by id or imported from
+    // an archive, with the full tweets from statuses/lookup, persisting the intervals they're in.
+    // Tweets which can't be looked up, e.g. because they've been deleted, are left as they were.
+    // Returns the full tweets.
+    pub fn hydrate(
+        &self,
+        user: &String,
+        ids: &[Snowflake],
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let interval_store_lock = match self.existing_interval_store(user) {
+            Some(interval_store_lock) => interval_store_lock,
+            None => return Ok(vec![]),
+        };
+        let mut hydrated = Vec::new();
+        for batch in ids.chunks(STATUSES_LOOKUP_BATCH_SIZE) {
+            hydrated.extend(self.lookup_statuses::<TweetFromTwitter>(batch, true)?);
+        }
+        self.replace_cached(user, &interval_store_lock, &hydrated, &HashSet::new())?;
+        Ok(hydrated)
+    }
+
+    // Looks up cached tweets which haven't been fetched or refreshed for longer than the policy
+    // allows, in batches, replacing them with what Twitter now says about them, and dropping those
+    // which statuses/show confirms have since been deleted. Protected accounts' tweets can't be looked up with app-only
+    // auth, so are left alone. Returns how many tweets were refreshed, and how many dropped.
+    pub fn refresh_stale(&self, policy: &TweetRefreshPolicy) -> Result<(usize, usize), FetchError> {
+        let oldest_fresh = SecondsSinceUnixEpoch(
+            SecondsSinceUnixEpoch::now()
+                .0
+                .saturating_sub(policy.max_staleness.as_secs()),
+        );
+        let mut tweets_left = policy.max_tweets.unwrap_or(usize::max_value());
+        let mut refreshed = 0;
+        let mut dropped = 0;
+        for user in self.users() {
+            if tweets_left == 0 {
+                break;
+            }
+            if self.protected_accounts.lock().unwrap().contains_key(&user) {
+                continue;
+            }
+            let interval_store_lock = match self.existing_interval_store(&user) {
+                Some(interval_store_lock) => interval_store_lock,
+                None => continue,
+            };
+            let mut ids = self.stale_ids(&user, &interval_store_lock, oldest_fresh);
+            ids.truncate(tweets_left);
+            tweets_left -= ids.len();
+            for batch in ids.chunks(STATUSES_LOOKUP_BATCH_SIZE) {
+                let looked_up = self.lookup_statuses::<TweetFromTwitter>(batch, true)?;
+                let found: HashSet<Snowflake> = looked_up.iter().map(|tweet| tweet.id).collect();
+                let gone = self.deleted_among(batch, &found)?;
+                refreshed += looked_up.len();
+                dropped += self.replace_cached(&user, &interval_store_lock, &looked_up, &gone)?;
+            }
+        }
+        Ok((refreshed, dropped))
+    }
+
+    // The user's cached tweets which were last fetched or refreshed before oldest_fresh. Tweets
+    // with no provenance, e.g. ones evicted from it, count as stale. Refresh times of tweets which
+    // are no longer cached are forgotten along the way.
+    fn stale_ids(
+        &self,
+        user: &String,
+        interval_store_lock: &RwLock<IntervalStore<Snowflake, TweetFromTwitter>>,
+        oldest_fresh: SecondsSinceUnixEpoch,
+    ) -> Vec<Snowflake> {
+        let provenance = self.provenance.read().unwrap();
+        let provenance = provenance.get(user);
+        let mut refreshed_at = self.refreshed_at.lock().unwrap();
+        let mut still_cached = BTreeMap::new();
+        let tweets = interval_store_lock.read().unwrap().view();
+        let mut ids = Vec::new();
+        for interval in tweets.covered().iter() {
+            for tweet in tweets.iter_within(interval) {
+                let refreshed = refreshed_at
+                    .get(user)
+                    .and_then(|refreshed_at| refreshed_at.get(&tweet.id))
+                    .cloned();
+                if let Some(refreshed) = refreshed {
+                    still_cached.insert(tweet.id, refreshed);
+                }
+                let last_fetched = refreshed.or_else(|| {
+                    provenance
+                        .and_then(|provenance| provenance.at(tweet.id))
+                        .map(|provenance| provenance.fetched_at)
+                });
+                if last_fetched.map_or(true, |last_fetched| last_fetched < oldest_fresh) {
+                    ids.push(tweet.id);
+                }
+            }
+        }
+        if still_cached.is_empty() {
+            refreshed_at.remove(user);
+        } else {
+            refreshed_at.insert(user.clone(), still_cached);
+        }
+        ids
+    }
+
+    // Replaces the user's cached tweets with ones looked up by id, and drops those which are gone,
+    // leaving their intervals covered so that replays don't resurrect them by refetching. The
+    // intervals which changed are persisted. Returns how many tweets were dropped.
+    fn replace_cached(
+        &self,
+        user: &String,
+        interval_store_lock: &RwLock<IntervalStore<Snowflake, TweetFromTwitter>>,
+        looked_up: &[TweetFromTwitter],
+        gone: &HashSet<Snowflake>,
+    ) -> Result<usize, FetchError> {
+        self.record_media_urls(looked_up);
+        self.forget_looked_up(gone);
+        {
+            let now = SecondsSinceUnixEpoch::now();
+            let mut refreshed_at = self.refreshed_at.lock().unwrap();
+            let refreshed_at = refreshed_at
+                .entry(user.clone())
+                .or_insert_with(BTreeMap::new);
+            for tweet in looked_up {
+                refreshed_at.insert(tweet.id, now);
+            }
+            for id in gone {
+                refreshed_at.remove(id);
+            }
+        }
+
+        let mut dropped = 0;
+        let mut changed = BTreeSet::new();
+        let changed_tweets: Vec<_> = {
+            let mut interval_store = interval_store_lock.write().unwrap();
+            let covered = interval_store.covered();
+            for tweet in looked_up {
+                // Only tweets which are still cached are replaced, so that e.g. ones dropped by
+                // revalidation aren't brought back.
+                let cached = interval_store
+                    .value_at_or_after(tweet.id)
+                    .map_or(false, |cached| cached.id == tweet.id);
+                if cached {
+                    interval_store.upsert(tweet.clone())?;
+                    changed.extend(
+                        covered
+                            .iter()
+                            .filter(|interval| interval.contains(&tweet.id))
+                            .cloned(),
+                    );
+                }
+            }
+            if !gone.is_empty() {
+                for interval in covered.iter() {
+                    let dropped_here =
+                        interval_store.retain_in(interval, |tweet| !gone.contains(&tweet.id));
+                    if dropped_here > 0 {
+                        dropped += dropped_here;
+                        changed.insert(*interval);
+                    }
+                }
+            }
+            changed
+                .into_iter()
+                .map(|interval| (interval, interval_store.get(&interval).unwrap_or_default()))
+                .collect()
+        };
+        if let Some(ref persistence) = self.persistence {
+            for (interval, tweets) in changed_tweets {
+                if let Err(err) = persistence.save(user, &interval, &tweets) {
+                    warn!("Error persisting looked up tweets for {}: {}", user, err);
+                }
+            }
+        }
+        Ok(dropped)
+    }
+
+    // The user's cached tweet with the id, along with the tweets it replies to and quotes, which
+    // are looked up by id and cached apart from any timeline. Looking up a tweet also records it in
+    // its conversation, so that threads looked up later include it. None if the tweet isn't
+    // cached.
+    pub fn thread(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        id: Snowflake,
+    ) -> Result<Option<TweetThread>, FetchError> {
+        self.check_visible(context, user)?;
+        let tweet = match self
+            .existing_interval_store(user)
+            .and_then(|interval_store_lock| {
+                interval_store_lock.read().unwrap().value_at_or_after(id)
+            })
+            .filter(|tweet| tweet.id == id)
+        {
+            Some(tweet) => tweet,
+            None => return Ok(None),
+        };
+        let mut ancestors = self.chain(tweet.in_reply_to_status_id, |tweet| {
+            tweet.in_reply_to_status_id
+        })?;
+        ancestors.reverse();
+        let quoted = self.chain(tweet.quoted_status_id, |tweet| tweet.quoted_status_id)?;
+
+        let first = ancestors.first().unwrap_or(&tweet).id;
+        let conversation_ids = {
+            let mut conversations = self.conversations.lock().unwrap();
+            let mut conversation = conversations.remove(&first).unwrap_or_default();
+            conversation.extend(ancestors.iter().map(|tweet| tweet.id));
+            conversation.insert(tweet.id);
+            conversations.insert(first, conversation.clone());
+            conversation
+        };
+        let in_thread: HashSet<Snowflake> = ancestors.iter().map(|tweet| tweet.id).collect();
+        let conversation = {
+            let mut tweets_by_id = self.tweets_by_id.lock().unwrap();
+            conversation_ids
+                .iter()
+                .filter(|id| **id != tweet.id && !in_thread.contains(id))
+                .filter_map(|id| tweets_by_id.get(id).cloned())
+                .collect()
+        };
+        Ok(Some(TweetThread {
+            ancestors,
+            tweet,
+            quoted,
+            conversation,
+        }))
+    }
+
+    // Forgets tweets which are gone from threads too, so that they aren't served from there.
+    fn forget_looked_up(&self, gone: &HashSet<Snowflake>) {
+        if gone.is_empty() {
+            return;
+        }
+        {
+            let mut tweets_by_id = self.tweets_by_id.lock().unwrap();
+            for id in gone {
+                tweets_by_id.remove(id);
+            }
+        }
+        self.conversations
+            .lock()
+            .unwrap()
+            .retain(|_, conversation| {
+                conversation.retain(|id| !gone.contains(id));
+                !conversation.is_empty()
+            });
+    }
+
+    // Follows next from each tweet to the one after it, starting from first, until a tweet has
+    // no next or can't be looked up.
+    fn chain<F>(
+        &self,
+        first: Option<Snowflake>,
+        next: F,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError>
+    where
+        F: Fn(&TweetFromTwitter) -> Option<Snowflake>,
+    {
+        let mut chain = Vec::new();
+        let mut id = first;
+        while let Some(tweet_id) = id {
+            if chain.len() >= MAX_CHAIN_LENGTH {
+                break;
+            }
+            match self.tweet_by_id(tweet_id)? {
+                Some(tweet) => {
+                    id = next(&tweet);
+                    chain.push(tweet);
+                }
+                None => break,
+            }
+        }
+        Ok(chain)
+    }
+
+    // The tweet, looked up if it hasn't been already, or None if it doesn't exist (any more) or
+    // can't be seen with app-only auth.
+    fn tweet_by_id(&self, id: Snowflake) -> Result<Option<TweetFromTwitter>, FetchError> {
+        if let Some(tweet) = self.tweets_by_id.lock().unwrap().get(&id) {
+            return Ok(Some(tweet.clone()));
+        }
+        let tweet = self
+            .lookup_statuses::<TweetFromTwitter>(&[id], true)?
+            .into_iter()
+            .next();
+        if let Some(ref tweet) = tweet {
+            self.tweets_by_id
+                .lock()
+                .unwrap()
+                .insert(tweet.id, tweet.clone());
+        }
+        Ok(tweet)
+    }
+
+    // The tweets with the given ids which still exist; deleted tweets are left out. Only full
+    // lookups include the tweets' authors and entities.
+    fn lookup_statuses<T: DeserializeOwned>(
+        &self,
+        ids: &[Snowflake],
+        full: bool,
+    ) -> Result<Vec<T>, FetchError> {
+        info!("Fetching from statuses/lookup"); // TODO: Binary log requests and responses.
+
+        let rate_limit_key = TweetStore::rate_limit_key(None, "statuses/lookup");
+        self.reserve_request(&rate_limit_key, ApiCategory::StatusesLookup)?;
+        let ids: Vec<String> = ids.iter().map(|id| format!("{}", id)).collect();
+        let params = vec![
+            ("id".to_owned(), ids.join(",")),
+            ("include_entities".to_owned(), format!("{}", full)),
+            ("trim_user".to_owned(), format!("{}", !full)),
+        ];
+        let request = ApiRequest::get(STATUSES_LOOKUP_URL, params);
+        let json_string = self.send(None, &request, rate_limit_key)?;
+
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err).into())
+    }
+
+    // Those of the ids which statuses/lookup didn't return, and which statuses/show confirms have
+    // been deleted. Lookups also leave out tweets which still exist but can't be seen, which
+    // shouldn't be dropped.
+    fn deleted_among(
+        &self,
+        ids: &[Snowflake],
+        looked_up: &HashSet<Snowflake>,
+    ) -> Result<HashSet<Snowflake>, FetchError> {
+        let mut deleted = HashSet::new();
+        for &id in ids.iter().filter(|id| !looked_up.contains(id)) {
+            if self.confirmed_deleted(id)? {
+                deleted.insert(id);
+            }
+        }
+        Ok(deleted)
+    }
+
+    // Whether statuses/show says the tweet doesn't exist, rather than just being unavailable, e.g.
+    // because its author is protected or suspended.
+    fn confirmed_deleted(&self, id: Snowflake) -> Result<bool, FetchError> {
+        let rate_limit_key = TweetStore::rate_limit_key(None, "statuses/show");
+        self.reserve_request(&rate_limit_key, ApiCategory::StatusesShow)?;
+        let params = vec![
+            ("id".to_owned(), format!("{}", id)),
+            ("trim_user".to_owned(), "true".to_owned()),
+        ];
+        let request = ApiRequest::get(STATUSES_SHOW_URL, params);
+        let json_string = self.send(None, &request, rate_limit_key)?;
+
+        let response: ErrorsFromTwitter = serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err))?;
+        Ok(response
+            .errors
+            .iter()
+            .any(|error| error.code == NO_STATUS_FOUND_ERROR_CODE))
+    }
+
+    // Writes out the user's tweets in the interval, fetching any which are missing as tweets
+    // would. Returns how many were written.
+    pub fn export<W: Write>(
+        &self,
+        context: Option<&Context>,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        format: ExportFormat,
+        writer: &mut W,
+    ) -> Result<usize, FetchError> {
+        let feed_tweets = self.tweets(context, user, interval, TimelineOptions::default())?;
+        // Serving cached tweets after an error is fine for a feed, but not for an export, which
+        // would look complete.
+        if feed_tweets.partial {
+            return Err(FetchError::Unavailable(format!(
+                "Only some of {}'s tweets in {:?} could be fetched",
+                user, interval
+            )));
+        }
+        export::write_tweets(&feed_tweets.tweets, format, writer)?;
+        Ok(feed_tweets.tweets.len())
+    }
+
+    // Screen names of users whose tweets are at least partially cached.
+    pub fn users(&self) -> Vec<String> {
+        let mut users = self.tweets.keys();
+        users.sort();
+        users
+    }
+
+    // The windows of the given length (aligned to multiples of it) with the most cached tweets,
+    // busiest first. Only cached tweets are considered; nothing is fetched.
+    pub fn busiest_windows(
+        &self,
+        user: &String,
+        window: Duration,
+        count: usize,
+    ) -> Vec<TweetDensity> {
+        let window_secs = window.as_secs();
+        let mut tweets_per_window = BTreeMap::new();
+        if let Some(interval_store_lock) = self.existing_interval_store(user) {
+            let tweets = interval_store_lock.read().unwrap().view();
+            for interval in tweets.covered().iter() {
+                for tweet in tweets.iter_within(interval) {
+                    let seconds_since_unix_epoch: SecondsSinceUnixEpoch = tweet.time().into();
+                    *tweets_per_window
+                        .entry(seconds_since_unix_epoch.0 / window_secs)
+                        .or_insert(0) += 1;
+                }
+            }
+        }
+        let mut windows: Vec<_> = tweets_per_window.into_iter().collect();
+        windows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        windows
+            .into_iter()
+            .take(count)
+            .map(|(window_index, tweets)| TweetDensity {
+                from: SecondsSinceUnixEpoch(window_index * window_secs),
+                until: SecondsSinceUnixEpoch((window_index + 1) * window_secs),
+                tweets,
+            })
+            .collect()
+    }
+
+    // Shrinks the interval to exclude uncached slivers at either end which are no longer than the
+    // slack, so that requests which almost line up with cached tweets don't need to fetch.
+    pub fn snap_to_cached(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+        slack: Duration,
+    ) -> Interval<Snowflake> {
+        let interval_store_lock = match self.existing_interval_store(user) {
+            Some(interval_store_lock) => interval_store_lock,
+            None => return *interval,
+        };
+        let interval_store = interval_store_lock.read().unwrap();
+        let missing = interval_store.missing(interval);
+        if missing.contains(interval) {
+            return *interval;
+        }
+        let is_sliver =
+            |missing_interval: &Interval<Snowflake>| missing_interval.duration() <= slack;
+        let mut snapped = *interval;
+        if let Some(first) = missing.iter().next() {
+            if first.0 == interval.0 && is_sliver(first) {
+                snapped.0 = first.1;
+            }
+        }
+        if let Some(last) = missing.iter().next_back() {
+            if last.1 == interval.1 && is_sliver(last) {
+                snapped.1 = last.0;
+            }
+        }
+        snapped
+    }
+
+    fn get_partially_known_tweets(
+        &self,
+        user: &String,
+        interval: &Interval<Snowflake>,
+    ) -> Option<Vec<TweetFromTwitter>> {
+        let interval_store_lock = self.interval_store(user);
+        let (tweets, missing) = interval_store_lock.read().unwrap().get_partial(interval);
+        if missing.contains(interval) {
+            None
+        } else {
+            Some(tweets)
+        }
+    }
+
+    fn interval_store(
+        &self,
+        user: &String,
+    ) -> Arc<RwLock<IntervalStore<Snowflake, TweetFromTwitter>>> {
+        if self.max_cached_tweets.is_some() {
+            self.users_last_used
+                .lock()
+                .unwrap()
+                .insert(user.clone(), Instant::now());
+        }
+        self.tweets.get_or_create(user)
+    }
+
+    fn existing_interval_store(
+        &self,
+        user: &String,
+    ) -> Option<Arc<RwLock<IntervalStore<Snowflake, TweetFromTwitter>>>> {
+        self.tweets.get(user)
+    }
+
+    // Preloaded tweets never expire, and aren't persisted.
+    pub fn preload(&self, preloaded: &[PreloadedInterval]) -> Result<(), String> {
+        for preloaded in preloaded {
+            let interval = Interval(preloaded.from, preloaded.until);
+            let mut tweets = preloaded.tweets.clone();
+            let full_ids: HashSet<Snowflake> = tweets.iter().map(|tweet| tweet.id).collect();
+            tweets.extend(
+                preloaded
+                    .tweet_ids
+                    .iter()
+                    .filter(|id| !full_ids.contains(id))
+                    .cloned()
+                    .map(TweetFromTwitter::new),
+            );
+            tweets.sort();
+            tweets.dedup();
+            if let Some(tweet) = tweets.iter().find(|tweet| !interval.contains(&tweet.id)) {
+                return Err(format!(
+                    "Preloaded tweet {} for {} is outside {} - {}",
+                    tweet.id, preloaded.user, interval.0, interval.1
+                ));
+            }
+            {
+                let interval_store_lock = self.interval_store(&preloaded.user);
+                let mut interval_store = interval_store_lock.write().unwrap();
+                interval_store.insert(&interval, tweets)?;
+            }
+            self.record_fetch_result(&preloaded.user, true);
+            self.record_provenance(&preloaded.user, &interval, TweetSource::Preload);
+        }
+        Ok(())
+    }
+}
+
+impl SourceClient for TweetStore {
+    fn get(&self, service: &str, request: &ApiRequest) -> Result<String, FetchError> {
+        self.send_unauthenticated(service, request)
+    }
+}
+
+#[derive(Deserialize)]
+struct LookedUpTweet {
+    pub id: Snowflake,
+    #[serde(default)]
+    pub withheld_in_countries: Vec<String>,
+    #[serde(default)]
+    pub withheld_copyright: bool,
+}
+
+impl LookedUpTweet {
+    fn is_withheld(&self) -> bool {
+        !self.withheld_in_countries.is_empty() || self.withheld_copyright
+    }
+}
+
+// Any errors in a response, which is otherwise e.g. a tweet.
+#[derive(Deserialize)]
+struct ErrorsFromTwitter {
+    #[serde(default)]
+    pub errors: Vec<ErrorFromTwitter>,
+}
+
+#[derive(Deserialize)]
+struct ErrorFromTwitter {
+    pub code: u64,
+}
+
+#[derive(Deserialize)]
+struct UserFromTwitter {
+    pub statuses_count: u64,
+}
+
+#[derive(Deserialize)]
+struct ResponseFromTwitter {
+    pub results: Vec<TweetFromTwitter>,
+    // Present if there are more pages of results, to be passed back to get the next one.
+    pub next: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CacheLimits, IntervalProvenance, PreloadedInterval, ProvenanceIndex, RefreshPolicy,
+        RevalidationPolicy, SearchEnvironments, SecondsSinceUnixEpoch, Snowflake, StaleIfError,
+        TweetEntities, TweetFromTwitter, TweetMedia, TweetRefreshPolicy, TweetSource, TweetStore,
+        STATUSES_LOOKUP_URL, STATUSES_SHOW_URL, USERS_SHOW_URL,
+    };
+    use cassette::{ApiRequest, ApiResponse, Cassette, CassetteMode};
+    use export::ExportFormat;
+    use intervalstore::ConflictPolicy;
+    use oauth;
+    use serde_json;
+    use std::collections::HashSet;
+    use std::env;
+    use std::time::{Duration, Instant};
+    use twitter::Twitter;
+    use {FetchStrategy, Interval};
+
+    // A store which can't make any requests, as it only replays from an empty cassette.
+    fn offline_store(refresh_policy: RefreshPolicy) -> TweetStore {
+        let dir = env::temp_dir().join(format!("twimetravel-offline-{}", ::std::process::id()));
+        TweetStore::new(
+            oauth::Oauth1Token {
+                oauth_token: "token".to_owned(),
+                oauth_token_secret: "secret".to_owned(),
+            },
+            HashSet::new(),
+            StaleIfError::default(),
+            refresh_policy,
+            FetchStrategy::default(),
+            SearchEnvironments::default(),
+            vec![],
+        )
+        .with_cassette(Cassette::new(CassetteMode::Replay, dir))
+    }
+
+    #[test]
+    fn revalidate_skips_protected_accounts() {
+        let store = offline_store(RefreshPolicy::default());
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        store
+            .protected_accounts
+            .lock()
+            .unwrap()
+            .insert(user.clone(), HashSet::new());
+
+        let policy = RevalidationPolicy {
+            max_age: None,
+            drop_withheld: true,
+        };
+        assert_eq!(store.revalidate(&policy).unwrap(), 0);
+        assert_eq!(
+            store
+                .existing_interval_store(&user)
+                .unwrap()
+                .read()
+                .unwrap()
+                .get(&Interval(Snowflake(10), Snowflake(20))),
+            Some(vec![TweetFromTwitter::new(Snowflake(15))])
+        );
+    }
+
+    #[test]
+    fn provenance_index_keeps_latest_fetch_of_each_part() {
+        let fetched = |from: u64, until: u64, source: TweetSource, at: u64| IntervalProvenance {
+            interval: Interval(Snowflake(from), Snowflake(until)),
+            source,
+            fetched_at: SecondsSinceUnixEpoch(at),
+        };
+        let mut index = ProvenanceIndex::default();
+        index.record(fetched(10, 30, TweetSource::Persistence, 1));
+        index.record(fetched(40, 50, TweetSource::Import, 2));
+        index.record(fetched(20, 45, TweetSource::UserTimeline, 3));
+
+        let parts: Vec<_> = index
+            .iter()
+            .map(|part| ((part.interval.0).0, (part.interval.1).0, part.source))
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                (10, 19, TweetSource::Persistence),
+                (20, 45, TweetSource::UserTimeline),
+                (46, 50, TweetSource::Import),
+            ]
+        );
+        assert_eq!(
+            index.at(Snowflake(30)).map(|part| part.fetched_at),
+            Some(SecondsSinceUnixEpoch(3))
+        );
+        assert_eq!(
+            index.at(Snowflake(50)).map(|part| part.source),
+            Some(TweetSource::Import)
+        );
+        assert!(index.at(Snowflake(5)).is_none());
+        assert!(index.at(Snowflake(51)).is_none());
+    }
+
+    #[test]
+    fn provenance_index_merges_adjacent_parts_from_same_source() {
+        let fetched = |from: u64, until: u64, at: u64| IntervalProvenance {
+            interval: Interval(Snowflake(from), Snowflake(until)),
+            source: TweetSource::UserTimeline,
+            fetched_at: SecondsSinceUnixEpoch(at),
+        };
+        let mut index = ProvenanceIndex::default();
+        index.record(fetched(10, 20, 2));
+        index.record(fetched(30, 40, 1));
+        index.record(fetched(21, 29, 3));
+
+        let parts: Vec<_> = index
+            .iter()
+            .map(|part| ((part.interval.0).0, (part.interval.1).0, part.fetched_at))
+            .collect();
+        assert_eq!(parts, vec![(10, 40, SecondsSinceUnixEpoch(1))]);
+    }
+
+    #[test]
+    fn tweets_due_for_refresh_are_refetched() {
+        let store = offline_store(RefreshPolicy {
+            user_timeline_secs: Some(60),
+            ..RefreshPolicy::default()
+        });
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(40),
+                tweet_ids: vec![Snowflake(15), Snowflake(35)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        assert_eq!(
+            store
+                .known_or_missing_tweets(&user, &Interval(Snowflake(10), Snowflake(40)))
+                .map(|tweets| tweets.len()),
+            Ok(2)
+        );
+
+        store
+            .provenance
+            .write()
+            .unwrap()
+            .get_mut(&user)
+            .unwrap()
+            .record(IntervalProvenance {
+                interval: Interval(Snowflake(30), Snowflake(50)),
+                source: TweetSource::UserTimeline,
+                fetched_at: SecondsSinceUnixEpoch(0),
+            });
+        let missing = store
+            .known_or_missing_tweets(&user, &Interval(Snowflake(10), Snowflake(40)))
+            .unwrap_err();
+        assert_eq!(
+            missing.iter().collect::<Vec<_>>(),
+            vec![&Interval(Snowflake(30), Snowflake(40))]
+        );
+    }
+
+    #[test]
+    fn refetched_tweets_replace_cached_ones() {
+        let store = offline_store(RefreshPolicy::default());
+        let user = "someone".to_owned();
+        let interval = Interval(Snowflake(10), Snowflake(40));
+        store
+            .cache(&user, &interval, vec![TweetFromTwitter::new(Snowflake(15))])
+            .unwrap();
+        // Tweets from anywhere else mustn't silently change what was cached.
+        assert!(store
+            .cache(&user, &interval, vec![TweetFromTwitter::new(Snowflake(20))])
+            .is_err());
+
+        store
+            .store_fetched(
+                &user,
+                &interval,
+                vec![TweetFromTwitter::new(Snowflake(20))],
+                TweetSource::UserTimeline,
+            )
+            .unwrap();
+        assert_eq!(
+            store
+                .existing_interval_store(&user)
+                .unwrap()
+                .read()
+                .unwrap()
+                .get(&interval),
+            Some(vec![TweetFromTwitter::new(Snowflake(20))])
+        );
+    }
+
+    #[test]
+    fn export_writes_every_tweet_in_interval() {
+        let store = offline_store(RefreshPolicy::default());
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(12), Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        let mut contents = Vec::new();
+        assert_eq!(
+            store.export(
+                None,
+                &user,
+                &Interval(Snowflake(10), Snowflake(20)),
+                ExportFormat::Ndjson,
+                &mut contents
+            ),
+            Ok(2)
+        );
+        assert_eq!(String::from_utf8(contents).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn search_environments_default_per_product() {
+        assert_eq!(
+            serde_json::from_str::<SearchEnvironments>(r#"{"full_archive": "prod"}"#).unwrap(),
+            SearchEnvironments {
+                thirty_day: Some("dev".to_owned()),
+                full_archive: Some("prod".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn timeline_horizon_is_kept_until_statuses_count_changes() {
+        let dir = env::temp_dir().join(format!("twimetravel-horizon-{}", ::std::process::id()));
+        let recorder = Cassette::new(CassetteMode::Record, dir.clone());
+        let user = "someone".to_owned();
+        let respond = |request: ApiRequest, body: String| {
+            let response = ApiResponse {
+                status: 200,
+                headers: vec![],
+                body,
+            };
+            recorder.record(&request, &response).unwrap();
+        };
+        let statuses_count = |count: u64| {
+            respond(
+                ApiRequest::get(USERS_SHOW_URL, TweetStore::users_show_params(&user)),
+                format!("{{\"statuses_count\": {}}}", count),
+            )
+        };
+        // user_timeline pages back to the oldest tweet, then returns nothing from before it.
+        let oldest_reachable = |oldest: u64| {
+            let page = vec![TweetFromTwitter::new(Snowflake(oldest))];
+            respond(
+                Twitter::usertimeline_request(&user, None, None),
+                serde_json::to_string(&page).unwrap(),
+            );
+            respond(
+                Twitter::usertimeline_request(&user, None, Some(Snowflake(oldest))),
+                serde_json::to_string(&page).unwrap(),
+            );
+        };
+        let store = offline_store(RefreshPolicy::default())
+            .with_cassette(Cassette::new(CassetteMode::Replay, dir));
+        let reaches = |from: u64| {
+            store
+                .timeline_horizon(None, &user)
+                .unwrap()
+                .reaches(&Interval(Snowflake(from), Snowflake(300)))
+        };
+
+        statuses_count(5000);
+        oldest_reachable(100);
+        assert!(reaches(150));
+
+        // The user hasn't tweeted since, so the horizon isn't looked up again.
+        oldest_reachable(200);
+        assert!(reaches(150));
+
+        statuses_count(5001);
+        assert!(!reaches(150));
+        assert!(reaches(200));
+    }
+
+    #[test]
+    fn saved_tweets_load_after_a_recent_interval_expires() {
+        let store = offline_store(RefreshPolicy::default());
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        store
+            .interval_store(&user)
+            .write()
+            .unwrap()
+            .insert_with_expiry(
+                &Interval(Snowflake(30), Snowflake(40)),
+                vec![TweetFromTwitter::new(Snowflake(35))],
+                ConflictPolicy::Reject,
+                Instant::now() - Duration::from_secs(1),
+            )
+            .unwrap();
+        let path = env::temp_dir().join(format!("twimetravel-save-{}", ::std::process::id()));
+        store.save(&path).unwrap();
+
+        let loaded = offline_store(RefreshPolicy::default());
+        assert_eq!(loaded.load(&path), Ok(1));
+        let interval_store_lock = loaded.existing_interval_store(&user).unwrap();
+        let interval_store = interval_store_lock.read().unwrap();
+        assert_eq!(
+            interval_store.covered().iter().collect::<Vec<_>>(),
+            vec![&Interval(Snowflake(10), Snowflake(20))]
+        );
+        assert!(interval_store
+            .get_available(&Interval(Snowflake(30), Snowflake(40)))
+            .is_empty());
+    }
+
+    #[test]
+    fn refresh_keeps_tweets_which_are_hidden_rather_than_deleted() {
+        let dir = env::temp_dir().join(format!("twimetravel-refresh-{}", ::std::process::id()));
+        let recorder = Cassette::new(CassetteMode::Record, dir.clone());
+        let respond = |url: &str, query: Vec<(&str, &str)>, status: u16, body: &str| {
+            let query = query
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect();
+            let response = ApiResponse {
+                status,
+                headers: vec![],
+                body: body.to_owned(),
+            };
+            recorder
+                .record(&ApiRequest::get(url, query), &response)
+                .unwrap();
+        };
+        respond(
+            STATUSES_LOOKUP_URL,
+            vec![
+                ("id", "12,15"),
+                ("include_entities", "true"),
+                ("trim_user", "false"),
+            ],
+            200,
+            "[]",
+        );
+        // 12 is from an account which has since been suspended; 15 has been deleted.
+        respond(
+            STATUSES_SHOW_URL,
+            vec![("id", "12"), ("trim_user", "true")],
+            403,
+            r#"{"errors": [{"code": 63, "message": "User has been suspended."}]}"#,
+        );
+        respond(
+            STATUSES_SHOW_URL,
+            vec![("id", "15"), ("trim_user", "true")],
+            404,
+            r#"{"errors": [{"code": 144, "message": "No status found with that ID."}]}"#,
+        );
+
+        let store = offline_store(RefreshPolicy::default())
+            .with_cassette(Cassette::new(CassetteMode::Replay, dir));
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(12), Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        // Tweets with no provenance count as stale.
+        store.provenance.write().unwrap().clear();
+
+        let policy = TweetRefreshPolicy {
+            max_staleness: Duration::from_secs(0),
+            max_tweets: None,
+        };
+        assert_eq!(store.refresh_stale(&policy).unwrap(), (0, 1));
+        assert_eq!(
+            store
+                .existing_interval_store(&user)
+                .unwrap()
+                .read()
+                .unwrap()
+                .get(&Interval(Snowflake(10), Snowflake(20))),
+            Some(vec![TweetFromTwitter::new(Snowflake(12))])
+        );
+    }
+
+    #[test]
+    fn gone_tweets_are_forgotten_from_threads() {
+        let store = offline_store(RefreshPolicy::default());
+        let user = "someone".to_owned();
+        store
+            .preload(&[PreloadedInterval {
+                user: user.clone(),
+                from: Snowflake(10),
+                until: Snowflake(20),
+                tweet_ids: vec![Snowflake(12), Snowflake(15)],
+                tweets: vec![],
+            }])
+            .unwrap();
+        for &id in [12, 15].iter() {
+            store
+                .tweets_by_id
+                .lock()
+                .unwrap()
+                .insert(Snowflake(id), TweetFromTwitter::new(Snowflake(id)));
+        }
+        store.conversations.lock().unwrap().insert(
+            Snowflake(12),
+            vec![Snowflake(12), Snowflake(15)].into_iter().collect(),
+        );
+
+        let gone = vec![Snowflake(12)].into_iter().collect();
+        let interval_store_lock = store.existing_interval_store(&user).unwrap();
+        assert_eq!(
+            store
+                .replace_cached(&user, &interval_store_lock, &[], &gone)
+                .unwrap(),
+            1
+        );
+        let mut tweets_by_id = store.tweets_by_id.lock().unwrap();
+        assert!(tweets_by_id.get(&Snowflake(12)).is_none());
+        assert!(tweets_by_id.get(&Snowflake(15)).is_some());
+        assert_eq!(
+            store.conversations.lock().unwrap().get(&Snowflake(12)),
+            Some(&vec![Snowflake(15)].into_iter().collect())
+        );
+    }
+
+    #[test]
+    fn evicted_users_media_urls_are_forgotten() {
+        let store = offline_store(RefreshPolicy::default()).with_cache_limits(CacheLimits {
+            max_tweets_per_user: None,
+            max_tweets: Some(1),
+        });
+        let with_media = |id: u64| {
+            let mut tweet = TweetFromTwitter::new(Snowflake(id));
+            tweet.entities = Some(TweetEntities {
+                media: vec![TweetMedia {
+                    id: Snowflake(id + 1000),
+                    media_url_https: format!("https://pbs.twimg.com/media/{}.jpg", id),
+                    media_type: "photo".to_owned(),
+                }],
+                ..TweetEntities::default()
+            });
+            tweet
+        };
+        store
+            .import(&"someone".to_owned(), vec![with_media(10)])
+            .unwrap();
+        assert!(store.media_url(Snowflake(1010)).is_some());
+
+        store
+            .import(&"someone_else".to_owned(), vec![with_media(20)])
+            .unwrap();
+        assert_eq!(store.media_url(Snowflake(1010)), None);
+        assert!(store.media_url(Snowflake(1020)).is_some());
+    }
 }
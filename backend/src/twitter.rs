@@ -0,0 +1,216 @@
+use cassette::ApiRequest;
+use serde_json;
+use source::{SocialSource, SourceCapabilities, SourceClient};
+use tweetstore::{FetchError, Snowflake, TweetFromTwitter};
+use Interval;
+
+const USER_TIMELINE_URL: &str = "https://api.twitter.com/1.1/statuses/user_timeline.json";
+pub const USER_TIMELINE_ENDPOINT: &str = "statuses/user_timeline";
+// The most tweets user_timeline returns per request.
+pub const USER_TIMELINE_PAGE_SIZE: usize = 200;
+// user_timeline only reaches back this many of a user's most recent tweets.
+pub const USER_TIMELINE_LIMIT: u64 = 3200;
+// More pages than this can't yield anything.
+pub const MAX_USER_TIMELINE_PAGES: usize =
+    USER_TIMELINE_LIMIT as usize / USER_TIMELINE_PAGE_SIZE + 1;
+
+// Twitter users' timelines, keyed by screen name. Requests need auth, and user_timeline only
+// reaches back so far, so the client is expected to authenticate them and the caller to check
+// the interval is reachable; TweetStore does both.
+pub struct Twitter;
+
+impl Twitter {
+    // Tweets after since_id, up to and including max_id (each, if any); at most a page's worth,
+    // newest first.
+    pub fn fetch_page(
+        client: &dyn SourceClient,
+        user: &str,
+        since_id: Option<Snowflake>,
+        max_id: Option<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        info!("Fetching from user timeline"); // TODO: Binary log requests and responses.
+
+        let request = Twitter::usertimeline_request(user, since_id, max_id);
+        let json_string = client.get(USER_TIMELINE_ENDPOINT, &request)?;
+        serde_json::from_str(&json_string)
+            .map_err(|err| format!("Error parsing JSON from Twitter: {:?}", err).into())
+    }
+
+    pub fn usertimeline_request(
+        user: &str,
+        since_id: Option<Snowflake>,
+        max_id: Option<Snowflake>,
+    ) -> ApiRequest {
+        let mut params = vec![
+            ("screen_name".to_owned(), user.to_owned()),
+            ("count".to_owned(), format!("{}", USER_TIMELINE_PAGE_SIZE)),
+            // Always fetch everything, and filter per TimelineOptions when serving. Excluding
+            // replies here would also make pages come back short or empty, which looks the same
+            // as having paged back as far as user_timeline goes.
+            ("include_rts".to_owned(), "true".to_owned()),
+            ("exclude_replies".to_owned(), "false".to_owned()),
+        ];
+        if let Some(max_id) = max_id {
+            params.push(("max_id".to_owned(), format!("{}", max_id)));
+        }
+        if let Some(since_id) = since_id {
+            params.push(("since_id".to_owned(), format!("{}", since_id)));
+        }
+        ApiRequest::get(USER_TIMELINE_URL, params)
+    }
+}
+
+impl SocialSource for Twitter {
+    fn name(&self) -> &'static str {
+        "twitter"
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        SourceCapabilities {
+            reposts: true,
+            replies: true,
+            entities: true,
+        }
+    }
+
+    fn parse_key(&self, key: &str) -> Result<String, String> {
+        parse_screen_name(key)
+    }
+
+    // Tweet ids are already Snowflakes.
+    fn snowflake(&self, post_id: &str) -> Option<Snowflake> {
+        post_id.parse().ok().map(Snowflake)
+    }
+
+    fn post_id(&self, snowflake: Snowflake) -> String {
+        format!("{}", snowflake)
+    }
+
+    // Pages back from the end of the interval until Twitter runs out of tweets in it. Within the
+    // timeline's horizon, running out of tweets means there are no more, so even an empty
+    // interval is known to be empty.
+    fn fetch_timeline(
+        &self,
+        client: &dyn SourceClient,
+        key: &str,
+        interval: &Interval<Snowflake>,
+    ) -> Result<Vec<TweetFromTwitter>, FetchError> {
+        let mut tweets = Vec::new();
+        let mut max_id = interval.1;
+        for _ in 0..MAX_USER_TIMELINE_PAGES {
+            let page = Twitter::fetch_page(client, key, Some(interval.0), Some(max_id))?;
+            let next_max_id = next_max_id(interval, &page);
+            tweets.extend(page);
+            match next_max_id {
+                Some(next_max_id) => max_id = next_max_id,
+                None => break,
+            }
+        }
+        tweets.sort();
+        tweets.dedup();
+        Ok(tweets)
+    }
+}
+
+// Screen names are up to 15 letters, digits and underscores.
+pub fn parse_screen_name(screen_name: &str) -> Result<String, String> {
+    let screen_name = screen_name.trim_start_matches('@');
+    if !screen_name.is_empty()
+        && screen_name.len() <= 15
+        && screen_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Ok(screen_name.to_owned())
+    } else {
+        Err(format!(
+            "Bad Twitter screen name {:?}, expected e.g. someone",
+            screen_name
+        ))
+    }
+}
+
+// Where to page back to after the page, or None if it was the last page in the interval.
+pub fn next_max_id(interval: &Interval<Snowflake>, page: &[TweetFromTwitter]) -> Option<Snowflake> {
+    let oldest = page.iter().map(|tweet| tweet.id).min()?;
+    // max_id is inclusive, so continue from just before the oldest tweet seen.
+    if oldest <= interval.0 || oldest.0 == 0 {
+        None
+    } else {
+        Some(Snowflake(oldest.0 - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_screen_name, Twitter};
+    use cassette::ApiRequest;
+    use serde_json;
+    use source::{SocialSource, SourceClient};
+    use std::sync::Mutex;
+    use tweetstore::{FetchError, Snowflake, TweetFromTwitter};
+    use Interval;
+
+    // Serves a timeline of the given tweets, a page of at most two at a time, recording each
+    // request's max_id.
+    struct Timeline {
+        ids: Vec<u64>,
+        max_ids: Mutex<Vec<Option<String>>>,
+    }
+
+    impl SourceClient for Timeline {
+        fn get(&self, _endpoint: &str, request: &ApiRequest) -> Result<String, FetchError> {
+            let param = |name: &str| {
+                request
+                    .query
+                    .iter()
+                    .find(|param| param.0 == name)
+                    .map(|param| param.1.clone())
+            };
+            let max_id = param("max_id");
+            self.max_ids.lock().unwrap().push(max_id.clone());
+            let max_id: u64 = max_id.map_or(u64::max_value(), |max_id| max_id.parse().unwrap());
+            let since_id: u64 = param("since_id").map_or(0, |since_id| since_id.parse().unwrap());
+            let page: Vec<TweetFromTwitter> = self
+                .ids
+                .iter()
+                .rev()
+                .filter(|&&id| id > since_id && id <= max_id)
+                .take(2)
+                .map(|&id| TweetFromTwitter::new(Snowflake(id)))
+                .collect();
+            Ok(serde_json::to_string(&page).unwrap())
+        }
+    }
+
+    #[test]
+    fn parse_screen_names() {
+        assert_eq!(parse_screen_name("@some_one"), Ok("some_one".to_owned()));
+        assert!(parse_screen_name("").is_err());
+        assert!(parse_screen_name("someone_with_a_long_name").is_err());
+        assert!(parse_screen_name("someone/../x").is_err());
+    }
+
+    #[test]
+    fn fetch_timeline_pages_back_through_interval() {
+        let timeline = Timeline {
+            ids: vec![5, 10, 15, 20, 25],
+            max_ids: Mutex::new(vec![]),
+        };
+        let tweets = Twitter
+            .fetch_timeline(&timeline, "someone", &Interval(Snowflake(9), Snowflake(22)))
+            .unwrap();
+        assert_eq!(
+            tweets.iter().map(|tweet| tweet.id).collect::<Vec<_>>(),
+            vec![Snowflake(10), Snowflake(15), Snowflake(20)]
+        );
+        assert_eq!(
+            *timeline.max_ids.lock().unwrap(),
+            vec![
+                Some("22".to_owned()),
+                Some("14".to_owned()),
+                Some("9".to_owned())
+            ]
+        );
+    }
+}
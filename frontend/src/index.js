@@ -121,8 +121,21 @@ class App extends React.Component {
     this.updateTweets = this.updateTweets.bind(this);
   }
 
+  componentDidMount() {
+    // Share links open /replay/:who/:from/:until/:speed/:expires/:signature, whose feed is served
+    // from /shared with the same path.
+    let sharedPath = window.location.pathname.match(/^\/replay\/([^/]+\/[^/]+\/[^/]+\/([^/]+)\/[^/]+\/[^/]+)$/);
+    if (sharedPath) {
+      this.fetchTweets("/shared/" + sharedPath[1], parseFloat(sharedPath[2]));
+    }
+  }
+
   updateTweets(who, from, until, rate) {
-    request(window.location.protocol + "//" + window.location.host + "/feed/" + who + "/" + (from / 1000) + "/" + (until / 1000), (error, response, body) => {
+    this.fetchTweets("/feed/" + who + "/" + (from / 1000) + "/" + (until / 1000), rate);
+  }
+
+  fetchTweets(path, rate) {
+    request(window.location.protocol + "//" + window.location.host + path, (error, response, body) => {
       if (error || response.statusCode !== 200) {
         this.setError(error || response.statusCode);
         return;